@@ -0,0 +1,11 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_x11_remapper::Config;
+
+fuzz_target!(|yaml: &str| {
+    // Any input is either a config or an error pointing into it, never a panic
+    if let Err(e) = Config::from_yaml(yaml) {
+        let _ = e.to_string();
+    }
+});
@@ -0,0 +1,15 @@
+#![no_main]
+
+use libfuzzer_sys::fuzz_target;
+use simple_x11_remapper::key_mapper::KeyParser;
+
+fuzz_target!(|key_expr: &str| {
+    let parser = KeyParser::new();
+    let Ok((_, modifiers)) = parser.parse(key_expr) else {
+        return;
+    };
+    // A parsed expression names a key, and every modifier it names
+    assert!(!key_expr.is_empty());
+    let separators = key_expr.matches('-').count();
+    assert!(modifiers == 0 || separators > 0, "{:?}", key_expr);
+});
@@ -0,0 +1,101 @@
+//! The X requests behind focus tracking and key grabs, as a trait so the
+//! logic deciding them can be unit-tested without a display.
+//! [`WindowManager`](crate::window_manager::WindowManager) implements it
+//! with Xlib, [`mock::MockBackend`] in memory.
+
+use crate::key_mapper::KeyPress;
+use x11::xlib::Window;
+
+pub trait XBackend {
+    /// The focused client window, or a root window when there's none.
+    fn active_window(&mut self) -> Option<Window>;
+    fn window_class(&self, window: Window) -> Option<String>;
+    fn window_role(&self, window: Window) -> Option<String>;
+    /// The window's _NET_WM_STATE flags, as `WindowManager` names them.
+    fn window_states(&self, window: Window) -> Vec<String>;
+    /// The center of `window` in root coordinates.
+    fn window_center(&self, window: Window) -> Option<(i32, i32)>;
+    /// Grabs `keys` on `window`, those also in `sync` in synchronous mode.
+    /// Returns the keys another client had already grabbed.
+    fn grab_keys(&mut self, window: Window, keys: &[KeyPress], sync: &[KeyPress]) -> Vec<KeyPress>;
+    /// Releases `keys` on `window`, which may no longer exist.
+    fn ungrab_keys(&mut self, window: Window, keys: &[KeyPress]);
+}
+
+#[cfg(test)]
+pub mod mock {
+    use super::*;
+    use std::collections::HashMap;
+
+    #[derive(Debug, Default, Clone)]
+    pub struct MockWindow {
+        pub class: Option<String>,
+        pub role: Option<String>,
+        pub states: Vec<String>,
+        pub center: Option<(i32, i32)>,
+    }
+
+    /// A display held in memory: its windows and the grabs made on them.
+    #[derive(Debug, Default)]
+    pub struct MockBackend {
+        pub active: Option<Window>,
+        pub windows: HashMap<Window, MockWindow>,
+        /// Grabs in place: the window, the key and whether it's synchronous.
+        pub grabs: Vec<(Window, KeyPress, bool)>,
+        /// Keys another client grabbed, which fail to grab.
+        pub taken: Vec<KeyPress>,
+        /// How many keys were grabbed or ungrabbed, to check that updates
+        /// don't redo grabs needlessly.
+        pub requests: usize,
+    }
+
+    impl XBackend for MockBackend {
+        fn active_window(&mut self) -> Option<Window> {
+            self.active
+        }
+
+        fn window_class(&self, window: Window) -> Option<String> {
+            self.windows.get(&window)?.class.clone()
+        }
+
+        fn window_role(&self, window: Window) -> Option<String> {
+            self.windows.get(&window)?.role.clone()
+        }
+
+        fn window_states(&self, window: Window) -> Vec<String> {
+            self.windows
+                .get(&window)
+                .map(|w| w.states.clone())
+                .unwrap_or_default()
+        }
+
+        fn window_center(&self, window: Window) -> Option<(i32, i32)> {
+            self.windows.get(&window)?.center
+        }
+
+        fn grab_keys(
+            &mut self,
+            window: Window,
+            keys: &[KeyPress],
+            sync: &[KeyPress],
+        ) -> Vec<KeyPress> {
+            let mut failed = Vec::new();
+            for &key in keys {
+                self.requests += 1;
+                if self.taken.contains(&key) {
+                    failed.push(key);
+                    continue;
+                }
+                self.grabs.retain(|&(w, k, _)| (w, k) != (window, key));
+                self.grabs.push((window, key, sync.contains(&key)));
+            }
+            failed
+        }
+
+        fn ungrab_keys(&mut self, window: Window, keys: &[KeyPress]) {
+            self.requests += keys.len();
+            self.grabs
+                .retain(|&(w, k, _)| w != window || !keys.contains(&k));
+        }
+    }
+}
@@ -1,108 +1,2673 @@
-use log::warn;
-use serde::{Deserialize, Serialize};
-use serde_yaml::Value;
-use std::collections::HashMap;
+use crate::key_mapper::KeyParser;
+use crate::template;
+use anyhow::Context;
+use log::{info, warn};
+use serde::de::{self, MapAccess, SeqAccess, Visitor};
+use serde::ser::SerializeMap;
+use serde::{Deserialize, Deserializer, Serialize, Serializer};
+use std::collections::BTreeMap;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::sync::OnceLock;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct Config {
+    /// Pause inserted between the keys of a sequence, in milliseconds.
+    #[serde(default)]
+    pub key_delay_ms: u64,
+    /// How close together the two keys of a chord like `j+k` must be pressed.
+    #[serde(default = "default_chord_timeout_ms", alias = "chord_window_ms")]
+    pub chord_timeout_ms: u64,
+    /// How long a key with a `hold` action must be held before that action
+    /// runs instead of the tap one.
+    #[serde(default = "default_hold_timeout_ms")]
+    pub hold_timeout_ms: u64,
+    /// How long a dual-role key (`caps_dual_role` or a home-row mod) may be
+    /// down and still be tapped.
+    #[serde(default = "default_tapping_term_ms")]
+    pub tapping_term_ms: u64,
+    /// Watch keys with XRecord instead of grabbing them where possible.
+    /// Remaps with `also_send_original` then need no grab and leave the combo
+    /// to other clients; the rest still grab so the original is swallowed.
+    /// Without XRecord, those are grabbed synchronously and replayed.
+    #[serde(default)]
+    pub observe: bool,
+    /// Window classes (e.g. VirtualBox, remote desktop clients, games) in
+    /// which nothing is grabbed, whatever the rules say.
+    #[serde(default)]
+    pub ignore_classes: Vec<String>,
+    /// Named groups of window classes, like `browsers: [chromium, firefox]`.
+    /// A group's name in `class_only`, `class_not` or `ignore_classes`
+    /// stands for all of its classes.
+    #[serde(default)]
+    pub classes: BTreeMap<String, Vec<String>>,
+    /// Where keys are grabbed: on the root window, for every window (the
+    /// default), or on the focused client only, regrabbing as focus moves.
+    #[serde(default)]
+    pub grab_on: GrabTarget,
+    /// Show a desktop notification when a named rule is enabled or
+    /// disabled at runtime, remapping is paused or resumed, or the config
+    /// is reloaded.
+    #[serde(default)]
+    pub notify: bool,
+    /// _NET_WM_WINDOW_TYPE values (without the prefix, e.g. `popup_menu`)
+    /// of windows whose focus doesn't switch rules: the previous window's
+    /// remaps stay, so opening a menu doesn't churn the grabs.
+    #[serde(default = "default_skip_window_types")]
+    pub skip_window_types: Vec<String>,
+    /// A shell command run whenever another window gets the focus, with
+    /// its class and title in `WINDOW_CLASS` and `WINDOW_TITLE`.
+    #[serde(default)]
+    pub on_focus_change: Option<String>,
+    /// Shared libraries and `.wasm` modules providing `{plugin: name}`
+    /// actions, resolved relative to the config file like scripts.
+    #[serde(default)]
+    pub plugins: Vec<PluginSource>,
+    /// Abbreviations like `;addr` and the text that replaces them once
+    /// typed, in every window. Typed keys are watched through XRecord.
+    #[serde(default)]
+    pub expansions: BTreeMap<String, String>,
+    /// Remaps grouped by layer name, active while a `{layer: name}` key is
+    /// held. `nav` is built in unless defined here.
+    #[serde(default, deserialize_with = "deserialize_layers")]
+    pub layers: BTreeMap<String, Vec<Remap>>,
+    /// Built-in behaviors turned on by name, like `caps_dual_role`.
+    #[serde(default)]
+    pub presets: Vec<Preset>,
+    /// Keys that type themselves when tapped and hold a modifier otherwise,
+    /// like `{a: Super, s: Alt, d: Shift, f: Ctrl}`.
+    #[serde(default)]
+    pub home_row_mods: BTreeMap<String, HomeRowMod>,
+    /// When a dual-role key (`caps_dual_role` or a home-row mod) pressed
+    /// together with another key counts as held rather than tapped.
+    #[serde(default)]
+    pub hold_flavor: HoldFlavor,
+    /// Follow a remap whose target is remapped too in the same window, so
+    /// `a: b` and `b: c` send `c` for `a` whatever order the grabs see
+    /// them in. A cycle of such remaps is a config error.
+    #[serde(default)]
+    pub resolve_chains: bool,
+    /// Read the `from` keys of remaps, chords and home-row mods as places
+    /// on a US QWERTY keyboard, so `C-q` stays on the key left of `w` with
+    /// a Dvorak or AZERTY layout active. Targets are still keysyms.
+    #[serde(default)]
+    pub physical_keys: bool,
+    /// Remaps for every window. A window rule remapping the same key
+    /// overrides them, and its `mask` lets keys through untouched.
+    #[serde(default, deserialize_with = "deserialize_remaps")]
+    pub global: Vec<Remap>,
+    /// xmodmap-style changes to the server's keyboard and modifier mapping,
+    /// made at startup and undone on exit.
+    #[serde(default, deserialize_with = "deserialize_modmap")]
+    pub modmap: Vec<ModmapEntry>,
     #[serde(default)]
     pub windows: Vec<WindowConfig>,
+    /// Values for `${name}` in the rest of the file, substituted by the
+    /// templating pass before the config is parsed. Not written back out,
+    /// as the rest of a dumped config has them substituted already.
+    #[serde(default, skip_serializing)]
+    pub variables: BTreeMap<String, serde_yaml::Value>,
+    /// Merge this config over the system-wide one at
+    /// [`SYSTEM_CONFIG_PATH`], if there is one.
+    #[serde(default = "default_system_config")]
+    pub system_config: bool,
+    /// The file the config was loaded from, for pointing into it.
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+    /// Remaps given on the command line, added again when the file is
+    /// reloaded.
+    #[serde(skip)]
+    pub adhoc: AdhocRemaps,
+    /// The source line of each remap, by its rule and index in that rule.
+    #[serde(skip)]
+    remap_lines: BTreeMap<(RuleId, usize), usize>,
+}
+
+fn default_chord_timeout_ms() -> u64 {
+    50
 }
 
-#[derive(Debug, Clone, Serialize)]
+fn default_system_config() -> bool {
+    true
+}
+
+fn default_hold_timeout_ms() -> u64 {
+    500
+}
+
+fn default_tapping_term_ms() -> u64 {
+    200
+}
+
+fn default_skip_window_types() -> Vec<String> {
+    [
+        "menu",
+        "dropdown_menu",
+        "popup_menu",
+        "tooltip",
+        "notification",
+        "combo",
+        "dnd",
+    ]
+    .map(str::to_string)
+    .to_vec()
+}
+
+fn default_enabled() -> bool {
+    true
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
 pub struct WindowConfig {
+    /// Name to enable and disable the rule by at runtime.
+    #[serde(default)]
+    pub name: Option<String>,
+    /// A disabled rule never matches.
+    #[serde(default = "default_enabled")]
+    pub enabled: bool,
+    #[serde(default)]
     pub class_only: Option<Vec<String>>,
+    #[serde(default)]
     pub class_not: Option<Vec<String>>,
+    /// XKB layout names (e.g. `us`, `de`) the rule is limited to.
+    #[serde(default)]
+    pub layout_only: Option<Vec<String>>,
+    /// WM_WINDOW_ROLE values the rule is limited to, matched like classes.
+    #[serde(default)]
+    pub role_only: Option<Vec<String>>,
+    /// WM_WINDOW_ROLE values the rule doesn't apply to.
+    #[serde(default)]
+    pub role_not: Option<Vec<String>>,
+    /// RandR monitor names (e.g. `DP-1`, as `xrandr --listmonitors` shows
+    /// them) the focused window must be on, judged by its center.
+    #[serde(default)]
+    pub monitor_only: Option<Vec<String>>,
+    /// _NET_WM_STATE flags (e.g. `fullscreen`, `maximized`, `above`) that
+    /// keep the rule from applying while the window has any of them.
+    #[serde(default)]
+    pub state_not: Option<Vec<String>>,
+    /// A flag a `{toggle: flag}` key turns on and off, which must be on for
+    /// the rule to apply, or off when written `!flag`.
+    #[serde(default)]
+    pub when: Option<String>,
+    #[serde(default, deserialize_with = "deserialize_remaps")]
     pub remaps: Vec<Remap>,
+    /// `global` remaps that don't apply in the matched windows, so these
+    /// keys reach the application.
+    #[serde(default)]
+    pub mask: Vec<String>,
+    /// Rules apply from lowest to highest priority, so a higher one wins
+    /// when both remap a key. Equal priorities go in file order.
+    #[serde(default)]
+    pub priority: i32,
+    /// Apply no rule after this one when it matches, so nothing overrides it.
+    #[serde(default)]
+    pub stop: bool,
+    /// Comes from the system-wide config, so it applies before every rule
+    /// of the user's, whatever their priorities.
+    #[serde(skip)]
+    pub system: bool,
+    /// Overrides the top-level `grab_on` for this rule's remaps.
+    #[serde(default)]
+    pub grab_on: Option<GrabTarget>,
+    /// Text expansions for the matched windows, overriding global ones
+    /// with the same abbreviation.
+    #[serde(default)]
+    pub expansions: BTreeMap<String, String>,
+}
+
+/// The window key grabs are installed on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum GrabTarget {
+    /// The root window: the combo is taken from every application, and
+    /// from the window manager if it grabbed it first.
+    #[default]
+    Root,
+    /// The focused client window, so other applications keep the combo.
+    Client,
+}
+
+/// When a dual-role key pressed together with another key counts as held.
+/// Either way it counts as held once down for `hold_timeout_ms`.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum HoldFlavor {
+    /// Once a key pressed after it is also released before it. Keys rolled
+    /// over while typing fast are let go after it, so they stay plain.
+    #[default]
+    PermissiveHold,
+    /// As soon as another key is pressed.
+    HoldOnOtherKeyPress,
+}
+
+/// A built-in behavior that would be fiddly to spell out as remaps.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Preset {
+    /// Caps Lock sends Escape when tapped alone and is Control while held
+    /// with other keys. A key pressed and released while Caps Lock is down
+    /// gets Control; one still down when Caps Lock is released was a
+    /// rolled-over keystroke and is typed as is after the Escape.
+    CapsDualRole,
+}
+
+impl Preset {
+    pub fn name(self) -> &'static str {
+        match self {
+            Preset::CapsDualRole => "caps_dual_role",
+        }
+    }
+
+    /// The key the preset takes over.
+    pub fn key(self) -> &'static str {
+        match self {
+            Preset::CapsDualRole => "Caps_Lock",
+        }
+    }
 }
 
-#[derive(Debug, Clone, Serialize)]
+#[derive(Debug, Clone, PartialEq)]
 pub struct Remap {
     pub from: String,
     pub to: KeyAction,
+    /// Replay the original key to the focused window after the action.
+    pub also_send_original: bool,
+    /// Let a script action decide at runtime whether the key is swallowed:
+    /// when it evaluates to `false`, the original key goes to the window.
+    pub conditional: bool,
+    /// Fire when the key is pressed (the default) or when it's released.
+    pub on: Trigger,
+    /// Run instead of `to` when the key is held past `hold_timeout_ms`.
+    pub hold: Option<KeyAction>,
+    /// Send the keys to this window instead of the focused one.
+    pub send_to: Option<WindowSelector>,
+    /// Timings of this remap that differ from the config's.
+    pub timing: Timing,
+}
+
+/// Timings a remap overrides, each defaulting to the top-level option of
+/// the same name. `tapping_term_ms` is set per home-row mod instead, see
+/// [`HomeRowMod`], as dual-role keys aren't remaps.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct Timing {
+    /// For a sequence target.
+    pub key_delay_ms: Option<u64>,
+    /// For a chord.
+    pub chord_timeout_ms: Option<u64>,
+    /// For a remap with a `hold` action.
+    pub hold_timeout_ms: Option<u64>,
 }
 
-#[derive(Debug, Clone, Serialize)]
+/// Picks a window by its class, matched like `class_only`.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct WindowSelector {
+    pub class: String,
+}
+
+/// The key event a remap fires on.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Trigger {
+    #[default]
+    Press,
+    Release,
+}
+
+#[derive(Debug, Clone, PartialEq)]
 pub enum KeyAction {
     Single(String),
-    Multiple(Vec<String>),
+    Multiple(Vec<SequenceStep>),
+    Script(PathBuf),
+    /// Switch keyboard layout: `next`, `prev` or a layout name like `us`.
+    Layout(String),
+    /// Run a shell command, without waiting for it.
+    Exec(String),
+    /// Add a modifier like `Super` to the next key pressed, so it doesn't
+    /// need to be held.
+    OneShot(String),
+    /// Have the window manager close, resize or move the focused window.
+    Window(WindowAction),
+    /// Click the mouse where the pointer is: `LeftClick`, `MiddleClick`,
+    /// `RightClick` or `DoubleClick`.
+    Click(MouseClick),
+    /// While the key is held, the remaps of this layer apply in every
+    /// window on top of the others.
+    Layer(String),
+    /// Drive the pointer from the keyboard until the exit key is pressed.
+    MouseMode(MouseModeSettings),
+    /// Start recording the keys typed into a macro slot, or stop recording.
+    RecordMacro(u32),
+    /// Type the keys recorded into a macro slot, with their timing.
+    PlayMacro(u32),
+    /// Type a compose sequence: `Multi_key`, then these keys, e.g. `a` and
+    /// `apostrophe` for `á`.
+    Compose(Vec<String>),
+    /// Run `then` if `modifier` (`Shift`, `Ctrl`, ...) is held when the key
+    /// fires and `otherwise` if not: `{if_shift: 'Home', else: 'Left'}`.
+    IfModifier {
+        modifier: String,
+        then: Box<KeyAction>,
+        otherwise: Box<KeyAction>,
+    },
+    /// Turn a flag on or off, for the rules with a `when` condition on it.
+    Toggle(String),
+    /// Run an action of a loaded plugin: `{plugin: osc52}` or
+    /// `{plugin: {name: ydotool, arg: 'type hello'}}`.
+    Plugin(PluginAction),
+    /// Swallow the key: `null` or `disabled` in the config.
+    Disabled,
+}
+
+/// The conditions of `{if_shift: ..., else: ...}` actions and the
+/// modifiers they test.
+const CONDITIONS: [(&str, &str); 6] = [
+    ("if_shift", "Shift"),
+    ("if_ctrl", "Ctrl"),
+    ("if_alt", "Alt"),
+    ("if_super", "Super"),
+    ("if_altgr", "AltGr"),
+    ("if_hyper", "Hyper"),
+];
+
+/// The modifier an `if_...` condition tests.
+fn condition_modifier(name: &str) -> Option<&'static str> {
+    CONDITIONS
+        .iter()
+        .find(|(condition, _)| *condition == name)
+        .map(|&(_, modifier)| modifier)
+}
+
+/// The `if_...` condition testing `modifier`.
+fn condition_name(modifier: &str) -> &'static str {
+    CONDITIONS
+        .iter()
+        .find(|(_, held)| *held == modifier)
+        .map_or("if_shift", |&(condition, _)| condition)
+}
+
+/// A plugin to load: a path, or `{path: ..., allow_exec: true}` to let a
+/// `.wasm` module run commands.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginSource {
+    pub path: PathBuf,
+    /// Whether a sandboxed plugin may call `exec`.
+    pub allow_exec: bool,
+}
+
+impl PluginSource {
+    /// Whether the plugin is a WebAssembly module, run in a sandbox.
+    pub fn is_wasm(&self) -> bool {
+        self.path.extension().is_some_and(|ext| ext == "wasm")
+    }
+}
+
+/// A `{plugin: ...}` action: the plugin's name, and the argument passed to
+/// it if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct PluginAction {
+    pub name: String,
+    pub arg: Option<String>,
+}
+
+/// A mouse click a key can be remapped to.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseClick {
+    Left,
+    Middle,
+    Right,
+    /// Two clicks of the left button.
+    Double,
+}
+
+impl MouseClick {
+    const ALL: [MouseClick; 4] = [
+        MouseClick::Left,
+        MouseClick::Middle,
+        MouseClick::Right,
+        MouseClick::Double,
+    ];
+
+    pub fn from_name(name: &str) -> Option<Self> {
+        Self::ALL.into_iter().find(|click| click.name() == name)
+    }
+
+    pub fn name(self) -> &'static str {
+        match self {
+            MouseClick::Left => "LeftClick",
+            MouseClick::Middle => "MiddleClick",
+            MouseClick::Right => "RightClick",
+            MouseClick::Double => "DoubleClick",
+        }
+    }
+
+    /// The pointer button to click and how many times.
+    pub fn button(self) -> (u32, u32) {
+        match self {
+            MouseClick::Left => (1, 1),
+            MouseClick::Middle => (2, 1),
+            MouseClick::Right => (3, 1),
+            MouseClick::Double => (1, 2),
+        }
+    }
+}
+
+/// How `{mouse_mode: {...}}` moves the pointer. hjkl move it, faster the
+/// longer they're held, and space clicks.
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct MouseModeSettings {
+    /// The key that leaves mouse mode.
+    #[serde(default = "default_mouse_exit")]
+    pub exit: String,
+    /// Pixels the pointer moves per step when a key is first pressed.
+    #[serde(default = "default_mouse_speed")]
+    pub speed: u32,
+    /// The most pixels per step it speeds up to.
+    #[serde(default = "default_mouse_max_speed")]
+    pub max_speed: u32,
+    /// Pixels per step added for each step the key stays held.
+    #[serde(default = "default_mouse_acceleration")]
+    pub acceleration: f64,
+}
+
+fn default_mouse_exit() -> String {
+    "Escape".to_string()
+}
+
+fn default_mouse_speed() -> u32 {
+    4
+}
+
+fn default_mouse_max_speed() -> u32 {
+    40
+}
+
+fn default_mouse_acceleration() -> f64 {
+    0.5
+}
+
+/// Things the window manager can do to the focused window.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum WindowAction {
+    Close,
+    Maximize,
+    FullscreenToggle,
+    Minimize,
+    /// Move the window to a desktop, counted from 1 as pagers show them.
+    MoveToDesktop(u32),
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum SequenceStep {
+    Key(String),
+    Delay(u64),
+    /// Press a modifier and keep it held for the keys that follow.
+    Down(String),
+    /// Release a modifier held by an earlier `Down`.
+    Up(String),
+    /// Send a key `count` times: `{key: 'Down', repeat: 10}`.
+    Repeat {
+        key: String,
+        count: u32,
+    },
+}
+
+impl SequenceStep {
+    pub fn has_delay(steps: &[SequenceStep]) -> bool {
+        steps
+            .iter()
+            .any(|step| matches!(step, SequenceStep::Delay(_)))
+    }
+}
+
+/// The modifier a home-row key holds: `Super`, or `{modifier: Super,
+/// tapping_term_ms: 250}` to time the key apart from the others.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum HomeRowMod {
+    Modifier(String),
+    Timed {
+        modifier: String,
+        tapping_term_ms: Option<u64>,
+    },
+}
+
+impl HomeRowMod {
+    pub fn modifier(&self) -> &str {
+        match self {
+            HomeRowMod::Modifier(modifier) | HomeRowMod::Timed { modifier, .. } => modifier,
+        }
+    }
+
+    /// Its own tapping term, overriding the top-level `tapping_term_ms`.
+    pub fn tapping_term_ms(&self) -> Option<u64> {
+        match self {
+            HomeRowMod::Modifier(_) => None,
+            HomeRowMod::Timed {
+                tapping_term_ms, ..
+            } => *tapping_term_ms,
+        }
+    }
+}
+
+/// One change to the keyboard mapping, written like an xmodmap line.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(untagged)]
+pub enum ModmapEntry {
+    /// `keycode 66 = Control_L`
+    Keycode { keycode: u8, keysyms: Vec<String> },
+    /// `keysym Caps_Lock = Control_L`: rebinds the key producing `keysym`.
+    Keysym {
+        keysym: String,
+        keysyms: Vec<String>,
+    },
+    /// `clear Lock`
+    Clear { clear: String },
+    /// `add Control = Control_L`
+    Add { add: String, keys: Vec<String> },
+    /// `remove Lock = Caps_Lock`
+    Remove { remove: String, keys: Vec<String> },
+}
+
+/// The index of a modifier in the modifier mapping: `Shift`, `Lock`,
+/// `Control`, `Mod1` to `Mod5`, as xmodmap names them.
+pub fn modifier_index(name: &str) -> Option<usize> {
+    [
+        "shift", "lock", "control", "mod1", "mod2", "mod3", "mod4", "mod5",
+    ]
+    .iter()
+    .position(|m| name.eq_ignore_ascii_case(m))
+}
+
+/// Where a remap comes from: the `global` block or a window rule, by its
+/// index in [`Config::windows`].
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub enum RuleId {
+    Global,
+    Window(usize),
+}
+
+impl fmt::Display for RuleId {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RuleId::Global => f.write_str("global"),
+            RuleId::Window(index) => write!(f, "{}", index),
+        }
+    }
+}
+
+/// The state window rules are matched against.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct MatchContext<'a> {
+    pub window_class: Option<&'a str>,
+    pub layout: Option<&'a str>,
+    pub window_role: Option<&'a str>,
+    pub monitor: Option<&'a str>,
+    /// The window's _NET_WM_STATE flags, as [`WindowConfig::state_not`]
+    /// names them.
+    pub window_states: &'a [String],
+    /// The `{toggle: flag}` flags that are on.
+    pub flags: &'a [String],
+}
+
+/// Remaps given on the command line, like `--remap C-b=Left --class
+/// chromium`, on top of the config file's.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct AdhocRemaps {
+    /// `from=to` pairs, the target written as in the config, like `Left`
+    /// or `{exec: xterm}`.
+    pub remaps: Vec<String>,
+    /// The window classes they apply in; every window without any.
+    pub classes: Vec<String>,
+}
+
+/// The name of the window rule [`AdhocRemaps`] become.
+pub const ADHOC_RULE_NAME: &str = "command line";
+
+impl AdhocRemaps {
+    /// The window rule the remaps make, applied after every other one.
+    fn rule(&self) -> Result<WindowConfig, ConfigError> {
+        let mut remaps = Vec::new();
+        for remap in &self.remaps {
+            let error = |message: String| ConfigError {
+                line: None,
+                column: None,
+                message: format!("--remap '{}': {}", remap, message),
+                source_line: None,
+            };
+            // The first character is never the separator, so `==Left`
+            // remaps the equals key
+            let (from, to) = remap
+                .char_indices()
+                .skip(1)
+                .find(|&(_, c)| c == '=')
+                .map(|(split, _)| (&remap[..split], &remap[split + 1..]))
+                .ok_or_else(|| error("expected FROM=TO, like C-b=Left".to_string()))?;
+            let to: serde_yaml::Value =
+                serde_yaml::from_str(to).map_err(|e| error(e.to_string()))?;
+            let mut entry = serde_yaml::Mapping::new();
+            entry.insert(from.into(), to);
+            remaps.push(serde_yaml::Value::Mapping(entry));
+        }
+        let mut rule = serde_yaml::Mapping::new();
+        rule.insert("name".into(), ADHOC_RULE_NAME.into());
+        if !self.classes.is_empty() {
+            rule.insert("class_only".into(), self.classes.clone().into());
+        }
+        rule.insert("priority".into(), i32::MAX.into());
+        rule.insert("remaps".into(), remaps.into());
+        serde_yaml::from_value(serde_yaml::Value::Mapping(rule)).map_err(|e| ConfigError {
+            line: None,
+            column: None,
+            message: format!("--remap: {}", e),
+            source_line: None,
+        })
+    }
+}
+
+/// A config error, pointing at the line of the YAML source it came from when known.
+#[derive(Debug)]
+pub struct ConfigError {
+    pub line: Option<usize>,
+    pub column: Option<usize>,
+    pub message: String,
+    pub source_line: Option<String>,
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self.line, self.column) {
+            (Some(line), Some(column)) => {
+                write!(f, "line {}, column {}: {}", line, column, self.message)?
+            }
+            (Some(line), None) => write!(f, "line {}: {}", line, self.message)?,
+            _ => write!(f, "{}", self.message)?,
+        }
+        if let (Some(line), Some(source_line)) = (self.line, &self.source_line) {
+            write!(f, "\n{:>5} | {}", line, source_line)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ConfigError {}
+
+impl ConfigError {
+    fn from_yaml_error(err: serde_yaml::Error, content: &str) -> Self {
+        let mut message = err.to_string();
+        let (line, column) = match err.location() {
+            Some(location) => {
+                // serde_yaml appends the location to the message; it is reported separately
+                let suffix = format!(" at line {} column {}", location.line(), location.column());
+                if let Some(stripped) = message.strip_suffix(&suffix) {
+                    message = stripped.to_string();
+                }
+                (Some(location.line()), Some(location.column()))
+            }
+            None => (None, None),
+        };
+        if let Some(suggested) = suggest_field(&message) {
+            message = suggested;
+        }
+        let source_line = line
+            .and_then(|l| content.lines().nth(l - 1))
+            .map(|l| l.to_string());
+
+        Self {
+            line,
+            column,
+            message,
+            source_line,
+        }
+    }
+}
+
+/// Rewrites serde's "unknown field `x`, expected one of ..." to name the
+/// closest field instead of all of them, when one is close enough.
+fn suggest_field(message: &str) -> Option<String> {
+    let (prefix, rest) = message.split_once("unknown field `")?;
+    let (field, expected) = rest.split_once("`, expected ")?;
+    let fields = expected.split('`').skip(1).step_by(2);
+    let closest = closest_name(field, fields)?;
+    Some(format!(
+        "{}unknown field `{}`, did you mean `{}`?",
+        prefix, field, closest
+    ))
+}
+
+/// The candidate nearest to `name` by edit distance, if it's within a third
+/// of the name's length (at least one edit), so only plausible typos match.
+fn closest_name<'a>(name: &str, candidates: impl IntoIterator<Item = &'a str>) -> Option<&'a str> {
+    let limit = (name.chars().count() / 3).max(1);
+    candidates
+        .into_iter()
+        .map(|candidate| (edit_distance(name, candidate), candidate))
+        .filter(|&(distance, _)| distance <= limit)
+        .min_by_key(|&(distance, _)| distance)
+        .map(|(_, candidate)| candidate)
+}
+
+/// Levenshtein distance, counting a swap of adjacent characters as one edit.
+fn edit_distance(a: &str, b: &str) -> usize {
+    let a: Vec<char> = a.to_lowercase().chars().collect();
+    let b: Vec<char> = b.to_lowercase().chars().collect();
+    let mut rows = vec![vec![0; b.len() + 1]; a.len() + 1];
+    for (i, row) in rows.iter_mut().enumerate() {
+        row[0] = i;
+    }
+    rows[0] = (0..=b.len()).collect();
+    for i in 1..=a.len() {
+        for j in 1..=b.len() {
+            let cost = usize::from(a[i - 1] != b[j - 1]);
+            let mut distance = (rows[i - 1][j] + 1)
+                .min(rows[i][j - 1] + 1)
+                .min(rows[i - 1][j - 1] + cost);
+            if i > 1 && j > 1 && a[i - 1] == b[j - 2] && a[i - 2] == b[j - 1] {
+                distance = distance.min(rows[i - 2][j - 2] + 1);
+            }
+            rows[i][j] = distance;
+        }
+    }
+    rows[a.len()][b.len()]
+}
+
+fn key_parser() -> &'static KeyParser {
+    static PARSER: OnceLock<KeyParser> = OnceLock::new();
+    PARSER.get_or_init(KeyParser::new)
+}
+
+fn validate_key_expr<E: de::Error>(key_expr: &str) -> Result<(), E> {
+    key_parser()
+        .parse(key_expr)
+        .map(|_| ())
+        .map_err(|e| E::custom(format!("invalid key expression '{}': {}", key_expr, e)))
+}
+
+fn validate_from<E: de::Error>(from: &str) -> Result<(), E> {
+    let Some((first, second)) = KeyParser::split_chord(from) else {
+        return validate_key_expr(from);
+    };
+    for key in [first, second] {
+        validate_key_expr::<E>(key)?;
+        if key_parser().parse(key).is_ok_and(|(_, mods)| mods != 0) {
+            return Err(E::custom(format!(
+                "chord '{}' can only combine keys without modifiers",
+                from
+            )));
+        }
+    }
+    Ok(())
+}
+
+/// Whether a rule has no conditions, so it matches in every window.
+fn is_catch_all(rule: &WindowConfig) -> bool {
+    rule.class_only.is_none()
+        && rule.class_not.is_none()
+        && rule.layout_only.is_none()
+        && rule.role_only.is_none()
+        && rule.role_not.is_none()
+        && rule.monitor_only.is_none()
+        && rule.state_not.is_none()
+        && rule.when.is_none()
+}
+
+/// Whether two key expressions mean the same key, e.g. `C-b` and `Ctrl-b`.
+pub(crate) fn same_key(a: &str, b: &str) -> bool {
+    match (key_parser().parse(a), key_parser().parse(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+/// Replaces each key target that another remap of `remaps` takes as its
+/// `from` with that remap's action, following chains to their end. Only
+/// remaps without options are followed, since a hold or release trigger
+/// means nothing for a sent key. Returns the keys of a cycle, leaving
+/// `remaps` as they were, when there is one.
+fn resolve_chains(remaps: &mut [(RuleId, Remap)]) -> Result<(), Vec<String>> {
+    let is_plain = |remap: &Remap| {
+        !remap.also_send_original
+            && !remap.conditional
+            && remap.on == Trigger::Press
+            && remap.hold.is_none()
+            && remap.send_to.is_none()
+    };
+    let mut resolved = Vec::with_capacity(remaps.len());
+    for (_, remap) in remaps.iter() {
+        let mut chain = vec![remap.from.clone()];
+        let mut action = &remap.to;
+        while let KeyAction::Single(key) = action {
+            // A key remapped to itself is left to the linter
+            if chain.len() == 1 && same_key(key, &remap.from) {
+                break;
+            }
+            let Some((_, next)) = remaps
+                .iter()
+                .find(|(_, next)| same_key(&next.from, key) && is_plain(next))
+            else {
+                break;
+            };
+            let cycles = chain.iter().any(|from| same_key(from, key));
+            chain.push(key.clone());
+            if cycles {
+                return Err(chain);
+            }
+            action = &next.to;
+        }
+        resolved.push(action.clone());
+    }
+    for ((_, remap), action) in remaps.iter_mut().zip(resolved) {
+        remap.to = action;
+    }
+    Ok(())
+}
+
+fn validate_action<E: de::Error>(action: &KeyAction) -> Result<(), E> {
+    match action {
+        KeyAction::Single(key) => validate_key_expr(key),
+        KeyAction::Multiple(steps) => {
+            for step in steps {
+                match step {
+                    SequenceStep::Key(key) => validate_key_expr(key)?,
+                    SequenceStep::Repeat { count: 0, .. } => {
+                        return Err(E::custom("repeat a key at least once"));
+                    }
+                    SequenceStep::Repeat { key, .. } => validate_key_expr(key)?,
+                    SequenceStep::Down(key) | SequenceStep::Up(key) => {
+                        key_parser().parse_modifier_key(key).map_err(E::custom)?;
+                    }
+                    SequenceStep::Delay(_) => {}
+                }
+            }
+            Ok(())
+        }
+        KeyAction::Compose(keys) => {
+            if keys.is_empty() {
+                return Err(E::custom("a compose sequence needs at least one key"));
+            }
+            keys.iter().try_for_each(|key| validate_key_expr(key))
+        }
+        KeyAction::IfModifier {
+            then, otherwise, ..
+        } => {
+            validate_action(then)?;
+            validate_action(otherwise)
+        }
+        KeyAction::OneShot(name) => key_parser()
+            .parse_modifier_key(name)
+            .map(|_| ())
+            .map_err(E::custom),
+        KeyAction::MouseMode(settings) => validate_key_expr(&settings.exit),
+        KeyAction::Window(WindowAction::MoveToDesktop(0)) => {
+            Err(E::custom("desktops are counted from 1"))
+        }
+        KeyAction::Script(_)
+        | KeyAction::Layout(_)
+        | KeyAction::Exec(_)
+        | KeyAction::Window(_)
+        | KeyAction::Click(_)
+        | KeyAction::Layer(_)
+        | KeyAction::RecordMacro(_)
+        | KeyAction::PlayMacro(_)
+        | KeyAction::Toggle(_)
+        | KeyAction::Plugin(_)
+        | KeyAction::Disabled => Ok(()),
+    }
+}
+
+/// Only a script can tell whether to swallow the key, and chords are
+/// matched across two presses, too late to replay the first one.
+fn validate_conditional<E: de::Error>(remap: &Remap) -> Result<(), E> {
+    if KeyParser::split_chord(&remap.from).is_some() {
+        return Err(E::custom(format!(
+            "'{}': conditional remaps can't be chords",
+            remap.from
+        )));
+    }
+    if !matches!(remap.to, KeyAction::Script(_)) {
+        return Err(E::custom(format!(
+            "'{}': conditional remaps need a {{script: ...}} action",
+            remap.from
+        )));
+    }
+    Ok(())
+}
+
+/// A chord fires once both keys are down, and a conditional remap has to
+/// decide while the press is frozen, so neither can wait for the release.
+/// A long-press remap already acts on release or timeout.
+fn validate_release<E: de::Error>(remap: &Remap) -> Result<(), E> {
+    if KeyParser::split_chord(&remap.from).is_some() {
+        return Err(E::custom(format!(
+            "'{}': chords can't fire on release",
+            remap.from
+        )));
+    }
+    if remap.conditional {
+        return Err(E::custom(format!(
+            "'{}': conditional remaps can't fire on release",
+            remap.from
+        )));
+    }
+    if remap.hold.is_some() {
+        return Err(E::custom(format!(
+            "'{}': remaps with a hold action can't fire on release",
+            remap.from
+        )));
+    }
+    Ok(())
+}
+
+/// The tap or hold is only known once the key is released or the timeout
+/// passes, too late to decide on a chord or a frozen conditional press.
+fn validate_hold<E: de::Error>(remap: &Remap) -> Result<(), E> {
+    if KeyParser::split_chord(&remap.from).is_some() {
+        return Err(E::custom(format!(
+            "'{}': chords can't have a hold action",
+            remap.from
+        )));
+    }
+    if remap.conditional {
+        return Err(E::custom(format!(
+            "'{}': conditional remaps can't have a hold action",
+            remap.from
+        )));
+    }
+    Ok(())
+}
+
+/// `{layer: name}` and `{toggle: flag}` change what keys are grabbed for,
+/// so they need the key's own press, and a layer its release too: they
+/// can't be a hold action, a branch of an `if_*` action, a chord's or fire
+/// on release. `name` is the action's, `is_own` picks it out.
+fn validate_own_action<E: de::Error>(
+    remap: &Remap,
+    name: &str,
+    is_own: fn(&KeyAction) -> bool,
+) -> Result<(), E> {
+    let problem = if remap.hold.as_ref().is_some_and(|hold| nests(hold, is_own)) {
+        "can't be a hold action"
+    } else if is_own(&remap.to) && KeyParser::split_chord(&remap.from).is_some() {
+        "can't be a chord's"
+    } else if is_own(&remap.to) && remap.on == Trigger::Release {
+        "can't fire on release"
+    } else if !is_own(&remap.to) && nests(&remap.to, is_own) {
+        "can't be in an if_* branch"
+    } else {
+        return Ok(());
+    };
+    Err(E::custom(format!(
+        "'{}': a {} action {}",
+        remap.from, name, problem
+    )))
+}
+
+/// Whether `action` or a branch of it is one `matches`.
+fn nests(action: &KeyAction, matches: fn(&KeyAction) -> bool) -> bool {
+    match action {
+        KeyAction::IfModifier {
+            then, otherwise, ..
+        } => matches(action) || nests(then, matches) || nests(otherwise, matches),
+        _ => matches(action),
+    }
+}
+
+/// A timing only means something for the kind of remap it times.
+fn validate_timing<E: de::Error>(remap: &Remap) -> Result<(), E> {
+    if remap.timing.chord_timeout_ms.is_some() && KeyParser::split_chord(&remap.from).is_none() {
+        return Err(E::custom(format!(
+            "'{}': chord_timeout_ms is only for chords",
+            remap.from
+        )));
+    }
+    if remap.timing.hold_timeout_ms.is_some() && remap.hold.is_none() {
+        return Err(E::custom(format!(
+            "'{}': hold_timeout_ms needs a hold action",
+            remap.from
+        )));
+    }
+    Ok(())
+}
+
+// The config notation has remaps and actions written as one-key maps, which
+// the derived (externally tagged) formats don't produce, so these are
+// written by hand to mirror the visitors below. A dumped config loads back.
+
+impl Serialize for SequenceStep {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            SequenceStep::Key(key) => serializer.serialize_str(key),
+            SequenceStep::Delay(ms) => single_entry(serializer, "delay", ms),
+            SequenceStep::Down(name) => single_entry(serializer, "down", name),
+            SequenceStep::Up(name) => single_entry(serializer, "up", name),
+            SequenceStep::Repeat { key, count } => {
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry("key", key)?;
+                map.serialize_entry("repeat", count)?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl Serialize for WindowAction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            WindowAction::Close => serializer.serialize_str("close"),
+            WindowAction::Maximize => serializer.serialize_str("maximize"),
+            WindowAction::FullscreenToggle => serializer.serialize_str("fullscreen_toggle"),
+            WindowAction::Minimize => serializer.serialize_str("minimize"),
+            WindowAction::MoveToDesktop(desktop) => {
+                single_entry(serializer, "move_to_desktop", desktop)
+            }
+        }
+    }
+}
+
+impl Serialize for PluginSource {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        if !self.allow_exec {
+            return self.path.serialize(serializer);
+        }
+        let mut map = serializer.serialize_map(Some(2))?;
+        map.serialize_entry("path", &self.path)?;
+        map.serialize_entry("allow_exec", &true)?;
+        map.end()
+    }
+}
+
+impl Serialize for PluginAction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match &self.arg {
+            None => serializer.serialize_str(&self.name),
+            Some(arg) => {
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry("name", &self.name)?;
+                map.serialize_entry("arg", arg)?;
+                map.end()
+            }
+        }
+    }
+}
+
+impl Serialize for KeyAction {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        match self {
+            KeyAction::Single(key) => serializer.serialize_str(key),
+            KeyAction::Multiple(steps) => steps.serialize(serializer),
+            KeyAction::Script(path) => single_entry(serializer, "script", path),
+            KeyAction::Layout(layout) => single_entry(serializer, "layout", layout),
+            KeyAction::Exec(command) => single_entry(serializer, "exec", command),
+            KeyAction::OneShot(name) => single_entry(serializer, "one_shot", name),
+            KeyAction::Window(action) => single_entry(serializer, "window", action),
+            KeyAction::Click(click) => serializer.serialize_str(click.name()),
+            KeyAction::Layer(name) => single_entry(serializer, "layer", name),
+            KeyAction::MouseMode(settings) => single_entry(serializer, "mouse_mode", settings),
+            KeyAction::RecordMacro(slot) => single_entry(serializer, "record_macro", slot),
+            KeyAction::PlayMacro(slot) => single_entry(serializer, "play_macro", slot),
+            KeyAction::Compose(keys) => single_entry(serializer, "compose", keys),
+            KeyAction::Toggle(flag) => single_entry(serializer, "toggle", flag),
+            KeyAction::Plugin(action) => single_entry(serializer, "plugin", action),
+            KeyAction::IfModifier {
+                modifier,
+                then,
+                otherwise,
+            } => {
+                let mut map = serializer.serialize_map(Some(2))?;
+                map.serialize_entry(condition_name(modifier), then)?;
+                map.serialize_entry("else", otherwise)?;
+                map.end()
+            }
+            KeyAction::Disabled => serializer.serialize_unit(),
+        }
+    }
+}
+
+/// Written as the `remaps` entry it came from: `from: to` plus the options
+/// that aren't at their defaults.
+impl Serialize for Remap {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        let mut map = serializer.serialize_map(None)?;
+        map.serialize_entry(&self.from, &self.to)?;
+        if self.also_send_original {
+            map.serialize_entry("also_send_original", &true)?;
+        }
+        if self.conditional {
+            map.serialize_entry("conditional", &true)?;
+        }
+        if self.on != Trigger::Press {
+            map.serialize_entry("on", &self.on)?;
+        }
+        if let Some(hold) = &self.hold {
+            map.serialize_entry("hold", hold)?;
+        }
+        if let Some(send_to) = &self.send_to {
+            map.serialize_entry("send_to", send_to)?;
+        }
+        let timings = [
+            ("key_delay_ms", self.timing.key_delay_ms),
+            ("chord_timeout_ms", self.timing.chord_timeout_ms),
+            ("hold_timeout_ms", self.timing.hold_timeout_ms),
+        ];
+        for (name, ms) in timings {
+            if let Some(ms) = ms {
+                map.serialize_entry(name, &ms)?;
+            }
+        }
+        map.end()
+    }
+}
+
+fn single_entry<S: Serializer>(
+    serializer: S,
+    key: &str,
+    value: &impl Serialize,
+) -> Result<S::Ok, S::Error> {
+    let mut map = serializer.serialize_map(Some(1))?;
+    map.serialize_entry(key, value)?;
+    map.end()
 }
 
-impl<'de> Deserialize<'de> for WindowConfig {
-    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
-    where
-        D: serde::Deserializer<'de>,
-    {
-        let mut map = HashMap::<String, Value>::deserialize(deserializer)?;
+impl<'de> Deserialize<'de> for SequenceStep {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct SequenceStepVisitor;
+
+        impl<'de> Visitor<'de> for SequenceStepVisitor {
+            type Value = SequenceStep;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str(
+                    "a key expression, {delay: milliseconds}, {down: modifier}, {up: modifier} or {key: key, repeat: count}",
+                )
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<SequenceStep, E> {
+                Ok(SequenceStep::Key(v.to_string()))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<SequenceStep, A::Error> {
+                let step = match map.next_key::<String>()?.as_deref() {
+                    Some("delay") => SequenceStep::Delay(map.next_value()?),
+                    Some("down") => SequenceStep::Down(map.next_value()?),
+                    Some("up") => SequenceStep::Up(map.next_value()?),
+                    Some(first @ ("key" | "repeat")) => {
+                        let (mut key, mut count) = (None, None);
+                        let mut field = first.to_string();
+                        loop {
+                            match field.as_str() {
+                                "key" => key = Some(map.next_value()?),
+                                "repeat" => count = Some(map.next_value()?),
+                                other => {
+                                    return Err(de::Error::unknown_field(other, &["key", "repeat"]))
+                                }
+                            }
+                            match map.next_key::<String>()? {
+                                Some(next) => field = next,
+                                None => break,
+                            }
+                        }
+                        return Ok(SequenceStep::Repeat {
+                            key: key.ok_or_else(|| de::Error::missing_field("key"))?,
+                            count: count.ok_or_else(|| de::Error::missing_field("repeat"))?,
+                        });
+                    }
+                    Some(other) => {
+                        return Err(de::Error::unknown_field(
+                            other,
+                            &["delay", "down", "up", "key", "repeat"],
+                        ));
+                    }
+                    None => return Err(de::Error::invalid_length(0, &self)),
+                };
+                if let Some(extra) = map.next_key::<String>()? {
+                    return Err(de::Error::custom(format!(
+                        "unexpected '{}' alongside sequence step",
+                        extra
+                    )));
+                }
+                Ok(step)
+            }
+        }
+
+        deserializer.deserialize_any(SequenceStepVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for WindowAction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct WindowActionVisitor;
+
+        const ACTIONS: &[&str] = &[
+            "close",
+            "maximize",
+            "fullscreen_toggle",
+            "minimize",
+            "move_to_desktop",
+        ];
+
+        impl<'de> Visitor<'de> for WindowActionVisitor {
+            type Value = WindowAction;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a window action like close or {move_to_desktop: 2}")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<WindowAction, E> {
+                match v {
+                    "close" => Ok(WindowAction::Close),
+                    "maximize" => Ok(WindowAction::Maximize),
+                    "fullscreen_toggle" => Ok(WindowAction::FullscreenToggle),
+                    "minimize" => Ok(WindowAction::Minimize),
+                    other => Err(de::Error::unknown_variant(other, ACTIONS)),
+                }
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<WindowAction, A::Error> {
+                let action = match map.next_key::<String>()?.as_deref() {
+                    Some("move_to_desktop") => WindowAction::MoveToDesktop(map.next_value()?),
+                    Some(other) => return Err(de::Error::unknown_variant(other, ACTIONS)),
+                    None => return Err(de::Error::invalid_length(0, &self)),
+                };
+                if let Some(extra) = map.next_key::<String>()? {
+                    return Err(de::Error::custom(format!(
+                        "unexpected '{}' alongside window action",
+                        extra
+                    )));
+                }
+                Ok(action)
+            }
+        }
+
+        deserializer.deserialize_any(WindowActionVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for PluginSource {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct PluginSourceVisitor;
+
+        impl<'de> Visitor<'de> for PluginSourceVisitor {
+            type Value = PluginSource;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a plugin path or {path: ..., allow_exec: true}")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<PluginSource, E> {
+                Ok(PluginSource {
+                    path: PathBuf::from(v),
+                    allow_exec: false,
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<PluginSource, A::Error> {
+                let (mut path, mut allow_exec) = (None, false);
+                while let Some(field) = map.next_key::<String>()? {
+                    match field.as_str() {
+                        "path" => path = Some(map.next_value()?),
+                        "allow_exec" => allow_exec = map.next_value()?,
+                        other => {
+                            return Err(de::Error::unknown_field(other, &["path", "allow_exec"]))
+                        }
+                    }
+                }
+                let source = PluginSource {
+                    path: path.ok_or_else(|| de::Error::missing_field("path"))?,
+                    allow_exec,
+                };
+                // Native plugins can do anything, there's nothing to allow
+                if source.allow_exec && !source.is_wasm() {
+                    return Err(de::Error::custom(format!(
+                        "'{}': allow_exec is only for .wasm plugins",
+                        source.path.display()
+                    )));
+                }
+                Ok(source)
+            }
+        }
+
+        deserializer.deserialize_any(PluginSourceVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for PluginAction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct PluginActionVisitor;
+
+        impl<'de> Visitor<'de> for PluginActionVisitor {
+            type Value = PluginAction;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a plugin name or {name: ..., arg: ...}")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<PluginAction, E> {
+                Ok(PluginAction {
+                    name: v.to_string(),
+                    arg: None,
+                })
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<PluginAction, A::Error> {
+                let (mut name, mut arg) = (None, None);
+                while let Some(field) = map.next_key::<String>()? {
+                    match field.as_str() {
+                        "name" => name = Some(map.next_value()?),
+                        "arg" => arg = Some(map.next_value()?),
+                        other => return Err(de::Error::unknown_field(other, &["name", "arg"])),
+                    }
+                }
+                Ok(PluginAction {
+                    name: name.ok_or_else(|| de::Error::missing_field("name"))?,
+                    arg,
+                })
+            }
+        }
+
+        deserializer.deserialize_any(PluginActionVisitor)
+    }
+}
+
+impl<'de> Deserialize<'de> for KeyAction {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct KeyActionVisitor;
+
+        impl<'de> Visitor<'de> for KeyActionVisitor {
+            type Value = KeyAction;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a key expression, a key sequence or an action like {script: path}")
+            }
+
+            fn visit_str<E: de::Error>(self, v: &str) -> Result<KeyAction, E> {
+                if v == "disabled" {
+                    return Ok(KeyAction::Disabled);
+                }
+                if let Some(click) = MouseClick::from_name(v) {
+                    return Ok(KeyAction::Click(click));
+                }
+                Ok(KeyAction::Single(v.to_string()))
+            }
+
+            fn visit_unit<E: de::Error>(self) -> Result<KeyAction, E> {
+                Ok(KeyAction::Disabled)
+            }
+
+            fn visit_seq<A: SeqAccess<'de>>(self, mut seq: A) -> Result<KeyAction, A::Error> {
+                let mut steps = Vec::new();
+                while let Some(step) = seq.next_element::<SequenceStep>()? {
+                    steps.push(step);
+                }
+                Ok(KeyAction::Multiple(steps))
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<KeyAction, A::Error> {
+                let action = match map.next_key::<String>()?.as_deref() {
+                    Some("script") => KeyAction::Script(map.next_value()?),
+                    Some("layout") => KeyAction::Layout(map.next_value()?),
+                    Some("exec") => KeyAction::Exec(map.next_value()?),
+                    Some("one_shot") => KeyAction::OneShot(map.next_value()?),
+                    Some("window") => KeyAction::Window(map.next_value()?),
+                    Some("layer") => KeyAction::Layer(map.next_value()?),
+                    Some("mouse_mode") => KeyAction::MouseMode(map.next_value()?),
+                    Some("record_macro") => KeyAction::RecordMacro(map.next_value()?),
+                    Some("play_macro") => KeyAction::PlayMacro(map.next_value()?),
+                    Some("compose") => KeyAction::Compose(map.next_value()?),
+                    Some("toggle") => KeyAction::Toggle(map.next_value()?),
+                    Some("plugin") => KeyAction::Plugin(map.next_value()?),
+                    Some(first) if first == "else" || condition_modifier(first).is_some() => {
+                        let (mut condition, mut otherwise) = (None, None);
+                        let mut field = first.to_string();
+                        loop {
+                            if field == "else" {
+                                otherwise = Some(map.next_value()?);
+                            } else if let Some(modifier) = condition_modifier(&field) {
+                                if condition.is_some() {
+                                    return Err(de::Error::custom(
+                                        "an action tests one modifier, nest them for more",
+                                    ));
+                                }
+                                condition = Some((modifier, map.next_value()?));
+                            } else {
+                                return Err(de::Error::custom(format!(
+                                    "unexpected '{}' alongside a condition",
+                                    field
+                                )));
+                            }
+                            match map.next_key::<String>()? {
+                                Some(next) => field = next,
+                                None => break,
+                            }
+                        }
+                        let (modifier, then) = condition.ok_or_else(|| {
+                            de::Error::custom("`else` needs a condition like `if_shift`")
+                        })?;
+                        return Ok(KeyAction::IfModifier {
+                            modifier: modifier.to_string(),
+                            then: Box::new(then),
+                            otherwise: Box::new(
+                                otherwise.ok_or_else(|| de::Error::missing_field("else"))?,
+                            ),
+                        });
+                    }
+                    Some(other) => {
+                        return Err(de::Error::unknown_field(
+                            other,
+                            &[
+                                "script",
+                                "layout",
+                                "exec",
+                                "one_shot",
+                                "window",
+                                "layer",
+                                "mouse_mode",
+                                "record_macro",
+                                "play_macro",
+                                "compose",
+                                "toggle",
+                                "plugin",
+                                "if_shift",
+                                "if_ctrl",
+                                "if_alt",
+                                "if_super",
+                                "if_altgr",
+                                "if_hyper",
+                            ],
+                        ));
+                    }
+                    None => return Err(de::Error::invalid_length(0, &self)),
+                };
+                if let Some(extra) = map.next_key::<String>()? {
+                    return Err(de::Error::custom(format!(
+                        "unexpected '{}' alongside action",
+                        extra
+                    )));
+                }
+                Ok(action)
+            }
+        }
+
+        deserializer.deserialize_any(KeyActionVisitor)
+    }
+}
+
+/// One entry of a `remaps` list. An entry is a mapping and may hold several
+/// `from: to` pairs, which expand to one `Remap` each, plus options such as
+/// `also_send_original` and `conditional` that apply to every pair in the
+/// entry.
+struct RemapEntry(Vec<Remap>);
+
+/// The key of a remap entry: a key or option name, or a list of keys that
+/// share one action, e.g. `['C-b', 'M-Left']: 'Left'`.
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RemapFrom {
+    Key(String),
+    Keys(Vec<String>),
+}
+
+/// The keys of a remap entry that are options rather than a `from` key.
+const REMAP_OPTIONS: [&str; 10] = [
+    "swap",
+    "modifiers",
+    "also_send_original",
+    "conditional",
+    "on",
+    "send_to",
+    "hold",
+    "key_delay_ms",
+    "chord_timeout_ms",
+    "hold_timeout_ms",
+];
+
+impl<'de> Deserialize<'de> for RemapEntry {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        struct RemapEntryVisitor;
+
+        impl<'de> Visitor<'de> for RemapEntryVisitor {
+            type Value = RemapEntry;
+
+            fn expecting(&self, f: &mut fmt::Formatter) -> fmt::Result {
+                f.write_str("a remap like 'C-b': 'Left'")
+            }
+
+            fn visit_map<A: MapAccess<'de>>(self, mut map: A) -> Result<RemapEntry, A::Error> {
+                let mut remaps = Vec::new();
+                let mut also_send_original = false;
+                let mut conditional = false;
+                let mut on = Trigger::Press;
+                let mut hold: Option<KeyAction> = None;
+                let mut send_to: Option<WindowSelector> = None;
+                let mut timing = Timing::default();
+                let mut swap: Option<Vec<String>> = None;
+                let mut swap_modifiers: Option<Vec<String>> = None;
+                while let Some(from) = map.next_key::<RemapFrom>()? {
+                    let key = match from {
+                        RemapFrom::Key(key) => key,
+                        RemapFrom::Keys(keys) => {
+                            let to = map.next_value::<KeyAction>()?;
+                            if keys.is_empty() {
+                                return Err(de::Error::custom("empty list of keys to remap"));
+                            }
+                            validate_action(&to)?;
+                            for from in keys {
+                                validate_from(&from)?;
+                                remaps.push(Remap {
+                                    from,
+                                    to: to.clone(),
+                                    also_send_original: false,
+                                    conditional: false,
+                                    on: Trigger::Press,
+                                    hold: None,
+                                    send_to: None,
+                                    timing: Timing::default(),
+                                });
+                            }
+                            continue;
+                        }
+                    };
+                    match key.as_str() {
+                        "also_send_original" => also_send_original = map.next_value()?,
+                        "conditional" => conditional = map.next_value()?,
+                        "on" => on = map.next_value()?,
+                        "send_to" => send_to = map.next_value()?,
+                        "swap" => swap = Some(map.next_value()?),
+                        "modifiers" => swap_modifiers = Some(map.next_value()?),
+                        "key_delay_ms" => timing.key_delay_ms = Some(map.next_value()?),
+                        "chord_timeout_ms" | "chord_window_ms" => {
+                            timing.chord_timeout_ms = Some(map.next_value()?)
+                        }
+                        "hold_timeout_ms" => timing.hold_timeout_ms = Some(map.next_value()?),
+                        "hold" => {
+                            let action = map.next_value()?;
+                            validate_action(&action)?;
+                            hold = Some(action);
+                        }
+                        _ => {
+                            if KeyParser::split_chord(&key).is_none()
+                                && key_parser().parse(&key).is_err()
+                            {
+                                if let Some(option) = closest_name(&key, REMAP_OPTIONS) {
+                                    return Err(de::Error::custom(format!(
+                                        "'{}' is neither a key nor a remap option, did you mean `{}`?",
+                                        key, option
+                                    )));
+                                }
+                            }
+                            let to = map.next_value::<KeyAction>()?;
+                            validate_from(&key)?;
+                            validate_action(&to)?;
+                            remaps.push(Remap {
+                                from: key,
+                                to,
+                                also_send_original: false,
+                                conditional: false,
+                                on: Trigger::Press,
+                                hold: None,
+                                send_to: None,
+                                timing: Timing::default(),
+                            });
+                        }
+                    }
+                }
+                match (swap, swap_modifiers) {
+                    (Some(keys), modifiers) => remaps.extend(swap_remaps(&keys, modifiers)?),
+                    (None, Some(_)) => {
+                        return Err(de::Error::custom("`modifiers` is only for swap entries"))
+                    }
+                    (None, None) => {}
+                }
+                if remaps.is_empty() {
+                    return Err(de::Error::custom("remap entry has no 'from: to' pair"));
+                }
+                for remap in &mut remaps {
+                    remap.also_send_original = also_send_original;
+                    remap.conditional = conditional;
+                    remap.on = on;
+                    remap.hold = hold.clone();
+                    remap.send_to = send_to.clone();
+                    remap.timing = timing;
+                    validate_timing(remap)?;
+                    validate_own_action(remap, "layer", |action| {
+                        matches!(action, KeyAction::Layer(_))
+                    })?;
+                    validate_own_action(remap, "toggle", |action| {
+                        matches!(action, KeyAction::Toggle(_))
+                    })?;
+                    if conditional {
+                        validate_conditional(remap)?;
+                    }
+                    if on == Trigger::Release {
+                        validate_release(remap)?;
+                    }
+                    if remap.hold.is_some() {
+                        validate_hold(remap)?;
+                    }
+                }
+                Ok(RemapEntry(remaps))
+            }
+        }
+
+        deserializer.deserialize_map(RemapEntryVisitor)
+    }
+}
+
+/// The remaps of a `swap: [a, b]` entry: `a` to `b` and back, alone and
+/// with every combination of `modifiers` (by default Shift, Ctrl, Alt and
+/// Super) held.
+fn swap_remaps<E: de::Error>(
+    keys: &[String],
+    modifiers: Option<Vec<String>>,
+) -> Result<Vec<Remap>, E> {
+    let [a, b] = keys else {
+        return Err(E::custom(format!(
+            "swap takes two keys, not {}",
+            keys.len()
+        )));
+    };
+    let modifiers = modifiers.unwrap_or_else(|| {
+        ["Shift", "Ctrl", "Alt", "Super"]
+            .map(str::to_string)
+            .to_vec()
+    });
+    let mut remaps = Vec::new();
+    for combination in 0..1usize << modifiers.len() {
+        let prefix: String = modifiers
+            .iter()
+            .enumerate()
+            .filter(|(i, _)| combination & (1 << i) != 0)
+            .map(|(_, modifier)| format!("{}-", modifier))
+            .collect();
+        for (from, to) in [(a, b), (b, a)] {
+            let from = format!("{}{}", prefix, from);
+            validate_from(&from)?;
+            remaps.push(Remap {
+                from,
+                to: KeyAction::Single(format!("{}{}", prefix, to)),
+                also_send_original: false,
+                conditional: false,
+                on: Trigger::Press,
+                hold: None,
+                send_to: None,
+                timing: Timing::default(),
+            });
+        }
+    }
+    Ok(remaps)
+}
+
+/// Finds the line of each remap in `content` by looking for its `from` key
+/// in file order. YAML anchors and merges can make it miss; those remaps get
+/// no line.
+fn remap_lines(content: &str, config: &Config) -> BTreeMap<(RuleId, usize), usize> {
+    let section = |name: &str| {
+        content
+            .lines()
+            .position(|line| line.starts_with(&format!("{}:", name)))
+    };
+    let windows = config.windows.iter().enumerate().flat_map(|(index, rule)| {
+        rule.remaps
+            .iter()
+            .enumerate()
+            .map(move |(i, remap)| ((RuleId::Window(index), i), remap))
+    });
+    let global = config
+        .global
+        .iter()
+        .enumerate()
+        .map(|(i, remap)| ((RuleId::Global, i), remap));
+    let in_file_order: Vec<_> = if section("windows") < section("global") {
+        windows.chain(global).collect()
+    } else {
+        global.chain(windows).collect()
+    };
+
+    let lines: Vec<&str> = content.lines().collect();
+    // Where the search for each `from` key continues, so a key remapped in
+    // several rules is found once for each
+    let mut next: BTreeMap<&str, usize> = BTreeMap::new();
+    let mut found = BTreeMap::new();
+    for (id, remap) in in_file_order {
+        let start = next.get(remap.from.as_str()).copied().unwrap_or(0);
+        let Some(offset) = lines[start.min(lines.len())..]
+            .iter()
+            .position(|line| has_key(line, &remap.from))
+        else {
+            continue;
+        };
+        next.insert(&remap.from, start + offset + 1);
+        found.insert(id, start + offset + 1);
+    }
+    found
+}
+
+/// Whether `line` has `key` as a mapping key, quoted or not.
+fn has_key(line: &str, key: &str) -> bool {
+    if line.contains(&format!("'{}':", key)) || line.contains(&format!("\"{}\":", key)) {
+        return true;
+    }
+    // One of several keys sharing an action: `['C-b', 'M-Left']: 'Left'`
+    if let Some(keys) = line.find("]:").and_then(|end| line[..end].rsplit_once('[')) {
+        let listed = keys
+            .1
+            .split(',')
+            .any(|listed| listed.trim().trim_matches(['\'', '"']) == key);
+        if listed {
+            return true;
+        }
+    }
+    let plain = format!("{}:", key);
+    line.match_indices(&plain).any(|(at, _)| {
+        let before = line[..at].chars().next_back();
+        let after = line[at + plain.len()..].chars().next();
+        before.is_none_or(|c| c.is_whitespace() || c == '{' || c == ',')
+            && after.is_none_or(char::is_whitespace)
+    })
+}
+
+fn default_paths(config_home: Option<PathBuf>, home: Option<PathBuf>) -> Vec<PathBuf> {
+    let config_home = config_home.filter(|dir| dir.is_absolute());
+    let fallback = home.map(|home| home.join(".config"));
+    let mut paths: Vec<PathBuf> = config_home
+        .into_iter()
+        .chain(fallback)
+        .map(|dir| dir.join("simple-x11-remapper").join("config.yaml"))
+        .collect();
+    paths.dedup();
+    paths
+}
+
+/// Runs the templating pass on a config's text.
+fn render(content: &str) -> Result<String, ConfigError> {
+    template::render(content, &template::Environment::current())
+}
+
+/// The top-level keys a config's text sets.
+fn top_level_keys(content: &str) -> Vec<String> {
+    match serde_yaml::from_str(content) {
+        Ok(serde_yaml::Value::Mapping(config)) => config
+            .keys()
+            .filter_map(|key| key.as_str().map(str::to_string))
+            .collect(),
+        _ => Vec::new(),
+    }
+}
+
+/// Whether two paths name the same file, through symlinks.
+fn same_file(a: &Path, b: &Path) -> bool {
+    match (fs::canonicalize(a), fs::canonicalize(b)) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+fn deserialize_modmap<'de, D>(deserializer: D) -> Result<Vec<ModmapEntry>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let entries = Vec::<ModmapEntry>::deserialize(deserializer)?;
+    for entry in &entries {
+        let (modifier, keysyms) = match entry {
+            ModmapEntry::Keycode { keysyms, .. } => (None, keysyms),
+            ModmapEntry::Keysym { keysym, keysyms } => {
+                validate_keysym(keysym)?;
+                (None, keysyms)
+            }
+            ModmapEntry::Clear { clear } => (Some(clear), &Vec::new()),
+            ModmapEntry::Add { add, keys } => (Some(add), keys),
+            ModmapEntry::Remove { remove, keys } => (Some(remove), keys),
+        };
+        if let Some(modifier) = modifier.filter(|m| modifier_index(m).is_none()) {
+            return Err(de::Error::custom(format!(
+                "unknown modifier '{}', expected Shift, Lock, Control or Mod1 to Mod5",
+                modifier
+            )));
+        }
+        for keysym in keysyms {
+            validate_keysym(keysym)?;
+        }
+    }
+    Ok(entries)
+}
+
+/// A bare keysym name as xmodmap takes it; `NoSymbol` leaves a level empty.
+fn validate_keysym<E: de::Error>(name: &str) -> Result<(), E> {
+    if name == "NoSymbol" || key_parser().parse(name).is_ok_and(|(_, mods)| mods == 0) {
+        Ok(())
+    } else {
+        Err(E::custom(format!("unknown keysym '{}'", name)))
+    }
+}
+
+fn deserialize_remaps<'de, D>(deserializer: D) -> Result<Vec<Remap>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let entries = Vec::<RemapEntry>::deserialize(deserializer)?;
+    Ok(entries.into_iter().flat_map(|entry| entry.0).collect())
+}
+
+fn deserialize_layers<'de, D>(deserializer: D) -> Result<BTreeMap<String, Vec<Remap>>, D::Error>
+where
+    D: Deserializer<'de>,
+{
+    let layers = BTreeMap::<String, Vec<RemapEntry>>::deserialize(deserializer)?;
+    Ok(layers
+        .into_iter()
+        .map(|(name, entries)| {
+            (
+                name,
+                entries.into_iter().flat_map(|entry| entry.0).collect(),
+            )
+        })
+        .collect())
+}
+
+/// The layer `{layer: nav}` uses unless the config defines its own:
+/// hjkl are the arrows and uiop Home, End, Page Up and Page Down.
+fn nav_layer() -> Vec<Remap> {
+    [
+        ("h", "Left"),
+        ("j", "Down"),
+        ("k", "Up"),
+        ("l", "Right"),
+        ("u", "Home"),
+        ("i", "End"),
+        ("o", "Prior"),
+        ("p", "Next"),
+    ]
+    .into_iter()
+    .map(|(from, to)| Remap {
+        from: from.to_string(),
+        to: KeyAction::Single(to.to_string()),
+        also_send_original: false,
+        conditional: false,
+        on: Trigger::Press,
+        hold: None,
+        send_to: None,
+        timing: Timing::default(),
+    })
+    .collect()
+}
+
+/// The system-wide config every user config is merged over, for defaults
+/// an administrator ships.
+pub const SYSTEM_CONFIG_PATH: &str = "/etc/simple-x11-remapper/config.yaml";
+
+impl Config {
+    /// Reads and parses a config file, merged over [`SYSTEM_CONFIG_PATH`]
+    /// when that exists. Relative script and plugin paths are resolved
+    /// against the directory the file naming them lives in.
+    pub fn load(path: &Path) -> anyhow::Result<Self> {
+        Self::load_with_adhoc(path, AdhocRemaps::default())
+    }
+
+    /// [`Config::load`] with `adhoc` added as a window rule applied after
+    /// every other, see [`AdhocRemaps`].
+    pub fn load_with_adhoc(path: &Path, adhoc: AdhocRemaps) -> anyhow::Result<Self> {
+        Self::load_over(path, Path::new(SYSTEM_CONFIG_PATH), adhoc)
+    }
+
+    /// Loads the config at `path` merged over the one at `system`, unless
+    /// there's none there, it's the same file, or the config sets
+    /// `system_config: false`.
+    fn load_over(path: &Path, system: &Path, adhoc: AdhocRemaps) -> anyhow::Result<Self> {
+        let (mut config, content) = Self::parse_file(path)?;
+        let layered = config.system_config && system.is_file() && !same_file(path, system);
+        if layered {
+            let (base, _) = Self::parse_file(system)?;
+            info!("Merging {} over {}", path.display(), system.display());
+            config = base.layered_under(config, &top_level_keys(&content));
+        }
+        config.push_adhoc(adhoc)?;
+        config
+            .finish(&content)
+            .with_context(|| format!("Invalid config file: {}", path.display()))?;
+        config.path = Some(path.to_path_buf());
+        for warning in config.lint() {
+            warn!("{}: {}", path.display(), warning);
+        }
+        Ok(config)
+    }
+
+    /// Adds `adhoc` as a window rule applied after every other, so its
+    /// remaps win unless a matching rule has `stop`, before the config is
+    /// [finished](Self::finish). It's kept for
+    /// [`Remapper::reload`](crate::Remapper::reload) to add again.
+    fn push_adhoc(&mut self, adhoc: AdhocRemaps) -> Result<(), ConfigError> {
+        if adhoc.remaps.is_empty() {
+            return Ok(());
+        }
+        self.windows.push(adhoc.rule()?);
+        self.adhoc = adhoc;
+        Ok(())
+    }
+
+    /// The config files looked for when none is given, in order:
+    /// `$XDG_CONFIG_HOME/simple-x11-remapper/config.yaml`, then the same under
+    /// `~/.config`, then [`SYSTEM_CONFIG_PATH`] on its own.
+    pub fn default_paths() -> Vec<PathBuf> {
+        let mut paths = default_paths(
+            std::env::var_os("XDG_CONFIG_HOME").map(PathBuf::from),
+            std::env::var_os("HOME").map(PathBuf::from),
+        );
+        paths.push(PathBuf::from(SYSTEM_CONFIG_PATH));
+        paths
+    }
+
+    fn resolve_paths(&mut self, base_dir: &Path) {
+        let windows = self.windows.iter_mut().flat_map(|w| &mut w.remaps);
+        for remap in self.global.iter_mut().chain(windows) {
+            if let KeyAction::Script(ref mut script) = remap.to {
+                if script.is_relative() {
+                    *script = base_dir.join(&*script);
+                }
+            }
+        }
+        for plugin in &mut self.plugins {
+            if plugin.path.is_relative() {
+                plugin.path = base_dir.join(&plugin.path);
+            }
+        }
+    }
+
+    /// Parses a config after its templating pass, which sees the machine's
+    /// host name and the process environment.
+    pub fn from_yaml(content: &str) -> Result<Self, ConfigError> {
+        Self::from_yaml_with_adhoc(content, AdhocRemaps::default())
+    }
+
+    /// [`Config::from_yaml`] with `adhoc` added as a window rule applied
+    /// after every other.
+    pub fn from_yaml_with_adhoc(content: &str, adhoc: AdhocRemaps) -> Result<Self, ConfigError> {
+        let content = &render(content)?;
+        let mut config = Self::parse(content)?;
+        config.push_adhoc(adhoc)?;
+        config.finish(content)?;
+        Ok(config)
+    }
+
+    /// Reads and parses the config file at `path`, with its paths resolved,
+    /// but not yet [finished](Self::finish). Returns its text after the
+    /// templating pass too, which remap lines point into.
+    fn parse_file(path: &Path) -> anyhow::Result<(Self, String)> {
+        let content = fs::read_to_string(path)
+            .with_context(|| format!("Failed to read config file: {}", path.display()))?;
+        let invalid = || format!("Invalid config file: {}", path.display());
+        let content = render(&content).with_context(invalid)?;
+        let mut config = Self::parse(&content).with_context(invalid)?;
+        if let Some(base_dir) = path.parent() {
+            config.resolve_paths(base_dir);
+        }
+        Ok((config, content))
+    }
+
+    /// The config `content` describes, before the checks that need all of
+    /// it, which may be merged from several files.
+    fn parse(content: &str) -> Result<Self, ConfigError> {
+        let mut config: Self =
+            serde_yaml::from_str(content).map_err(|e| ConfigError::from_yaml_error(e, content))?;
+        config.remap_lines = remap_lines(content, &config);
+        Ok(config)
+    }
+
+    /// Expands class groups and checks what spans sections: chains,
+    /// layers, flags and dual-role keys. `content` is the text remap lines
+    /// point into.
+    fn finish(&mut self, content: &str) -> Result<(), ConfigError> {
+        self.expand_class_groups();
+        if self.resolve_chains {
+            self.check_chains(content)?;
+        }
+        self.check_layers()?;
+        self.check_flags()?;
+        self.check_dual_role_keys()
+    }
+
+    /// `user` merged over this, the system-wide config. The top-level
+    /// options `user_keys` says the user's file sets replace the system's,
+    /// named maps like `classes` and `layers` combine entry by entry, and
+    /// so do the rules: the user's apply after the system's, so they win,
+    /// and a system global remap of a key the user's `global` remaps too,
+    /// or a window rule with the `name` of one of the user's, is dropped.
+    fn layered_under(self, mut user: Config, user_keys: &[String]) -> Config {
+        let system = self;
+        macro_rules! keep_unless_set {
+            ($($field:ident),*) => {
+                $(if !user_keys.iter().any(|key| key == stringify!($field)) {
+                    user.$field = system.$field;
+                })*
+            };
+        }
+        keep_unless_set!(
+            key_delay_ms,
+            hold_timeout_ms,
+            tapping_term_ms,
+            observe,
+            ignore_classes,
+            grab_on,
+            notify,
+            skip_window_types,
+            on_focus_change,
+            plugins,
+            presets,
+            hold_flavor,
+            resolve_chains,
+            physical_keys,
+            modmap
+        );
+        // Its one option with an alias
+        if !user_keys
+            .iter()
+            .any(|key| key == "chord_timeout_ms" || key == "chord_window_ms")
+        {
+            user.chord_timeout_ms = system.chord_timeout_ms;
+        }
+        macro_rules! combine {
+            ($($field:ident),*) => {
+                $(let mut merged = system.$field;
+                merged.append(&mut user.$field);
+                user.$field = merged;)*
+            };
+        }
+        combine!(classes, expansions, layers, home_row_mods, variables);
+
+        let global: Vec<Remap> = system
+            .global
+            .into_iter()
+            .filter(|remap| {
+                !user
+                    .global
+                    .iter()
+                    .any(|own| same_key(&own.from, &remap.from))
+            })
+            .collect();
+        let windows: Vec<WindowConfig> = system
+            .windows
+            .into_iter()
+            .filter(|rule| {
+                rule.name.is_none() || !user.windows.iter().any(|own| own.name == rule.name)
+            })
+            .map(|rule| WindowConfig {
+                system: true,
+                ..rule
+            })
+            .collect();
+        // The user's remaps moved back by the system's kept before them
+        user.remap_lines = user
+            .remap_lines
+            .into_iter()
+            .map(|((rule, index), line)| match rule {
+                RuleId::Global => ((rule, index + global.len()), line),
+                RuleId::Window(w) => ((RuleId::Window(w + windows.len()), index), line),
+            })
+            .collect();
+        user.global = global.into_iter().chain(user.global).collect();
+        user.windows = windows.into_iter().chain(user.windows).collect();
+        user
+    }
+
+    /// Fails on a bad home-row mod, or a remap of a key a preset or
+    /// home-row mod takes over.
+    fn check_dual_role_keys(&self) -> Result<(), ConfigError> {
+        let error = |message: String| ConfigError {
+            line: None,
+            column: None,
+            message,
+            source_line: None,
+        };
+        for (key, home_row_mod) in &self.home_row_mods {
+            match key_parser().parse(key) {
+                Ok((_, 0)) => {}
+                Ok(_) => {
+                    return Err(error(format!(
+                        "home-row mod '{}' can't have modifiers of its own",
+                        key
+                    )))
+                }
+                Err(e) => return Err(error(format!("home-row mod '{}': {}", key, e))),
+            }
+            if let Err(e) = key_parser().parse_modifier_key(home_row_mod.modifier()) {
+                return Err(error(format!("home-row mod '{}': {}", key, e)));
+            }
+        }
+        let remaps = self
+            .global
+            .iter()
+            .chain(self.windows.iter().flat_map(|rule| &rule.remaps));
+        for remap in remaps {
+            if let Some(preset) = self.presets.iter().find(|p| p.key() == remap.from) {
+                return Err(error(format!(
+                    "'{}' is remapped, but the {} preset already uses it",
+                    remap.from,
+                    preset.name()
+                )));
+            }
+            if self.home_row_mods.contains_key(&remap.from) {
+                return Err(error(format!(
+                    "'{}' is remapped, but it's also a home-row mod",
+                    remap.from
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    /// The remaps of the layer called `name`.
+    pub fn layer(&self, name: &str) -> Option<Vec<Remap>> {
+        match self.layers.get(name) {
+            Some(remaps) => Some(remaps.clone()),
+            None if name == "nav" => Some(nav_layer()),
+            None => None,
+        }
+    }
+
+    /// Fails on a `{layer: name}` action naming a layer that doesn't exist.
+    fn check_layers(&self) -> Result<(), ConfigError> {
+        let remaps = self
+            .global
+            .iter()
+            .chain(self.windows.iter().flat_map(|rule| &rule.remaps));
+        for remap in remaps {
+            for action in std::iter::once(&remap.to).chain(&remap.hold) {
+                if let KeyAction::Layer(name) = action {
+                    if self.layer(name).is_none() {
+                        return Err(ConfigError {
+                            line: None,
+                            column: None,
+                            message: format!(
+                                "'{}' switches to layer '{}', which isn't in `layers`",
+                                remap.from, name
+                            ),
+                            source_line: None,
+                        });
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails on a rule waiting for a flag that no key toggles, most likely
+    /// a typo.
+    fn check_flags(&self) -> Result<(), ConfigError> {
+        let remaps = self
+            .global
+            .iter()
+            .chain(self.windows.iter().flat_map(|rule| &rule.remaps))
+            .chain(self.layers.values().flatten());
+        let toggled: Vec<&str> = remaps
+            .filter_map(|remap| match &remap.to {
+                KeyAction::Toggle(flag) => Some(flag.as_str()),
+                _ => None,
+            })
+            .collect();
+        for (index, rule) in self.windows.iter().enumerate() {
+            let Some(when) = &rule.when else {
+                continue;
+            };
+            let flag = when.strip_prefix('!').unwrap_or(when);
+            if !toggled.contains(&flag) {
+                return Err(ConfigError {
+                    line: None,
+                    column: None,
+                    message: format!(
+                        "{} waits for flag '{}', which no {{toggle: {}}} key turns on",
+                        self.rule_label(RuleId::Window(index)),
+                        flag,
+                        flag
+                    ),
+                    source_line: None,
+                });
+            }
+        }
+        Ok(())
+    }
+
+    /// Fails on a cycle of chained remaps in `global` or in any window rule
+    /// applied on top of it.
+    fn check_chains(&self, content: &str) -> Result<(), ConfigError> {
+        let rule_sets = std::iter::once(Vec::new()).chain(
+            self.windows
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| vec![(index, rule)]),
+        );
+        for rules in rule_sets {
+            let mut remaps = self.combine_remaps(&rules);
+            let Err(cycle) = resolve_chains(&mut remaps) else {
+                continue;
+            };
+            let (rule, from) = remaps
+                .iter()
+                .find(|(_, remap)| same_key(&remap.from, &cycle[0]))
+                .map(|(rule, remap)| (*rule, remap.from.clone()))
+                .expect("a cycle starts at a remap");
+            let line = self
+                .rule_remaps(rule)
+                .iter()
+                .rposition(|remap| remap.from == from)
+                .and_then(|index| self.remap_lines.get(&(rule, index)).copied());
+            return Err(ConfigError {
+                line,
+                column: None,
+                message: format!(
+                    "remaps in {} chain into a cycle: {}",
+                    self.rule_label(rule),
+                    cycle.join(" -> ")
+                ),
+                source_line: line
+                    .and_then(|l| content.lines().nth(l - 1))
+                    .map(str::to_string),
+            });
+        }
+        Ok(())
+    }
+
+    /// Where the remap at `index` in `rule` is written, as `path:line` or
+    /// `line N` when the config wasn't loaded from a file.
+    pub fn remap_location(&self, rule: RuleId, index: usize) -> Option<String> {
+        let line = self.remap_lines.get(&(rule, index))?;
+        Some(match &self.path {
+            Some(path) => format!("{}:{}", path.display(), line),
+            None => format!("line {}", line),
+        })
+    }
+
+    /// Replaces the names of `classes` groups with their classes.
+    fn expand_class_groups(&mut self) {
+        if self.classes.is_empty() {
+            return;
+        }
+        let groups = &self.classes;
+        let expand = |list: &mut Vec<String>| {
+            *list = list
+                .iter()
+                .flat_map(|name| match groups.get(name) {
+                    Some(classes) => classes.clone(),
+                    None => vec![name.clone()],
+                })
+                .collect();
+        };
+        expand(&mut self.ignore_classes);
+        for window in &mut self.windows {
+            window.class_only.iter_mut().for_each(expand);
+            window.class_not.iter_mut().for_each(expand);
+        }
+    }
+
+    pub fn remaps_for_window(&self, context: &MatchContext) -> Vec<Remap> {
+        self.matching_remaps(context)
+            .into_iter()
+            .map(|(_, remap)| remap)
+            .collect()
+    }
+
+    /// The remaps that apply in `context`, one per key, and the rule each
+    /// comes from. `global` applies first, then the matching window rules in
+    /// [`Config::applied_rules`] order; when several remap the same key the
+    /// last one wins, and `mask` drops global remaps.
+    pub fn matching_remaps(&self, context: &MatchContext) -> Vec<(RuleId, Remap)> {
+        if self.is_ignored(context.window_class) {
+            return Vec::new();
+        }
+        let mut remaps = self.combine_remaps(&self.applied_rules(context));
+        if self.resolve_chains {
+            if let Err(cycle) = resolve_chains(&mut remaps) {
+                warn!(
+                    "Not resolving remap chains, they cycle: {}",
+                    cycle.join(" -> ")
+                );
+            }
+        }
+        remaps
+    }
+
+    /// The remaps written in `rule`.
+    pub(crate) fn rule_remaps(&self, rule: RuleId) -> &[Remap] {
+        match rule {
+            RuleId::Global => &self.global,
+            RuleId::Window(index) => &self.windows[index].remaps,
+        }
+    }
+
+    /// `global` and the remaps of `rules` applied in order, one per key.
+    fn combine_remaps(&self, rules: &[(usize, &WindowConfig)]) -> Vec<(RuleId, Remap)> {
+        let masked = |from: &str| {
+            rules
+                .iter()
+                .any(|(_, rule)| rule.mask.iter().any(|key| same_key(key, from)))
+        };
+
+        let global = self
+            .global
+            .iter()
+            .filter(|remap| !masked(&remap.from))
+            .map(|remap| (RuleId::Global, remap));
+        let windows = rules.iter().flat_map(|(index, rule)| {
+            rule.remaps
+                .iter()
+                .map(|remap| (RuleId::Window(*index), remap))
+        });
+
+        let mut remaps: Vec<(RuleId, Remap)> = Vec::new();
+        for (rule, remap) in global.chain(windows) {
+            remaps.retain(|(_, earlier)| !same_key(&earlier.from, &remap.from));
+            remaps.push((rule, remap.clone()));
+        }
+        remaps
+    }
+
+    /// The text expansions that apply in `context`: the global ones, then
+    /// those of the applied rules in order, later ones replacing earlier
+    /// ones with the same abbreviation.
+    pub fn matching_expansions(&self, context: &MatchContext) -> BTreeMap<String, String> {
+        if self.is_ignored(context.window_class) {
+            return BTreeMap::new();
+        }
+        let mut expansions = self.expansions.clone();
+        for (_, rule) in self.applied_rules(context) {
+            expansions.extend(rule.expansions.clone());
+        }
+        expansions
+    }
+
+    /// Whether any rule has text expansions, which need XRecord.
+    pub fn has_expansions(&self) -> bool {
+        !self.expansions.is_empty() || self.windows.iter().any(|w| !w.expansions.is_empty())
+    }
+
+    /// The matching window rules in the order they apply: the system
+    /// config's before the user's, each by priority, then file order, and
+    /// ending at the first one with `stop`. A system rule with `stop` only
+    /// ends the system's rules; the user's still apply after it.
+    pub fn applied_rules<'a>(
+        &'a self,
+        context: &'a MatchContext,
+    ) -> Vec<(usize, &'a WindowConfig)> {
+        let (mut system, mut user): (Vec<_>, Vec<_>) = self
+            .rules_for_window(context)
+            .partition(|(_, rule)| rule.system);
+        for rules in [&mut system, &mut user] {
+            rules.sort_by_key(|(_, rule)| rule.priority);
+            if let Some(stop) = rules.iter().position(|(_, rule)| rule.stop) {
+                rules.truncate(stop + 1);
+            }
+        }
+        system.append(&mut user);
+        system
+    }
+
+    /// The window rules that apply in `context`, with their index in
+    /// [`Config::windows`]. None apply in an ignored window.
+    pub fn rules_for_window<'a>(
+        &'a self,
+        context: &'a MatchContext,
+    ) -> impl Iterator<Item = (usize, &'a WindowConfig)> + 'a {
+        let ignored = self.is_ignored(context.window_class);
+        self.windows
+            .iter()
+            .enumerate()
+            .filter(move |(_, window_config)| {
+                !ignored && self.matches_window(window_config, context)
+            })
+    }
 
-        let class_only = map
-            .remove("class_only")
-            .and_then(|v| serde_yaml::from_value::<Vec<String>>(v).ok());
-        let class_not = map
-            .remove("class_not")
-            .and_then(|v| serde_yaml::from_value::<Vec<String>>(v).ok());
+    /// Every remap in the config with the rule it comes from, matching or not.
+    pub fn all_remaps(&self) -> impl Iterator<Item = (RuleId, &Remap)> {
+        let global = self.global.iter().map(|remap| (RuleId::Global, remap));
+        let windows = self.windows.iter().enumerate().flat_map(|(index, rule)| {
+            rule.remaps
+                .iter()
+                .map(move |remap| (RuleId::Window(index), remap))
+        });
+        global.chain(windows)
+    }
 
-        let remaps_value = map
-            .remove("remaps")
-            .ok_or_else(|| serde::de::Error::missing_field("remaps"))?;
+    /// How messages name a rule: by its `name`, or by its index.
+    pub fn rule_label(&self, rule: RuleId) -> String {
+        match rule {
+            RuleId::Global => "global".to_string(),
+            RuleId::Window(index) => match &self.windows[index].name {
+                Some(name) => format!("rule '{}'", name),
+                None => format!("window rule {}", index),
+            },
+        }
+    }
 
-        let remaps_list =
-            serde_yaml::from_value::<Vec<Value>>(remaps_value).map_err(serde::de::Error::custom)?;
+    /// Mistakes that parse but can't do what was meant: a key remapped
+    /// twice in one rule, rules that never apply because an earlier
+    /// catch-all rule has `stop`, classes both in `class_only` and
+    /// `class_not`, and keys remapped to themselves.
+    pub fn lint(&self) -> Vec<String> {
+        let mut warnings = Vec::new();
+        let rules = std::iter::once((RuleId::Global, &self.global)).chain(
+            self.windows
+                .iter()
+                .enumerate()
+                .map(|(index, rule)| (RuleId::Window(index), &rule.remaps)),
+        );
+        for (rule, remaps) in rules {
+            for (i, remap) in remaps.iter().enumerate() {
+                if remaps[..i]
+                    .iter()
+                    .any(|earlier| same_key(&earlier.from, &remap.from))
+                {
+                    warnings.push(format!(
+                        "'{}' is remapped more than once in {}; only the last one applies",
+                        remap.from,
+                        self.rule_label(rule)
+                    ));
+                }
+                // Unless it keeps a global remap of the key out of the window
+                let overrides = rule != RuleId::Global
+                    && self
+                        .global
+                        .iter()
+                        .any(|global| same_key(&global.from, &remap.from));
+                if !overrides
+                    && matches!(&remap.to, KeyAction::Single(to) if same_key(to, &remap.from))
+                {
+                    warnings.push(format!(
+                        "'{}' in {} is remapped to itself",
+                        remap.from,
+                        self.rule_label(rule)
+                    ));
+                }
+            }
+        }
 
-        let mut remaps = Vec::new();
-        for remap_value in remaps_list {
-            if let Value::Mapping(map) = remap_value {
-                for (key, value) in map {
-                    let from =
-                        serde_yaml::from_value::<String>(key).map_err(serde::de::Error::custom)?;
-
-                    let to = match value {
-                        Value::String(s) => KeyAction::Single(s),
-                        Value::Sequence(seq) => {
-                            let strings = seq
-                                .into_iter()
-                                .map(|v| serde_yaml::from_value::<String>(v))
-                                .collect::<Result<Vec<_>, _>>()
-                                .map_err(serde::de::Error::custom)?;
-                            KeyAction::Multiple(strings)
-                        }
-                        _ => return Err(serde::de::Error::custom("Invalid 'to' value")),
-                    };
+        for (index, rule) in self.windows.iter().enumerate() {
+            if let (Some(only), Some(not)) = (&rule.class_only, &rule.class_not) {
+                for class in only
+                    .iter()
+                    .filter(|c| not.iter().any(|n| n.eq_ignore_ascii_case(c)))
+                {
+                    warnings.push(format!(
+                        "'{}' is in both class_only and class_not of {}",
+                        class,
+                        self.rule_label(RuleId::Window(index))
+                    ));
+                }
+            }
+        }
 
-                    remaps.push(Remap { from, to });
+        // Rules apply by priority, then file order, so one that sorts after
+        // a catch-all rule with `stop` from the same file is never reached
+        let catch_alls = self
+            .windows
+            .iter()
+            .enumerate()
+            .filter(|(_, rule)| rule.enabled && rule.stop && is_catch_all(rule));
+        for (stop_index, stop) in catch_alls {
+            for (index, rule) in self.windows.iter().enumerate() {
+                if rule.system == stop.system
+                    && (rule.priority, index) > (stop.priority, stop_index)
+                {
+                    warnings.push(format!(
+                        "{} never applies: {} matches every window and has `stop`",
+                        self.rule_label(RuleId::Window(index)),
+                        self.rule_label(RuleId::Window(stop_index))
+                    ));
                 }
             }
         }
+        warnings
+    }
 
-        Ok(WindowConfig {
-            class_only,
-            class_not,
-            remaps,
+    /// Enables or disables the window rules called `name`. Returns whether
+    /// there were any.
+    pub fn set_rule_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        let mut found = false;
+        for rule in &mut self.windows {
+            if rule.name.as_deref() == Some(name) {
+                rule.enabled = enabled;
+                found = true;
+            }
+        }
+        found
+    }
+
+    /// Whether `window_class` is in `ignore_classes`, matched like
+    /// `class_only`.
+    pub fn is_ignored(&self, window_class: Option<&str>) -> bool {
+        let Some(class) = window_class else {
+            return false;
+        };
+        let class = class.to_lowercase();
+        self.ignore_classes
+            .iter()
+            .any(|c| class.contains(&c.to_lowercase()))
+    }
+
+    /// Whether a window of these types is one whose focus keeps the
+    /// previous window's rules.
+    pub fn is_skipped_window_type(&self, window_types: &[String]) -> bool {
+        // The first type is the one the window manager goes by
+        window_types.first().is_some_and(|window_type| {
+            self.skip_window_types
+                .iter()
+                .any(|t| t.eq_ignore_ascii_case(window_type))
         })
     }
-}
 
-impl Config {
-    pub fn from_yaml(content: &str) -> anyhow::Result<Self> {
-        let config: Config = serde_yaml::from_str(content)?;
-        Ok(config)
+    fn matches_window(&self, config: &WindowConfig, context: &MatchContext) -> bool {
+        self.mismatch(config, context).is_none()
     }
 
-    pub fn remaps_for_window(&self, window_class: Option<&str>) -> Vec<Remap> {
-        let mut remaps = Vec::new();
+    /// Why `config` doesn't match in `context`, or `None` when it does.
+    pub fn mismatch(&self, config: &WindowConfig, context: &MatchContext) -> Option<&'static str> {
+        if !config.enabled {
+            Some("the rule is disabled")
+        } else if !self.matches_class(config, context.window_class) {
+            Some(if config.class_only.is_some() {
+                "the window class isn't in class_only"
+            } else {
+                "the window class is in class_not"
+            })
+        } else if !self.matches_layout(config, context.layout) {
+            Some("the layout isn't in layout_only")
+        } else if !self.matches_role(config, context.window_role) {
+            Some("the window role doesn't match role_only or role_not")
+        } else if !self.matches_monitor(config, context.monitor) {
+            Some("the window isn't on a monitor in monitor_only")
+        } else if !self.matches_state(config, context.window_states) {
+            Some("the window has a state in state_not")
+        } else if !self.matches_flags(config, context.flags) {
+            Some("the flag in `when` isn't toggled that way")
+        } else {
+            None
+        }
+    }
 
-        for window_config in &self.windows {
-            if self.matches_window(window_config, window_class) {
-                for remap in &window_config.remaps {
-                    remaps.push(remap.clone());
-                }
-            }
+    /// Like class matching: a window without a role matches `role_not`
+    /// rules but no `role_only` ones.
+    fn matches_role(&self, config: &WindowConfig, window_role: Option<&str>) -> bool {
+        let role = window_role.map(str::to_lowercase);
+        let has_role = |roles: &Vec<String>| {
+            role.as_ref()
+                .is_some_and(|role| roles.iter().any(|r| role.contains(&r.to_lowercase())))
+        };
+        config.role_only.as_ref().is_none_or(has_role)
+            && !config.role_not.as_ref().is_some_and(has_role)
+    }
+
+    fn matches_monitor(&self, config: &WindowConfig, monitor: Option<&str>) -> bool {
+        let Some(ref monitor_only) = config.monitor_only else {
+            return true;
+        };
+        monitor.is_some_and(|monitor| monitor_only.iter().any(|m| m.eq_ignore_ascii_case(monitor)))
+    }
+
+    /// Where the remaps of `rule` are grabbed.
+    pub fn grab_target(&self, rule: RuleId) -> GrabTarget {
+        match rule {
+            RuleId::Global => self.grab_on,
+            RuleId::Window(index) => self.windows[index].grab_on.unwrap_or(self.grab_on),
         }
+    }
 
-        remaps
+    /// Whether any rule depends on the monitor, so window moves matter.
+    pub fn uses_monitors(&self) -> bool {
+        self.windows.iter().any(|w| w.monitor_only.is_some())
+    }
+
+    fn matches_state(&self, config: &WindowConfig, states: &[String]) -> bool {
+        let Some(ref state_not) = config.state_not else {
+            return true;
+        };
+        !state_not
+            .iter()
+            .any(|s| states.iter().any(|state| state.eq_ignore_ascii_case(s)))
+    }
+
+    fn matches_flags(&self, config: &WindowConfig, flags: &[String]) -> bool {
+        let Some(ref when) = config.when else {
+            return true;
+        };
+        match when.strip_prefix('!') {
+            Some(flag) => !flags.iter().any(|f| f == flag),
+            None => flags.iter().any(|f| f == when),
+        }
+    }
+
+    fn matches_layout(&self, config: &WindowConfig, layout: Option<&str>) -> bool {
+        let Some(ref layout_only) = config.layout_only else {
+            return true;
+        };
+        match layout {
+            Some(layout) => layout_only.iter().any(|l| l.eq_ignore_ascii_case(layout)),
+            None => {
+                warn!("No keyboard layout detected - layout_only rules won't apply");
+                false
+            }
+        }
     }
 
-    fn matches_window(&self, config: &WindowConfig, window_class: Option<&str>) -> bool {
+    fn matches_class(&self, config: &WindowConfig, window_class: Option<&str>) -> bool {
         // If both class_only and class_not are None, this rule applies to all windows
         if config.class_only.is_none() && config.class_not.is_none() {
             return true;
@@ -135,3 +2700,1432 @@ impl Config {
         true
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_error_points_at_bad_remap_line() {
+        let yaml = r#"
+windows:
+  - class_only:
+      - 'chromium'
+    remaps:
+      - 'C-b': 'Left'
+      - 'C-Foo': 'Right'
+"#;
+
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert_eq!(err.line, Some(7));
+        assert!(err.message.contains("'C-Foo'"), "{}", err.message);
+        assert!(
+            err.message.contains("windows[0].remaps[1]"),
+            "{}",
+            err.message
+        );
+        assert_eq!(err.source_line.as_deref(), Some("      - 'C-Foo': 'Right'"));
+    }
+
+    #[test]
+    fn test_error_names_bad_target_in_sequence() {
+        let yaml = r#"
+windows:
+  - remaps:
+      - 'C-k': ['Shift-End', 'Hyperr-x']
+"#;
+
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert_eq!(err.line, Some(4));
+        assert!(
+            err.message.contains("unknown modifier 'Hyperr'"),
+            "{}",
+            err.message
+        );
+    }
+
+    #[test]
+    fn test_script_action_resolves_relative_to_config() {
+        let yaml = r#"
+windows:
+  - remaps:
+      - 'C-j': {script: 'actions/join.rhai'}
+"#;
+
+        let mut config = Config::from_yaml(yaml).unwrap();
+        config.resolve_paths(Path::new("/etc/remapper"));
+        match &config.windows[0].remaps[0].to {
+            KeyAction::Script(path) => {
+                assert_eq!(path, Path::new("/etc/remapper/actions/join.rhai"))
+            }
+            other => panic!("unexpected action {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sequence_with_delay() {
+        let yaml = r#"
+key_delay_ms: 10
+windows:
+  - remaps:
+      - 'C-k': ['Shift-End', {delay: 50}, 'Ctrl-x']
+"#;
+
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(config.key_delay_ms, 10);
+        match &config.windows[0].remaps[0].to {
+            KeyAction::Multiple(steps) => assert_eq!(
+                steps,
+                &[
+                    SequenceStep::Key("Shift-End".to_string()),
+                    SequenceStep::Delay(50),
+                    SequenceStep::Key("Ctrl-x".to_string()),
+                ]
+            ),
+            other => panic!("unexpected action {:?}", other),
+        }
+    }
+
+    #[test]
+    fn test_sequence_holds_modifier() {
+        let yaml = r#"
+windows:
+  - remaps:
+      - 'C-S-e': [{down: 'Shift'}, 'End', 'Down', {up: 'Shift'}]
+"#;
+
+        let config = Config::from_yaml(yaml).unwrap();
+        match &config.windows[0].remaps[0].to {
+            KeyAction::Multiple(steps) => {
+                assert_eq!(steps[0], SequenceStep::Down("Shift".to_string()));
+                assert_eq!(steps[3], SequenceStep::Up("Shift".to_string()));
+            }
+            other => panic!("unexpected action {:?}", other),
+        }
+
+        let yaml = "windows: [{remaps: [{'C-a': [{down: 'a'}]}]}]";
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert!(err.message.contains("not a modifier"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_sequence_repeats_key() {
+        let yaml = "global: [{'C-n': [{key: 'Down', repeat: 10}, {repeat: 2, key: 'End'}]}]";
+        let config = Config::from_yaml(yaml).unwrap();
+        let repeat = |key: &str, count| SequenceStep::Repeat {
+            key: key.to_string(),
+            count,
+        };
+        match &config.global[0].to {
+            KeyAction::Multiple(steps) => {
+                assert_eq!(steps, &[repeat("Down", 10), repeat("End", 2)])
+            }
+            other => panic!("unexpected action {:?}", other),
+        }
+
+        for (yaml, expected) in [
+            (
+                "global: [{'C-n': [{key: 'Down', repeat: 0}]}]",
+                "at least once",
+            ),
+            ("global: [{'C-n': [{key: 'Down'}]}]", "repeat"),
+            ("global: [{'C-n': [{key: 'Down', times: 2}]}]", "times"),
+            ("global: [{'C-n': [{key: 'Dwn', repeat: 2}]}]", "Dwn"),
+        ] {
+            let err = Config::from_yaml(yaml).unwrap_err();
+            assert!(err.message.contains(expected), "{}", err.message);
+        }
+    }
+
+    #[test]
+    fn test_disabled_action() {
+        let yaml = r#"
+windows:
+  - class_only: ['urxvt']
+    remaps:
+      - 'C-w': null
+      - 'C-q': disabled
+      - 'C-d': 'disabled'
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert!(config.windows[0]
+            .remaps
+            .iter()
+            .all(|remap| matches!(remap.to, KeyAction::Disabled)));
+    }
+
+    #[test]
+    fn test_serialize_round_trip() {
+        let yaml = r#"
+classes:
+  terminals: ['urxvt', 'kitty']
+global:
+  - 'C-b': 'Left'
+  - 'C-a': ['Home', {delay: 20}, {down: 'Shift'}, 'End', {up: 'Shift'}]
+  - 'C-q': null
+  - 'Super_R': {one_shot: 'Super'}
+  - 'C-M-d': {window: {move_to_desktop: 2}}
+    'C-M-c': {window: close}
+  - 'Caps_Lock': 'Escape'
+    hold: 'Control_L'
+  - 'F5': {exec: 'notify-send hi'}
+    also_send_original: true
+    send_to: {class: 'mpv'}
+  - 'Menu': {layout: 'next'}
+    on: release
+windows:
+  - class_not: ['terminals']
+    state_not: ['fullscreen']
+    grab_on: client
+    remaps:
+      - 'C-k': ['S-End', 'C-x']
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        let dumped = serde_yaml::to_string(&config).unwrap();
+        assert!(dumped.contains("- C-b: Left\n"), "{}", dumped);
+        assert!(dumped.contains("- C-q: null\n"), "{}", dumped);
+
+        let reloaded = Config::from_yaml(&dumped).unwrap();
+        assert_eq!(serde_yaml::to_string(&reloaded).unwrap(), dumped);
+        assert_eq!(reloaded.global.len(), config.global.len());
+        let caps = &reloaded.global[6];
+        assert!(matches!(caps.hold, Some(KeyAction::Single(ref key)) if key == "Control_L"));
+        assert_eq!(reloaded.global[8].on, Trigger::Release);
+        assert_eq!(
+            reloaded.windows[0].class_not.as_deref(),
+            Some(&["urxvt".to_string(), "kitty".to_string()][..])
+        );
+    }
+
+    #[test]
+    fn test_grab_on() {
+        let yaml = r#"
+windows:
+  - class_only: ['firefox']
+    grab_on: client
+    remaps: [{'C-b': 'Left'}]
+  - class_only: ['urxvt']
+    remaps: [{'C-b': 'Left'}]
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(config.grab_on, GrabTarget::Root);
+        assert_eq!(config.grab_target(RuleId::Global), GrabTarget::Root);
+        assert_eq!(config.grab_target(RuleId::Window(0)), GrabTarget::Client);
+        assert_eq!(config.grab_target(RuleId::Window(1)), GrabTarget::Root);
+
+        let config = Config::from_yaml("grab_on: client").unwrap();
+        assert_eq!(config.grab_target(RuleId::Global), GrabTarget::Client);
+        assert!(Config::from_yaml("grab_on: screen").is_err());
+    }
+
+    #[test]
+    fn test_matching_expansions() {
+        let yaml = r#"
+expansions:
+  ';addr': '1 Main St'
+  ';sig': 'Regards'
+windows:
+  - class_only: ['thunderbird']
+    expansions:
+      ';sig': 'Best regards'
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert!(config.has_expansions());
+        let context = MatchContext {
+            window_class: Some("thunderbird"),
+            ..Default::default()
+        };
+        let expansions = config.matching_expansions(&context);
+        assert_eq!(expansions[";sig"], "Best regards");
+        assert_eq!(expansions[";addr"], "1 Main St");
+        assert_eq!(
+            config.matching_expansions(&MatchContext::default())[";sig"],
+            "Regards"
+        );
+    }
+
+    #[test]
+    fn test_resolve_chains() {
+        let yaml = r#"
+resolve_chains: true
+global:
+  - 'C-h': 'BackSpace'
+  - 'BackSpace': 'Delete'
+windows:
+  - class_only: ['kitty']
+    remaps:
+      - 'Delete': ['End', 'S-Home']
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        let to = |class: &str| {
+            let context = MatchContext {
+                window_class: Some(class),
+                ..Default::default()
+            };
+            let remaps = config.remaps_for_window(&context);
+            serde_json::to_string(&remaps[0].to).unwrap()
+        };
+        assert_eq!(to("firefox"), r#""Delete""#);
+        assert_eq!(to("kitty"), r#"["End","S-Home"]"#);
+
+        let yaml =
+            "resolve_chains: true\nglobal:\n  - 'a': 'b'\n  - 'b': 'Ctrl-a'\n  - 'C-a': 'a'\n";
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert_eq!(err.line, Some(3));
+        assert_eq!(
+            err.message,
+            "remaps in global chain into a cycle: a -> b -> Ctrl-a -> a"
+        );
+    }
+
+    #[test]
+    fn test_click_action() {
+        let yaml = "global: [{'F9': 'LeftClick'}, {'F10': 'DoubleClick'}]";
+        let config = Config::from_yaml(yaml).unwrap();
+        assert!(matches!(
+            config.global[1].to,
+            KeyAction::Click(MouseClick::Double)
+        ));
+        let yaml = serde_yaml::to_string(&config.global[0]).unwrap();
+        assert_eq!(yaml.trim(), "F9: LeftClick");
+    }
+
+    #[test]
+    fn test_presets() {
+        let config = Config::from_yaml("presets: [caps_dual_role]").unwrap();
+        assert_eq!(config.presets, vec![Preset::CapsDualRole]);
+        assert!(Config::from_yaml("{}").unwrap().presets.is_empty());
+
+        let yaml = "presets: [caps_dual_role]\nglobal: [{'Caps_Lock': 'Escape'}]";
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert!(
+            err.message.contains("caps_dual_role preset"),
+            "{}",
+            err.message
+        );
+        assert!(Config::from_yaml("presets: [caps_escape]").is_err());
+    }
+
+    #[test]
+    fn test_template_variables() {
+        let yaml = r#"
+variables:
+  mod: 'Super'
+  delay: 20
+key_delay_ms: ${delay}
+global:
+  - '${mod}-Return': {exec: 'xterm -e echo ${SHELL}'}
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(config.key_delay_ms, 20);
+        assert_eq!(config.global[0].from, "Super-Return");
+        assert_eq!(
+            config.global[0].to,
+            KeyAction::Exec("xterm -e echo ${SHELL}".to_string())
+        );
+        assert_eq!(
+            config.remap_location(RuleId::Global, 0).as_deref(),
+            Some("line 7")
+        );
+    }
+
+    #[test]
+    fn test_user_config_over_system_config() {
+        let dir = std::env::temp_dir().join(format!("remapper-layers-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let system = dir.join("system.yaml");
+        let user = dir.join("user.yaml");
+        fs::write(
+            &system,
+            r#"
+key_delay_ms: 5
+ignore_classes: ['VirtualBox']
+classes: {browsers: ['firefox']}
+global:
+  - 'C-b': 'Left'
+  - 'C-f': 'Right'
+windows:
+  - name: 'terminals'
+    class_only: ['xterm']
+    remaps: [{'C-a': 'Home'}]
+  - class_only: ['browsers']
+    remaps: [{'C-l': 'F6'}]
+"#,
+        )
+        .unwrap();
+        fs::write(
+            &user,
+            r#"ignore_classes: []
+classes: {terminals: ['kitty']}
+global:
+  - 'C-b': 'BackSpace'
+windows:
+  - name: 'terminals'
+    class_only: ['terminals']
+    remaps: [{'C-e': 'End'}]
+"#,
+        )
+        .unwrap();
+
+        let config = Config::load_over(&user, &system, AdhocRemaps::default()).unwrap();
+        assert_eq!(config.key_delay_ms, 5);
+        assert!(config.ignore_classes.is_empty());
+        let froms: Vec<_> = config.global.iter().map(|r| r.from.as_str()).collect();
+        assert_eq!(froms, ["C-f", "C-b"]);
+        assert_eq!(config.windows.len(), 2);
+        assert_eq!(
+            config.windows[0].class_only,
+            Some(vec!["firefox".to_string()])
+        );
+        assert_eq!(
+            config.windows[1].class_only,
+            Some(vec!["kitty".to_string()])
+        );
+        // Lines point into the user's file, at the user's remaps
+        let location = config.remap_location(RuleId::Global, 1).unwrap();
+        assert!(location.ends_with("user.yaml:4"), "{}", location);
+        assert_eq!(config.remap_location(RuleId::Global, 0), None);
+        let location = config.remap_location(RuleId::Window(1), 0).unwrap();
+        assert!(location.ends_with("user.yaml:8"), "{}", location);
+
+        fs::write(&user, "system_config: false\nglobal: [{'C-b': 'Left'}]\n").unwrap();
+        let config = Config::load_over(&user, &system, AdhocRemaps::default()).unwrap();
+        assert_eq!(config.key_delay_ms, 0);
+        assert!(config.windows.is_empty());
+
+        // The system config alone isn't merged over itself
+        let config = Config::load_over(&system, &system, AdhocRemaps::default()).unwrap();
+        assert_eq!(config.global.len(), 2);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_system_rules_apply_first() {
+        let dir = std::env::temp_dir().join(format!("remapper-system-{}", std::process::id()));
+        fs::create_dir_all(&dir).unwrap();
+        let system = dir.join("system.yaml");
+        let user = dir.join("user.yaml");
+        fs::write(
+            &system,
+            r#"
+windows:
+  - priority: 10
+    remaps: [{'C-a': 'Home'}]
+  - priority: 20
+    stop: true
+    remaps: [{'C-e': 'End'}]
+  - priority: 30
+    remaps: [{'C-k': 'S-End'}]
+"#,
+        )
+        .unwrap();
+        fs::write(
+            &user,
+            "windows:
+  - remaps: [{'C-a': 'BackSpace'}]
+",
+        )
+        .unwrap();
+
+        let config = Config::load_over(&user, &system, AdhocRemaps::default()).unwrap();
+        assert_eq!(
+            config.lint(),
+            ["window rule 2 never applies: window rule 1 matches every window and has `stop`"]
+        );
+        let context = MatchContext::default();
+        // The system's rules by priority up to its `stop`, then the user's,
+        // despite their lower priority
+        let applied: Vec<_> = config
+            .applied_rules(&context)
+            .into_iter()
+            .map(|(index, _)| index)
+            .collect();
+        assert_eq!(applied, [0, 1, 3]);
+        let remaps = config.remaps_for_window(&context);
+        let c_a = remaps.iter().rfind(|r| r.from == "C-a").unwrap();
+        assert_eq!(c_a.to, KeyAction::Single("BackSpace".to_string()));
+        fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_on_focus_change() {
+        let config = Config::from_yaml("on_focus_change: 'echo $WINDOW_CLASS'").unwrap();
+        assert_eq!(
+            config.on_focus_change.as_deref(),
+            Some("echo $WINDOW_CLASS")
+        );
+        assert!(Config::from_yaml("{}").unwrap().on_focus_change.is_none());
+    }
+
+    #[test]
+    fn test_physical_keys() {
+        assert!(
+            Config::from_yaml("physical_keys: true")
+                .unwrap()
+                .physical_keys
+        );
+        assert!(!Config::from_yaml("{}").unwrap().physical_keys);
+    }
+
+    #[test]
+    fn test_swap() {
+        let config = Config::from_yaml("global: [{swap: ['Left', 'Right']}]").unwrap();
+        assert_eq!(config.global.len(), 32);
+        assert_eq!(config.global[0].from, "Left");
+        assert!(matches!(config.global[0].to, KeyAction::Single(ref key) if key == "Right"));
+        assert_eq!(config.global[1].from, "Right");
+        assert!(config
+            .global
+            .iter()
+            .any(|remap| remap.from == "Shift-Ctrl-Right"
+                && matches!(remap.to, KeyAction::Single(ref key) if key == "Shift-Ctrl-Left")));
+
+        let yaml = "global: [{swap: ['a', 'b'], modifiers: [C]}, {'F1': 'F2'}]";
+        let config = Config::from_yaml(yaml).unwrap();
+        let froms: Vec<_> = config.global.iter().map(|r| r.from.as_str()).collect();
+        assert_eq!(froms, ["a", "b", "C-a", "C-b", "F1"]);
+
+        for (yaml, expected) in [
+            ("global: [{swap: ['a']}]", "two keys"),
+            ("global: [{swap: ['a', 'b'], modifiers: [Shoe]}]", "Shoe"),
+            ("global: [{'a': 'b', modifiers: [C]}]", "only for swap"),
+        ] {
+            let err = Config::from_yaml(yaml).unwrap_err();
+            assert!(err.message.contains(expected), "{}", err.message);
+        }
+    }
+
+    #[test]
+    fn test_several_keys_share_an_action() {
+        let yaml = "global:\n  - ['C-b', 'M-Left']: 'Left'\n    'C-f': 'Right'\n";
+        let config = Config::from_yaml(yaml).unwrap();
+        let remaps: Vec<_> = config
+            .global
+            .iter()
+            .map(|remap| (remap.from.as_str(), remap.to.clone()))
+            .collect();
+        let left = KeyAction::Single("Left".to_string());
+        assert_eq!(
+            remaps,
+            [
+                ("C-b", left.clone()),
+                ("M-Left", left),
+                ("C-f", KeyAction::Single("Right".to_string())),
+            ]
+        );
+        assert_eq!(
+            config.remap_location(RuleId::Global, 1).as_deref(),
+            Some("line 2")
+        );
+
+        for (yaml, expected) in [
+            ("global: [{[]: 'Left'}]", "empty list"),
+            ("global: [{['C-b', 'C-Shoe']: 'Left'}]", "Shoe"),
+        ] {
+            let err = Config::from_yaml(yaml).unwrap_err();
+            assert!(err.message.contains(expected), "{}", err.message);
+        }
+    }
+
+    #[test]
+    fn test_remap_timing() {
+        let yaml = r#"
+chord_window_ms: 80
+tapping_term_ms: 180
+global:
+  - 'j+k': 'Escape'
+    chord_timeout_ms: 30
+  - 'F1': 'F1'
+    hold: 'F2'
+    hold_timeout_ms: 1000
+  - 'C-k': ['Shift-End', 'Ctrl-x']
+    key_delay_ms: 20
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(config.chord_timeout_ms, 80);
+        assert_eq!(config.tapping_term_ms, 180);
+        assert_eq!(config.global[0].timing.chord_timeout_ms, Some(30));
+        assert_eq!(config.global[1].timing.hold_timeout_ms, Some(1000));
+        assert_eq!(config.global[2].timing.key_delay_ms, Some(20));
+        assert_eq!(config.global[2].timing.hold_timeout_ms, None);
+        let yaml = serde_yaml::to_string(&config.global[1]).unwrap();
+        assert!(yaml.contains("hold_timeout_ms: 1000"), "{}", yaml);
+
+        for (yaml, expected) in [
+            (
+                "global: [{'a': 'b', chord_timeout_ms: 30}]",
+                "only for chords",
+            ),
+            (
+                "global: [{'a': 'b', hold_timeout_ms: 30}]",
+                "needs a hold action",
+            ),
+        ] {
+            let err = Config::from_yaml(yaml).unwrap_err();
+            assert!(err.message.contains(expected), "{}", err.message);
+        }
+    }
+
+    #[test]
+    fn test_home_row_mods() {
+        let yaml = r#"
+home_row_mods: {a: Super, s: Alt, d: Shift, f: Ctrl}
+hold_flavor: hold_on_other_key_press
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(config.home_row_mods["f"].modifier(), "Ctrl");
+        assert_eq!(config.home_row_mods["f"].tapping_term_ms(), None);
+        assert_eq!(config.hold_flavor, HoldFlavor::HoldOnOtherKeyPress);
+        let config =
+            Config::from_yaml("home_row_mods: {a: {modifier: Super, tapping_term_ms: 300}}")
+                .unwrap();
+        assert_eq!(config.home_row_mods["a"].modifier(), "Super");
+        assert_eq!(config.home_row_mods["a"].tapping_term_ms(), Some(300));
+        let config = Config::from_yaml("{}").unwrap();
+        assert_eq!(config.hold_flavor, HoldFlavor::PermissiveHold);
+
+        for (yaml, expected) in [
+            ("home_row_mods: {a: Shoe}", "home-row mod 'a'"),
+            ("home_row_mods: {a: {modifier: Shoe}}", "home-row mod 'a'"),
+            ("home_row_mods: {C-a: Shift}", "modifiers of its own"),
+            (
+                "home_row_mods: {a: Shift}\nglobal: [{'a': 'b'}]",
+                "also a home-row mod",
+            ),
+        ] {
+            let err = Config::from_yaml(yaml).unwrap_err();
+            assert!(err.message.contains(expected), "{}", err.message);
+        }
+    }
+
+    #[test]
+    fn test_layers() {
+        let yaml = r#"
+global:
+  - 'Henkan': {layer: nav}
+  - 'Muhenkan': {layer: symbols}
+layers:
+  symbols:
+    - 'a': 'exclam'
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert!(matches!(config.global[0].to, KeyAction::Layer(ref name) if name == "nav"));
+        assert_eq!(config.layer("nav").unwrap().len(), 8);
+        assert_eq!(config.layer("symbols").unwrap()[0].from, "a");
+
+        let err = Config::from_yaml("global: [{'Henkan': {layer: numbers}}]").unwrap_err();
+        assert!(err.message.contains("layer 'numbers'"), "{}", err.message);
+
+        for (yaml, expected) in [
+            (
+                "[{'F1': 'F1', hold: {layer: nav}}]",
+                "can't be a hold action",
+            ),
+            ("[{'j+k': {layer: nav}}]", "can't be a chord's"),
+            (
+                "[{'Henkan': {layer: nav}, on: release}]",
+                "can't fire on release",
+            ),
+            (
+                "[{'Henkan': {if_shift: {layer: nav}, else: 'a'}}]",
+                "can't be in an if_* branch",
+            ),
+        ] {
+            let err = Config::from_yaml(&format!("global: {}", yaml)).unwrap_err();
+            assert!(err.message.contains(expected), "{}", err.message);
+        }
+    }
+
+    #[test]
+    fn test_one_shot_action() {
+        let yaml = "global: [{'Super_R': {one_shot: 'Super'}}]";
+        let config = Config::from_yaml(yaml).unwrap();
+        assert!(matches!(
+            config.global[0].to,
+            KeyAction::OneShot(ref name) if name == "Super"
+        ));
+
+        let yaml = "global: [{'F12': {one_shot: 'f'}}]";
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert!(err.message.contains("not a modifier"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_compose_action() {
+        let yaml = "global: [{'M-e': {compose: ['a', \"'\"]}}]";
+        let config = Config::from_yaml(yaml).unwrap();
+        assert!(matches!(
+            config.global[0].to,
+            KeyAction::Compose(ref keys) if keys == &["a", "'"]
+        ));
+
+        let yaml = "global: [{'M-e': {compose: []}}]";
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert!(err.message.contains("at least one key"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_if_modifier_action() {
+        let yaml = "global: [{'C-a': {if_shift: 'Home', else: ['Left', 'Left']}}]";
+        let config = Config::from_yaml(yaml).unwrap();
+        let left = SequenceStep::Key("Left".to_string());
+        assert_eq!(
+            config.global[0].to,
+            KeyAction::IfModifier {
+                modifier: "Shift".to_string(),
+                then: Box::new(KeyAction::Single("Home".to_string())),
+                otherwise: Box::new(KeyAction::Multiple(vec![left.clone(), left])),
+            }
+        );
+
+        let yaml = "global: [{'C-a': {else: 'Left', if_ctrl: 'Home'}}]";
+        let config = Config::from_yaml(yaml).unwrap();
+        assert!(matches!(
+            config.global[0].to,
+            KeyAction::IfModifier { ref modifier, .. } if modifier == "Ctrl"
+        ));
+
+        for (yaml, expected) in [
+            ("global: [{'C-a': {if_shift: 'Home'}}]", "else"),
+            ("global: [{'C-a': {else: 'Left'}}]", "needs a condition"),
+            ("global: [{'C-a': {if_shift: 'Hme', else: 'Left'}}]", "Hme"),
+            (
+                "global: [{'C-a': {if_shift: 'Home', if_alt: 'End', else: 'Left'}}]",
+                "one modifier",
+            ),
+        ] {
+            let err = Config::from_yaml(yaml).unwrap_err();
+            assert!(err.message.contains(expected), "{}", err.message);
+        }
+    }
+
+    #[test]
+    fn test_window_actions() {
+        let yaml = r#"
+global:
+  - 'Super-q': {window: close}
+  - 'Super-f': {window: fullscreen_toggle}
+  - 'Super-S-2': {window: {move_to_desktop: 2}}
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        let actions: Vec<_> = config
+            .global
+            .iter()
+            .map(|remap| match remap.to {
+                KeyAction::Window(action) => action,
+                ref other => panic!("unexpected action {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            actions,
+            [
+                WindowAction::Close,
+                WindowAction::FullscreenToggle,
+                WindowAction::MoveToDesktop(2)
+            ]
+        );
+
+        let yaml = "global: [{'Super-0': {window: {move_to_desktop: 0}}}]";
+        assert!(Config::from_yaml(yaml).is_err());
+    }
+
+    #[test]
+    fn test_chord_rejects_modifiers() {
+        let yaml = r#"
+windows:
+  - remaps:
+      - 'j+k': 'Escape'
+      - 'C-j+k': 'Escape'
+"#;
+
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert_eq!(err.line, Some(5));
+        assert!(err.message.contains("'C-j+k'"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_also_send_original_option() {
+        let yaml = r#"
+windows:
+  - remaps:
+      - 'C-s': 'Ctrl-f'
+        also_send_original: true
+      - 'C-b': 'Left'
+"#;
+
+        let config = Config::from_yaml(yaml).unwrap();
+        let remaps = &config.windows[0].remaps;
+        assert_eq!(remaps.len(), 2);
+        assert!(remaps[0].also_send_original);
+        assert!(!remaps[1].also_send_original);
+    }
+
+    #[test]
+    fn test_remap_on_release() {
+        let yaml = r#"
+windows:
+  - remaps:
+      - 'F13': 'C-S-m'
+        on: release
+      - 'F14': 'C-S-n'
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(config.windows[0].remaps[0].on, Trigger::Release);
+        assert_eq!(config.windows[0].remaps[1].on, Trigger::Press);
+
+        let yaml = "windows: [{remaps: [{'j+k': 'Escape', on: release}]}]";
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert!(err.message.contains("chords can't"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_remap_hold() {
+        let yaml = r#"
+hold_timeout_ms: 300
+global:
+  - 'Escape': 'Escape'
+    hold: {exec: 'loginctl lock-session'}
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(config.hold_timeout_ms, 300);
+        assert!(matches!(
+            config.global[0].hold,
+            Some(KeyAction::Exec(ref command)) if command == "loginctl lock-session"
+        ));
+        assert_eq!(Config::from_yaml("{}").unwrap().hold_timeout_ms, 500);
+
+        let yaml = "global: [{'F1': 'F1', hold: 'C-j+k'}]";
+        assert!(Config::from_yaml(yaml).is_err());
+        let yaml = "global: [{'F1': 'F1', hold: 'F2', on: release}]";
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert!(err.message.contains("hold action"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_send_to() {
+        let yaml = r#"
+global:
+  - 'XF86AudioPlay': 'space'
+    send_to: {class: 'mpv'}
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(
+            config.global[0].send_to,
+            Some(WindowSelector {
+                class: "mpv".to_string()
+            })
+        );
+
+        let yaml = "global: [{'F1': 'space', send_to: {name: 'mpv'}}]";
+        assert!(Config::from_yaml(yaml).is_err());
+    }
+
+    #[test]
+    fn test_conditional_needs_script() {
+        let yaml = r#"
+windows:
+  - remaps:
+      - 'C-k': {script: 'kill_line.rhai'}
+        conditional: true
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert!(config.windows[0].remaps[0].conditional);
+
+        let yaml = r#"
+windows:
+  - remaps:
+      - 'C-k': 'S-End'
+        conditional: true
+"#;
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert!(err.message.contains("script"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_class_groups() {
+        let yaml = r#"
+classes:
+  browsers: ['chromium', 'firefox']
+  terminals: ['urxvt', 'alacritty']
+ignore_classes: ['terminals']
+windows:
+  - class_only: ['browsers', 'Slack']
+    remaps:
+      - 'C-b': 'Left'
+  - class_not: ['browsers']
+    remaps:
+      - 'C-f': 'Right'
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(config.ignore_classes, ["urxvt", "alacritty"]);
+        assert_eq!(
+            config.windows[0].class_only.as_deref(),
+            Some(
+                &[
+                    "chromium".to_string(),
+                    "firefox".to_string(),
+                    "Slack".to_string()
+                ][..]
+            )
+        );
+        let context = |window_class| MatchContext {
+            window_class,
+            ..Default::default()
+        };
+        assert_eq!(config.remaps_for_window(&context(Some("firefox"))).len(), 1);
+        assert_eq!(config.remaps_for_window(&context(Some("emacs"))).len(), 1);
+        assert!(config.remaps_for_window(&context(Some("urxvt"))).is_empty());
+    }
+
+    #[test]
+    fn test_adhoc_remaps() {
+        let yaml = r#"
+classes: {browsers: ['chromium', 'firefox']}
+windows:
+  - class_only: ['chromium']
+    priority: 10
+    remaps: [{'C-b': 'C-a'}, {'C-f': 'Right'}]
+"#;
+        let adhoc = AdhocRemaps {
+            remaps: vec![
+                "C-b=Left".to_string(),
+                "==plus".to_string(),
+                "C-t={exec: xterm}".to_string(),
+            ],
+            classes: vec!["browsers".to_string()],
+        };
+        let config = Config::from_yaml_with_adhoc(yaml, adhoc.clone()).unwrap();
+        assert_eq!(config.adhoc, adhoc);
+        let rule = &config.windows[1];
+        assert_eq!(rule.name.as_deref(), Some(ADHOC_RULE_NAME));
+        assert_eq!(
+            rule.class_only,
+            Some(vec!["chromium".to_string(), "firefox".to_string()])
+        );
+        assert_eq!(rule.remaps[1].from, "=");
+        assert!(matches!(&rule.remaps[2].to, KeyAction::Exec(command) if command == "xterm"));
+
+        let context = MatchContext {
+            window_class: Some("chromium"),
+            ..Default::default()
+        };
+        let remaps = config.remaps_for_window(&context);
+        let to = |from: &str| {
+            remaps
+                .iter()
+                .find(|remap| remap.from == from)
+                .map(|remap| remap.to.clone())
+        };
+        assert!(matches!(to("C-b"), Some(KeyAction::Single(key)) if key == "Left"));
+        assert!(matches!(to("C-f"), Some(KeyAction::Single(key)) if key == "Right"));
+
+        let bad = |remap: &str| {
+            let adhoc = AdhocRemaps {
+                remaps: vec![remap.to_string()],
+                classes: Vec::new(),
+            };
+            Config::from_yaml_with_adhoc("{}", adhoc)
+                .unwrap_err()
+                .message
+        };
+        assert!(bad("C-b").contains("expected FROM=TO"), "{}", bad("C-b"));
+        assert!(bad("C-b=Lfet").contains("--remap"), "{}", bad("C-b=Lfet"));
+    }
+
+    #[test]
+    fn test_skip_window_types() {
+        let config = Config::from_yaml("{}").unwrap();
+        let types = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        assert!(config.is_skipped_window_type(&types(&["popup_menu"])));
+        assert!(!config.is_skipped_window_type(&types(&["dialog", "popup_menu"])));
+        assert!(!config.is_skipped_window_type(&[]));
+
+        let config = Config::from_yaml("skip_window_types: ['Dialog']").unwrap();
+        assert!(config.is_skipped_window_type(&types(&["dialog"])));
+        assert!(!config.is_skipped_window_type(&types(&["tooltip"])));
+    }
+
+    #[test]
+    fn test_ignore_classes() {
+        let yaml = r#"
+ignore_classes: ['VirtualBox']
+windows:
+  - remaps:
+      - 'C-b': 'Left'
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        let context = |window_class| MatchContext {
+            window_class,
+            ..Default::default()
+        };
+        assert!(config
+            .remaps_for_window(&context(Some("VirtualBox Machine")))
+            .is_empty());
+        assert_eq!(config.remaps_for_window(&context(Some("firefox"))).len(), 1);
+        assert_eq!(config.remaps_for_window(&context(None)).len(), 1);
+    }
+
+    #[test]
+    fn test_global_remaps_overridden_and_masked() {
+        let yaml = r#"
+global:
+  - 'C-b': 'Left'
+  - 'C-f': 'Right'
+  - 'C-a': 'Home'
+windows:
+  - class_only: ['emacs']
+    mask: ['C-a']
+    remaps:
+      - 'Ctrl-f': 'End'
+  # A rule can mask without remapping anything
+  - class_only: ['kitty']
+    mask: ['C-b', 'C-f']
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        let context = |window_class| MatchContext {
+            window_class,
+            ..Default::default()
+        };
+
+        let remaps = config.matching_remaps(&context(Some("emacs")));
+        let froms: Vec<_> = remaps
+            .iter()
+            .map(|(rule, remap)| (*rule, remap.from.as_str()))
+            .collect();
+        assert_eq!(
+            froms,
+            [(RuleId::Global, "C-b"), (RuleId::Window(0), "Ctrl-f")]
+        );
+        assert_eq!(config.remaps_for_window(&context(Some("firefox"))).len(), 3);
+        let remaps = config.remaps_for_window(&context(Some("kitty")));
+        let froms: Vec<_> = remaps.iter().map(|remap| remap.from.as_str()).collect();
+        assert_eq!(froms, ["C-a"]);
+    }
+
+    #[test]
+    fn test_rule_priority_and_stop() {
+        let yaml = r#"
+windows:
+  - priority: 10
+    remaps:
+      - 'C-b': 'Left'
+  - remaps:
+      - 'C-b': 'Home'
+      - 'C-f': 'Right'
+  - class_only: ['emacs']
+    priority: 5
+    stop: true
+    remaps:
+      - 'C-f': 'End'
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        let targets = |window_class| {
+            let context = MatchContext {
+                window_class,
+                ..Default::default()
+            };
+            config
+                .matching_remaps(&context)
+                .into_iter()
+                .map(|(rule, remap)| match remap.to {
+                    KeyAction::Single(key) => (rule, key),
+                    _ => unreachable!(),
+                })
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(
+            targets(Some("firefox")),
+            [
+                (RuleId::Window(1), "Right".to_string()),
+                (RuleId::Window(0), "Left".to_string())
+            ]
+        );
+        // The stop rule at priority 5 keeps the priority 10 rule out
+        assert_eq!(
+            targets(Some("emacs")),
+            [
+                (RuleId::Window(1), "Home".to_string()),
+                (RuleId::Window(2), "End".to_string())
+            ]
+        );
+    }
+
+    #[test]
+    fn test_unknown_fields_suggest_the_closest() {
+        let err = Config::from_yaml("windows:\n  - class_olny: ['firefox']\n    remaps: []\n")
+            .unwrap_err();
+        assert_eq!(
+            err.message,
+            "windows[0]: unknown field `class_olny`, did you mean `class_only`?"
+        );
+        assert_eq!(err.line, Some(2));
+
+        let err = Config::from_yaml("key_dealy_ms: 10").unwrap_err();
+        assert!(err.message.contains("did you mean `key_delay_ms`?"));
+
+        // Nothing close: all the fields are listed
+        let err = Config::from_yaml("colors: true").unwrap_err();
+        assert!(err.message.contains("expected one of `key_delay_ms`"));
+
+        let err =
+            Config::from_yaml("global: [{'C-b': 'Left', also_send_orignal: true}]").unwrap_err();
+        assert!(err.message.contains("did you mean `also_send_original`?"));
+    }
+
+    #[test]
+    fn test_lint() {
+        let yaml = r#"
+global:
+  - 'C-a': 'Home'
+  - 'Ctrl-a': 'End'
+windows:
+  - name: terminals
+    class_only: ['kitty', 'XTerm']
+    class_not: ['xterm']
+    remaps:
+      - 'C-b': 'C-b'
+  - stop: true
+    remaps:
+      - 'C-f': 'Right'
+  - priority: -1
+    remaps:
+      - 'C-n': 'Down'
+  - class_only: ['emacs']
+    remaps:
+      - 'C-p': 'Up'
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(
+            config.lint(),
+            [
+                "'Ctrl-a' is remapped more than once in global; only the last one applies",
+                "'C-b' in rule 'terminals' is remapped to itself",
+                "'XTerm' is in both class_only and class_not of rule 'terminals'",
+                "window rule 3 never applies: window rule 1 matches every window and has `stop`",
+            ]
+        );
+
+        let clean = Config::from_yaml("global: [{'C-a': 'Home'}, {'C-e': 'End'}]").unwrap();
+        assert!(clean.lint().is_empty());
+
+        // Overriding a global remap in a window is fine, but a key gets
+        // only one remap per rule, whatever its trigger
+        let yaml = r#"
+global:
+  - 'C-b': 'Left'
+  - 'Super_L': 'Super_L'
+  - 'Super_L': {exec: 'rofi -show run'}
+    on: release
+windows:
+  - class_only: ['emacs']
+    remaps:
+      - 'C-b': 'C-b'
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(
+            config.lint(),
+            [
+                "'Super_L' in global is remapped to itself",
+                "'Super_L' is remapped more than once in global; only the last one applies",
+            ]
+        );
+    }
+
+    #[test]
+    fn test_disabled_rule_never_matches() {
+        let yaml = r#"
+windows:
+  - name: 'emacs-keys'
+    remaps:
+      - 'C-b': 'Left'
+  - name: 'paste'
+    enabled: false
+    remaps:
+      - 'C-y': 'C-v'
+"#;
+        let mut config = Config::from_yaml(yaml).unwrap();
+        let context = MatchContext::default();
+        assert_eq!(config.remaps_for_window(&context).len(), 1);
+
+        assert!(config.set_rule_enabled("paste", true));
+        assert!(config.set_rule_enabled("emacs-keys", false));
+        assert!(!config.set_rule_enabled("missing", false));
+        let remaps = config.remaps_for_window(&context);
+        assert_eq!(remaps.len(), 1);
+        assert_eq!(remaps[0].from, "C-y");
+    }
+
+    #[test]
+    fn test_default_paths() {
+        let paths = default_paths(Some("/xdg".into()), Some("/home/me".into()));
+        assert_eq!(
+            paths,
+            [
+                PathBuf::from("/xdg/simple-x11-remapper/config.yaml"),
+                PathBuf::from("/home/me/.config/simple-x11-remapper/config.yaml"),
+            ]
+        );
+        // The spec says to ignore a relative XDG_CONFIG_HOME
+        let paths = default_paths(Some("relative".into()), Some("/home/me".into()));
+        assert_eq!(paths.len(), 1);
+        assert!(default_paths(None, None).is_empty());
+    }
+
+    #[test]
+    fn test_modmap_entries() {
+        let yaml = r#"
+modmap:
+  - clear: 'Lock'
+  - keysym: 'Caps_Lock'
+    keysyms: ['Control_L']
+  - add: 'Control'
+    keys: ['Control_L']
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(config.modmap.len(), 3);
+        assert_eq!(
+            config.modmap[0],
+            ModmapEntry::Clear {
+                clear: "Lock".to_string()
+            }
+        );
+
+        let err = Config::from_yaml("modmap: [{clear: 'Hyper'}]").unwrap_err();
+        assert!(err.message.contains("unknown modifier"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_layout_only_rule() {
+        let yaml = r#"
+windows:
+  - layout_only: ['us']
+    remaps:
+      - 'C-b': 'Left'
+  - remaps:
+      - 'C-f': 'Right'
+"#;
+
+        let config = Config::from_yaml(yaml).unwrap();
+        let us = MatchContext {
+            layout: Some("us"),
+            ..Default::default()
+        };
+        let de = MatchContext {
+            layout: Some("de"),
+            ..Default::default()
+        };
+        assert_eq!(config.remaps_for_window(&us).len(), 2);
+        assert_eq!(config.remaps_for_window(&de).len(), 1);
+    }
+
+    #[test]
+    fn test_non_ascii_class_match() {
+        let yaml = r#"
+windows:
+  - class_only: ['ターミナル', '🦀']
+    remaps:
+      - 'C-b': 'Left'
+"#;
+        let config = Config::from_yaml(yaml).unwrap();
+        let context = |window_class| MatchContext {
+            window_class: Some(window_class),
+            ..Default::default()
+        };
+        assert_eq!(
+            config.remaps_for_window(&context("ターミナル — zsh")).len(),
+            1
+        );
+        assert_eq!(
+            config
+                .remaps_for_window(&context("🦀 Rust - Firefox"))
+                .len(),
+            1
+        );
+        assert!(config.remaps_for_window(&context("端末")).is_empty());
+    }
+
+    #[test]
+    fn test_role_rules() {
+        let yaml = r#"
+windows:
+  - class_only: ['firefox']
+    role_only: ['browser']
+    remaps:
+      - 'C-b': 'Left'
+  - role_not: ['Preferences']
+    remaps:
+      - 'C-f': 'Right'
+"#;
+
+        let config = Config::from_yaml(yaml).unwrap();
+        let context = |window_role| MatchContext {
+            window_class: Some("firefox"),
+            window_role,
+            ..Default::default()
+        };
+        assert_eq!(config.remaps_for_window(&context(Some("browser"))).len(), 2);
+        assert!(config
+            .remaps_for_window(&context(Some("Preferences")))
+            .is_empty());
+        assert_eq!(config.remaps_for_window(&context(None)).len(), 1);
+    }
+
+    #[test]
+    fn test_monitor_only_rule() {
+        let yaml = r#"
+windows:
+  - monitor_only: ['HDMI-1']
+    remaps:
+      - 'C-b': 'Left'
+"#;
+
+        let config = Config::from_yaml(yaml).unwrap();
+        assert!(config.uses_monitors());
+        let context = |monitor| MatchContext {
+            monitor,
+            ..Default::default()
+        };
+        assert_eq!(config.remaps_for_window(&context(Some("hdmi-1"))).len(), 1);
+        assert!(config.remaps_for_window(&context(Some("DP-1"))).is_empty());
+        assert!(config.remaps_for_window(&context(None)).is_empty());
+    }
+
+    #[test]
+    fn test_toggle_flags() {
+        let yaml = r#"
+global:
+  - 'Super-r': {toggle: 'resize'}
+windows:
+  - when: 'resize'
+    remaps:
+      - 'h': 'Super-Left'
+  - when: '!resize'
+    remaps:
+      - 'C-b': 'Left'
+"#;
+
+        let config = Config::from_yaml(yaml).unwrap();
+        assert_eq!(config.global[0].to, KeyAction::Toggle("resize".to_string()));
+        let froms = |flags: &[String]| {
+            let context = MatchContext {
+                flags,
+                ..Default::default()
+            };
+            config
+                .remaps_for_window(&context)
+                .iter()
+                .map(|remap| remap.from.clone())
+                .collect::<Vec<_>>()
+        };
+        assert_eq!(froms(&[]), ["Super-r", "C-b"]);
+        assert_eq!(froms(&["resize".to_string()]), ["Super-r", "h"]);
+
+        for (yaml, expected) in [
+            (
+                "[{'F1': 'F1', hold: {toggle: zoom}}]",
+                "can't be a hold action",
+            ),
+            ("[{'j+k': {toggle: zoom}}]", "can't be a chord's"),
+            (
+                "[{'F2': {toggle: zoom}, on: release}]",
+                "can't fire on release",
+            ),
+            (
+                "[{'F2': {if_shift: {toggle: zoom}, else: 'a'}}]",
+                "can't be in an if_* branch",
+            ),
+        ] {
+            let err = Config::from_yaml(&format!("global: {}", yaml)).unwrap_err();
+            assert!(err.message.contains(expected), "{}", err.message);
+        }
+
+        let yaml = "windows: [{when: 'rezise', remaps: [{'h': 'Left'}]}]";
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert!(err.message.contains("'rezise'"), "{}", err.message);
+    }
+
+    #[test]
+    fn test_plugin_action() {
+        let yaml = r#"
+plugins: ['plugins/libosc52.so', '/usr/lib/sxr/libydotool.so']
+global:
+  - 'C-c': {plugin: 'osc52'}
+  - 'F8': {plugin: {name: 'ydotool', arg: 'type hello'}}
+"#;
+
+        let mut config = Config::from_yaml(yaml).unwrap();
+        config.resolve_paths(Path::new("/etc/remapper"));
+        let paths: Vec<_> = config.plugins.iter().map(|p| p.path.as_path()).collect();
+        assert_eq!(
+            paths,
+            [
+                Path::new("/etc/remapper/plugins/libosc52.so"),
+                Path::new("/usr/lib/sxr/libydotool.so")
+            ]
+        );
+        assert_eq!(
+            config.global[0].to,
+            KeyAction::Plugin(PluginAction {
+                name: "osc52".to_string(),
+                arg: None
+            })
+        );
+        let ydotool = KeyAction::Plugin(PluginAction {
+            name: "ydotool".to_string(),
+            arg: Some("type hello".to_string()),
+        });
+        assert_eq!(config.global[1].to, ydotool);
+        let dumped = serde_yaml::to_string(&ydotool).unwrap();
+        assert_eq!(serde_yaml::from_str::<KeyAction>(&dumped).unwrap(), ydotool);
+
+        let yaml = "global: [{'F8': {plugin: {name: 'ydotool', args: 'x'}}}]";
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert!(
+            err.message.contains("unknown field `args`"),
+            "{}",
+            err.message
+        );
+    }
+
+    #[test]
+    fn test_wasm_plugin_source() {
+        let yaml = "plugins: ['tmux.wasm', {path: 'zoom.wasm', allow_exec: true}]";
+        let config = Config::from_yaml(yaml).unwrap();
+        assert!(config.plugins.iter().all(PluginSource::is_wasm));
+        assert!(!config.plugins[0].allow_exec);
+        assert!(config.plugins[1].allow_exec);
+        let dumped = serde_yaml::to_string(&config.plugins).unwrap();
+        assert_eq!(
+            serde_yaml::from_str::<Vec<PluginSource>>(&dumped).unwrap(),
+            config.plugins
+        );
+
+        let yaml = "plugins: [{path: 'libosc52.so', allow_exec: true}]";
+        let err = Config::from_yaml(yaml).unwrap_err();
+        assert!(
+            err.message.contains("allow_exec is only for .wasm plugins"),
+            "{}",
+            err.message
+        );
+    }
+
+    #[test]
+    fn test_state_not_rule() {
+        let yaml = r#"
+windows:
+  - state_not: ['fullscreen']
+    remaps:
+      - 'C-b': 'Left'
+"#;
+
+        let config = Config::from_yaml(yaml).unwrap();
+        let states = ["maximized_vert".to_string(), "fullscreen".to_string()];
+        let fullscreen = MatchContext {
+            window_states: &states,
+            ..Default::default()
+        };
+        assert!(config.remaps_for_window(&fullscreen).is_empty());
+        assert_eq!(config.remaps_for_window(&MatchContext::default()).len(), 1);
+    }
+}
@@ -0,0 +1,140 @@
+//! The control socket: a Unix socket the running remapper answers one-line
+//! commands on, e.g. `stats`. Each connection sends a command and reads the
+//! reply until the remapper closes it.
+
+use log::{debug, warn};
+use std::io::{self, BufRead, BufReader, Read, Write};
+use std::os::fd::{AsRawFd, RawFd};
+use std::os::unix::net::{UnixListener, UnixStream};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+
+/// How long a client gets to send its command before it's dropped, so a
+/// stuck client can't stall the event loop.
+const READ_TIMEOUT: Duration = Duration::from_millis(100);
+
+/// `$XDG_RUNTIME_DIR/simple-x11-remapper.sock`, or a per-user path in `/tmp`
+/// when the runtime dir isn't set.
+pub fn default_path() -> PathBuf {
+    match std::env::var_os("XDG_RUNTIME_DIR") {
+        Some(dir) if !dir.is_empty() => Path::new(&dir).join("simple-x11-remapper.sock"),
+        _ => {
+            let uid = unsafe { libc::getuid() };
+            PathBuf::from(format!("/tmp/simple-x11-remapper-{}.sock", uid))
+        }
+    }
+}
+
+/// Sends `command` to the remapper listening on `path` and returns its reply.
+pub fn request(path: &Path, command: &str) -> io::Result<String> {
+    let mut stream = UnixStream::connect(path)?;
+    writeln!(stream, "{}", command)?;
+    stream.shutdown(std::net::Shutdown::Write)?;
+    let mut reply = String::new();
+    stream.read_to_string(&mut reply)?;
+    Ok(reply)
+}
+
+/// The listening side, removed from the filesystem when dropped.
+#[derive(Debug)]
+pub(crate) struct ControlSocket {
+    listener: UnixListener,
+    path: PathBuf,
+}
+
+impl ControlSocket {
+    /// Listens on `path`, replacing a stale socket left by a remapper that
+    /// didn't exit cleanly but not one that's still answering.
+    pub fn bind(path: &Path) -> io::Result<Self> {
+        if path.exists() {
+            if UnixStream::connect(path).is_ok() {
+                return Err(io::Error::new(
+                    io::ErrorKind::AddrInUse,
+                    "another remapper is listening on it",
+                ));
+            }
+            std::fs::remove_file(path)?;
+        }
+        let listener = UnixListener::bind(path)?;
+        listener.set_nonblocking(true)?;
+        Ok(Self {
+            listener,
+            path: path.to_path_buf(),
+        })
+    }
+
+    pub fn fd(&self) -> RawFd {
+        self.listener.as_raw_fd()
+    }
+
+    /// Answers every waiting connection with `respond(command)`.
+    pub fn serve(&self, mut respond: impl FnMut(&str) -> String) {
+        loop {
+            let stream = match self.listener.accept() {
+                Ok((stream, _)) => stream,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => return,
+                Err(e) => {
+                    warn!("Failed to accept control connection: {}", e);
+                    return;
+                }
+            };
+            if let Err(e) = Self::answer(stream, &mut respond) {
+                debug!("Control connection failed: {}", e);
+            }
+        }
+    }
+
+    fn answer(stream: UnixStream, respond: &mut impl FnMut(&str) -> String) -> io::Result<()> {
+        stream.set_nonblocking(false)?;
+        stream.set_read_timeout(Some(READ_TIMEOUT))?;
+        stream.set_write_timeout(Some(READ_TIMEOUT))?;
+
+        let mut command = String::new();
+        BufReader::new(&stream).read_line(&mut command)?;
+        let command = command.trim();
+        if command.is_empty() {
+            // A probe like the one in `bind`, which connects and hangs up
+            return Ok(());
+        }
+        debug!("Control command: {:?}", command);
+        (&stream).write_all(respond(command).as_bytes())
+    }
+}
+
+impl Drop for ControlSocket {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.path);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::thread;
+
+    #[test]
+    fn test_request_round_trip() {
+        let path =
+            std::env::temp_dir().join(format!("remapper-control-{}.sock", std::process::id()));
+        let socket = ControlSocket::bind(&path).unwrap();
+        assert!(ControlSocket::bind(&path).is_err());
+
+        let client = {
+            let path = path.clone();
+            thread::spawn(move || request(&path, "stats").unwrap())
+        };
+        let mut served = Vec::new();
+        while served.is_empty() {
+            socket.serve(|command| {
+                served.push(command.to_string());
+                format!("got {}\n", command)
+            });
+            thread::sleep(Duration::from_millis(5));
+        }
+
+        assert_eq!(client.join().unwrap(), "got stats\n");
+        assert_eq!(served, ["stats"]);
+        drop(socket);
+        assert!(!path.exists());
+    }
+}
@@ -0,0 +1,283 @@
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int, c_ulong};
+use std::ptr;
+use std::slice;
+use x11::xlib::{self, Atom, Display, Window, XTextProperty};
+
+/// An open X connection, closed when dropped. Everything that borrows the raw
+/// pointer through [`DisplayHandle::as_ptr`] must be dropped first.
+#[derive(Debug)]
+pub struct DisplayHandle {
+    display: *mut Display,
+}
+
+impl DisplayHandle {
+    /// Connects to `name`, or to `$DISPLAY` when it's empty.
+    pub fn open(name: &CStr) -> Option<Self> {
+        let display = unsafe { xlib::XOpenDisplay(name.as_ptr()) };
+        if display.is_null() {
+            None
+        } else {
+            Some(Self { display })
+        }
+    }
+
+    /// The display name of `display` as passed to XOpenDisplay, so a thread
+    /// can reconnect with [`DisplayHandle::open`].
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn name_of(display: *mut Display) -> CString {
+        unsafe { CStr::from_ptr(xlib::XDisplayString(display)) }.to_owned()
+    }
+
+    pub fn as_ptr(&self) -> *mut Display {
+        self.display
+    }
+}
+
+impl Drop for DisplayHandle {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XCloseDisplay(self.display);
+        }
+    }
+}
+
+/// The root window of every screen, default screen first. Zaphod-style
+/// setups run one screen per monitor, each with its own root.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn root_windows(display: *mut Display) -> Vec<Window> {
+    unsafe {
+        let default_screen = xlib::XDefaultScreen(display);
+        let mut screens: Vec<c_int> = (0..xlib::XScreenCount(display)).collect();
+        screens.sort_by_key(|&screen| screen != default_screen);
+        screens
+            .into_iter()
+            .map(|screen| xlib::XRootWindow(display, screen))
+            .collect()
+    }
+}
+
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn intern_atom(display: *mut Display, name: &CStr, only_if_exists: bool) -> Atom {
+    let only_if_exists = if only_if_exists {
+        xlib::True
+    } else {
+        xlib::False
+    };
+    unsafe { xlib::XInternAtom(display, name.as_ptr(), only_if_exists) }
+}
+
+/// The name of `atom`, or `None` when the server doesn't know it.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn atom_name(display: *mut Display, atom: Atom) -> Option<String> {
+    unsafe {
+        let name = xlib::XGetAtomName(display, atom);
+        if name.is_null() {
+            return None;
+        }
+        let result = CStr::from_ptr(name).to_string_lossy().into_owned();
+        xlib::XFree(name as *mut _);
+        Some(result)
+    }
+}
+
+/// A window property read with XGetWindowProperty, freed when dropped.
+pub struct Property {
+    data: *mut u8,
+    nitems: usize,
+    format: c_int,
+}
+
+impl Property {
+    /// Reads up to `long_length` 32-bit units of `property` on `window`.
+    /// Returns `None` when the property is missing or the request failed.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn get(
+        display: *mut Display,
+        window: Window,
+        property: Atom,
+        req_type: Atom,
+        long_length: i64,
+    ) -> Option<Self> {
+        let mut actual_type: c_ulong = 0;
+        let mut format: c_int = 0;
+        let mut nitems: c_ulong = 0;
+        let mut bytes_after: c_ulong = 0;
+        let mut data: *mut u8 = ptr::null_mut();
+
+        let result = unsafe {
+            xlib::XGetWindowProperty(
+                display,
+                window,
+                property,
+                0,
+                long_length,
+                xlib::False,
+                req_type,
+                &mut actual_type,
+                &mut format,
+                &mut nitems,
+                &mut bytes_after,
+                &mut data,
+            )
+        };
+
+        let property = Self {
+            data,
+            nitems: nitems as usize,
+            format,
+        };
+        if result != xlib::Success as i32 || data.is_null() || nitems == 0 {
+            return None;
+        }
+        Some(property)
+    }
+
+    /// The items of a format-8 property.
+    pub fn bytes(&self) -> &[u8] {
+        if self.format != 8 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.data, self.nitems) }
+    }
+
+    /// The items of a format-32 property, which Xlib stores as longs.
+    pub fn longs(&self) -> &[c_ulong] {
+        if self.format != 32 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.data as *const c_ulong, self.nitems) }
+    }
+}
+
+impl Drop for Property {
+    fn drop(&mut self) {
+        if !self.data.is_null() {
+            unsafe {
+                xlib::XFree(self.data as *mut _);
+            }
+        }
+    }
+}
+
+/// An XTextProperty whose value is freed when dropped.
+pub struct TextProperty {
+    display: *mut Display,
+    prop: XTextProperty,
+}
+
+impl TextProperty {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn get(display: *mut Display, window: Window, property: Atom) -> Option<Self> {
+        let mut text = Self::empty(display);
+        let status = unsafe { xlib::XGetTextProperty(display, window, &mut text.prop, property) };
+        (status != 0 && text.is_set()).then_some(text)
+    }
+
+    /// WM_NAME through XGetWMName.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn wm_name(display: *mut Display, window: Window) -> Option<Self> {
+        let mut text = Self::empty(display);
+        let status = unsafe { xlib::XGetWMName(display, window, &mut text.prop) };
+        (status != 0 && text.is_set()).then_some(text)
+    }
+
+    fn empty(display: *mut Display) -> Self {
+        Self {
+            display,
+            prop: XTextProperty {
+                value: ptr::null_mut(),
+                encoding: 0,
+                format: 0,
+                nitems: 0,
+            },
+        }
+    }
+
+    fn is_set(&self) -> bool {
+        self.prop.nitems > 0 && !self.prop.value.is_null()
+    }
+
+    /// The value up to its first NUL, for STRING properties and the instance
+    /// name of WM_CLASS. STRING is Latin-1, which maps byte for byte onto
+    /// the first 256 code points.
+    pub fn first_string(&self) -> String {
+        decode_first(self.bytes(), false)
+    }
+
+    /// The first string of the property as UTF-8. STRING and UTF8_STRING
+    /// (what `_NET_WM_NAME` uses) are decoded directly, so titles don't
+    /// depend on the locale; COMPOUND_TEXT goes through Xlib.
+    pub fn to_text(&self) -> String {
+        if self.prop.encoding == xlib::XA_STRING {
+            return self.first_string();
+        }
+        if self.prop.encoding == intern_atom(self.display, c"UTF8_STRING", false) {
+            return decode_first(self.bytes(), true);
+        }
+
+        unsafe {
+            let mut list: *mut *mut c_char = ptr::null_mut();
+            let mut count: c_int = 0;
+            let status =
+                xlib::Xutf8TextPropertyToTextList(self.display, &self.prop, &mut list, &mut count);
+            if status != xlib::Success as i32 || count == 0 || list.is_null() {
+                return String::new();
+            }
+            let text = CStr::from_ptr(*list).to_string_lossy().into_owned();
+            xlib::XFreeStringList(list);
+            text
+        }
+    }
+
+    /// The raw value. Text properties have format 8, one byte per item.
+    fn bytes(&self) -> &[u8] {
+        if !self.is_set() || self.prop.format != 8 {
+            return &[];
+        }
+        unsafe { slice::from_raw_parts(self.prop.value, self.prop.nitems as usize) }
+    }
+}
+
+/// The first NUL-separated string of a text property value, decoded as
+/// UTF-8 or as Latin-1.
+fn decode_first(bytes: &[u8], utf8: bool) -> String {
+    let first = bytes.split(|&b| b == 0).next().unwrap_or_default();
+    if utf8 {
+        String::from_utf8_lossy(first).into_owned()
+    } else {
+        first.iter().map(|&b| b as char).collect()
+    }
+}
+
+impl Drop for TextProperty {
+    fn drop(&mut self) {
+        if !self.prop.value.is_null() {
+            unsafe {
+                xlib::XFree(self.prop.value as *mut _);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_decode_utf8_titles() {
+        let title = "ターミナル — 🦀 cargo build";
+        assert_eq!(decode_first(title.as_bytes(), true), title);
+        // WM_CLASS-style lists stop at the first NUL
+        assert_eq!(decode_first("端末\0Terminal\0".as_bytes(), true), "端末");
+        // Truncated multi-byte sequences don't drop the rest of the title
+        let truncated = &"日本語".as_bytes()[..7];
+        assert_eq!(decode_first(truncated, true), "日本\u{fffd}");
+    }
+
+    #[test]
+    fn test_decode_latin1_strings() {
+        assert_eq!(decode_first(b"caf\xe9\0", false), "café");
+        assert_eq!(decode_first(b"", false), "");
+    }
+}
@@ -0,0 +1,276 @@
+//! Checks of the X environment and a config for the `doctor` subcommand,
+//! each with a hint on what to do when it fails.
+
+use crate::config::Config;
+use crate::display::{intern_atom, root_windows, DisplayHandle, Property};
+use crate::key_mapper::{KeyMapper, KeyParser};
+use crate::xrecord::Recorder;
+use crate::xtest::XTest;
+use std::ffi::CString;
+use std::fmt;
+use x11::keysym;
+use x11::xlib::{self, Display, KeySym};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Severity {
+    Ok,
+    Warning,
+    Error,
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Check {
+    pub severity: Severity,
+    pub message: String,
+    pub hint: Option<String>,
+}
+
+impl Check {
+    fn ok(message: impl Into<String>) -> Self {
+        Self {
+            severity: Severity::Ok,
+            message: message.into(),
+            hint: None,
+        }
+    }
+
+    fn problem(severity: Severity, message: impl Into<String>, hint: impl Into<String>) -> Self {
+        Self {
+            severity,
+            message: message.into(),
+            hint: Some(hint.into()),
+        }
+    }
+}
+
+impl fmt::Display for Check {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let label = match self.severity {
+            Severity::Ok => "ok",
+            Severity::Warning => "warn",
+            Severity::Error => "error",
+        };
+        write!(f, "{:<5} {}", label, self.message)?;
+        if let Some(hint) = &self.hint {
+            write!(f, "\n      {}", hint)?;
+        }
+        Ok(())
+    }
+}
+
+/// Runs every check against `display_name` (`$DISPLAY` when empty) and, if
+/// given, the keys of `config`.
+pub fn diagnose(display_name: &str, config: Option<&Config>) -> Vec<Check> {
+    let shown_name = if display_name.is_empty() {
+        std::env::var("DISPLAY").unwrap_or_default()
+    } else {
+        display_name.to_string()
+    };
+    let handle = CString::new(display_name)
+        .ok()
+        .and_then(|name| DisplayHandle::open(&name));
+    let Some(handle) = handle else {
+        return vec![Check::problem(
+            Severity::Error,
+            format!("Can't connect to X display '{}'", shown_name),
+            "Check that $DISPLAY is set and that this user may connect (XAUTHORITY, xhost)",
+        )];
+    };
+    let display = handle.as_ptr();
+
+    let mut checks = vec![Check::ok(format!(
+        "Connected to X display '{}'",
+        shown_name
+    ))];
+    checks.extend(check_extensions(display, config));
+    checks.push(check_active_window(display));
+
+    let key_mapper = KeyMapper::new(display);
+    checks.extend(check_modifiers(&key_mapper));
+    if let Some(config) = config {
+        checks.extend(check_config_keys(&key_mapper, config));
+        checks.extend(check_config_lint(config));
+    }
+    checks
+}
+
+fn check_extensions(display: *mut Display, config: Option<&Config>) -> Vec<Check> {
+    let mut checks = Vec::new();
+    checks.push(if has_extension(display, "XKEYBOARD") {
+        Check::ok("XKEYBOARD extension available")
+    } else {
+        Check::problem(
+            Severity::Error,
+            "XKEYBOARD extension missing",
+            "Layout rules and layout switching need an X server with XKB",
+        )
+    });
+    checks.push(if XTest::open(display).is_some() {
+        Check::ok("XTEST extension available")
+    } else {
+        Check::problem(
+            Severity::Warning,
+            "XTEST unavailable, keys are sent with XSendEvent",
+            "Some applications ignore synthetic events; install libXtst (libxtst6)",
+        )
+    });
+    let observe = config.is_some_and(|config| config.observe);
+    checks.push(if Recorder::open(display).is_some() {
+        Check::ok("RECORD extension available")
+    } else {
+        Check::problem(
+            if observe {
+                Severity::Error
+            } else {
+                Severity::Warning
+            },
+            "RECORD extension unavailable",
+            "`observe: true` falls back to grabbing keys; install libXtst and enable RECORD in the X server",
+        )
+    });
+    checks.push(if has_extension(display, "XInputExtension") {
+        Check::ok("XInput extension available")
+    } else {
+        Check::problem(
+            Severity::Warning,
+            "XInput extension missing",
+            "Remapping works without it, but the server is unusually limited",
+        )
+    });
+    checks
+}
+
+fn has_extension(display: *mut Display, name: &str) -> bool {
+    let name = CString::new(name).expect("extension names have no NUL");
+    let (mut opcode, mut event, mut error) = (0, 0, 0);
+    unsafe {
+        xlib::XQueryExtension(display, name.as_ptr(), &mut opcode, &mut event, &mut error) != 0
+    }
+}
+
+fn check_active_window(display: *mut Display) -> Check {
+    let supported_atom = intern_atom(display, c"_NET_SUPPORTED", true);
+    let active_atom = intern_atom(display, c"_NET_ACTIVE_WINDOW", true);
+    let supported = root_windows(display).into_iter().any(|root| {
+        Property::get(display, root, supported_atom, xlib::XA_ATOM, 4096)
+            .is_some_and(|atoms| atoms.longs().contains(&active_atom))
+    });
+    if supported && active_atom != 0 {
+        Check::ok("Window manager supports _NET_ACTIVE_WINDOW")
+    } else {
+        Check::problem(
+            Severity::Warning,
+            "Window manager doesn't advertise _NET_ACTIVE_WINDOW",
+            "The focused window is taken from the input focus instead, which may be a child \
+             window without a class; run `simple-x11-remapper status` to see what's detected",
+        )
+    }
+}
+
+fn check_modifiers(key_mapper: &KeyMapper) -> Vec<Check> {
+    let lookup = |keysym: u32| {
+        let keycode = key_mapper.keycode_from_keysym(keysym as KeySym);
+        key_mapper.modifier_mask(keycode)
+    };
+    let num_lock = match lookup(keysym::XK_Num_Lock) {
+        Some(mask) => Check::ok(format!("NumLock is {}", modifier_name(mask))),
+        None => Check::problem(
+            Severity::Warning,
+            "NumLock isn't bound to a modifier",
+            "Keypad remaps assume Mod2; add it with a modmap entry `{add: 'Mod2', keys: ['Num_Lock']}`",
+        ),
+    };
+    let altgr = match lookup(keysym::XK_ISO_Level3_Shift) {
+        Some(mask) => Check::ok(format!(
+            "AltGr (ISO_Level3_Shift) is {}",
+            modifier_name(mask)
+        )),
+        None => Check::problem(
+            Severity::Warning,
+            "AltGr (ISO_Level3_Shift) isn't bound to a modifier",
+            "`AltGr-` remaps and level-3 characters won't work; pick a layout with AltGr or add \
+             `{add: 'Mod5', keys: ['ISO_Level3_Shift']}` to modmap",
+        ),
+    };
+    vec![num_lock, altgr]
+}
+
+/// `Mod2` for `Mod2Mask`.
+fn modifier_name(mask: u32) -> &'static str {
+    const NAMES: [&str; 8] = [
+        "Shift", "Lock", "Control", "Mod1", "Mod2", "Mod3", "Mod4", "Mod5",
+    ];
+    NAMES
+        .get(mask.trailing_zeros() as usize)
+        .copied()
+        .unwrap_or("no modifier")
+}
+
+fn check_config_keys(key_mapper: &KeyMapper, config: &Config) -> Vec<Check> {
+    let mut checks = Vec::new();
+    let mut count = 0;
+    for (rule, remap) in config.all_remaps() {
+        let keys = match KeyParser::split_chord(&remap.from) {
+            Some((first, second)) => vec![first, second],
+            None => vec![remap.from.as_str()],
+        };
+        for key in keys {
+            count += 1;
+            let resolved = key_mapper
+                .parse_key(key)
+                .map(|(keysym, _)| key_mapper.keycode_from_keysym(keysym) != 0);
+            if resolved != Some(true) {
+                checks.push(Check::problem(
+                    Severity::Error,
+                    format!(
+                        "'{}' in {} doesn't resolve to a key",
+                        key,
+                        config.rule_label(rule)
+                    ),
+                    "No key on the current layout produces it, so it can't be grabbed; \
+                     check the spelling with `xev` or add the layout it's on",
+                ));
+            }
+        }
+    }
+    if checks.is_empty() {
+        checks.push(Check::ok(format!(
+            "All {} remapped keys resolve to keycodes",
+            count
+        )));
+    }
+    checks
+}
+
+fn check_config_lint(config: &Config) -> Vec<Check> {
+    let warnings = config.lint();
+    if warnings.is_empty() {
+        return vec![Check::ok(
+            "The config has no duplicate or unreachable remaps",
+        )];
+    }
+    warnings
+        .into_iter()
+        .map(|warning| {
+            Check::problem(
+                Severity::Warning,
+                warning,
+                "The config loads, but this part of it has no effect",
+            )
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_check_display() {
+        assert_eq!(modifier_name(xlib::Mod2Mask), "Mod2");
+
+        let check = Check::problem(Severity::Warning, "NumLock unbound", "Bind it");
+        assert_eq!(check.to_string(), "warn  NumLock unbound\n      Bind it");
+        assert_eq!(Check::ok("Connected").to_string(), "ok    Connected");
+    }
+}
@@ -0,0 +1,413 @@
+use crate::config::HoldFlavor;
+use x11::xlib::{KeyCode, KeySym, Time};
+
+/// A key that is one key when tapped and a modifier when held with others:
+/// Caps Lock with the `caps_dual_role` preset, or a home-row mod.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DualRoleKey {
+    pub keycode: KeyCode,
+    /// The modifier bits it holds.
+    pub modifiers: u32,
+    /// The key sent for a tap, or `None` to type the key itself.
+    pub tap: Option<KeySym>,
+    /// XKB toggles Caps Lock when the key goes down; undo that.
+    pub restores_lock: bool,
+    /// How long it must be down alone to count as held.
+    pub tapping_term_ms: u64,
+}
+
+/// A key press, possibly held back until a dual-role key was decided.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct BufferedKey {
+    pub keycode: KeyCode,
+    pub state: u32,
+    pub time: Time,
+}
+
+/// What the event handler should do, in order, for a key event the
+/// dual-role keys had a say in.
+#[derive(Debug, PartialEq, Eq)]
+pub enum DualRoleAction {
+    /// Handle a key press as usual. Its state has the modifiers of the held
+    /// dual-role keys, and it came through their keyboard grab.
+    Press(BufferedKey),
+    /// Handle a key release as usual.
+    Release(KeyCode),
+    /// Time the dual-role key for the hold timeout.
+    StartTimer(KeyCode),
+    CancelTimer(KeyCode),
+    /// The dual-role key was tapped, and pressed as `press`.
+    Tap {
+        key: DualRoleKey,
+        press: BufferedKey,
+    },
+    /// The held dual-role key was let go; `state` is the one it was pressed in.
+    Released {
+        key: DualRoleKey,
+        state: u32,
+    },
+}
+
+#[derive(Debug)]
+enum Queued {
+    Press(BufferedKey),
+    Release(KeyCode),
+}
+
+/// A dual-role key that is down and not yet known to be tapped or held,
+/// with the key events that came after it.
+#[derive(Debug)]
+struct Pending {
+    key: DualRoleKey,
+    press: BufferedKey,
+    queue: Vec<Queued>,
+}
+
+/// The dual-role keys and which of them are down. One key at a time is
+/// undecided; the events after it wait until it counts as held, which
+/// depends on the [`HoldFlavor`], or it has been down for the hold timeout.
+/// Released first, it was tapped, and the keys pressed meanwhile were
+/// rolled over from fast typing rather than meant as combos.
+#[derive(Debug, Default)]
+pub struct DualRoles {
+    keys: Vec<DualRoleKey>,
+    flavor: HoldFlavor,
+    pending: Option<Pending>,
+    /// Keys decided held that are still down, and the state each was
+    /// pressed in.
+    held: Vec<(DualRoleKey, u32)>,
+}
+
+impl DualRoles {
+    pub fn new(keys: Vec<DualRoleKey>, flavor: HoldFlavor) -> Self {
+        Self {
+            keys,
+            flavor,
+            pending: None,
+            held: Vec::new(),
+        }
+    }
+
+    pub fn keys(&self) -> &[DualRoleKey] {
+        &self.keys
+    }
+
+    pub fn flavor(&self) -> HoldFlavor {
+        self.flavor
+    }
+
+    /// A dual-role key is down, undecided or held. Meanwhile the keyboard
+    /// stays grabbed, so the keys rolled over it still come to us once it's
+    /// released and its passive grab with it.
+    pub fn is_active(&self) -> bool {
+        self.pending.is_some() || !self.held.is_empty()
+    }
+
+    fn held_mask(&self) -> u32 {
+        self.held
+            .iter()
+            .fold(0, |mask, (key, _)| mask | key.modifiers)
+    }
+
+    /// The actions for a key press, or `None` if the dual-role keys have
+    /// nothing to do with it.
+    pub fn press(
+        &mut self,
+        keycode: KeyCode,
+        state: u32,
+        time: Time,
+    ) -> Option<Vec<DualRoleAction>> {
+        let mut actions = Vec::new();
+        let press = BufferedKey {
+            keycode,
+            state,
+            time,
+        };
+        if let Some(pending) = &mut self.pending {
+            // The pending key repeating
+            if keycode != pending.key.keycode {
+                pending.queue.push(Queued::Press(press));
+                if self.flavor == HoldFlavor::HoldOnOtherKeyPress {
+                    self.hold(&mut actions);
+                }
+            }
+            return Some(actions);
+        }
+        if self.held.iter().any(|(key, _)| key.keycode == keycode) {
+            return Some(actions);
+        }
+        let press = BufferedKey {
+            state: state | self.held_mask(),
+            ..press
+        };
+        if let Some(key) = self.keys.iter().find(|key| key.keycode == keycode) {
+            self.pending = Some(Pending {
+                key: *key,
+                press,
+                queue: Vec::new(),
+            });
+            actions.push(DualRoleAction::StartTimer(keycode));
+        } else if self.held.is_empty() {
+            return None;
+        } else {
+            actions.push(DualRoleAction::Press(press));
+        }
+        Some(actions)
+    }
+
+    /// The actions for a key release, or `None` if the dual-role keys have
+    /// nothing to do with it.
+    pub fn release(&mut self, keycode: KeyCode) -> Option<Vec<DualRoleAction>> {
+        let mut actions = Vec::new();
+        if let Some(pending) = &mut self.pending {
+            if keycode == pending.key.keycode {
+                let pending = self.pending.take()?;
+                actions.push(DualRoleAction::CancelTimer(keycode));
+                actions.push(DualRoleAction::Tap {
+                    key: pending.key,
+                    press: pending.press,
+                });
+                self.replay(pending.queue, &mut actions);
+                return Some(actions);
+            }
+            let pressed_since = pending
+                .queue
+                .iter()
+                .any(|queued| matches!(queued, Queued::Press(press) if press.keycode == keycode));
+            // A key pressed before the dual-role key doesn't decide it
+            if !pressed_since {
+                return None;
+            }
+            // A whole keystroke inside the dual-role key makes a combo
+            pending.queue.push(Queued::Release(keycode));
+            self.hold(&mut actions);
+            return Some(actions);
+        }
+        let index = self
+            .held
+            .iter()
+            .position(|(key, _)| key.keycode == keycode)?;
+        let (key, state) = self.held.remove(index);
+        actions.push(DualRoleAction::Released { key, state });
+        Some(actions)
+    }
+
+    /// The hold timeout of `keycode` passed.
+    pub fn timeout(&mut self, keycode: KeyCode) -> Vec<DualRoleAction> {
+        let mut actions = Vec::new();
+        if self
+            .pending
+            .as_ref()
+            .is_some_and(|pending| pending.key.keycode == keycode)
+        {
+            self.hold(&mut actions);
+        }
+        actions
+    }
+
+    /// Decides the pending key is held and goes on with the events queued
+    /// behind it.
+    fn hold(&mut self, actions: &mut Vec<DualRoleAction>) {
+        let Some(pending) = self.pending.take() else {
+            return;
+        };
+        actions.push(DualRoleAction::CancelTimer(pending.key.keycode));
+        self.held.push((pending.key, pending.press.state));
+        self.replay(pending.queue, actions);
+    }
+
+    fn replay(&mut self, queue: Vec<Queued>, actions: &mut Vec<DualRoleAction>) {
+        for queued in queue {
+            match queued {
+                Queued::Press(press) => match self.press(press.keycode, press.state, press.time) {
+                    Some(more) => actions.extend(more),
+                    None => actions.push(DualRoleAction::Press(press)),
+                },
+                Queued::Release(keycode) => match self.release(keycode) {
+                    Some(more) => actions.extend(more),
+                    None => actions.push(DualRoleAction::Release(keycode)),
+                },
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x11::xlib;
+
+    const CAPS: KeyCode = 66;
+    const A: KeyCode = 38;
+    const S: KeyCode = 39;
+    const J: KeyCode = 44;
+
+    fn dual_role(keycode: KeyCode, modifiers: u32) -> DualRoleKey {
+        DualRoleKey {
+            keycode,
+            modifiers,
+            tap: None,
+            restores_lock: false,
+            tapping_term_ms: 200,
+        }
+    }
+
+    fn press(keycode: KeyCode, state: u32, time: Time) -> BufferedKey {
+        BufferedKey {
+            keycode,
+            state,
+            time,
+        }
+    }
+
+    fn home_row(flavor: HoldFlavor) -> DualRoles {
+        let keys = vec![dual_role(A, xlib::Mod4Mask), dual_role(S, xlib::Mod1Mask)];
+        DualRoles::new(keys, flavor)
+    }
+
+    #[test]
+    fn test_tap_alone() {
+        let caps = DualRoleKey {
+            tap: Some(0xff1b),
+            restores_lock: true,
+            ..dual_role(CAPS, xlib::ControlMask)
+        };
+        let mut keys = DualRoles::new(vec![caps], HoldFlavor::PermissiveHold);
+        assert_eq!(
+            keys.press(CAPS, 0x2, 1),
+            Some(vec![DualRoleAction::StartTimer(CAPS)])
+        );
+        assert_eq!(keys.press(CAPS, 0x2, 2), Some(Vec::new()));
+        assert_eq!(
+            keys.release(CAPS),
+            Some(vec![
+                DualRoleAction::CancelTimer(CAPS),
+                DualRoleAction::Tap {
+                    key: caps,
+                    press: press(CAPS, 0x2, 1)
+                },
+            ])
+        );
+        assert_eq!(keys.press(J, 0, 3), None);
+    }
+
+    #[test]
+    fn test_permissive_hold() {
+        let mut keys = home_row(HoldFlavor::PermissiveHold);
+        keys.press(A, 0, 1);
+        assert_eq!(keys.press(J, 0, 2), Some(Vec::new()));
+        assert_eq!(
+            keys.release(J),
+            Some(vec![
+                DualRoleAction::CancelTimer(A),
+                DualRoleAction::Press(press(J, xlib::Mod4Mask, 2)),
+                DualRoleAction::Release(J),
+            ])
+        );
+        assert_eq!(
+            keys.press(J, 0, 3),
+            Some(vec![DualRoleAction::Press(press(J, xlib::Mod4Mask, 3))])
+        );
+        assert_eq!(keys.release(J), None);
+        assert_eq!(
+            keys.release(A),
+            Some(vec![DualRoleAction::Released {
+                key: dual_role(A, xlib::Mod4Mask),
+                state: 0
+            }])
+        );
+    }
+
+    #[test]
+    fn test_roll_over_taps() {
+        let mut keys = home_row(HoldFlavor::PermissiveHold);
+        // A key still down from before doesn't make a combo
+        keys.press(A, 0, 1);
+        assert_eq!(keys.release(J), None);
+        // Rolling from a to s types both
+        keys.press(S, 0, 2);
+        assert_eq!(
+            keys.release(A),
+            Some(vec![
+                DualRoleAction::CancelTimer(A),
+                DualRoleAction::Tap {
+                    key: dual_role(A, xlib::Mod4Mask),
+                    press: press(A, 0, 1)
+                },
+                DualRoleAction::StartTimer(S),
+            ])
+        );
+        assert_eq!(
+            keys.release(S).unwrap()[1],
+            DualRoleAction::Tap {
+                key: dual_role(S, xlib::Mod1Mask),
+                press: press(S, 0, 2)
+            }
+        );
+    }
+
+    #[test]
+    fn test_active_through_roll() {
+        let mut keys = home_row(HoldFlavor::PermissiveHold);
+        assert!(!keys.is_active());
+        keys.press(A, 0, 1);
+        keys.press(S, 0, 2);
+        assert!(keys.is_active());
+        // a is up, but s is still to be decided, and its release must
+        // come to us rather than go to the window
+        keys.release(A);
+        assert!(keys.is_active());
+        assert!(keys.release(S).is_some());
+        assert!(!keys.is_active());
+
+        keys.press(A, 0, 3);
+        keys.press(J, 0, 4);
+        keys.release(J);
+        assert!(keys.is_active(), "a is held");
+        keys.release(A);
+        assert!(!keys.is_active());
+    }
+
+    #[test]
+    fn test_nested_mods() {
+        let mut keys = home_row(HoldFlavor::PermissiveHold);
+        keys.press(A, 0, 1);
+        keys.press(S, 0, 2);
+        keys.press(J, 0, 3);
+        let actions = keys.release(J).unwrap();
+        assert!(actions.contains(&DualRoleAction::Press(press(
+            J,
+            xlib::Mod4Mask | xlib::Mod1Mask,
+            3
+        ))));
+    }
+
+    #[test]
+    fn test_hold_on_other_key_press() {
+        let mut keys = home_row(HoldFlavor::HoldOnOtherKeyPress);
+        keys.press(A, 0, 1);
+        assert_eq!(
+            keys.press(J, 0, 2),
+            Some(vec![
+                DualRoleAction::CancelTimer(A),
+                DualRoleAction::Press(press(J, xlib::Mod4Mask, 2)),
+            ])
+        );
+    }
+
+    #[test]
+    fn test_timeout_holds() {
+        let mut keys = home_row(HoldFlavor::PermissiveHold);
+        assert_eq!(keys.timeout(A), Vec::new());
+        keys.press(A, 0, 1);
+        keys.press(J, 0, 2);
+        assert_eq!(keys.timeout(S), Vec::new());
+        assert_eq!(
+            keys.timeout(A),
+            vec![
+                DualRoleAction::CancelTimer(A),
+                DualRoleAction::Press(press(J, xlib::Mod4Mask, 2)),
+            ]
+        );
+    }
+}
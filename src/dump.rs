@@ -0,0 +1,40 @@
+use crate::config::{Config, KeyAction};
+use serde::Serialize;
+use x11::xlib::KeyCode;
+
+/// The running remapper's effective configuration, for the `dump` command:
+/// the config as loaded and what of it applies on each display right now.
+#[derive(Debug, Clone, Serialize)]
+pub struct Dump {
+    pub config: Config,
+    pub displays: Vec<DisplayDump>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct DisplayDump {
+    pub display: String,
+    pub window_class: Option<String>,
+    pub layout: Option<String>,
+    /// Indices into `config.windows` of the rules applied to the focused
+    /// window, in the order they apply.
+    pub applied_rules: Vec<usize>,
+    /// The remaps in effect, one per key.
+    pub remaps: Vec<ResolvedRemap>,
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct ResolvedRemap {
+    /// `global` or the index of the window rule.
+    pub rule: String,
+    pub from: String,
+    pub to: KeyAction,
+    /// The keys `from` is grabbed as; two for a chord.
+    pub keys: Vec<ResolvedKey>,
+}
+
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct ResolvedKey {
+    /// `None` when the keyboard layout has no key for it.
+    pub keycode: Option<KeyCode>,
+    pub modifiers: u32,
+}
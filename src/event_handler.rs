@@ -1,46 +1,509 @@
-use crate::config::{Config, KeyAction, Remap};
-use crate::key_mapper::{KeyMapper, KeyPress};
+use crate::backend::XBackend;
+use crate::config::{
+    Config, GrabTarget, KeyAction, MatchContext, MouseClick, MouseModeSettings, Preset, Remap,
+    RuleId, SequenceStep, Trigger,
+};
+use crate::display::{root_windows, DisplayHandle};
+use crate::dual_role::{DualRoleAction, DualRoleKey, DualRoles};
+use crate::dump::{DisplayDump, ResolvedKey, ResolvedRemap};
+use crate::ewmh;
+use crate::expand::{char_keysym, keysym_char, Expander, Expansion};
+use crate::explain::{self, KeyExplanation};
+use crate::grabs::{GrabSet, Grabs};
+use crate::key_mapper::{is_lock_keysym, is_modifier_keysym, KeyMapper, KeyParser, KeyPress};
+use crate::macros::Macros;
+use crate::modmap::Modmap;
+use crate::mouse_keys::{MouseKey, MouseKeys, STEP_INTERVAL};
+use crate::plugin::{KeySender, Plugins};
+use crate::script::{self, ScriptContext};
+use crate::stats::Stats;
+use crate::status::DisplayStatus;
+use crate::timer::Timers;
 use crate::window_manager::WindowManager;
+use crate::x_error::{self, XError};
+use crate::xinput::Hotplug;
+use crate::xkb::{LayoutTarget, Xkb};
+use crate::xrandr::Monitors;
+use crate::xrecord::{RecordedKey, Recorder};
 use log::{debug, error, info, warn};
-use std::collections::HashMap;
+use std::cell::{Cell, RefCell};
+use std::collections::{BTreeMap, HashMap};
+use std::path::Path;
 use std::rc::Rc;
-use std::thread;
-use std::time::Duration;
-use x11::xlib::{self, Display, KeyCode, Window};
+use std::time::{Duration, Instant};
+use x11::keysym;
+use x11::xlib::{self, Atom, Display, KeyCode, KeySym, Time, Window, XEvent};
+
+/// Two keys that trigger an action when pressed within the chord timeout.
+struct Chord {
+    keycodes: [KeyCode; 2],
+    handler: Rc<dyn Fn()>,
+    timeout_ms: u64,
+}
+
+/// Window changes seen in the current batch of events, applied once the
+/// batch is drained. A focus switch arrives as a burst of FocusIn/FocusOut,
+/// PropertyNotify and ConfigureNotify events, and looking the window up
+/// again for each one costs round trips and may regrab keys several times.
+#[derive(Debug, Default)]
+struct WindowChanges {
+    focus: bool,
+    state: bool,
+    geometry: bool,
+}
+
+/// A chord key that was pressed and is held back until we know whether the
+/// other half of a chord follows.
+struct PendingKey {
+    keycode: KeyCode,
+    state: u32,
+    time: Time,
+}
+
+/// The remap of a bare modifier or lock key, which runs when the key is
+/// released without another key pressed in between.
+struct ReleaseRemap {
+    handler: Rc<dyn Fn()>,
+    /// Set for lock keys: the locked modifier to restore after release.
+    lock_mask: Option<u32>,
+}
+
+/// A bare modifier or lock key that is currently held down.
+struct HeldModifier {
+    keycode: KeyCode,
+    state: u32,
+    interrupted: bool,
+}
+
+/// A remap with a `hold` action: `tap` runs when the key is released
+/// before the hold timeout, `hold` once the key has been down that long.
+struct LongPress {
+    tap: Rc<dyn Fn()>,
+    hold: Rc<dyn Fn()>,
+    timeout: Duration,
+}
+
+/// A long-press key that is down, and whether its hold action already ran.
+struct HeldLongPress {
+    long_press: Rc<LongPress>,
+    held: bool,
+}
+
+/// The focused window and the properties rules match on.
+#[derive(Default)]
+struct Focus {
+    window: Option<Window>,
+    class: Option<String>,
+    role: Option<String>,
+    monitor: Option<String>,
+    states: Vec<String>,
+}
+
+/// How long an armed one-shot modifier waits for its key before it's
+/// dropped and the keyboard let go.
+const ONE_SHOT_TIMEOUT: Duration = Duration::from_secs(3);
+
+/// Deadlines the event handler asked the event loop for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum TimerEvent {
+    /// The held chord key wasn't followed by its partner in time.
+    ChordTimeout,
+    /// The long-press key with this keycode has been held long enough.
+    HoldTimeout(KeyCode),
+    /// Move the pointer another step in mouse mode.
+    MouseStep,
+    /// The dual-role key with this keycode has been down long enough to
+    /// count as held.
+    DualRoleTimeout(KeyCode),
+    /// The one-shot modifiers armed went unused.
+    OneShotTimeout,
+}
 
 pub struct EventHandler {
     display: *mut Display,
     config: Config,
     window_manager: WindowManager,
-    key_mapper: KeyMapper,
+    key_mapper: Rc<KeyMapper>,
+    xkb: Xkb,
+    roots: Vec<Window>,
+    current_layout: Option<String>,
     key_handlers: HashMap<KeyPress, Rc<dyn Fn()>>,
+    /// Conditional remaps, grabbed synchronously: the keyboard stays frozen
+    /// until the handler says whether to swallow the key (`true`) or replay it
+    /// to the window.
+    conditional_handlers: HashMap<KeyPress, Rc<dyn Fn() -> bool>>,
+    /// Remaps served from XRecord without a grab, in observe mode.
+    observed_handlers: HashMap<KeyPress, Rc<dyn Fn()>>,
+    recorder: Option<Recorder>,
+    /// Keypad remaps that only apply with NumLock on (`true`) or off.
+    numlock_handlers: HashMap<(KeyPress, bool), Rc<dyn Fn()>>,
+    num_lock_mask: u32,
+    /// Lock, NumLock and ScrollLock, whichever bits they're bound to.
+    lock_mask: u32,
+    release_remaps: HashMap<KeyCode, ReleaseRemap>,
+    /// Remaps with `on: release`, armed by the press.
+    release_handlers: HashMap<KeyPress, Rc<dyn Fn()>>,
+    /// Armed `on: release` handlers by the keycode whose release runs them.
+    pending_releases: HashMap<KeyCode, Rc<dyn Fn()>>,
+    /// Remaps with a `hold` action.
+    long_press_handlers: HashMap<KeyPress, Rc<LongPress>>,
+    /// Long-press keys that are down, by keycode.
+    held_long_presses: HashMap<KeyCode, HeldLongPress>,
+    /// Modifiers a `one_shot` action added to the next key press. The
+    /// keyboard is grabbed while any are armed, so that press comes to us.
+    one_shot: Rc<Cell<u32>>,
+    /// The one-shot modifiers [`TimerEvent::OneShotTimeout`] is scheduled
+    /// for.
+    one_shot_timed: u32,
+    macros: Rc<RefCell<Macros>>,
+    /// The config's plugins, for `{plugin: name}` actions.
+    plugins: Rc<Plugins>,
+    /// Keys with a `{layer: name}` action, and the layer each turns on.
+    layer_keys: HashMap<KeyPress, String>,
+    /// The layer turned on, and the keycode whose release turns it off.
+    active_layer: Option<(String, KeyCode)>,
+    /// Keys with a `{toggle: flag}` action, and the flag each flips.
+    toggle_keys: HashMap<KeyPress, String>,
+    /// The flags toggled on, which rules with `when` wait for.
+    flags: Vec<String>,
+    /// The key that keeps a config on trial, grabbed in every window
+    /// meanwhile whatever the config says.
+    confirm_key: Option<String>,
+    confirm_key_press: Option<KeyPress>,
+    /// The confirm key was pressed since [`EventHandler::take_confirmed`].
+    confirmed: bool,
+    /// Mouse mode, while it's on. The keyboard is grabbed meanwhile, so
+    /// every key comes to us.
+    mouse_keys: Rc<RefCell<Option<MouseKeys>>>,
+    /// The text expansions of the focused window, by abbreviation.
+    expansions: BTreeMap<String, String>,
+    expander: Expander,
+    held_modifier: Option<HeldModifier>,
+    /// Keys that are one key tapped and a modifier held: Caps Lock with the
+    /// `caps_dual_role` preset, and home-row mods.
+    dual_roles: DualRoles,
+    /// The keyboard is grabbed for the dual-role keys, see
+    /// [`DualRoles::is_active`].
+    dual_role_grab: bool,
     grabbed_keys: Vec<KeyPress>,
+    /// The keys of `grabbed_keys` that rules with `grab_on: client` want
+    /// grabbed on the focused window rather than the root.
+    client_keys: Vec<KeyPress>,
+    grabs: Grabs,
+    /// The rule and remap, less its `from`, each key press was registered
+    /// with, so keys listed together that turn out to be one key register
+    /// once.
+    registered_remaps: HashMap<KeyPress, (RuleId, Remap)>,
+    /// The windows `send_to` remaps resolved to.
+    send_to_windows: Vec<Window>,
+    chords: Vec<Chord>,
+    pending_chord_key: Option<PendingKey>,
+    timers: Timers<TimerEvent>,
+    active_window: Option<Window>,
+    window_class: Option<String>,
+    /// The focused window's WM_WINDOW_ROLE.
+    window_role: Option<String>,
+    /// The monitor the focused window is on.
+    monitor: Option<String>,
+    monitors: Option<Monitors>,
+    hotplug: Option<Hotplug>,
+    /// The focused window's _NET_WM_STATE flags.
+    window_states: Vec<String>,
+    window_changes: WindowChanges,
+    stats: Rc<RefCell<Stats>>,
+    /// The config's `modmap` changes, undone when dropped.
+    modmap: Option<Modmap>,
+    /// Remapping is paused: nothing is grabbed until it's resumed.
+    paused: bool,
 }
 
 impl EventHandler {
     pub fn new(display: *mut Display, config: Config) -> Self {
         let window_manager = WindowManager::new(display);
-        let key_mapper = KeyMapper::new(display);
+        let key_mapper = Rc::new(KeyMapper::new(display));
+        key_mapper.set_physical_keys(config.physical_keys);
+        let xkb = Xkb::new(display);
+        let stats = Rc::new(RefCell::new(Stats::new(&config)));
+        let macros = Rc::new(RefCell::new(Macros::new(display, key_mapper.clone())));
+        let recorder = open_recorder(display, &config);
+        let plugins = Rc::new(Plugins::load(&config.plugins, key_sender(&key_mapper)));
 
         Self {
             display,
             config,
             window_manager,
             key_mapper,
+            xkb,
+            roots: root_windows(display),
+            current_layout: None,
             key_handlers: HashMap::new(),
+            conditional_handlers: HashMap::new(),
+            observed_handlers: HashMap::new(),
+            recorder,
+            numlock_handlers: HashMap::new(),
+            num_lock_mask: xlib::Mod2Mask,
+            lock_mask: xlib::LockMask | xlib::Mod2Mask,
+            release_remaps: HashMap::new(),
+            release_handlers: HashMap::new(),
+            pending_releases: HashMap::new(),
+            long_press_handlers: HashMap::new(),
+            held_long_presses: HashMap::new(),
+            one_shot: Rc::new(Cell::new(0)),
+            one_shot_timed: 0,
+            macros,
+            plugins,
+            layer_keys: HashMap::new(),
+            active_layer: None,
+            toggle_keys: HashMap::new(),
+            flags: Vec::new(),
+            confirm_key: None,
+            confirm_key_press: None,
+            confirmed: false,
+            mouse_keys: Rc::new(RefCell::new(None)),
+            expansions: BTreeMap::new(),
+            expander: Expander::default(),
+            held_modifier: None,
+            dual_roles: DualRoles::default(),
+            dual_role_grab: false,
             grabbed_keys: Vec::new(),
+            client_keys: Vec::new(),
+            grabs: Grabs::default(),
+            registered_remaps: HashMap::new(),
+            send_to_windows: Vec::new(),
+            chords: Vec::new(),
+            pending_chord_key: None,
+            timers: Timers::default(),
+            active_window: None,
+            window_class: None,
+            window_role: None,
+            monitor: None,
+            monitors: Monitors::open(display),
+            hotplug: Hotplug::open(display),
+            window_states: Vec::new(),
+            window_changes: WindowChanges::default(),
+            stats,
+            modmap: None,
+            paused: false,
+        }
+    }
+
+    pub fn status(&self) -> DisplayStatus {
+        DisplayStatus {
+            display: DisplayHandle::name_of(self.display)
+                .to_string_lossy()
+                .into_owned(),
+            window_class: self.window_class.clone(),
+            layout: self.current_layout.clone(),
+            grabbed_keys: self.grabbed_keys.len(),
+            paused: self.paused,
+            grab_conflicts: self
+                .grabs
+                .conflicts()
+                .iter()
+                .map(|&key| self.key_mapper.describe(key))
+                .collect(),
+        }
+    }
+
+    /// The rules and remaps applied to the focused window, with the keys
+    /// they're grabbed as.
+    pub fn dump(&self) -> DisplayDump {
+        let context = MatchContext {
+            window_class: self.window_class.as_deref(),
+            layout: self.current_layout.as_deref(),
+            window_role: self.window_role.as_deref(),
+            monitor: self.monitor.as_deref(),
+            window_states: &self.window_states,
+            flags: &self.flags,
+        };
+        let resolve = |key: &str| {
+            self.key_mapper
+                .parse_key(key)
+                .map(|(keysym, modifiers)| ResolvedKey {
+                    keycode: Some(self.key_mapper.from_keycode(keysym))
+                        .filter(|&keycode| keycode != 0),
+                    modifiers,
+                })
+        };
+        let remaps = self
+            .config
+            .matching_remaps(&context)
+            .into_iter()
+            .map(|(rule, remap)| {
+                let keys = match KeyParser::split_chord(&remap.from) {
+                    Some((first, second)) => vec![first, second],
+                    None => vec![remap.from.as_str()],
+                };
+                ResolvedRemap {
+                    rule: rule.to_string(),
+                    keys: keys.into_iter().filter_map(resolve).collect(),
+                    from: remap.from,
+                    to: remap.to,
+                }
+            })
+            .collect();
+
+        DisplayDump {
+            display: DisplayHandle::name_of(self.display)
+                .to_string_lossy()
+                .into_owned(),
+            window_class: self.window_class.clone(),
+            layout: self.current_layout.clone(),
+            applied_rules: self
+                .config
+                .applied_rules(&context)
+                .into_iter()
+                .map(|(index, _)| index)
+                .collect(),
+            remaps,
+        }
+    }
+
+    /// What `key` does in the focused window.
+    pub fn explain(&self, key: &str) -> KeyExplanation {
+        let context = MatchContext {
+            window_class: self.window_class.as_deref(),
+            layout: self.current_layout.as_deref(),
+            window_role: self.window_role.as_deref(),
+            monitor: self.monitor.as_deref(),
+            window_states: &self.window_states,
+            flags: &self.flags,
+        };
+        let mut explanation = explain::explain(&self.config, &context, key);
+        explanation.display = DisplayHandle::name_of(self.display)
+            .to_string_lossy()
+            .into_owned();
+        explanation.grab_conflict = explanation.fires.is_some()
+            && self
+                .key_mapper
+                .parse_key(key)
+                .is_some_and(|(keysym, modifiers)| {
+                    let keycode = self.key_mapper.from_keycode(keysym);
+                    self.grabs
+                        .conflicts()
+                        .contains(&KeyPress { keycode, modifiers })
+                });
+        explanation
+    }
+
+    pub fn config(&self) -> &Config {
+        &self.config
+    }
+
+    /// Enables or disables the window rules called `name` and applies the
+    /// change to the grabs. Returns whether there were any.
+    pub fn set_rule_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        let found = self.config.set_rule_enabled(name, enabled);
+        if found {
+            info!(
+                "Rule '{}' {}",
+                name,
+                if enabled { "enabled" } else { "disabled" }
+            );
+            self.update_key_mappings();
+        }
+        found
+    }
+
+    /// Pauses or resumes remapping. While paused every grab is released,
+    /// so keys reach applications as typed.
+    pub fn set_paused(&mut self, paused: bool) {
+        if self.paused != paused {
+            info!("Remapping {}", if paused { "paused" } else { "resumed" });
+            self.paused = paused;
+            self.update_key_mappings();
         }
     }
 
+    pub fn is_paused(&self) -> bool {
+        self.paused
+    }
+
+    /// The flags toggled on.
+    pub fn flags(&self) -> &[String] {
+        &self.flags
+    }
+
+    /// Turns on `flags` and no others, e.g. those saved by an earlier run.
+    pub fn set_flags(&mut self, flags: Vec<String>) {
+        if self.flags != flags {
+            info!("Flags on: {:?}", flags);
+            self.flags = flags;
+            self.update_key_mappings();
+        }
+    }
+
+    /// Grabs `key` in every window until it's set to `None`, for confirming
+    /// a config on trial.
+    pub fn set_confirm_key(&mut self, key: Option<String>) {
+        self.confirm_key = key;
+        self.confirmed = false;
+        self.update_key_mappings();
+    }
+
+    /// Whether the confirm key was pressed since the last call.
+    pub fn take_confirmed(&mut self) -> bool {
+        std::mem::take(&mut self.confirmed)
+    }
+
+    /// Switches to `config`, redoing the modmap and grabs. Hit counts start
+    /// over, as the rules they're kept by may have moved.
+    pub fn set_config(&mut self, config: Config) {
+        // Undo the old modmap before applying the new one over it
+        self.modmap = None;
+        self.stats.replace(Stats::new(&config));
+        let wants_recorder = config.observe || config.has_expansions();
+        if wants_recorder != self.recorder.is_some() {
+            self.recorder = open_recorder(self.display, &config);
+        }
+        self.key_mapper.set_physical_keys(config.physical_keys);
+        if config.plugins != self.config.plugins {
+            self.plugins = Rc::new(Plugins::load(&config.plugins, key_sender(&self.key_mapper)));
+        }
+        self.config = config;
+        self.initialize();
+    }
+
+    /// Remap hits since startup.
+    pub fn stats(&self) -> Stats {
+        self.stats.borrow().clone()
+    }
+
     pub fn initialize(&mut self) {
         info!("Initializing event handler");
+        if !self.config.modmap.is_empty() {
+            self.modmap = Some(Modmap::apply(self.display, &self.config.modmap));
+            self.key_mapper.refresh_mapping();
+        }
         self.update_key_mappings();
         info!("Event handler initialization complete");
     }
 
-    pub fn handle_key_press(&mut self, keycode: KeyCode, state: u32) {
-        let filtered_state =
-            state & (xlib::ControlMask | xlib::ShiftMask | xlib::Mod1Mask | xlib::Mod4Mask);
+    pub fn handle_key_press(&mut self, keycode: KeyCode, state: u32, time: Time) {
+        // Keys typed right after a focus switch go by the new window's remaps
+        self.apply_window_changes();
+        self.key_mapper.set_event_time(time);
+        if self.mouse_keys.borrow().is_some() {
+            self.handle_mouse_key(keycode, true);
+            return;
+        }
+        match self.dual_roles.press(keycode, state, time) {
+            Some(actions) => self.run_dual_role_actions(actions),
+            None => self.dispatch_key_press(keycode, state, time, false),
+        }
+        self.sync_one_shot();
+    }
+
+    /// Runs the remap of a key press. `grabbed` is set for keys that came
+    /// to us through a keyboard grab and are replayed if nothing remaps them.
+    fn dispatch_key_press(&mut self, keycode: KeyCode, state: u32, time: Time, grabbed: bool) {
+        let one_shot = self.take_one_shot(keycode);
+        let state = state | one_shot;
+        let mut filtered_state = state & self.modifier_filter();
+        if let Some((_, layer_keycode)) = &self.active_layer {
+            // A modifier held as the layer key doesn't count for its remaps
+            filtered_state &= !self.key_mapper.modifier_mask(*layer_keycode).unwrap_or(0);
+        }
         let key_press = KeyPress {
             keycode,
             modifiers: filtered_state,
@@ -51,12 +514,160 @@ impl EventHandler {
             keycode, state, filtered_state
         );
 
-        if let Some(handler) = self.key_handlers.get(&key_press) {
+        if self.confirm_key_press == Some(key_press) {
+            info!("Confirm key pressed");
+            self.confirmed = true;
+            // Thaw the keyboard in case a conditional remap of the same key
+            // grabbed it synchronously
+            unsafe {
+                xlib::XAllowEvents(self.display, xlib::AsyncKeyboard, time);
+                xlib::XFlush(self.display);
+            }
+            return;
+        }
+
+        if let Some(layer) = self.layer_keys.get(&key_press).cloned() {
+            if self.active_layer.is_none() {
+                info!("Layer '{}' on", layer);
+                self.active_layer = Some((layer, keycode));
+                self.update_key_mappings();
+            }
+            return;
+        }
+
+        if let Some(flag) = self.toggle_keys.get(&key_press).cloned() {
+            self.toggle_flag(flag);
+            return;
+        }
+
+        if let Some(handler) = self.conditional_handlers.get(&key_press).cloned() {
+            let swallow = handler();
+            debug!(
+                "Conditional remap {} keycode={}",
+                if swallow { "swallowed" } else { "replayed" },
+                keycode
+            );
+            let mode = if swallow {
+                xlib::AsyncKeyboard
+            } else {
+                xlib::ReplayKeyboard
+            };
+            unsafe {
+                xlib::XAllowEvents(self.display, mode, time);
+                xlib::XFlush(self.display);
+            }
+            return;
+        }
+        // A press from a synchronous grab that was since replaced leaves the
+        // keyboard frozen; this is a no-op when it isn't
+        unsafe {
+            xlib::XAllowEvents(self.display, xlib::AsyncKeyboard, time);
+        }
+
+        if self.release_remaps.contains_key(&keycode) {
+            if self.held_modifier.is_none() {
+                debug!("Holding bare modifier keycode={} until release", keycode);
+                self.held_modifier = Some(HeldModifier {
+                    keycode,
+                    state,
+                    interrupted: false,
+                });
+            }
+            return;
+        }
+        // While a grabbed modifier is down the keyboard stays grabbed, so every
+        // other key comes to us and is used as a combo with that modifier
+        let during_held_modifier = match &mut self.held_modifier {
+            Some(held) => {
+                held.interrupted = true;
+                true
+            }
+            None => false,
+        };
+
+        let mut after_pending_key = false;
+        if let Some(pending) = self.take_pending_chord_key() {
+            let elapsed = time.wrapping_sub(pending.time);
+            if let Some(chord) = self.chord(pending.keycode, keycode) {
+                if elapsed <= chord.timeout_ms {
+                    info!(
+                        "Chord keycodes {}+{} pressed within {}ms, executing remap",
+                        pending.keycode, keycode, elapsed
+                    );
+                    let handler = chord.handler.clone();
+                    handler();
+                    return;
+                }
+            }
+            self.release_pending_key(pending);
+            after_pending_key = true;
+        }
+
+        if let Some(timeout_ms) = self
+            .chord_timeout_ms(keycode)
+            .filter(|_| filtered_state == 0)
+        {
+            debug!("Holding keycode={} back for a possible chord", keycode);
+            self.pending_chord_key = Some(PendingKey {
+                keycode,
+                state,
+                time,
+            });
+            self.timers
+                .schedule(Duration::from_millis(timeout_ms), TimerEvent::ChordTimeout);
+            return;
+        }
+
+        if self.held_long_presses.contains_key(&keycode) {
+            // Auto-repeat of a long-press key; it acts once, on release or timeout
+            return;
+        }
+        if let Some(long_press) = self.long_press_handlers.get(&key_press) {
+            debug!("Timing keycode={} for a long press", keycode);
+            self.held_long_presses.insert(
+                keycode,
+                HeldLongPress {
+                    long_press: long_press.clone(),
+                    held: false,
+                },
+            );
+            self.timers
+                .schedule(long_press.timeout, TimerEvent::HoldTimeout(keycode));
+            return;
+        }
+
+        if let Some(handler) = self.release_handlers.get(&key_press) {
+            debug!("Remap for keycode={} armed until release", keycode);
+            self.pending_releases.insert(keycode, handler.clone());
+            return;
+        }
+
+        let numlock_on = state & self.num_lock_mask != 0;
+        let handler = self
+            .numlock_handlers
+            .get(&(key_press, numlock_on))
+            .or_else(|| self.key_handlers.get(&key_press));
+
+        if let Some(handler) = handler {
             info!(
                 "Found handler for keycode={}, state={:#x}, executing remap",
                 keycode, filtered_state
             );
             handler();
+        } else if after_pending_key || during_held_modifier || one_shot != 0 || grabbed {
+            // The keyboard is still grabbed by the held key or the one-shot
+            // modifier, so this press never reached the focused window
+            self.replay_key(keycode, state);
+        } else if self
+            .numlock_handlers
+            .contains_key(&(key_press, !numlock_on))
+        {
+            debug!(
+                "Keypad remap for keycode={} needs NumLock {}, passing key through",
+                keycode,
+                if numlock_on { "off" } else { "on" }
+            );
+            self.replay_key(keycode, state);
         } else {
             debug!(
                 "No handler found for keycode={}, state={:#x}",
@@ -69,49 +680,908 @@ impl EventHandler {
         }
     }
 
-    pub fn handle_property_notify(&mut self) {
-        // Add delay similar to original implementation
-        thread::sleep(Duration::from_millis(100));
+    pub fn handle_key_release(&mut self, keycode: KeyCode, time: Time) {
+        self.key_mapper.set_event_time(time);
+        if self.mouse_keys.borrow().is_some() {
+            self.handle_mouse_key(keycode, false);
+            return;
+        }
+        match self.dual_roles.release(keycode) {
+            Some(actions) => self.run_dual_role_actions(actions),
+            None => self.dispatch_key_release(keycode),
+        }
+        self.sync_one_shot();
+    }
+
+    fn dispatch_key_release(&mut self, keycode: KeyCode) {
+        if let Some((layer, _)) = self
+            .active_layer
+            .take_if(|(_, layer_keycode)| *layer_keycode == keycode)
+        {
+            info!("Layer '{}' off", layer);
+            self.update_key_mappings();
+            return;
+        }
+        if let Some(handler) = self.pending_releases.remove(&keycode) {
+            info!("Keycode={} released, executing remap", keycode);
+            handler();
+            return;
+        }
+        if let Some(held) = self.held_long_presses.remove(&keycode) {
+            self.timers
+                .cancel(|event| *event == TimerEvent::HoldTimeout(keycode));
+            if !held.held {
+                info!("Keycode={} tapped, executing remap", keycode);
+                (held.long_press.tap)();
+            }
+            return;
+        }
+        if let Some(held) = self.held_modifier.take() {
+            if held.keycode == keycode {
+                self.release_held_modifier(held);
+                return;
+            }
+            self.held_modifier = Some(held);
+        }
+
+        let is_pending = |p: &PendingKey| p.keycode == keycode;
+        if self.pending_chord_key.as_ref().is_some_and(is_pending) {
+            if let Some(pending) = self.take_pending_chord_key() {
+                debug!("Chord key keycode={} released alone", keycode);
+                self.release_pending_key(pending);
+            }
+        }
+    }
+
+    /// Moves or clicks the pointer for a key pressed or released in mouse
+    /// mode. Keys mouse mode doesn't use are dropped.
+    fn handle_mouse_key(&mut self, keycode: KeyCode, pressed: bool) {
+        let mut mouse_keys = self.mouse_keys.borrow_mut();
+        let Some(keys) = mouse_keys.as_mut() else {
+            return;
+        };
+        match (keys.key(keycode), pressed) {
+            (Some(MouseKey::Move(dx, dy)), true) => {
+                let was_still = keys.press(keycode, (dx, dy));
+                if was_still {
+                    // A step still due from before would move it twice
+                    self.timers.cancel(|event| *event == TimerEvent::MouseStep);
+                    self.timers.schedule(Duration::ZERO, TimerEvent::MouseStep);
+                }
+            }
+            (Some(MouseKey::Move(..)), false) => keys.release(keycode),
+            (Some(MouseKey::Click), true) => self.key_mapper.click(MouseClick::Left),
+            (Some(MouseKey::Exit), true) => {
+                info!("Leaving mouse mode");
+                *mouse_keys = None;
+                self.timers.cancel(|event| *event == TimerEvent::MouseStep);
+                unsafe {
+                    xlib::XUngrabKeyboard(self.display, xlib::CurrentTime);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    /// The XRecord connections to wait on: observe mode's and the one of a
+    /// macro recording.
+    pub fn recorder_fds(&self) -> Vec<i32> {
+        self.recorder
+            .iter()
+            .map(Recorder::fd)
+            .chain(self.macros.borrow().recorder_fd())
+            .collect()
+    }
+
+    /// Runs the observed remaps for the key presses XRecord saw, and adds
+    /// the keys typed to the macro being recorded.
+    pub fn handle_recorded_keys(&mut self) {
+        let injected = self.key_mapper.take_injected();
+        self.macros.borrow_mut().take_recorded(&injected);
+        let Some(recorder) = &self.recorder else {
+            return;
+        };
+        // Keys we sent ourselves, say by a remap observed here, don't fire
+        // observed remaps again
+        let keys = self.expander.typed_keys(recorder.take_keys(), &injected);
+        for &RecordedKey {
+            keycode,
+            state,
+            pressed,
+            ..
+        } in &keys
+        {
+            if !pressed {
+                continue;
+            }
+            let key_press = KeyPress {
+                keycode,
+                modifiers: state & self.modifier_filter(),
+            };
+            if let Some(handler) = self.observed_handlers.get(&key_press) {
+                debug!("Observed keycode={}, state={:#x}", keycode, state);
+                handler();
+            }
+        }
+        if !self.expansions.is_empty() {
+            self.expand_typed(keys);
+        }
+    }
+
+    /// Feeds the keys the user typed to the expander and replaces the
+    /// abbreviations they complete with their text.
+    fn expand_typed(&mut self, keys: Vec<RecordedKey>) {
+        let shortcut_modifiers = xlib::ControlMask | xlib::Mod1Mask | xlib::Mod4Mask;
+        for key in keys {
+            if !key.pressed {
+                continue;
+            }
+            let keysym = self.key_mapper.typed_keysym(key.keycode, key.state);
+            if is_modifier_keysym(keysym) {
+                continue;
+            }
+            if key.state & shortcut_modifiers != 0 {
+                self.expander.reset();
+            } else if keysym == keysym::XK_BackSpace as KeySym {
+                self.expander.backspace();
+            } else if let Some(c) = keysym_char(keysym) {
+                if let Some(expansion) = self.expander.type_char(c, &self.expansions) {
+                    self.send_expansion(expansion);
+                }
+            } else {
+                self.expander.reset();
+            }
+        }
+    }
+
+    fn send_expansion(&self, expansion: Expansion) {
+        info!("Expanding abbreviation into {:?}", expansion.text);
+        let window = self.active_window.unwrap_or(self.roots[0]);
+        self.key_mapper.set_event_time(xlib::CurrentTime);
+        for _ in 0..expansion.erase {
+            self.key_mapper
+                .send_key(window, keysym::XK_BackSpace as KeySym, 0);
+        }
+        for c in expansion.text.chars() {
+            self.key_mapper.send_key(window, char_keysym(c), 0);
+        }
+    }
+
+    /// Carries out what the dual-role keys decided for a key event.
+    fn run_dual_role_actions(&mut self, actions: Vec<DualRoleAction>) {
+        for action in actions {
+            match action {
+                DualRoleAction::Press(press) => {
+                    self.key_mapper.set_event_time(press.time);
+                    self.dispatch_key_press(press.keycode, press.state, press.time, true);
+                }
+                DualRoleAction::Release(keycode) => self.dispatch_key_release(keycode),
+                DualRoleAction::StartTimer(keycode) => {
+                    debug!("Timing dual-role keycode={}", keycode);
+                    let tapping_term_ms = self
+                        .dual_roles
+                        .keys()
+                        .iter()
+                        .find(|key| key.keycode == keycode)
+                        .map_or(self.config.tapping_term_ms, |key| key.tapping_term_ms);
+                    self.timers.schedule(
+                        Duration::from_millis(tapping_term_ms),
+                        TimerEvent::DualRoleTimeout(keycode),
+                    );
+                }
+                DualRoleAction::CancelTimer(keycode) => self
+                    .timers
+                    .cancel(|event| *event == TimerEvent::DualRoleTimeout(keycode)),
+                DualRoleAction::Tap { key, press } => {
+                    info!("Dual-role keycode={} tapped", key.keycode);
+                    self.restore_caps_lock(key, press.state);
+                    self.key_mapper.set_event_time(press.time);
+                    match key.tap {
+                        Some(tap) => {
+                            let window = self
+                                .active_window
+                                .unwrap_or(unsafe { xlib::XDefaultRootWindow(self.display) });
+                            self.key_mapper.send_key(window, tap, 0);
+                        }
+                        None => {
+                            // Typed like any key, so remaps of it with held mods apply
+                            self.dispatch_key_press(key.keycode, press.state, press.time, true)
+                        }
+                    }
+                }
+                DualRoleAction::Released { key, state } => self.restore_caps_lock(key, state),
+            }
+        }
+        self.sync_dual_role_grab();
+    }
+
+    /// Grabs the keyboard while a dual-role key is down, and lets it go
+    /// once none is, unless a one-shot modifier or mouse mode still needs it.
+    fn sync_dual_role_grab(&mut self) {
+        let wanted = self.dual_roles.is_active();
+        if wanted && !self.dual_role_grab {
+            debug!("Grabbing the keyboard for the dual-role keys");
+            self.dual_role_grab = grab_keyboard(self.display);
+            if !self.dual_role_grab {
+                warn!("Can't grab the keyboard, keys rolled over a dual-role key may stick");
+            }
+        } else if !wanted && self.dual_role_grab {
+            debug!("Releasing the keyboard grabbed for the dual-role keys");
+            self.dual_role_grab = false;
+            if self.one_shot.get() == 0 && self.mouse_keys.borrow().is_none() {
+                unsafe {
+                    xlib::XUngrabKeyboard(self.display, xlib::CurrentTime);
+                }
+            }
+        }
+    }
+
+    /// XKB toggled Caps Lock when a dual-role Caps Lock went down, whatever
+    /// our grab; put it back the way it was in `state`.
+    fn restore_caps_lock(&self, key: DualRoleKey, state: u32) {
+        if key.restores_lock {
+            self.xkb
+                .set_locked_modifiers(xlib::LockMask, state & xlib::LockMask != 0);
+        }
+    }
+
+    /// The armed one-shot modifiers, which `keycode` uses up unless it's a
+    /// modifier key itself.
+    fn take_one_shot(&self, keycode: KeyCode) -> u32 {
+        let mask = self.one_shot.get();
+        if mask == 0 || self.key_mapper.modifier_mask(keycode).is_some() {
+            return 0;
+        }
+        debug!(
+            "Adding one-shot modifiers {:#x} to keycode={}",
+            mask, keycode
+        );
+        self.one_shot.set(0);
+        mask
+    }
+
+    /// Times out newly armed one-shot modifiers, and once none are armed
+    /// any more, cancels the timeout and lets go of the keyboard.
+    fn sync_one_shot(&mut self) {
+        let mask = self.one_shot.get();
+        if mask == self.one_shot_timed {
+            return;
+        }
+        self.timers
+            .cancel(|event| *event == TimerEvent::OneShotTimeout);
+        if mask != 0 {
+            self.timers
+                .schedule(ONE_SHOT_TIMEOUT, TimerEvent::OneShotTimeout);
+        } else if !self.dual_role_grab && self.mouse_keys.borrow().is_none() {
+            unsafe {
+                xlib::XUngrabKeyboard(self.display, xlib::CurrentTime);
+            }
+        }
+        self.one_shot_timed = mask;
+    }
+
+    /// The modifier bits remaps match on: everything but the lock modifiers.
+    fn modifier_filter(&self) -> u32 {
+        (xlib::ControlMask
+            | xlib::ShiftMask
+            | xlib::Mod1Mask
+            | xlib::Mod2Mask
+            | xlib::Mod3Mask
+            | xlib::Mod4Mask
+            | xlib::Mod5Mask)
+            & !(self.num_lock_mask | self.lock_mask)
+    }
+
+    /// Turns `flag` on or off and regrabs for the rules waiting on it.
+    fn toggle_flag(&mut self, flag: String) {
+        if let Some(index) = self.flags.iter().position(|f| *f == flag) {
+            info!("Flag '{}' off", flag);
+            self.flags.remove(index);
+        } else {
+            info!("Flag '{}' on", flag);
+            self.flags.push(flag);
+        }
+        self.update_key_mappings();
+    }
+
+    fn take_pending_chord_key(&mut self) -> Option<PendingKey> {
+        self.timers
+            .cancel(|event| *event == TimerEvent::ChordTimeout);
+        self.pending_chord_key.take()
+    }
+
+    /// How long the event loop may wait for X events before
+    /// [`EventHandler::handle_timers`] has work to do.
+    pub fn next_timeout(&self) -> Option<Duration> {
+        let now = Instant::now();
+        let macro_key = self
+            .macros
+            .borrow()
+            .next_due()
+            .map(|due| due.saturating_duration_since(now));
+        self.timers
+            .next_timeout(now)
+            .into_iter()
+            .chain(macro_key)
+            .min()
+    }
+
+    pub fn handle_timers(&mut self) {
+        self.key_mapper.set_event_time(xlib::CurrentTime);
+        self.macros.borrow_mut().play_due(Instant::now());
+        for event in self.timers.take_expired(Instant::now()) {
+            match event {
+                TimerEvent::ChordTimeout => {
+                    if let Some(pending) = self.pending_chord_key.take() {
+                        debug!(
+                            "Chord timeout passed, passing keycode={} through",
+                            pending.keycode
+                        );
+                        self.key_mapper.set_event_time(pending.time);
+                        self.release_pending_key(pending);
+                        self.key_mapper.set_event_time(xlib::CurrentTime);
+                    }
+                }
+                TimerEvent::HoldTimeout(keycode) => {
+                    if let Some(held) = self.held_long_presses.get_mut(&keycode) {
+                        info!("Keycode={} held, executing hold remap", keycode);
+                        held.held = true;
+                        let hold = held.long_press.hold.clone();
+                        hold();
+                    }
+                }
+                TimerEvent::DualRoleTimeout(keycode) => {
+                    debug!("Dual-role keycode={} held past the hold timeout", keycode);
+                    let actions = self.dual_roles.timeout(keycode);
+                    self.run_dual_role_actions(actions);
+                }
+                TimerEvent::OneShotTimeout => {
+                    info!(
+                        "One-shot modifiers {:#x} unused, dropping them",
+                        self.one_shot.get()
+                    );
+                    self.one_shot.set(0);
+                }
+                TimerEvent::MouseStep => {
+                    let step = self
+                        .mouse_keys
+                        .borrow_mut()
+                        .as_mut()
+                        .and_then(MouseKeys::step);
+                    if let Some((dx, dy)) = step {
+                        unsafe {
+                            xlib::XWarpPointer(self.display, 0, 0, 0, 0, 0, 0, dx, dy);
+                            xlib::XFlush(self.display);
+                        }
+                        self.timers.schedule(STEP_INTERVAL, TimerEvent::MouseStep);
+                    }
+                }
+            }
+        }
+        self.sync_one_shot();
+    }
+
+    /// How long to wait for a chord partner of `keycode`, the longest of its
+    /// chords' timeouts, or `None` if it isn't a chord key.
+    fn chord_timeout_ms(&self, keycode: KeyCode) -> Option<u64> {
+        self.chords
+            .iter()
+            .filter(|c| c.keycodes.contains(&keycode))
+            .map(|c| c.timeout_ms)
+            .max()
+    }
+
+    fn chord(&self, first: KeyCode, second: KeyCode) -> Option<&Chord> {
+        self.chords
+            .iter()
+            .find(|c| c.keycodes == [first, second] || c.keycodes == [second, first])
+    }
+
+    /// A held chord key turned out to be a plain press: run its own remap if it
+    /// has one, or hand the original key to the focused window.
+    fn release_pending_key(&self, pending: PendingKey) {
+        let key_press = KeyPress {
+            keycode: pending.keycode,
+            modifiers: 0,
+        };
+        if let Some(handler) = self.key_handlers.get(&key_press) {
+            handler();
+        } else {
+            self.replay_key(pending.keycode, pending.state);
+        }
+    }
+
+    fn release_held_modifier(&self, held: HeldModifier) {
+        let Some(remap) = self.release_remaps.get(&held.keycode) else {
+            return;
+        };
+        if let Some(mask) = remap.lock_mask {
+            // XKB toggled the lock on press regardless of our grab
+            self.xkb.set_locked_modifiers(mask, held.state & mask != 0);
+        }
+        if held.interrupted {
+            debug!(
+                "Modifier keycode={} was used in a combo, not remapping",
+                held.keycode
+            );
+        } else {
+            info!(
+                "Bare modifier keycode={} released, executing remap",
+                held.keycode
+            );
+            (remap.handler)();
+        }
+    }
+
+    fn replay_key(&self, keycode: KeyCode, state: u32) {
+        let window = self
+            .active_window
+            .unwrap_or(unsafe { xlib::XDefaultRootWindow(self.display) });
+        debug!("Replaying keycode={} to window={}", keycode, window);
+        self.key_mapper.send_keycode(window, keycode, state);
+    }
+
+    pub fn handle_property_notify(&mut self, atom: Atom) {
+        if self.window_manager.is_focus_property(atom) {
+            self.handle_focus_change();
+        } else if self.window_manager.is_state_property(atom) {
+            self.window_changes.state = true;
+        }
+    }
+
+    /// Called on FocusIn/FocusOut and _NET_ACTIVE_WINDOW changes.
+    pub fn handle_focus_change(&mut self) {
+        self.window_changes.focus = true;
+    }
+
+    /// Called on ConfigureNotify for top-level windows: the focused window
+    /// may have moved to another monitor.
+    pub fn handle_configure_notify(&mut self) {
+        self.window_changes.geometry = true;
+    }
+
+    /// Acts on the window changes recorded since the last call, once each.
+    /// The event loop calls this after draining the pending events.
+    pub fn apply_window_changes(&mut self) {
+        let changes = std::mem::take(&mut self.window_changes);
+        if changes.focus {
+            self.apply_focus_change();
+        }
+        if changes.state {
+            self.apply_state_change();
+        }
+        if changes.geometry {
+            self.apply_geometry_change();
+        }
+    }
 
+    fn apply_focus_change(&mut self) {
         if self.window_manager.has_window_changed() {
+            if let Some(window) = self.window_manager.current_window() {
+                let window_types = self.window_manager.get_window_types(window);
+                if self.config.is_skipped_window_type(&window_types) {
+                    debug!(
+                        "Focus moved to {:?} window {}, keeping the current remaps",
+                        window_types, window
+                    );
+                    return;
+                }
+            }
             info!("Active window changed, updating key mappings");
             self.update_key_mappings();
+            self.run_focus_hook();
+        }
+    }
+
+    /// Runs the `on_focus_change` command for the newly focused window.
+    fn run_focus_hook(&self) {
+        let Some(command) = &self.config.on_focus_change else {
+            return;
+        };
+        // The window may be gone already
+        let title = self.active_window.and_then(|window| {
+            x_error::trap(self.display, || {
+                self.window_manager.get_window_title(window)
+            })
+            .0
+        });
+        let env = [
+            (
+                "WINDOW_CLASS",
+                self.window_class.clone().unwrap_or_default(),
+            ),
+            ("WINDOW_TITLE", title.unwrap_or_default()),
+        ];
+        debug!("Running focus hook: {} {:?}", command, env);
+        spawn_command_with_env(command, &env);
+    }
+
+    fn apply_state_change(&mut self) {
+        let Some(window) = self.active_window else {
+            return;
+        };
+        let states = self.window_manager.get_window_states(window);
+        if states != self.window_states {
+            info!(
+                "Window state changed to {:?}, updating key mappings",
+                states
+            );
+            self.update_key_mappings();
         }
     }
 
-    pub fn handle_mapping_notify(&mut self) {
+    fn apply_geometry_change(&mut self) {
+        let (Some(monitors), Some(window)) = (&self.monitors, self.active_window) else {
+            return;
+        };
+        if !self.config.uses_monitors() {
+            return;
+        }
+        let monitor = self
+            .window_manager
+            .window_center(window)
+            .and_then(|(x, y)| monitors.monitor_at(x, y));
+        if monitor != self.monitor {
+            info!(
+                "Window moved to monitor {:?}, updating key mappings",
+                monitor
+            );
+            self.update_key_mappings();
+        }
+    }
+
+    /// Reacts to an X error from an earlier request. A BadWindow for the
+    /// focused window means it's gone, so the handlers bound to it are stale.
+    pub fn handle_x_error(&mut self, x_error: &XError) {
+        if x_error.is_bad_window() && Some(x_error.resource_id) == self.active_window {
+            info!(
+                "Active window {:#x} no longer exists, updating key mappings",
+                x_error.resource_id
+            );
+            self.update_key_mappings();
+        }
+    }
+
+    /// Called on MappingNotify: a keyboard was plugged in, setxkbmap or
+    /// xmodmap ran, or our own modmap was applied. Keycodes may have moved,
+    /// so everything looked up from the old mapping is looked up again
+    /// before regrabbing.
+    pub fn handle_mapping_notify(&mut self, event: &mut xlib::XMappingEvent) {
+        if event.request == xlib::MappingPointer {
+            return;
+        }
+        unsafe {
+            xlib::XRefreshKeyboardMapping(event);
+        }
+        self.key_mapper.refresh_mapping();
         self.update_key_mappings();
     }
 
+    /// Whether `event` is an XInput2 event, which may report a keyboard
+    /// plugged in or removed.
+    pub fn is_hotplug_event(&self, event: &XEvent) -> bool {
+        self.hotplug
+            .as_ref()
+            .is_some_and(|hotplug| hotplug.is_event(event))
+    }
+
+    /// Sets the remapper up again when a keyboard is plugged in or removed,
+    /// e.g. on docking a laptop. The server gives the core keyboard the new
+    /// keyboard's keymap, dropping the modmap changes, so those are made
+    /// again before everything is regrabbed.
+    pub fn handle_hotplug_event(&mut self, event: &XEvent) {
+        if !self
+            .hotplug
+            .as_ref()
+            .is_some_and(|hotplug| hotplug.keyboards_changed(event))
+        {
+            return;
+        }
+        info!("Keyboard plugged in or removed, reinitializing");
+        // The server reset the mapping for the new keyboard, so the modmap
+        // is applied over it afresh rather than first undone
+        if let Some(modmap) = self.modmap.take() {
+            modmap.forget();
+        }
+        self.key_mapper.refresh_mapping();
+        self.initialize();
+    }
+
+    /// Whether `event` is an XKB event this handler wants to see.
+    pub fn is_xkb_event(&self, event: &XEvent) -> bool {
+        self.xkb.is_group_change(event)
+    }
+
+    pub fn handle_xkb_event(&mut self, event: &XEvent) {
+        if self.xkb.is_group_change(event) && self.xkb.current_layout() != self.current_layout {
+            info!("Keyboard layout changed, updating key mappings");
+            self.update_key_mappings();
+        }
+    }
+
     fn update_key_mappings(&mut self) {
         debug!("Updating key mappings");
-        self.ungrab_all_keys();
+        self.grabbed_keys.clear();
+        self.client_keys.clear();
         self.key_handlers.clear();
-        self.grabbed_keys.clear(); // Clear the grabbed keys list to prevent duplicates
+        self.conditional_handlers.clear();
+        self.observed_handlers.clear();
+        self.layer_keys.clear();
+        self.toggle_keys.clear();
+        self.numlock_handlers.clear();
+        self.registered_remaps.clear();
+        self.release_remaps.clear();
+        // Armed releases stay, so a key held across the update still fires
+        self.release_handlers.clear();
+        self.long_press_handlers.clear();
+        self.held_modifier = None;
+        self.send_to_windows.clear();
+        self.num_lock_mask = self.key_mapper.num_lock_mask();
+        self.lock_mask = self.key_mapper.lock_mask();
+        self.chords.clear();
+        self.pending_chord_key = None;
+        self.timers
+            .cancel(|event| *event == TimerEvent::ChordTimeout);
+
+        let focus = self.query_focus();
+        let (active_window, window_class) = (focus.window, focus.class);
+        self.active_window = active_window;
+        self.window_class = window_class.clone();
+        self.window_role = focus.role;
+        self.monitor = focus.monitor;
+        self.window_states = focus.states;
+        self.window_manager.watch_focus(active_window);
 
-        let active_window = self.window_manager.get_active_window();
-        let window_class = active_window.and_then(|w| self.window_manager.get_window_class(w));
+        self.current_layout = self.xkb.current_layout();
 
         info!(
-            "Active window: {:?}, class: {:?}",
-            active_window, window_class
+            "Active window: {:?}, class: {:?}, role: {:?}, state: {:?}, monitor: {:?}, layout: {:?}",
+            active_window,
+            window_class,
+            self.window_role,
+            self.window_states,
+            self.monitor,
+            self.current_layout
         );
 
-        let remaps = self.config.remaps_for_window(window_class.as_deref());
+        if self.config.is_ignored(window_class.as_deref()) {
+            info!("Window class is in ignore_classes, releasing all grabs");
+        }
+        let context = MatchContext {
+            window_class: window_class.as_deref(),
+            layout: self.current_layout.as_deref(),
+            window_role: self.window_role.as_deref(),
+            monitor: self.monitor.as_deref(),
+            window_states: &self.window_states,
+            flags: &self.flags,
+        };
+        let remaps = if self.paused {
+            Vec::new()
+        } else {
+            self.config.matching_remaps(&context)
+        };
+        self.expansions = if self.paused || self.recorder.is_none() {
+            BTreeMap::new()
+        } else {
+            self.config.matching_expansions(&context)
+        };
+        self.expander.reset();
         info!("Found {} remaps for current window", remaps.len());
 
-        for remap in remaps {
+        let layer_remaps = self
+            .active_layer
+            .as_ref()
+            .and_then(|(layer, _)| self.config.layer(layer))
+            .unwrap_or_default();
+        // The layer's remaps come last so they override the window's
+        let remaps = remaps.into_iter().chain(
+            layer_remaps
+                .into_iter()
+                .map(|remap| (RuleId::Global, remap)),
+        );
+        for (rule, remap) in remaps {
             debug!("Registering remap: {} -> {:?}", remap.from, remap.to);
-            self.register_remap(remap, active_window);
+            self.register_remap(rule, remap, active_window, window_class.as_deref());
         }
+        let ignored = self.config.is_ignored(window_class.as_deref());
+        self.register_dual_roles(!self.paused && !ignored);
+        self.register_confirm_key();
+
+        let wanted = wanted_grabs(
+            active_window,
+            &self.roots,
+            &self.grabbed_keys,
+            &self.client_keys,
+            self.conditional_handlers.keys().copied().collect(),
+        );
 
-        info!("Grabbing {} keys", self.grabbed_keys.len());
-        self.grab_keys();
+        info!(
+            "Grabbing {} keys ({} on the focused window)",
+            wanted.len(),
+            wanted.client.as_ref().map_or(0, |(_, keys)| keys.len())
+        );
+        self.key_mapper.set_grabbed_keys(&self.grabbed_keys);
+        self.key_mapper.set_send_to_windows(&self.send_to_windows);
+        if self.lock_mask != self.window_manager.lock_mask() {
+            debug!("Lock modifiers are now {:#x}, regrabbing", self.lock_mask);
+            // Grabs made with the old lock modifiers are released with them
+            self.grabs
+                .update(&mut self.window_manager, &self.roots, GrabSet::default());
+            self.window_manager.set_lock_mask(self.lock_mask);
+        }
+        for key in self
+            .grabs
+            .update(&mut self.window_manager, &self.roots, wanted)
+        {
+            warn!(
+                "Can't grab {}: another client (often the window manager or a hotkey daemon) already grabbed it, so its remap won't fire",
+                self.key_mapper.describe(key)
+            );
+        }
     }
 
-    fn register_remap(&mut self, remap: Remap, target_window: Option<Window>) {
+    /// Sets up the dual-role keys of the `presets` and `home_row_mods`,
+    /// grabbing them if `grab` is set.
+    fn register_dual_roles(&mut self, grab: bool) {
+        let mut keys = Vec::new();
+        if self.config.presets.contains(&Preset::CapsDualRole) {
+            let keycode = self
+                .key_mapper
+                .keycode_from_keysym(keysym::XK_Caps_Lock as KeySym);
+            if keycode == 0 {
+                warn!("No key produces Caps_Lock, the caps_dual_role preset is off");
+            } else {
+                keys.push(DualRoleKey {
+                    keycode,
+                    modifiers: xlib::ControlMask,
+                    tap: Some(keysym::XK_Escape as KeySym),
+                    restores_lock: true,
+                    tapping_term_ms: self.config.tapping_term_ms,
+                });
+            }
+        }
+        for (key, home_row_mod) in &self.config.home_row_mods {
+            let Some((keysym, _)) = self.key_mapper.parse_key(key) else {
+                continue;
+            };
+            let keycode = self.key_mapper.from_keycode(keysym);
+            let Some((_, modifiers)) = self.key_mapper.modifier_key(home_row_mod.modifier()) else {
+                continue;
+            };
+            if keycode == 0 || modifiers == 0 {
+                warn!("Home-row mod '{}' has no key or modifier, skipping it", key);
+                continue;
+            }
+            keys.push(DualRoleKey {
+                keycode,
+                modifiers,
+                tap: None,
+                restores_lock: false,
+                tapping_term_ms: home_row_mod
+                    .tapping_term_ms()
+                    .unwrap_or(self.config.tapping_term_ms),
+            });
+        }
+        // Keys held across the update stay tapped or held as they were
+        if self.dual_roles.keys() != keys || self.dual_roles.flavor() != self.config.hold_flavor {
+            self.dual_roles = DualRoles::new(keys, self.config.hold_flavor);
+            self.sync_dual_role_grab();
+        }
+        if grab {
+            let keycodes: Vec<KeyCode> = self
+                .dual_roles
+                .keys()
+                .iter()
+                .map(|key| key.keycode)
+                .collect();
+            for keycode in keycodes {
+                self.add_grab(
+                    RuleId::Global,
+                    KeyPress {
+                        keycode,
+                        modifiers: 0,
+                    },
+                );
+            }
+        }
+    }
+
+    /// Grabs the confirm key, if a config is on trial, over any remap of
+    /// the same key.
+    fn register_confirm_key(&mut self) {
+        self.confirm_key_press = None;
+        let Some(key) = &self.confirm_key else {
+            return;
+        };
+        let Some((keysym, modifiers)) = self.key_mapper.parse_key(key) else {
+            warn!("Can't parse confirm key '{}'", key);
+            return;
+        };
+        let keycode = self.key_mapper.from_keycode(keysym);
+        if keycode == 0 {
+            warn!("No key produces the confirm key '{}'", key);
+            return;
+        }
+        let key_press = KeyPress { keycode, modifiers };
+        self.confirm_key_press = Some(key_press);
+        self.add_grab(RuleId::Global, key_press);
+    }
+
+    /// Adds `key_press` to the grabbed keys, on the window `rule` grabs on.
+    fn add_grab(&mut self, rule: RuleId, key_press: KeyPress) {
+        if !self.grabbed_keys.contains(&key_press) {
+            self.grabbed_keys.push(key_press);
+        }
+        if self.config.grab_target(rule) == GrabTarget::Client
+            && !self.client_keys.contains(&key_press)
+        {
+            self.client_keys.push(key_press);
+        }
+    }
+
+    /// The focused window and its properties. Focus can move on while we
+    /// look at the window, e.g. when a popup closes, and then the lookups
+    /// fail with BadWindow; retry against the new focus rather than keep a
+    /// dead window.
+    fn query_focus(&mut self) -> Focus {
+        const ATTEMPTS: usize = 3;
+
+        for attempt in 1..=ATTEMPTS {
+            let window_manager = &mut self.window_manager;
+            let monitors = self.monitors.as_ref();
+            let (focus, errors) = x_error::trap(self.display, || {
+                read_focus(
+                    window_manager,
+                    monitors.map(|monitors| |x, y| monitors.monitor_at(x, y)),
+                )
+            });
+
+            match errors.iter().find(|e| e.is_bad_window()) {
+                None => return focus,
+                Some(e) => debug!(
+                    "Window {:#x} vanished while reading focus (attempt {}/{})",
+                    e.resource_id, attempt, ATTEMPTS
+                ),
+            }
+        }
+
+        warn!("Focus kept changing while reading it, using global rules only");
+        Focus::default()
+    }
+
+    fn register_remap(
+        &mut self,
+        rule: RuleId,
+        remap: Remap,
+        target_window: Option<Window>,
+        window_class: Option<&str>,
+    ) {
+        let window = match &remap.send_to {
+            Some(selector) => {
+                let Some(window) = self.window_manager.find_window_by_class(&selector.class) else {
+                    info!(
+                        "No '{}' window to send '{}' to, leaving the key alone",
+                        selector.class, remap.from
+                    );
+                    return;
+                };
+                debug!("Sending '{}' to window={}", remap.from, window);
+                if !self.send_to_windows.contains(&window) {
+                    self.send_to_windows.push(window);
+                }
+                window
+            }
+            None => target_window.unwrap_or(unsafe { xlib::XDefaultRootWindow(self.display) }),
+        };
+
+        if let Some((first, second)) = KeyParser::split_chord(&remap.from) {
+            self.register_chord(rule, &remap, first, second, window, window_class);
+            return;
+        }
+        let key_delay = self.key_delay(&remap);
+
         if let Some((from_keysym, from_mods)) = self.key_mapper.parse_key(&remap.from) {
-            let keycode = self.key_mapper.keycode_from_keysym(from_keysym);
+            let keycode = self.key_mapper.from_keycode(from_keysym);
             let key_press = KeyPress {
                 keycode,
                 modifiers: from_mods,
@@ -130,35 +1600,151 @@ impl EventHandler {
                 return;
             }
 
-            let key_mapper = KeyMapper::new(self.display);
-            let window = target_window.unwrap_or(unsafe { xlib::XDefaultRootWindow(self.display) });
-
-            let handler: Rc<dyn Fn()> = match remap.to {
-                KeyAction::Single(key) => {
-                    let key_clone = key.clone();
-                    Rc::new(move || {
-                        debug!("Executing single key remap: {}", key_clone);
-                        if let Some((keysym, mods)) = key_mapper.parse_key(&key_clone) {
-                            key_mapper.send_key(window, keysym, mods);
-                        } else {
-                            warn!("Failed to parse target key: {}", key_clone);
-                        }
-                    })
+            // The grab without the tested modifier doesn't fire with it held.
+            // A remap of the key with it held that came first stays.
+            if let KeyAction::IfModifier { modifier, .. } = &remap.to {
+                let mask = self.key_mapper.named_modifier_mask(modifier).unwrap_or(0);
+                let held = KeyPress {
+                    keycode,
+                    modifiers: from_mods | mask,
+                };
+                if from_mods & mask == 0 && !self.registered_remaps.contains_key(&held) {
+                    let held = Remap {
+                        from: format!("{}-{}", modifier, remap.from),
+                        ..remap.clone()
+                    };
+                    self.register_remap(rule, held, target_window, window_class);
                 }
-                KeyAction::Multiple(keys) => {
-                    let keys_clone = keys.clone();
+            }
+
+            let registered = Remap {
+                from: String::new(),
+                ..remap.clone()
+            };
+            if self.registered_remaps.get(&key_press) == Some(&(rule, registered.clone())) {
+                debug!(
+                    "'{}' is a key already remapped the same way, skipping it",
+                    remap.from
+                );
+                return;
+            }
+            self.registered_remaps.insert(key_press, (rule, registered));
+
+            if let KeyAction::Layer(layer) = &remap.to {
+                debug!("'{}' turns on layer '{}' while held", remap.from, layer);
+                self.layer_keys.insert(key_press, layer.clone());
+                self.add_grab(rule, key_press);
+                return;
+            }
+            if let KeyAction::Toggle(flag) = &remap.to {
+                debug!("'{}' toggles flag '{}'", remap.from, flag);
+                self.toggle_keys.insert(key_press, flag.clone());
+                self.add_grab(rule, key_press);
+                return;
+            }
+
+            if let (true, KeyAction::Script(path)) = (remap.conditional, &remap.to) {
+                let handler = self.build_conditional_handler(path, window, window_class);
+                let stats = self.stats.clone();
+                let from = remap.from.clone();
+                let window_class = window_class.map(str::to_string);
+                self.conditional_handlers.insert(
+                    key_press,
                     Rc::new(move || {
-                        debug!("Executing multi-key remap: {:?}", keys_clone);
-                        key_mapper.send_key_sequence(window, &keys_clone);
-                    })
+                        stats
+                            .borrow_mut()
+                            .record(rule, &from, window_class.as_deref());
+                        handler()
+                    }),
+                );
+                self.add_grab(rule, key_press);
+                return;
+            }
+
+            let mut handler = match self.modifier_target(&remap.to) {
+                Some(modifier) => {
+                    let key_mapper = self.key_mapper.clone();
+                    Rc::new(move || key_mapper.hold_modifier(window, modifier, keycode))
                 }
+                None => self.build_handler(remap.to, window, window_class, key_delay),
             };
+            if remap.also_send_original
+                && self.config.observe
+                && remap.on == Trigger::Press
+                && remap.hold.is_none()
+            {
+                let handler = self.counted(handler, rule, &remap.from, window_class);
+                if self.recorder.is_some() {
+                    // The key reaches the window anyway, so seeing it is enough
+                    debug!("Observing '{}' instead of grabbing it", remap.from);
+                    self.observed_handlers.insert(key_press, handler);
+                } else {
+                    // Without XRecord, grab it synchronously and replay the
+                    // original once the remap has run
+                    debug!("Grabbing '{}' synchronously to observe it", remap.from);
+                    self.conditional_handlers.insert(
+                        key_press,
+                        Rc::new(move || {
+                            handler();
+                            false
+                        }),
+                    );
+                    self.add_grab(rule, key_press);
+                }
+                return;
+            }
+            if remap.also_send_original {
+                handler = self.with_original_key(handler, window, &[keycode], from_mods);
+            }
+            let handler = self.counted(handler, rule, &remap.from, window_class);
 
             // Only add if not already present
-            if !self.grabbed_keys.contains(&key_press) {
-                self.grabbed_keys.push(key_press);
+            self.add_grab(rule, key_press);
+
+            if remap.on == Trigger::Release {
+                self.release_handlers.insert(key_press, handler);
+                return;
+            }
+            if let Some(hold) = remap.hold {
+                let hold = self.build_handler(hold, window, window_class, key_delay);
+                let hold = self.counted(hold, rule, &remap.from, window_class);
+                let timeout_ms = remap.timing.hold_timeout_ms;
+                self.long_press_handlers.insert(
+                    key_press,
+                    Rc::new(LongPress {
+                        tap: handler,
+                        hold,
+                        timeout: Duration::from_millis(
+                            timeout_ms.unwrap_or(self.config.hold_timeout_ms),
+                        ),
+                    }),
+                );
+                return;
+            }
+            let is_lock_key = is_lock_keysym(from_keysym);
+            if from_mods == 0 && (is_lock_key || is_modifier_keysym(from_keysym)) {
+                let lock_mask = if is_lock_key {
+                    self.key_mapper.modifier_mask(keycode)
+                } else {
+                    None
+                };
+                debug!(
+                    "Registered '{}' as a bare modifier, handled on release",
+                    remap.from
+                );
+                self.release_remaps
+                    .insert(keycode, ReleaseRemap { handler, lock_mask });
+                return;
+            }
+            match self.key_mapper.numlock_requirement(from_keysym) {
+                Some(numlock_on) => {
+                    self.numlock_handlers
+                        .insert((key_press, numlock_on), handler);
+                }
+                None => {
+                    self.key_handlers.insert(key_press, handler);
+                }
             }
-            self.key_handlers.insert(key_press, handler);
             debug!(
                 "Successfully registered handler for keycode={}, mods={:#x}",
                 keycode, from_mods
@@ -168,80 +1754,555 @@ impl EventHandler {
         }
     }
 
-    fn grab_keys(&self) {
-        unsafe {
-            let root = xlib::XDefaultRootWindow(self.display);
-
-            for key_press in &self.grabbed_keys {
-                debug!(
-                    "Grabbing key: keycode={}, modifiers={:#x}",
-                    key_press.keycode, key_press.modifiers
+    fn register_chord(
+        &mut self,
+        rule: RuleId,
+        remap: &Remap,
+        first: &str,
+        second: &str,
+        window: Window,
+        window_class: Option<&str>,
+    ) {
+        let from = &remap.from;
+        let mut keycodes = [0; 2];
+        for (keycode, key) in keycodes.iter_mut().zip([first, second]) {
+            let Some((keysym, _)) = self.key_mapper.parse_key(key) else {
+                warn!("Failed to parse chord key '{}' in '{}'", key, from);
+                return;
+            };
+            *keycode = self.key_mapper.from_keycode(keysym);
+            if *keycode == 0 {
+                warn!(
+                    "Failed to get keycode for chord key '{}' in '{}'",
+                    key, from
                 );
+                return;
+            }
+        }
 
-                let grab_result = xlib::XGrabKey(
-                    self.display,
-                    key_press.keycode as i32,
-                    key_press.modifiers,
-                    root,
-                    xlib::True,
-                    xlib::GrabModeAsync,
-                    xlib::GrabModeAsync,
-                );
+        debug!(
+            "Registering chord: '{}' -> keycodes={:?}, to={:?}",
+            from, keycodes, remap.to
+        );
 
-                if grab_result != 0 {
-                    debug!("Failed to grab key: keycode={}, modifiers={:#x} (this is usually due to X11 permissions or another app using the key)", 
-                          key_press.keycode, key_press.modifiers);
-                } else {
-                    debug!(
-                        "Successfully grabbed key: keycode={}, modifiers={:#x}",
-                        key_press.keycode, key_press.modifiers
-                    );
-                }
+        for keycode in keycodes {
+            let key_press = KeyPress {
+                keycode,
+                modifiers: 0,
+            };
+            self.add_grab(rule, key_press);
+        }
 
-                // Also grab with NumLock
-                xlib::XGrabKey(
-                    self.display,
-                    key_press.keycode as i32,
-                    key_press.modifiers | xlib::Mod2Mask,
-                    root,
-                    xlib::True,
-                    xlib::GrabModeAsync,
-                    xlib::GrabModeAsync,
-                );
+        let key_delay = self.key_delay(remap);
+        let mut handler = self.build_handler(remap.to.clone(), window, window_class, key_delay);
+        if remap.also_send_original {
+            handler = self.with_original_key(handler, window, &keycodes, 0);
+        }
+        let handler = self.counted(handler, rule, from, window_class);
+        self.chords.push(Chord {
+            keycodes,
+            handler,
+            timeout_ms: remap
+                .timing
+                .chord_timeout_ms
+                .unwrap_or(self.config.chord_timeout_ms),
+        });
+    }
 
-                // Also grab with CapsLock
-                xlib::XGrabKey(
-                    self.display,
-                    key_press.keycode as i32,
-                    key_press.modifiers | xlib::LockMask,
-                    root,
-                    xlib::True,
-                    xlib::GrabModeAsync,
-                    xlib::GrabModeAsync,
-                );
+    /// The keysym of a lone modifier target like `Control_R`, which is held for
+    /// as long as the source key rather than tapped.
+    fn modifier_target(&self, action: &KeyAction) -> Option<KeySym> {
+        let KeyAction::Single(key) = action else {
+            return None;
+        };
+        match self.key_mapper.parse_key(key) {
+            Some((keysym, 0)) if is_modifier_keysym(keysym) => Some(keysym),
+            _ => None,
+        }
+    }
 
-                // Also grab with both NumLock and CapsLock
-                xlib::XGrabKey(
-                    self.display,
-                    key_press.keycode as i32,
-                    key_press.modifiers | xlib::Mod2Mask | xlib::LockMask,
-                    root,
-                    xlib::True,
-                    xlib::GrabModeAsync,
-                    xlib::GrabModeAsync,
+    /// Wraps a handler so the grabbed keys are replayed after the action runs.
+    fn with_original_key(
+        &self,
+        handler: Rc<dyn Fn()>,
+        window: Window,
+        keycodes: &[KeyCode],
+        modifiers: u32,
+    ) -> Rc<dyn Fn()> {
+        let key_mapper = self.key_mapper.clone();
+        let keycodes = keycodes.to_vec();
+        Rc::new(move || {
+            handler();
+            for &keycode in &keycodes {
+                debug!(
+                    "Replaying original keycode={} to window={}",
+                    keycode, window
                 );
+                key_mapper.send_keycode(window, keycode, modifiers);
             }
+        })
+    }
 
-            xlib::XFlush(self.display);
+    /// Wraps a handler so its activations show up in [`EventHandler::stats`].
+    fn counted(
+        &self,
+        handler: Rc<dyn Fn()>,
+        rule: RuleId,
+        from: &str,
+        window_class: Option<&str>,
+    ) -> Rc<dyn Fn()> {
+        let stats = self.stats.clone();
+        let from = from.to_string();
+        let window_class = window_class.map(str::to_string);
+        Rc::new(move || {
+            stats
+                .borrow_mut()
+                .record(rule, &from, window_class.as_deref());
+            handler();
+        })
+    }
+
+    /// Runs a script and returns whether it swallowed the key. A failing
+    /// script passes the key on rather than losing it.
+    fn build_conditional_handler(
+        &self,
+        path: &Path,
+        window: Window,
+        window_class: Option<&str>,
+    ) -> Rc<dyn Fn() -> bool> {
+        let key_mapper = self.key_mapper.clone();
+        let path = path.to_path_buf();
+        let window_class = window_class.map(str::to_string);
+        Rc::new(move || {
+            debug!("Executing conditional script remap: {}", path.display());
+            let context = ScriptContext {
+                key_mapper: key_mapper.clone(),
+                window,
+                window_class: window_class.clone(),
+            };
+            script::run(&path, context).unwrap_or_else(|e| {
+                error!("Script '{}' failed: {}", path.display(), e);
+                false
+            })
+        })
+    }
+
+    /// The pause between the keys of `remap`'s sequences.
+    fn key_delay(&self, remap: &Remap) -> Duration {
+        Duration::from_millis(
+            remap
+                .timing
+                .key_delay_ms
+                .unwrap_or(self.config.key_delay_ms),
+        )
+    }
+
+    fn build_handler(
+        &self,
+        action: KeyAction,
+        window: Window,
+        window_class: Option<&str>,
+        key_delay: Duration,
+    ) -> Rc<dyn Fn()> {
+        let key_mapper = self.key_mapper.clone();
+
+        match action {
+            KeyAction::Single(key) => match key_mapper.parse_key(&key) {
+                Some((keysym, mods)) => Rc::new(move || {
+                    debug!("Executing single key remap: {}", key);
+                    key_mapper.send_key(window, keysym, mods);
+                }),
+                None => {
+                    warn!("Failed to parse target key: {}", key);
+                    Rc::new(|| {})
+                }
+            },
+            KeyAction::Multiple(steps) => Rc::new(move || {
+                debug!("Executing multi-key remap: {:?}", steps);
+                if key_delay.is_zero() && !SequenceStep::has_delay(&steps) {
+                    key_mapper.send_steps(window, &steps, key_delay);
+                } else {
+                    key_mapper.spawn_steps(window, steps.clone(), key_delay);
+                }
+            }),
+            KeyAction::Script(path) => {
+                let window_class = window_class.map(str::to_string);
+                Rc::new(move || {
+                    debug!("Executing script remap: {}", path.display());
+                    let context = ScriptContext {
+                        key_mapper: key_mapper.clone(),
+                        window,
+                        window_class: window_class.clone(),
+                    };
+                    if let Err(e) = script::run(&path, context) {
+                        error!("Script '{}' failed: {}", path.display(), e);
+                    }
+                })
+            }
+            KeyAction::Layout(layout) => {
+                let xkb = self.xkb.clone();
+                let target = LayoutTarget::parse(&layout);
+                Rc::new(move || {
+                    debug!("Executing layout switch: {:?}", target);
+                    xkb.switch_layout(&target);
+                })
+            }
+            KeyAction::Exec(command) => Rc::new(move || {
+                debug!("Executing command: {}", command);
+                spawn_command(&command);
+            }),
+            KeyAction::Disabled => Rc::new(|| debug!("Swallowing disabled key")),
+            KeyAction::Window(action) => {
+                let display = self.display;
+                Rc::new(move || ewmh::perform(display, window, &action))
+            }
+            KeyAction::OneShot(name) => {
+                let Some((_, mask)) = key_mapper.modifier_key(&name).filter(|&(_, m)| m != 0)
+                else {
+                    warn!("Modifier '{}' isn't bound to a modifier bit", name);
+                    return Rc::new(|| {});
+                };
+                let one_shot = self.one_shot.clone();
+                let display = self.display;
+                Rc::new(move || {
+                    if one_shot.get() & mask != 0 {
+                        // Tapped again before use: cancel it
+                        debug!("Cancelling one-shot modifier {}", name);
+                        one_shot.set(one_shot.get() & !mask);
+                        return;
+                    }
+                    debug!("Arming one-shot modifier {}", name);
+                    if one_shot.get() == 0 && !grab_keyboard(display) {
+                        warn!("Can't grab the keyboard, one-shot {} not armed", name);
+                        return;
+                    }
+                    one_shot.set(one_shot.get() | mask);
+                })
+            }
+            KeyAction::Click(click) => Rc::new(move || key_mapper.click(click)),
+            KeyAction::Layer(layer) => {
+                warn!("Layer '{}' only works as a remap's own action", layer);
+                Rc::new(|| {})
+            }
+            KeyAction::Toggle(flag) => {
+                warn!("Toggling '{}' only works as a remap's own action", flag);
+                Rc::new(|| {})
+            }
+            KeyAction::Plugin(action) => {
+                if !self.plugins.contains(&action.name) {
+                    warn!("No plugin '{}' is loaded", action.name);
+                    return Rc::new(|| {});
+                }
+                let plugins = self.plugins.clone();
+                let window_class = window_class.map(str::to_string);
+                Rc::new(move || {
+                    debug!("Running plugin action: {}", action.name);
+                    let result = plugins.run(
+                        &action.name,
+                        action.arg.as_deref(),
+                        window,
+                        window_class.as_deref(),
+                    );
+                    if let Err(e) = result {
+                        error!("Plugin '{}' failed: {}", action.name, e);
+                    }
+                })
+            }
+            KeyAction::MouseMode(settings) => {
+                let keys = mouse_mode_keys(&key_mapper, &settings);
+                let mouse_keys = self.mouse_keys.clone();
+                let display = self.display;
+                Rc::new(move || {
+                    if mouse_keys.borrow().is_some() {
+                        return;
+                    }
+                    if !grab_keyboard(display) {
+                        warn!("Can't grab the keyboard, not entering mouse mode");
+                        return;
+                    }
+                    info!("Entering mouse mode, {} leaves it", settings.exit);
+                    *mouse_keys.borrow_mut() = Some(MouseKeys::new(settings.clone(), keys.clone()));
+                })
+            }
+            KeyAction::RecordMacro(slot) => {
+                let macros = self.macros.clone();
+                Rc::new(move || macros.borrow_mut().toggle_recording(slot))
+            }
+            KeyAction::PlayMacro(slot) => {
+                let macros = self.macros.clone();
+                Rc::new(move || macros.borrow_mut().play(slot))
+            }
+            KeyAction::IfModifier {
+                modifier,
+                then,
+                otherwise,
+            } => {
+                let Some(mask) = key_mapper.named_modifier_mask(&modifier) else {
+                    warn!("Unknown modifier '{}' in condition", modifier);
+                    return Rc::new(|| {});
+                };
+                let then = self.build_handler(*then, window, window_class, key_delay);
+                let otherwise = self.build_handler(*otherwise, window, window_class, key_delay);
+                Rc::new(move || {
+                    if key_mapper.held_modifiers() & mask != 0 {
+                        debug!("{} is held, taking the first branch", modifier);
+                        then()
+                    } else {
+                        otherwise()
+                    }
+                })
+            }
+            KeyAction::Compose(keys) => {
+                let steps: Vec<SequenceStep> = std::iter::once("Multi_key".to_string())
+                    .chain(keys)
+                    .map(SequenceStep::Key)
+                    .collect();
+                Rc::new(move || {
+                    debug!("Typing compose sequence: {:?}", steps);
+                    if key_delay.is_zero() {
+                        key_mapper.send_steps(window, &steps, key_delay);
+                    } else {
+                        key_mapper.spawn_steps(window, steps.clone(), key_delay);
+                    }
+                })
+            }
         }
     }
 
     fn ungrab_all_keys(&self) {
         debug!("Ungrabbing all keys");
         unsafe {
-            let root = xlib::XDefaultRootWindow(self.display);
-            xlib::XUngrabKey(self.display, xlib::AnyKey, xlib::AnyModifier, root);
+            for &root in &self.roots {
+                xlib::XUngrabKey(self.display, xlib::AnyKey, xlib::AnyModifier, root);
+            }
+        }
+        if let Some((window, _)) = self.grabs.current().client {
+            x_error::trap(self.display, || unsafe {
+                xlib::XUngrabKey(self.display, xlib::AnyKey, xlib::AnyModifier, window);
+                xlib::XSync(self.display, xlib::False);
+            });
+        }
+        unsafe {
             xlib::XFlush(self.display);
         }
     }
 }
+
+/// The XRecord recorder for observe mode and text expansions, when the
+/// config uses either.
+fn open_recorder(display: *mut Display, config: &Config) -> Option<Recorder> {
+    if !config.observe && !config.has_expansions() {
+        return None;
+    }
+    let recorder = Recorder::open(display);
+    if recorder.is_none() && config.observe {
+        warn!("XRecord unavailable, grabbing observed keys synchronously instead");
+    }
+    if recorder.is_none() && config.has_expansions() {
+        warn!("XRecord unavailable, text expansions won't work");
+    }
+    recorder
+}
+
+/// Where to grab the `grabbed` keys with `active_window` focused: those
+/// in `client_keys` on the window, the rest on the roots. Without a client
+/// window, client grabs fall back to the roots.
+fn wanted_grabs(
+    active_window: Option<Window>,
+    roots: &[Window],
+    grabbed: &[KeyPress],
+    client_keys: &[KeyPress],
+    sync: Vec<KeyPress>,
+) -> GrabSet {
+    let client = active_window
+        .filter(|w| !roots.contains(w) && !client_keys.is_empty())
+        .map(|window| (window, client_keys.to_vec()));
+    let client_keys = client.as_ref().map_or(&[][..], |(_, keys)| keys);
+    GrabSet {
+        root: grabbed
+            .iter()
+            .filter(|key| !client_keys.contains(key))
+            .copied()
+            .collect(),
+        sync,
+        client,
+    }
+}
+
+/// The focused window and its properties. `monitor_at` names the monitor
+/// at a point; without it the window's position isn't looked up.
+fn read_focus(
+    backend: &mut impl XBackend,
+    monitor_at: Option<impl Fn(i32, i32) -> Option<String>>,
+) -> Focus {
+    let Some(window) = backend.active_window() else {
+        return Focus::default();
+    };
+    Focus {
+        window: Some(window),
+        class: backend.window_class(window),
+        role: backend.window_role(window),
+        monitor: monitor_at.and_then(|monitor_at| {
+            let (x, y) = backend.window_center(window)?;
+            monitor_at(x, y)
+        }),
+        states: backend.window_states(window),
+    }
+}
+
+/// The keys of mouse mode by keycode: hjkl move, space clicks and the
+/// `exit` key of `settings` leaves.
+fn mouse_mode_keys(
+    key_mapper: &KeyMapper,
+    settings: &MouseModeSettings,
+) -> Vec<(KeyCode, MouseKey)> {
+    let keysyms = [
+        (keysym::XK_h, MouseKey::Move(-1, 0)),
+        (keysym::XK_j, MouseKey::Move(0, 1)),
+        (keysym::XK_k, MouseKey::Move(0, -1)),
+        (keysym::XK_l, MouseKey::Move(1, 0)),
+        (keysym::XK_space, MouseKey::Click),
+    ]
+    .map(|(keysym, key)| (keysym as KeySym, key));
+    let exit = key_mapper
+        .parse_key(&settings.exit)
+        .map(|(keysym, _)| (keysym, MouseKey::Exit));
+    // The exit key comes first so it wins if it's one of the others
+    exit.into_iter()
+        .chain(keysyms)
+        .map(|(keysym, key)| (key_mapper.keycode_from_keysym(keysym), key))
+        .filter(|(keycode, _)| *keycode != 0)
+        .collect()
+}
+
+/// Grabs the whole keyboard so every key press comes to us.
+fn grab_keyboard(display: *mut Display) -> bool {
+    unsafe {
+        xlib::XGrabKeyboard(
+            display,
+            xlib::XDefaultRootWindow(display),
+            xlib::False,
+            xlib::GrabModeAsync,
+            xlib::GrabModeAsync,
+            xlib::CurrentTime,
+        ) == xlib::GrabSuccess
+    }
+}
+
+/// How sandboxed plugins type keys: parsed and sent like a remap's target.
+fn key_sender(key_mapper: &Rc<KeyMapper>) -> KeySender {
+    let key_mapper = key_mapper.clone();
+    Rc::new(move |window, key| match key_mapper.parse_key(key) {
+        Some((keysym, mods)) => {
+            key_mapper.send_key(window, keysym, mods);
+            true
+        }
+        None => false,
+    })
+}
+
+/// Starts `command` with `sh -c` and reaps it from a thread once it exits,
+/// so slow commands don't hold up the event loop.
+pub(crate) fn spawn_command(command: &str) {
+    spawn_command_with_env(command, &[]);
+}
+
+/// Runs `command` like [`spawn_command`], with `env` added to its
+/// environment.
+fn spawn_command_with_env(command: &str, env: &[(&str, String)]) {
+    let child = std::process::Command::new("sh")
+        .args(["-c", command])
+        .envs(env.iter().map(|(name, value)| (name, value)))
+        .stdin(std::process::Stdio::null())
+        .spawn();
+    match child {
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => error!("Failed to run '{}': {}", command, e),
+    }
+}
+
+impl Drop for EventHandler {
+    fn drop(&mut self) {
+        info!("Releasing key grabs");
+        self.ungrab_all_keys();
+        unsafe {
+            xlib::XUngrabKeyboard(self.display, xlib::CurrentTime);
+        }
+        self.key_mapper.restore_keyboard_mapping();
+        self.modmap = None;
+        unsafe {
+            xlib::XSync(self.display, xlib::False);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::{MockBackend, MockWindow};
+
+    #[test]
+    fn test_read_focus() {
+        let mut backend = MockBackend {
+            active: Some(10),
+            ..MockBackend::default()
+        };
+        backend.windows.insert(
+            10,
+            MockWindow {
+                class: Some("firefox".into()),
+                role: Some("browser".into()),
+                states: vec!["fullscreen".into()],
+                center: Some((1500, 300)),
+            },
+        );
+        let monitor_at = |x: i32, _| Some(if x < 1920 { "DP-1" } else { "HDMI-1" }.into());
+
+        let focus = read_focus(&mut backend, Some(monitor_at));
+        assert_eq!(focus.window, Some(10));
+        assert_eq!(focus.class.as_deref(), Some("firefox"));
+        assert_eq!(focus.role.as_deref(), Some("browser"));
+        assert_eq!(focus.monitor.as_deref(), Some("DP-1"));
+        assert_eq!(focus.states, ["fullscreen"]);
+
+        backend.active = None;
+        assert_eq!(read_focus(&mut backend, Some(monitor_at)).window, None);
+    }
+
+    #[test]
+    fn test_grabs_follow_focus() {
+        let a = KeyPress {
+            keycode: 38,
+            modifiers: 0,
+        };
+        let b = KeyPress {
+            keycode: 56,
+            modifiers: xlib::ControlMask,
+        };
+        let roots = [1];
+        let mut backend = MockBackend {
+            active: Some(10),
+            ..MockBackend::default()
+        };
+        let mut grabs = Grabs::default();
+        let mut regrab = |backend: &mut MockBackend| {
+            let focus = read_focus(backend, None::<fn(i32, i32) -> Option<String>>);
+            let wanted = wanted_grabs(focus.window, &roots, &[a, b], &[b], vec![a]);
+            grabs.update(backend, &roots, wanted)
+        };
+
+        assert!(regrab(&mut backend).is_empty());
+        let mut grabbed = backend.grabs.clone();
+        grabbed.sort_by_key(|&(window, key, _)| (window, key.keycode));
+        assert_eq!(grabbed, [(1, a, true), (10, b, false)]);
+
+        // Focus on the root moves the client grab there
+        backend.active = Some(1);
+        regrab(&mut backend);
+        let mut grabbed = backend.grabs.clone();
+        grabbed.sort_by_key(|&(window, key, _)| (window, key.keycode));
+        assert_eq!(grabbed, [(1, a, true), (1, b, false)]);
+    }
+}
@@ -0,0 +1,95 @@
+//! Window management through EWMH client messages to the root window, for
+//! `{window: ...}` actions.
+
+use crate::config::WindowAction;
+use crate::display::intern_atom;
+use log::{debug, warn};
+use std::ffi::CStr;
+use std::os::raw::c_long;
+use x11::xlib::{self, Display, Window};
+
+/// `_NET_WM_STATE` actions.
+const STATE_ADD: c_long = 1;
+const STATE_TOGGLE: c_long = 2;
+/// The source indication of a pager or taskbar, which window managers honor
+/// more readily than requests from applications.
+const SOURCE_PAGER: c_long = 2;
+
+/// Asks the window manager to do `action` to `window`.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn perform(display: *mut Display, window: Window, action: &WindowAction) {
+    let root = unsafe { xlib::XDefaultRootWindow(display) };
+    if window == root {
+        warn!("No focused window for {:?}", action);
+        return;
+    }
+    debug!("Window action {:?} on window={}", action, window);
+    let atom = |name: &CStr| intern_atom(display, name, false) as c_long;
+    match action {
+        WindowAction::Close => send(
+            display,
+            window,
+            c"_NET_CLOSE_WINDOW",
+            [xlib::CurrentTime as c_long, SOURCE_PAGER, 0, 0, 0],
+        ),
+        WindowAction::Maximize => send(
+            display,
+            window,
+            c"_NET_WM_STATE",
+            [
+                STATE_ADD,
+                atom(c"_NET_WM_STATE_MAXIMIZED_VERT"),
+                atom(c"_NET_WM_STATE_MAXIMIZED_HORZ"),
+                SOURCE_PAGER,
+                0,
+            ],
+        ),
+        WindowAction::FullscreenToggle => send(
+            display,
+            window,
+            c"_NET_WM_STATE",
+            [
+                STATE_TOGGLE,
+                atom(c"_NET_WM_STATE_FULLSCREEN"),
+                0,
+                SOURCE_PAGER,
+                0,
+            ],
+        ),
+        // EWMH leaves minimizing to ICCCM's WM_CHANGE_STATE, which this sends
+        WindowAction::Minimize => unsafe {
+            xlib::XIconifyWindow(display, window, xlib::XDefaultScreen(display));
+        },
+        WindowAction::MoveToDesktop(desktop) => send(
+            display,
+            window,
+            c"_NET_WM_DESKTOP",
+            [*desktop as c_long - 1, SOURCE_PAGER, 0, 0, 0],
+        ),
+    }
+    unsafe {
+        xlib::XFlush(display);
+    }
+}
+
+/// Sends a client message about `window` to the root, where the window
+/// manager listens for it.
+fn send(display: *mut Display, window: Window, message_type: &CStr, data: [c_long; 5]) {
+    unsafe {
+        let mut event: xlib::XClientMessageEvent = std::mem::zeroed();
+        event.type_ = xlib::ClientMessage;
+        event.window = window;
+        event.message_type = intern_atom(display, message_type, false);
+        event.format = 32;
+        for (i, value) in data.into_iter().enumerate() {
+            event.data.set_long(i, value);
+        }
+        xlib::XSendEvent(
+            display,
+            xlib::XDefaultRootWindow(display),
+            xlib::False,
+            xlib::SubstructureRedirectMask | xlib::SubstructureNotifyMask,
+            &mut event as *mut xlib::XClientMessageEvent as *mut xlib::XEvent,
+        );
+    }
+}
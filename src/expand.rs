@@ -0,0 +1,122 @@
+//! Text expansion: watches the characters typed for abbreviations like
+//! `;addr` and replaces them with their text.
+
+use crate::xrecord::{drop_injected, RecordedKey};
+use std::collections::BTreeMap;
+use x11::keysym;
+use x11::xlib::{KeyCode, KeySym};
+
+/// How many typed characters are kept to match abbreviations against.
+const MAX_TYPED: usize = 64;
+
+/// The characters typed since the last key that can't be part of an
+/// abbreviation, like an arrow key or a shortcut.
+#[derive(Debug, Default)]
+pub struct Expander {
+    typed: String,
+    /// Keys we injected that XRecord hasn't reported yet, so an expansion
+    /// isn't read back as typed.
+    injected: Vec<(KeyCode, bool)>,
+}
+
+/// An abbreviation that was just typed out.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Expansion {
+    /// How many characters to erase with BackSpace.
+    pub erase: usize,
+    pub text: String,
+}
+
+impl Expander {
+    /// The recorded `keys` the user typed, less those among the keys we
+    /// `injected` since the last call.
+    pub fn typed_keys(
+        &mut self,
+        keys: Vec<RecordedKey>,
+        injected: &[(KeyCode, bool)],
+    ) -> Vec<RecordedKey> {
+        self.injected.extend(injected);
+        drop_injected(keys, &mut self.injected)
+    }
+
+    /// Adds a typed character and returns the expansion of the longest
+    /// abbreviation it completes.
+    pub fn type_char(
+        &mut self,
+        c: char,
+        expansions: &BTreeMap<String, String>,
+    ) -> Option<Expansion> {
+        self.typed.push(c);
+        if self.typed.chars().count() > MAX_TYPED {
+            self.typed.remove(0);
+        }
+        let (abbreviation, text) = expansions
+            .iter()
+            .filter(|(abbreviation, _)| !abbreviation.is_empty())
+            .filter(|(abbreviation, _)| self.typed.ends_with(abbreviation.as_str()))
+            .max_by_key(|(abbreviation, _)| abbreviation.len())?;
+        self.typed.clear();
+        Some(Expansion {
+            erase: abbreviation.chars().count(),
+            text: text.clone(),
+        })
+    }
+
+    pub fn backspace(&mut self) {
+        self.typed.pop();
+    }
+
+    pub fn reset(&mut self) {
+        self.typed.clear();
+    }
+}
+
+/// The character a keysym types, for printable Latin-1 and Unicode keysyms.
+pub fn keysym_char(keysym: KeySym) -> Option<char> {
+    let code = match keysym {
+        0x20..=0x7e | 0xa0..=0xff => keysym as u32,
+        0x0100_0000..=0x0110_ffff => (keysym - 0x0100_0000) as u32,
+        _ => return None,
+    };
+    char::from_u32(code).filter(|c| !c.is_control())
+}
+
+/// The keysym that types `c`.
+pub fn char_keysym(c: char) -> KeySym {
+    match c {
+        '\n' => keysym::XK_Return as KeySym,
+        '\t' => keysym::XK_Tab as KeySym,
+        ' '..='~' | '\u{a0}'..='\u{ff}' => c as KeySym,
+        _ => 0x0100_0000 + c as KeySym,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_type_char_expands_the_longest_abbreviation() {
+        let expansions = BTreeMap::from([
+            ("dr".to_string(), "Doctor".to_string()),
+            (";addr".to_string(), "1 Main St".to_string()),
+        ]);
+        let mut expander = Expander::default();
+        let typed = |text: &str, expander: &mut Expander| {
+            text.chars()
+                .filter_map(|c| expander.type_char(c, &expansions))
+                .last()
+        };
+
+        assert_eq!(typed("x;adx", &mut expander), None);
+        expander.backspace();
+        let expansion = typed("dr", &mut expander).unwrap();
+        assert_eq!(expansion.erase, 5);
+        assert_eq!(expansion.text, "1 Main St");
+
+        expander.reset();
+        assert_eq!(keysym_char(char_keysym('é')), Some('é'));
+        assert_eq!(keysym_char(char_keysym('→')), Some('→'));
+        assert_eq!(keysym_char(keysym::XK_Return as KeySym), None);
+    }
+}
@@ -0,0 +1,233 @@
+use crate::config::{same_key, Config, KeyAction, MatchContext, RuleId, WindowConfig};
+use std::fmt;
+
+/// What a key does in the focused window of each display, for the
+/// `explain` command.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Explanation {
+    pub displays: Vec<KeyExplanation>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct KeyExplanation {
+    pub display: String,
+    pub key: String,
+    pub window_class: Option<String>,
+    /// The window's class is in `ignore_classes`, so no remap applies.
+    pub ignored: bool,
+    /// The remap that fires.
+    pub fires: Option<Candidate>,
+    /// Another client grabbed the key first, so it doesn't reach the
+    /// remapper even though a remap applies.
+    pub grab_conflict: bool,
+    /// The other remaps of the key, each with why it doesn't fire.
+    pub skipped: Vec<(Candidate, String)>,
+}
+
+/// A remap of the explained key.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Candidate {
+    /// The rule, as [`Config::rule_label`] names it.
+    pub rule: String,
+    /// `path:line` of the remap, when known.
+    pub location: Option<String>,
+    /// What the remap sends or does, in config notation.
+    pub to: String,
+}
+
+/// Works out which remap of `key` fires in `context` and why the others
+/// don't, following the same steps as [`Config::matching_remaps`].
+pub fn explain(config: &Config, context: &MatchContext, key: &str) -> KeyExplanation {
+    let mut explanation = KeyExplanation {
+        key: key.to_string(),
+        window_class: context.window_class.map(str::to_string),
+        ignored: config.is_ignored(context.window_class),
+        ..KeyExplanation::default()
+    };
+    let applied = config.applied_rules(context);
+    let winner = config
+        .matching_remaps(context)
+        .into_iter()
+        .find(|(_, remap)| same_key(&remap.from, key))
+        .map(|(rule, _)| rule);
+    // Within a rule the last remap of a key is the one kept
+    let winner = winner.and_then(|rule| {
+        let index = config
+            .rule_remaps(rule)
+            .iter()
+            .rposition(|remap| same_key(&remap.from, key))?;
+        Some((rule, index))
+    });
+
+    for rule in std::iter::once(RuleId::Global).chain((0..config.windows.len()).map(RuleId::Window))
+    {
+        for (index, remap) in config.rule_remaps(rule).iter().enumerate() {
+            if !same_key(&remap.from, key) {
+                continue;
+            }
+            let candidate = Candidate {
+                rule: config.rule_label(rule),
+                location: config.remap_location(rule, index),
+                to: describe_action(&remap.to),
+            };
+            if winner == Some((rule, index)) {
+                explanation.fires = Some(candidate);
+                continue;
+            }
+            let reason = if explanation.ignored {
+                "the window class is in ignore_classes".to_string()
+            } else {
+                skip_reason(config, context, &applied, rule, winner, &remap.from)
+            };
+            explanation.skipped.push((candidate, reason));
+        }
+    }
+    explanation
+}
+
+/// Why the remap of `from` in `rule` isn't the one that fires, given that
+/// the window isn't ignored.
+fn skip_reason(
+    config: &Config,
+    context: &MatchContext,
+    applied: &[(usize, &WindowConfig)],
+    rule: RuleId,
+    winner: Option<(RuleId, usize)>,
+    from: &str,
+) -> String {
+    match rule {
+        RuleId::Global => {
+            let masked_by = applied
+                .iter()
+                .find(|(_, window)| window.mask.iter().any(|key| same_key(key, from)));
+            if let Some((index, _)) = masked_by {
+                return format!("masked by {}", config.rule_label(RuleId::Window(*index)));
+            }
+        }
+        RuleId::Window(index) => {
+            if let Some(reason) = config.mismatch(&config.windows[index], context) {
+                return reason.to_string();
+            }
+            if !applied.iter().any(|(applied, _)| *applied == index) {
+                return "an earlier rule with `stop` ends the rules before it".to_string();
+            }
+        }
+    }
+    match winner {
+        Some((winner, _)) if winner == rule => {
+            "a later remap of the same key in the rule replaces it".to_string()
+        }
+        Some((winner, _)) => format!("overridden by {}", config.rule_label(winner)),
+        None => "it doesn't apply here".to_string(),
+    }
+}
+
+fn describe_action(action: &KeyAction) -> String {
+    match action {
+        KeyAction::Single(key) => key.clone(),
+        other => serde_json::to_string(other).unwrap_or_default(),
+    }
+}
+
+impl fmt::Display for Candidate {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} in {}", self.to, self.rule)?;
+        if let Some(location) = &self.location {
+            write!(f, " ({})", location)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Explanation {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for explanation in &self.displays {
+            writeln!(
+                f,
+                "Display {}, window class {}",
+                explanation.display,
+                explanation.window_class.as_deref().unwrap_or("(unknown)")
+            )?;
+            match &explanation.fires {
+                Some(candidate) if explanation.grab_conflict => writeln!(
+                    f,
+                    "  '{}' would send {}, but another client grabbed the key first",
+                    explanation.key, candidate
+                )?,
+                Some(candidate) => writeln!(f, "  '{}' sends {}", explanation.key, candidate)?,
+                None if explanation.ignored => writeln!(
+                    f,
+                    "  '{}' isn't remapped: the window class is in ignore_classes",
+                    explanation.key
+                )?,
+                None => writeln!(f, "  '{}' isn't remapped in this window", explanation.key)?,
+            }
+            for (candidate, reason) in &explanation.skipped {
+                writeln!(f, "  not {}: {}", candidate, reason)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const YAML: &str = r#"
+global:
+  - 'C-b': 'Left'
+  - 'C-w': 'Home'
+windows:
+  - name: terminals
+    class_only: ['kitty']
+    mask: ['C-w']
+    remaps:
+      - 'C-b': 'Home'
+  - name: browsers
+    class_only: ['firefox']
+    remaps:
+      - 'Ctrl-b': 'C-Left'
+"#;
+
+    fn context(window_class: &str) -> MatchContext<'_> {
+        MatchContext {
+            window_class: Some(window_class),
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn test_explain_picks_the_winner() {
+        let config = Config::from_yaml(YAML).unwrap();
+        let explanation = explain(&config, &context("firefox"), "C-b");
+
+        let fires = explanation.fires.unwrap();
+        assert_eq!(fires.rule, "rule 'browsers'");
+        assert_eq!(fires.to, "C-Left");
+        assert_eq!(fires.location.as_deref(), Some("line 14"));
+        let reasons: Vec<_> = explanation
+            .skipped
+            .iter()
+            .map(|(candidate, reason)| (candidate.rule.as_str(), reason.as_str()))
+            .collect();
+        assert_eq!(
+            reasons,
+            [
+                ("global", "overridden by rule 'browsers'"),
+                ("rule 'terminals'", "the window class isn't in class_only"),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_explain_masked_and_unmapped() {
+        let config = Config::from_yaml(YAML).unwrap();
+        let explanation = explain(&config, &context("kitty"), "C-w");
+        assert!(explanation.fires.is_none());
+        assert_eq!(explanation.skipped[0].1, "masked by rule 'terminals'");
+
+        let explanation = explain(&config, &context("kitty"), "C-x");
+        assert!(explanation.fires.is_none() && explanation.skipped.is_empty());
+    }
+}
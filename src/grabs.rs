@@ -0,0 +1,267 @@
+use crate::backend::XBackend;
+use crate::key_mapper::KeyPress;
+use log::debug;
+use std::collections::HashMap;
+use x11::xlib::Window;
+
+/// The keys to grab and where.
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct GrabSet {
+    /// Keys grabbed on every root window.
+    pub root: Vec<KeyPress>,
+    /// The focused client window and the keys grabbed on it, for rules
+    /// with `grab_on: client`.
+    pub client: Option<(Window, Vec<KeyPress>)>,
+    /// Keys grabbed in synchronous mode, wherever they're grabbed.
+    pub sync: Vec<KeyPress>,
+}
+
+impl GrabSet {
+    pub fn len(&self) -> usize {
+        self.root.len() + self.client.as_ref().map_or(0, |(_, keys)| keys.len())
+    }
+}
+
+/// The grabs in place, updated from one [`GrabSet`] to the next.
+#[derive(Debug, Default)]
+pub struct Grabs {
+    current: GrabSet,
+    /// The keys each window really has grabbed, and the synchronous ones
+    /// among them. Keys that failed to grab are left out, so the next
+    /// update tries them again.
+    grabbed: HashMap<Window, (Vec<KeyPress>, Vec<KeyPress>)>,
+    /// Keys another client grabbed first, so their remaps can't fire.
+    conflicts: Vec<KeyPress>,
+}
+
+impl Grabs {
+    /// Moves the grabs to `wanted`. Only the grabs that change are touched,
+    /// so keys held across a focus switch aren't dropped in a window between
+    /// ungrab and grab. Returns the keys that newly failed to grab.
+    pub fn update(
+        &mut self,
+        backend: &mut impl XBackend,
+        roots: &[Window],
+        wanted: GrabSet,
+    ) -> Vec<KeyPress> {
+        self.current = wanted;
+        let current = &self.current;
+        let mut windows: Vec<(Window, &[KeyPress])> = roots
+            .iter()
+            .map(|&root| (root, &current.root[..]))
+            .collect();
+        if let Some((client, keys)) = &current.client {
+            windows.push((*client, keys));
+        }
+
+        // A client that lost the focus keeps none of its grabs
+        self.grabbed.retain(|window, (keys, _)| {
+            let kept = windows.iter().any(|(wanted, _)| wanted == window);
+            if !kept {
+                backend.ungrab_keys(*window, keys);
+            }
+            kept
+        });
+        let mut failed = Vec::new();
+        for (window, keys) in windows {
+            let previous = self.grabbed.remove(&window).unwrap_or_default();
+            let (grabbed, newly_failed) = move_grabs(
+                backend,
+                window,
+                (&previous.0, &previous.1),
+                (keys, &current.sync),
+                &mut self.conflicts,
+            );
+            let sync = current
+                .sync
+                .iter()
+                .filter(|key| grabbed.contains(key))
+                .copied()
+                .collect();
+            self.grabbed.insert(window, (grabbed, sync));
+            failed.extend(newly_failed);
+        }
+        let wanted = |key: &KeyPress| {
+            current.root.contains(key)
+                || current
+                    .client
+                    .as_ref()
+                    .is_some_and(|(_, keys)| keys.contains(key))
+        };
+        self.conflicts.retain(wanted);
+        failed
+    }
+
+    pub fn current(&self) -> &GrabSet {
+        &self.current
+    }
+
+    pub fn conflicts(&self) -> &[KeyPress] {
+        &self.conflicts
+    }
+}
+
+/// Changes the grabs on `window` from `previous` to `next`, each given as
+/// the keys and the synchronous ones among them. Returns the keys grabbed
+/// now, and the ones that failed and weren't in `conflicts` already, which
+/// records them.
+fn move_grabs(
+    backend: &mut impl XBackend,
+    window: Window,
+    previous: (&[KeyPress], &[KeyPress]),
+    next: (&[KeyPress], &[KeyPress]),
+    conflicts: &mut Vec<KeyPress>,
+) -> (Vec<KeyPress>, Vec<KeyPress>) {
+    let (mut to_ungrab, mut to_grab) = grab_diff(previous.0, next.0);
+    // Keys that stay grabbed but switch between synchronous and
+    // asynchronous mode are grabbed again
+    for key in next.0 {
+        if previous.0.contains(key) && previous.1.contains(key) != next.1.contains(key) {
+            to_ungrab.push(*key);
+            to_grab.push(*key);
+        }
+    }
+    debug!(
+        "Grabs on window={}: {} new, {} released",
+        window,
+        to_grab.len(),
+        to_ungrab.len()
+    );
+
+    backend.ungrab_keys(window, &to_ungrab);
+    let failed = backend.grab_keys(window, &to_grab, next.1);
+    let grabbed = next
+        .0
+        .iter()
+        .filter(|key| !failed.contains(key))
+        .copied()
+        .collect();
+    // A key that grabs now is no longer taken
+    conflicts.retain(|key| !to_grab.contains(key) || failed.contains(key));
+    let newly_failed: Vec<KeyPress> = failed
+        .into_iter()
+        .filter(|key| !conflicts.contains(key))
+        .collect();
+    conflicts.extend(&newly_failed);
+    (grabbed, newly_failed)
+}
+
+/// The keys to ungrab and to grab to get from the `old` grab set to `new`.
+fn grab_diff(old: &[KeyPress], new: &[KeyPress]) -> (Vec<KeyPress>, Vec<KeyPress>) {
+    let to_ungrab = old.iter().filter(|k| !new.contains(k)).copied().collect();
+    let to_grab = new.iter().filter(|k| !old.contains(k)).copied().collect();
+    (to_ungrab, to_grab)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::MockBackend;
+    use x11::xlib;
+
+    const ROOT: Window = 1;
+
+    fn key(keycode: u8, modifiers: u32) -> KeyPress {
+        KeyPress { keycode, modifiers }
+    }
+
+    #[test]
+    fn test_grab_diff_keeps_shared_keys() {
+        let old = [key(56, xlib::ControlMask), key(41, xlib::ControlMask)];
+        let new = [key(41, xlib::ControlMask), key(33, xlib::Mod1Mask)];
+
+        let (to_ungrab, to_grab) = grab_diff(&old, &new);
+        assert_eq!(to_ungrab, vec![key(56, xlib::ControlMask)]);
+        assert_eq!(to_grab, vec![key(33, xlib::Mod1Mask)]);
+    }
+
+    #[test]
+    fn test_client_grabs_follow_focus() {
+        let (c_b, c_f) = (key(56, xlib::ControlMask), key(41, xlib::ControlMask));
+        let mut backend = MockBackend::default();
+        let mut grabs = Grabs::default();
+        let set = |client: Window| GrabSet {
+            root: vec![c_f],
+            client: Some((client, vec![c_b])),
+            sync: Vec::new(),
+        };
+
+        grabs.update(&mut backend, &[ROOT], set(10));
+        assert_eq!(backend.grabs, vec![(ROOT, c_f, false), (10, c_b, false)]);
+
+        grabs.update(&mut backend, &[ROOT], set(20));
+        assert_eq!(backend.grabs, vec![(ROOT, c_f, false), (20, c_b, false)]);
+
+        grabs.update(&mut backend, &[ROOT], GrabSet::default());
+        assert!(backend.grabs.is_empty());
+    }
+
+    #[test]
+    fn test_unchanged_grabs_are_kept() {
+        let c_b = key(56, xlib::ControlMask);
+        let mut backend = MockBackend::default();
+        let mut grabs = Grabs::default();
+        let set = GrabSet {
+            root: vec![c_b],
+            ..GrabSet::default()
+        };
+
+        grabs.update(&mut backend, &[ROOT], set.clone());
+        let requests = backend.requests;
+        grabs.update(&mut backend, &[ROOT], set.clone());
+        assert_eq!(backend.requests, requests);
+
+        // Becoming conditional regrabs the key synchronously
+        let sync = GrabSet {
+            sync: vec![c_b],
+            ..set
+        };
+        grabs.update(&mut backend, &[ROOT], sync);
+        assert_eq!(backend.grabs, vec![(ROOT, c_b, true)]);
+    }
+
+    #[test]
+    fn test_conflicts_are_reported_once() {
+        let c_b = key(56, xlib::ControlMask);
+        let mut backend = MockBackend {
+            taken: vec![c_b],
+            ..MockBackend::default()
+        };
+        let mut grabs = Grabs::default();
+        let set = GrabSet {
+            root: vec![c_b],
+            ..GrabSet::default()
+        };
+
+        assert_eq!(grabs.update(&mut backend, &[ROOT], set.clone()), vec![c_b]);
+        assert_eq!(grabs.conflicts(), [c_b]);
+        // The key stays wanted; grabbing it again isn't a new conflict
+        assert!(grabs.update(&mut backend, &[ROOT], set).is_empty());
+
+        grabs.update(&mut backend, &[ROOT], GrabSet::default());
+        assert!(grabs.conflicts().is_empty());
+    }
+
+    #[test]
+    fn test_failed_grabs_are_retried() {
+        let c_b = key(56, xlib::ControlMask);
+        let mut backend = MockBackend {
+            taken: vec![c_b],
+            ..MockBackend::default()
+        };
+        let mut grabs = Grabs::default();
+        let set = GrabSet {
+            root: vec![c_b],
+            ..GrabSet::default()
+        };
+
+        grabs.update(&mut backend, &[ROOT], set.clone());
+        assert!(backend.grabs.is_empty());
+
+        // Once the other client lets go, the next update grabs the key
+        backend.taken.clear();
+        assert!(grabs.update(&mut backend, &[ROOT], set).is_empty());
+        assert_eq!(backend.grabs, vec![(ROOT, c_b, false)]);
+        assert!(grabs.conflicts().is_empty());
+    }
+}
@@ -0,0 +1,737 @@
+//! Converters from other remappers' config formats, for the `import`
+//! subcommand. Whatever has no equivalent here is reported as a warning
+//! and left out.
+
+use crate::config::{
+    modifier_index, Config, KeyAction, ModmapEntry, Remap, SequenceStep, Timing, Trigger,
+    WindowConfig,
+};
+use crate::key_mapper::KeyParser;
+use serde_yaml::{Mapping, Value};
+use std::collections::BTreeMap;
+use thiserror::Error;
+use x11::xlib;
+
+#[derive(Debug, Error)]
+pub enum ImportError {
+    #[error("invalid YAML: {0}")]
+    Yaml(#[from] serde_yaml::Error),
+    #[error("{0}")]
+    Format(String),
+}
+
+/// A converted config and what couldn't be carried over.
+#[derive(Debug)]
+pub struct Import {
+    pub config: Config,
+    pub warnings: Vec<String>,
+}
+
+/// Converts a k0kubun/xremap config: `keymap` entries become window rules,
+/// with `application.only`/`not` as `class_only`/`class_not`, and `modmap`
+/// entries for every window become `modmap` changes.
+pub fn xremap(yaml: &str) -> Result<Import, ImportError> {
+    let root: Value = serde_yaml::from_str(yaml)?;
+    let root = root
+        .as_mapping()
+        .ok_or_else(|| ImportError::Format("expected a mapping at the top level".to_string()))?;
+
+    let mut config = Config::from_yaml("{}").expect("empty config is valid");
+    let mut warnings = Vec::new();
+    let parser = KeyParser::new();
+
+    for section in ["modmap", "keymap"] {
+        let Some(entries) = root.get(section) else {
+            continue;
+        };
+        let entries = entries
+            .as_sequence()
+            .ok_or_else(|| ImportError::Format(format!("'{}' must be a list", section)))?;
+        for (index, entry) in entries.iter().enumerate() {
+            let Some(entry) = entry.as_mapping() else {
+                warnings.push(format!("{}[{}]: not a mapping, skipped", section, index));
+                continue;
+            };
+            let label = match entry.get("name").and_then(Value::as_str) {
+                Some(name) => format!("{} '{}'", section, name),
+                None => format!("{}[{}]", section, index),
+            };
+            let modmap = section == "modmap";
+            if modmap && !entry.contains_key("application") {
+                xremap_modmap(entry, &label, &parser, &mut config.modmap, &mut warnings);
+                continue;
+            }
+            if modmap {
+                warnings.push(format!(
+                    "{}: a modmap for some applications becomes remaps, so its keys can't be held as modifiers",
+                    label
+                ));
+            }
+            let rule = xremap_rule(entry, modmap, &label, &parser, &mut warnings);
+            if !rule.remaps.is_empty() {
+                config.windows.push(rule);
+            }
+        }
+    }
+
+    for key in root.keys().filter_map(Value::as_str) {
+        if !["modmap", "keymap"].contains(&key) {
+            warnings.push(format!("'{}': not supported, skipped", key));
+        }
+    }
+    Ok(Import { config, warnings })
+}
+
+fn xremap_rule(
+    entry: &Mapping,
+    modmap: bool,
+    label: &str,
+    parser: &KeyParser,
+    warnings: &mut Vec<String>,
+) -> WindowConfig {
+    let mut rule = WindowConfig {
+        name: entry
+            .get("name")
+            .and_then(Value::as_str)
+            .map(str::to_string),
+        enabled: true,
+        class_only: None,
+        class_not: None,
+        layout_only: None,
+        role_only: None,
+        role_not: None,
+        monitor_only: None,
+        state_not: None,
+        when: None,
+        remaps: Vec::new(),
+        mask: Vec::new(),
+        priority: 0,
+        stop: false,
+        system: false,
+        grab_on: None,
+        expansions: BTreeMap::new(),
+    };
+
+    if let Some(application) = entry.get("application").and_then(Value::as_mapping) {
+        rule.class_only = string_list(application.get("only"));
+        rule.class_not = string_list(application.get("not"));
+    }
+    for key in entry.keys().filter_map(Value::as_str) {
+        if !["name", "application", "remap"].contains(&key) {
+            warnings.push(format!("{}: '{}' not supported, ignored", label, key));
+        }
+    }
+
+    let Some(remap) = entry.get("remap").and_then(Value::as_mapping) else {
+        warnings.push(format!("{}: no 'remap' mapping, skipped", label));
+        return rule;
+    };
+    for (from, to) in remap {
+        let Some(from) = from.as_str() else {
+            continue;
+        };
+        let Some(converted_from) = xremap_key(from, parser) else {
+            warnings.push(format!("{}: unknown key '{}', skipped", label, from));
+            continue;
+        };
+        let action = match to {
+            Value::String(to) => xremap_key(to, parser).map(KeyAction::Single),
+            Value::Sequence(keys) if !modmap => keys
+                .iter()
+                .map(|key| {
+                    key.as_str()
+                        .and_then(|key| xremap_key(key, parser))
+                        .map(SequenceStep::Key)
+                })
+                .collect::<Option<Vec<_>>>()
+                .map(KeyAction::Multiple),
+            _ => None,
+        };
+        match action {
+            Some(to) => rule.remaps.push(Remap {
+                from: converted_from,
+                to,
+                also_send_original: false,
+                conditional: false,
+                on: Trigger::Press,
+                hold: None,
+                send_to: None,
+                timing: Timing::default(),
+            }),
+            None => warnings.push(format!(
+                "{}: '{}' maps to {}, which has no equivalent, skipped",
+                label,
+                from,
+                describe_value(to)
+            )),
+        }
+    }
+    rule
+}
+
+/// Converts an xremap `modmap` entry for every window: each key is rebound
+/// to its target, leaving its modifier and joining the target's, so e.g.
+/// `CapsLock: Ctrl_L` can be held as Control.
+fn xremap_modmap(
+    entry: &Mapping,
+    label: &str,
+    parser: &KeyParser,
+    modmap: &mut Vec<ModmapEntry>,
+    warnings: &mut Vec<String>,
+) {
+    for key in entry.keys().filter_map(Value::as_str) {
+        if !["name", "remap"].contains(&key) {
+            warnings.push(format!("{}: '{}' not supported, ignored", label, key));
+        }
+    }
+    let Some(remap) = entry.get("remap").and_then(Value::as_mapping) else {
+        warnings.push(format!("{}: no 'remap' mapping, skipped", label));
+        return;
+    };
+    let keysym = |key: &str| xremap_key(key, parser).filter(|key| is_keysym(key));
+    for (from, to) in remap {
+        let Some(from) = from.as_str() else {
+            continue;
+        };
+        let Some(converted_from) = keysym(from) else {
+            warnings.push(format!("{}: unknown key '{}', skipped", label, from));
+            continue;
+        };
+        let Some(converted_to) = to.as_str().and_then(keysym) else {
+            warnings.push(format!(
+                "{}: '{}' maps to {}, which has no equivalent, skipped",
+                label,
+                from,
+                describe_value(to)
+            ));
+            continue;
+        };
+        if let Some(modifier) = default_modifier(&converted_from) {
+            modmap.push(ModmapEntry::Remove {
+                remove: modifier.to_string(),
+                keys: vec![converted_from.clone()],
+            });
+        }
+        modmap.push(ModmapEntry::Keysym {
+            keysym: converted_from,
+            keysyms: vec![converted_to.clone()],
+        });
+        if let Some(modifier) = default_modifier(&converted_to) {
+            modmap.push(ModmapEntry::Add {
+                add: modifier.to_string(),
+                keys: vec![converted_to],
+            });
+        }
+    }
+}
+
+/// The modifier the default X mapping puts a modifier key in.
+fn default_modifier(keysym: &str) -> Option<&'static str> {
+    match keysym {
+        "Shift_L" | "Shift_R" => Some("Shift"),
+        "Caps_Lock" => Some("Lock"),
+        "Control_L" | "Control_R" => Some("Control"),
+        "Alt_L" | "Alt_R" | "Meta_L" | "Meta_R" => Some("Mod1"),
+        "Num_Lock" => Some("Mod2"),
+        "Super_L" | "Super_R" | "Hyper_L" | "Hyper_R" => Some("Mod4"),
+        "ISO_Level3_Shift" | "Mode_switch" => Some("Mod5"),
+        _ => None,
+    }
+}
+
+fn string_list(value: Option<&Value>) -> Option<Vec<String>> {
+    match value? {
+        Value::String(s) => Some(vec![s.clone()]),
+        Value::Sequence(items) => Some(
+            items
+                .iter()
+                .filter_map(|item| item.as_str().map(str::to_string))
+                .collect(),
+        ),
+        _ => None,
+    }
+}
+
+fn describe_value(value: &Value) -> String {
+    match value {
+        Value::Mapping(map) => match map.keys().next().and_then(Value::as_str) {
+            Some(key) => format!("'{}: ...'", key),
+            None => "an empty mapping".to_string(),
+        },
+        Value::Null => "null".to_string(),
+        _ => serde_yaml::to_string(value)
+            .map(|s| format!("'{}'", s.trim()))
+            .unwrap_or_default(),
+    }
+}
+
+/// Converts an xremap key like `C-M-left` or `KEY_CAPSLOCK` to this crate's
+/// notation (`C-M-Left`, `Caps_Lock`). Sided modifiers such as `C_L-` lose
+/// their side, which remaps here don't distinguish.
+fn xremap_key(key: &str, parser: &KeyParser) -> Option<String> {
+    let mut parts: Vec<&str> = key.split('-').collect();
+    // A trailing `-` is the minus key
+    if key.ends_with('-') && parts.len() > 1 {
+        parts.pop();
+        *parts.last_mut()? = "minus";
+    }
+    let (name, modifiers) = parts.split_last()?;
+
+    let mut converted = String::new();
+    for modifier in modifiers {
+        let base = modifier
+            .strip_suffix("_L")
+            .or_else(|| modifier.strip_suffix("_R"))
+            .unwrap_or(modifier);
+        let prefix = match base.to_lowercase().as_str() {
+            "c" | "ctrl" | "control" => "C",
+            "m" | "alt" => "M",
+            "s" | "shift" => "S",
+            "super" | "win" | "windows" => "Super",
+            _ => return None,
+        };
+        converted.push_str(prefix);
+        converted.push('-');
+    }
+    converted.push_str(&xremap_key_name(name));
+    parser.parse(&converted).ok().map(|_| converted)
+}
+
+fn xremap_key_name(name: &str) -> String {
+    let lower = name.to_lowercase();
+    let lower = lower.strip_prefix("key_").unwrap_or(&lower);
+    let converted = match lower {
+        "esc" | "escape" => "Escape",
+        "enter" | "return" => "Return",
+        "backspace" => "BackSpace",
+        "tab" => "Tab",
+        "space" => "space",
+        "delete" => "Delete",
+        "insert" => "Insert",
+        "home" => "Home",
+        "end" => "End",
+        "pageup" => "Prior",
+        "pagedown" => "Next",
+        "left" => "Left",
+        "right" => "Right",
+        "up" => "Up",
+        "down" => "Down",
+        "capslock" => "Caps_Lock",
+        "numlock" => "Num_Lock",
+        "scrolllock" => "Scroll_Lock",
+        "minus" => "minus",
+        "equal" => "equal",
+        "leftbrace" => "bracketleft",
+        "rightbrace" => "bracketright",
+        "semicolon" => "semicolon",
+        "apostrophe" => "apostrophe",
+        "grave" => "grave",
+        "backslash" => "backslash",
+        "comma" => "comma",
+        "dot" => "period",
+        "slash" => "slash",
+        "leftctrl" | "control_l" | "ctrl_l" => "Control_L",
+        "rightctrl" | "control_r" | "ctrl_r" => "Control_R",
+        "leftshift" | "shift_l" => "Shift_L",
+        "rightshift" | "shift_r" => "Shift_R",
+        "leftalt" | "alt_l" => "Alt_L",
+        "rightalt" | "alt_r" => "Alt_R",
+        "leftmeta" | "super_l" | "win_l" => "Super_L",
+        "rightmeta" | "super_r" | "win_r" => "Super_R",
+        "henkan" => "Henkan",
+        "muhenkan" => "Muhenkan",
+        "katakanahiragana" => "Hiragana_Katakana",
+        _ if lower.len() > 1 && lower.starts_with('f') && lower[1..].parse::<u8>().is_ok() => {
+            return lower.to_uppercase();
+        }
+        _ if name.chars().count() == 1 => return lower.to_string(),
+        _ => return name.to_string(),
+    };
+    converted.to_string()
+}
+
+/// Converts an `~/.Xmodmap` file: its `keycode`, `keysym`, `clear`, `add`
+/// and `remove` lines become `modmap` entries.
+pub fn xmodmap(text: &str) -> Result<Import, ImportError> {
+    let mut config = Config::from_yaml("{}").expect("empty config is valid");
+    let mut warnings = Vec::new();
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('!') {
+            continue;
+        }
+        match xmodmap_line(line) {
+            Ok(entry) => config.modmap.push(entry),
+            Err(reason) => warnings.push(format!("line {}: {}, skipped", index + 1, reason)),
+        }
+    }
+    Ok(Import { config, warnings })
+}
+
+fn xmodmap_line(line: &str) -> Result<ModmapEntry, String> {
+    let (command, rest) = line.split_once(char::is_whitespace).unwrap_or((line, ""));
+    let (target, values) = match rest.split_once('=') {
+        Some((target, values)) => (target.trim(), Some(values)),
+        None => (rest.trim(), None),
+    };
+    let keysyms = |values: Option<&str>| -> Result<Vec<String>, String> {
+        let values = values.ok_or_else(|| format!("'{}' needs '= ...'", command))?;
+        let keysyms: Vec<String> = values.split_whitespace().map(str::to_string).collect();
+        match keysyms.iter().find(|keysym| !is_keysym(keysym)) {
+            Some(unknown) => Err(format!("unknown keysym '{}'", unknown)),
+            None => Ok(keysyms),
+        }
+    };
+    let modifier = |name: &str| match modifier_index(name) {
+        Some(_) => Ok(name.to_string()),
+        None => Err(format!("unknown modifier '{}'", name)),
+    };
+
+    match command {
+        "keycode" => {
+            let keycode = match target {
+                "any" => return Err("'keycode any' is not supported".to_string()),
+                _ if target.starts_with("0x") => u8::from_str_radix(&target[2..], 16).ok(),
+                _ => target.parse().ok(),
+            }
+            .ok_or_else(|| format!("invalid keycode '{}'", target))?;
+            Ok(ModmapEntry::Keycode {
+                keycode,
+                keysyms: keysyms(values)?,
+            })
+        }
+        "keysym" if is_keysym(target) => Ok(ModmapEntry::Keysym {
+            keysym: target.to_string(),
+            keysyms: keysyms(values)?,
+        }),
+        "keysym" => Err(format!("unknown keysym '{}'", target)),
+        "clear" => Ok(ModmapEntry::Clear {
+            clear: modifier(target)?,
+        }),
+        "add" => Ok(ModmapEntry::Add {
+            add: modifier(target)?,
+            keys: keysyms(values)?,
+        }),
+        "remove" => Ok(ModmapEntry::Remove {
+            remove: modifier(target)?,
+            keys: keysyms(values)?,
+        }),
+        _ => Err(format!("'{}' not supported", command)),
+    }
+}
+
+/// Converts an `~/.xbindkeysrc`: each quoted command followed by its key
+/// line becomes a global `{exec: ...}` remap.
+pub fn xbindkeys(text: &str) -> Result<Import, ImportError> {
+    let mut config = Config::from_yaml("{}").expect("empty config is valid");
+    let mut warnings = Vec::new();
+    let parser = KeyParser::new();
+    let mut command: Option<(usize, String)> = None;
+
+    for (index, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(quoted) = line.strip_prefix('"') {
+            if let Some((line, _)) = &command {
+                warnings.push(format!("line {}: command without keys, skipped", line));
+            }
+            let text = quoted.strip_suffix('"').unwrap_or(quoted);
+            command = Some((index + 1, text.replace("\\\"", "\"")));
+            continue;
+        }
+        let Some((_, to)) = command.take() else {
+            warnings.push(format!(
+                "line {}: '{}' not supported, skipped",
+                index + 1,
+                line
+            ));
+            continue;
+        };
+        match xbindkeys_key(line, &parser) {
+            Ok(from) => config.global.push(Remap {
+                from,
+                to: KeyAction::Exec(to),
+                also_send_original: false,
+                conditional: false,
+                on: Trigger::Press,
+                hold: None,
+                send_to: None,
+                timing: Timing::default(),
+            }),
+            Err(reason) => warnings.push(format!("line {}: {}, skipped", index + 1, reason)),
+        }
+    }
+    if let Some((line, _)) = command {
+        warnings.push(format!("line {}: command without keys, skipped", line));
+    }
+    Ok(Import { config, warnings })
+}
+
+/// Converts an xbindkeys key line like `control+alt + t` or `m:0x40 + Return`.
+fn xbindkeys_key(line: &str, parser: &KeyParser) -> Result<String, String> {
+    let parts: Vec<&str> = line.split('+').map(str::trim).collect();
+    let (name, modifiers) = parts.split_last().ok_or("empty key")?;
+
+    let mut converted = String::new();
+    for modifier in modifiers {
+        let prefixes: &[&str] = match modifier.to_lowercase().as_str() {
+            "control" | "ctrl" => &["C"],
+            "shift" => &["S"],
+            "alt" | "mod1" => &["M"],
+            "mod4" | "super" => &["Super"],
+            "mod2" | "numlock" | "lock" | "capslock" => &[],
+            "release" => return Err("release bindings are not supported".to_string()),
+            mask if mask.starts_with("m:") => {
+                let mask = u32::from_str_radix(mask[2..].trim_start_matches("0x"), 16)
+                    .map_err(|_| format!("invalid modifier mask '{}'", modifier))?;
+                let mut prefixes = Vec::new();
+                for (bit, prefix) in [
+                    (xlib::ControlMask, "C"),
+                    (xlib::Mod1Mask, "M"),
+                    (xlib::ShiftMask, "S"),
+                    (xlib::Mod4Mask, "Super"),
+                ] {
+                    if mask & bit != 0 {
+                        prefixes.push(prefix);
+                    }
+                }
+                for prefix in prefixes {
+                    converted.push_str(prefix);
+                    converted.push('-');
+                }
+                continue;
+            }
+            _ => return Err(format!("unknown modifier '{}'", modifier)),
+        };
+        for prefix in prefixes {
+            converted.push_str(prefix);
+            converted.push('-');
+        }
+    }
+    if name.starts_with("c:") || name.starts_with("b:") {
+        return Err(format!(
+            "'{}': keycodes and mouse buttons are not supported",
+            name
+        ));
+    }
+    converted.push_str(name);
+    match parser.parse(&converted) {
+        Ok(_) => Ok(converted),
+        Err(_) => Err(format!("unknown key '{}'", name)),
+    }
+}
+
+fn is_keysym(name: &str) -> bool {
+    name == "NoSymbol"
+        || KeyParser::new()
+            .parse(name)
+            .is_ok_and(|(_, mods)| mods == 0)
+}
+
+/// `config` as YAML, without the top-level settings it leaves at their
+/// defaults, so the output can be appended to an existing config.
+pub fn to_yaml(config: &Config) -> Result<String, serde_yaml::Error> {
+    let defaults = Config::from_yaml("{}").expect("empty config is valid");
+    let Value::Mapping(defaults) = serde_yaml::to_value(&defaults)? else {
+        unreachable!("a config serializes to a mapping");
+    };
+    let Value::Mapping(mut config) = serde_yaml::to_value(config)? else {
+        unreachable!("a config serializes to a mapping");
+    };
+    config.retain(|key, value| defaults.get(key) != Some(value));
+    serde_yaml::to_string(&config)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_import_xremap() {
+        let yaml = r#"
+modmap:
+  - name: Global
+    remap:
+      CapsLock: Ctrl_L
+  - name: Terminal
+    application:
+      only: [Alacritty]
+    remap:
+      Alt_R: Esc
+keymap:
+  - name: Emacs
+    application:
+      only: [Google-chrome, Slack]
+    remap:
+      C-b: left
+      Ctrl_L-M-f: KEY_RIGHT
+      C-k: [Shift-end, C-x]
+      C-x:
+        C-s: C-s
+      Super-e: { launch: ["nautilus"] }
+"#;
+        let import = xremap(yaml).unwrap();
+        assert_eq!(import.warnings.len(), 3, "{:?}", import.warnings);
+        assert!(
+            import.warnings[0].contains("can't be held as modifiers"),
+            "{:?}",
+            import.warnings
+        );
+
+        let yaml = to_yaml(&import.config).unwrap();
+        let config = Config::from_yaml(&yaml).unwrap();
+        // Caps Lock leaves Lock and holds Control
+        let keys = |key: &str| vec![key.to_string()];
+        assert_eq!(
+            config.modmap,
+            [
+                ModmapEntry::Remove {
+                    remove: "Lock".to_string(),
+                    keys: keys("Caps_Lock"),
+                },
+                ModmapEntry::Keysym {
+                    keysym: "Caps_Lock".to_string(),
+                    keysyms: keys("Control_L"),
+                },
+                ModmapEntry::Add {
+                    add: "Control".to_string(),
+                    keys: keys("Control_L"),
+                },
+            ]
+        );
+        assert_eq!(config.windows.len(), 2);
+        assert_eq!(config.windows[0].remaps[0].from, "Alt_R");
+        let emacs = &config.windows[1];
+        assert_eq!(emacs.name.as_deref(), Some("Emacs"));
+        assert_eq!(
+            emacs.class_only.as_deref(),
+            Some(&["Google-chrome".to_string(), "Slack".to_string()][..])
+        );
+        let froms: Vec<_> = emacs.remaps.iter().map(|r| r.from.as_str()).collect();
+        assert_eq!(froms, ["C-b", "C-M-f", "C-k"]);
+        assert!(matches!(
+            &emacs.remaps[2].to,
+            KeyAction::Multiple(steps) if steps == &[
+                SequenceStep::Key("S-End".to_string()),
+                SequenceStep::Key("C-x".to_string()),
+            ]
+        ));
+    }
+
+    #[test]
+    fn test_imported_config_round_trips() {
+        let yaml = r#"
+keymap:
+  - name: Emacs
+    application:
+      only: [Emacs]
+    remap:
+      C-b: left
+"#;
+        let mut config = xremap(yaml).unwrap().config;
+        let options = r#"
+global:
+  - 'F5': {exec: 'notify-send hi'}
+    on: release
+    also_send_original: true
+    send_to: {class: 'mpv'}
+  - 'Escape': 'Escape'
+    hold: {exec: 'loginctl lock-session'}
+    hold_timeout_ms: 2000
+  - 'C-k': ['S-End', 'C-x']
+    key_delay_ms: 20
+"#;
+        config.global = Config::from_yaml(options).unwrap().global;
+        config.windows[0].mask = vec!["C-a".to_string()];
+        config.windows[0].priority = 5;
+        config.windows[0].stop = true;
+
+        let dumped = to_yaml(&config).unwrap();
+        assert!(dumped.starts_with("global:\n"), "{}", dumped);
+        let reloaded = Config::from_yaml(&dumped).unwrap();
+        assert_eq!(to_yaml(&reloaded).unwrap(), dumped);
+        let [f5, escape, kill] = &reloaded.global[..] else {
+            panic!("{}", dumped);
+        };
+        assert_eq!(f5.on, Trigger::Release);
+        assert!(f5.also_send_original);
+        assert!(f5.send_to.is_some());
+        assert!(escape.hold.is_some());
+        assert_eq!(escape.timing.hold_timeout_ms, Some(2000));
+        assert_eq!(kill.timing.key_delay_ms, Some(20));
+        let emacs = &reloaded.windows[0];
+        assert_eq!(emacs.mask, ["C-a"]);
+        assert_eq!((emacs.priority, emacs.stop), (5, true));
+    }
+
+    #[test]
+    fn test_import_xmodmap() {
+        let text = "\
+! Caps Lock as Control
+clear Lock
+keycode 66 = Control_L
+keysym Escape = Escape asciitilde
+add Control = Control_L
+remove Mod4 = Super_R
+pointer = 3 2 1
+keycode 0x42 = Bogus_Key
+";
+        let import = xmodmap(text).unwrap();
+        assert_eq!(import.warnings.len(), 2, "{:?}", import.warnings);
+        assert!(import.warnings[0].starts_with("line 7:"));
+
+        let config = Config::from_yaml(&to_yaml(&import.config).unwrap()).unwrap();
+        assert_eq!(config.modmap, import.config.modmap);
+        assert_eq!(
+            config.modmap[1],
+            ModmapEntry::Keycode {
+                keycode: 66,
+                keysyms: vec!["Control_L".to_string()],
+            }
+        );
+    }
+
+    #[test]
+    fn test_import_xbindkeys() {
+        let text = r#"
+# Volume
+"pactl set-sink-volume @DEFAULT_SINK@ +5%"
+    XF86AudioRaiseVolume
+
+"xterm -title \"main\""
+    control+alt + t
+
+"rofi -show run"
+    m:0x40 + Return
+
+"xdotool click 1"
+    b:2
+"#;
+        let import = xbindkeys(text).unwrap();
+        assert_eq!(import.warnings.len(), 1, "{:?}", import.warnings);
+
+        let config = Config::from_yaml(&to_yaml(&import.config).unwrap()).unwrap();
+        let remaps: Vec<_> = config
+            .global
+            .iter()
+            .map(|remap| match &remap.to {
+                KeyAction::Exec(command) => (remap.from.as_str(), command.as_str()),
+                other => panic!("unexpected action {:?}", other),
+            })
+            .collect();
+        assert_eq!(
+            remaps,
+            [
+                (
+                    "XF86AudioRaiseVolume",
+                    "pactl set-sink-volume @DEFAULT_SINK@ +5%"
+                ),
+                ("C-M-t", r#"xterm -title "main""#),
+                ("Super-Return", "rofi -show run"),
+            ]
+        );
+    }
+}
@@ -0,0 +1,244 @@
+//! The `init` wizard: watches which windows get focus and which keys are
+//! pressed in them, then writes a starter config with a rule per window.
+
+use crate::display::DisplayHandle;
+use crate::key_mapper::{is_modifier_keysym, KeyMapper, KeyPress};
+use crate::window_manager::WindowManager;
+use crate::xrecord::Recorder;
+use std::ffi::CString;
+use std::fmt::Write;
+use thiserror::Error;
+use x11::xlib;
+
+#[derive(Debug, Error)]
+pub enum InitError {
+    #[error("failed to open X display '{0}'")]
+    Display(String),
+    #[error("the RECORD extension is unavailable, so keys can't be watched")]
+    Record,
+}
+
+/// A window class and the keys pressed while it had focus, in first-seen order.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct SeenWindow {
+    pub class: String,
+    pub keys: Vec<String>,
+}
+
+/// A key pressed in a window, in config notation.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Press {
+    pub class: String,
+    pub key: String,
+    /// Types a character rather than being a shortcut: a printable key
+    /// with at most Shift or AltGr held.
+    pub typing: bool,
+    /// Control, Alt or Super was held.
+    pub shortcut: bool,
+}
+
+/// Watches focus and keys without grabbing anything. The class focused when
+/// it starts, normally the terminal running `init`, is left out.
+pub struct Watcher {
+    window_manager: WindowManager,
+    key_mapper: KeyMapper,
+    recorder: Recorder,
+    own_class: Option<String>,
+    current_class: Option<String>,
+    windows: Vec<SeenWindow>,
+    // Dropped last: everything above borrows its connection
+    handle: DisplayHandle,
+}
+
+impl Watcher {
+    pub fn open(display_name: &str) -> Result<Self, InitError> {
+        let handle = CString::new(display_name)
+            .ok()
+            .and_then(|name| DisplayHandle::open(&name))
+            .ok_or_else(|| InitError::Display(display_name.to_string()))?;
+        let display = handle.as_ptr();
+        let recorder = Recorder::open(display).ok_or(InitError::Record)?;
+        let mut watcher = Self {
+            window_manager: WindowManager::new(display),
+            key_mapper: KeyMapper::new(display),
+            recorder,
+            own_class: None,
+            current_class: None,
+            windows: Vec::new(),
+            handle,
+        };
+        watcher.own_class = watcher.focused_class();
+        Ok(watcher)
+    }
+
+    fn focused_class(&mut self) -> Option<String> {
+        let window = self.window_manager.get_active_window()?;
+        self.window_manager.get_window_class(window)
+    }
+
+    /// Records focus moves and the shortcuts pressed since the last call.
+    /// Returns what's new, as `class` or `class: key` lines to show the user.
+    /// Keys pressed without Control, Alt or Super aren't recorded, so the
+    /// starter config doesn't grab typing, Return or the arrow keys.
+    pub fn poll(&mut self) -> Vec<String> {
+        let mut news = Vec::new();
+        let previous_class = self.current_class.clone();
+        for press in self.take_presses() {
+            if !press.shortcut {
+                continue;
+            }
+            let window = self.seen(&press.class);
+            if !window.keys.contains(&press.key) {
+                window.keys.push(press.key.clone());
+                news.push(format!("{}: {}", press.class, press.key));
+            }
+        }
+
+        if self.current_class != previous_class {
+            if let Some(class) = self.current_class.clone() {
+                if !self.windows.iter().any(|window| window.class == class) {
+                    news.push(class.clone());
+                }
+                self.seen(&class);
+            }
+        }
+        news
+    }
+
+    /// The keys pressed since the last call, with the class focused at the
+    /// time, leaving out modifier keys and the class focused at the start.
+    /// Then follows focus to the window the next keys go to.
+    pub fn take_presses(&mut self) -> Vec<Press> {
+        let mut presses = Vec::new();
+        // Keys arrived before the focus check, so they belong to the old window
+        for key in self.recorder.take_keys() {
+            if !key.pressed {
+                continue;
+            }
+            let Some(class) = self.current_class.clone() else {
+                continue;
+            };
+            let keysym =
+                unsafe { xlib::XkbKeycodeToKeysym(self.handle.as_ptr(), key.keycode, 0, 0) };
+            if is_modifier_keysym(keysym) {
+                continue;
+            }
+            let modifiers = key.state
+                & (xlib::ControlMask
+                    | xlib::ShiftMask
+                    | xlib::Mod1Mask
+                    | xlib::Mod4Mask
+                    | xlib::Mod5Mask)
+                & !self.key_mapper.lock_mask();
+            let shortcut_modifiers = xlib::ControlMask | xlib::Mod1Mask | xlib::Mod4Mask;
+            let shortcut = modifiers & shortcut_modifiers != 0;
+            presses.push(Press {
+                class,
+                key: self.key_mapper.describe(KeyPress {
+                    keycode: key.keycode,
+                    modifiers,
+                }),
+                typing: !shortcut && (0x20..=0xff).contains(&keysym),
+                shortcut,
+            });
+        }
+
+        let class = self
+            .focused_class()
+            .filter(|class| Some(class) != self.own_class.as_ref());
+        self.current_class = class;
+        presses
+    }
+
+    fn seen(&mut self, class: &str) -> &mut SeenWindow {
+        let index = match self.windows.iter().position(|window| window.class == class) {
+            Some(index) => index,
+            None => {
+                self.windows.push(SeenWindow {
+                    class: class.to_string(),
+                    keys: Vec::new(),
+                });
+                self.windows.len() - 1
+            }
+        };
+        &mut self.windows[index]
+    }
+
+    pub fn windows(&self) -> &[SeenWindow] {
+        &self.windows
+    }
+}
+
+/// A config with one rule per window. The shortcuts pressed in it are
+/// listed commented out, each remapped to itself, so nothing is grabbed
+/// until they're uncommented and their targets filled in.
+pub fn starter_config(windows: &[SeenWindow]) -> String {
+    let mut out = String::new();
+    out.push_str("# Starter config written by `simple-x11-remapper init`.\n");
+    out.push_str(
+        "# Uncomment the remaps you want and replace their targets, e.g. 'C-b': 'Left'.\n",
+    );
+    if windows.is_empty() {
+        out.push_str("windows: []\n");
+        return out;
+    }
+    out.push_str("windows:\n");
+    for window in windows {
+        let _ = writeln!(out, "  - name: {}", quote(&window.class));
+        let _ = writeln!(out, "    class_only: [{}]", quote(&window.class));
+        if window.keys.is_empty() {
+            out.push_str("    # Add remaps like - 'C-a': 'Home'\n");
+            out.push_str("    remaps: []\n");
+            continue;
+        }
+        out.push_str("    # remaps:\n");
+        for key in &window.keys {
+            let _ = writeln!(out, "    #   - {}: {}", quote(key), quote(key));
+        }
+    }
+    out
+}
+
+fn quote(s: &str) -> String {
+    format!("'{}'", s.replace('\'', "''"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::config::Config;
+
+    #[test]
+    fn test_starter_config() {
+        let windows = [
+            SeenWindow {
+                class: "firefox".to_string(),
+                keys: vec!["C-b".to_string(), "M-'".to_string()],
+            },
+            SeenWindow {
+                class: "Slack".to_string(),
+                keys: Vec::new(),
+            },
+        ];
+        let starter = starter_config(&windows);
+        let config = Config::from_yaml(&starter).unwrap();
+        assert_eq!(config.windows.len(), 2);
+        assert_eq!(config.windows[0].name.as_deref(), Some("firefox"));
+        // Nothing is grabbed or linted until the remaps are uncommented
+        assert!(config.windows[0].remaps.is_empty());
+        assert!(config.lint().is_empty(), "{:?}", config.lint());
+
+        let uncommented = starter.replace("    # remaps:", "    remaps:");
+        let uncommented = uncommented.replace("    #   - ", "      - ");
+        let config = Config::from_yaml(&uncommented).unwrap();
+        let froms: Vec<_> = config.windows[0]
+            .remaps
+            .iter()
+            .map(|remap| remap.from.as_str())
+            .collect();
+        assert_eq!(froms, ["C-b", "M-'"]);
+        assert!(config.windows[1].remaps.is_empty());
+
+        assert!(Config::from_yaml(&starter_config(&[])).is_ok());
+    }
+}
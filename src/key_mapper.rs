@@ -1,7 +1,18 @@
+use crate::config::{MouseClick, SequenceStep};
+use crate::display::DisplayHandle;
+use crate::xkb::XKB_USE_CORE_KBD;
+use crate::xtest::XTest;
 use log::{debug, warn};
+use std::cell::{Cell, RefCell};
 use std::collections::HashMap;
+use std::ffi::{CStr, CString};
+use std::os::raw::{c_char, c_int};
+use std::rc::Rc;
+use std::thread;
+use std::time::Duration;
+use thiserror::Error;
 use x11::keysym;
-use x11::xlib::{self, Display, KeyCode, KeySym, XKeyEvent};
+use x11::xlib::{self, Display, KeyCode, KeySym, Time, XKeyEvent};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
 pub struct KeyPress {
@@ -9,15 +20,34 @@ pub struct KeyPress {
     pub modifiers: u32,
 }
 
+#[derive(Debug, Clone, PartialEq, Eq, Error)]
+pub enum KeyParseError {
+    #[error("unknown modifier '{0}'")]
+    UnknownModifier(String),
+    #[error("unknown key '{0}'")]
+    UnknownKey(String),
+    #[error("'{0}' is not a modifier key")]
+    NotModifier(String),
+    #[error("no key in '{0}'")]
+    MissingKey(String),
+}
+
+/// Parses key expressions like `C-M-a` into a keysym and modifier mask.
+/// Doesn't need an X connection, so config loading can validate expressions.
 #[derive(Debug, Clone)]
-pub struct KeyMapper {
-    display: *mut Display,
+pub struct KeyParser {
     keysym_map: HashMap<String, KeySym>,
     modifier_map: HashMap<String, u32>,
 }
 
-impl KeyMapper {
-    pub fn new(display: *mut Display) -> Self {
+impl Default for KeyParser {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl KeyParser {
+    pub fn new() -> Self {
         let mut keysym_map = HashMap::new();
         let mut modifier_map = HashMap::new();
 
@@ -62,53 +92,517 @@ impl KeyMapper {
         modifier_map.insert("Shift".to_string(), xlib::ShiftMask);
         modifier_map.insert("S".to_string(), xlib::ShiftMask);
         modifier_map.insert("Super".to_string(), xlib::Mod4Mask);
+        // Where AltGr and Hyper live depends on the keymap; KeyMapper replaces
+        // these usual defaults with what the server reports
+        modifier_map.insert("AltGr".to_string(), xlib::Mod5Mask);
+        modifier_map.insert("G".to_string(), xlib::Mod5Mask);
+        modifier_map.insert("Hyper".to_string(), xlib::Mod3Mask);
+        modifier_map.insert("H".to_string(), xlib::Mod3Mask);
+        modifier_map.insert("Mod3".to_string(), xlib::Mod3Mask);
+        modifier_map.insert("Mod5".to_string(), xlib::Mod5Mask);
 
         Self {
-            display,
             keysym_map,
             modifier_map,
         }
     }
 
-    pub fn parse_key(&self, key_expr: &str) -> Option<(KeySym, u32)> {
-        debug!("Parsing key expression: '{}'", key_expr);
-        let parts: Vec<&str> = key_expr.split('-').collect();
+    /// The bit of a modifier like `Shift` or `AltGr`, as names in key
+    /// expressions use it.
+    pub fn modifier_mask(&self, name: &str) -> Option<u32> {
+        self.modifier_map.get(name).copied()
+    }
+
+    /// Points the modifier `names` at `mask`.
+    pub fn set_modifier_mask(&mut self, names: &[&str], mask: u32) {
+        for name in names {
+            self.modifier_map.insert(name.to_string(), mask);
+        }
+    }
+
+    /// Splits a chord expression like `j+k` into its two keys. A lone `+` or
+    /// an expression ending in `-+` is the plus key, not a chord.
+    pub fn split_chord(key_expr: &str) -> Option<(&str, &str)> {
+        key_expr
+            .split_once('+')
+            .filter(|(first, second)| !first.is_empty() && !second.is_empty())
+    }
+
+    pub fn parse(&self, key_expr: &str) -> Result<(KeySym, u32), KeyParseError> {
+        // The minus key itself is a `-` alone or after the last separator
+        let (modifier_part, key_part) = match key_expr.strip_suffix("--") {
+            Some(modifier_part) => (Some(modifier_part), "-"),
+            None if key_expr == "-" => (None, "-"),
+            None => match key_expr.rsplit_once('-') {
+                Some((modifier_part, key_part)) => (Some(modifier_part), key_part),
+                None => (None, key_expr),
+            },
+        };
+        if key_part.is_empty() {
+            return Err(KeyParseError::MissingKey(key_expr.to_string()));
+        }
+
         let mut modifiers = 0u32;
-        let mut key_part = "";
-
-        for (i, part) in parts.iter().enumerate() {
-            if i == parts.len() - 1 {
-                key_part = part;
-            } else if let Some(mod_mask) = self.modifier_map.get(*part) {
-                modifiers |= mod_mask;
-                debug!("Found modifier '{}' -> {:#x}", part, mod_mask);
-            } else {
-                warn!("Unknown modifier: '{}'", part);
+        for part in modifier_part.into_iter().flat_map(|m| m.split('-')) {
+            match self.modifier_map.get(part) {
+                Some(mod_mask) => modifiers |= mod_mask,
+                None => return Err(KeyParseError::UnknownModifier(part.to_string())),
             }
         }
 
-        let keysym = if key_part.len() == 1 {
-            let ch = key_part.chars().next().unwrap();
-            ch as KeySym
+        let mut chars = key_part.chars();
+        let keysym = if let (Some(ch), None) = (chars.next(), chars.next()) {
+            keysym_from_char(ch)
         } else {
             match self.keysym_map.get(key_part) {
                 Some(sym) => *sym,
-                None => {
-                    warn!("Unknown key: '{}'", key_part);
-                    return None;
-                }
+                None => match keysym_from_name(key_part) {
+                    Some(sym) => sym,
+                    None => return Err(KeyParseError::UnknownKey(key_part.to_string())),
+                },
             }
         };
 
-        debug!(
-            "Parsed '{}' -> keysym={:#x}, modifiers={:#x}",
-            key_expr, keysym, modifiers
-        );
-        Some((keysym, modifiers))
+        Ok((keysym, modifiers))
+    }
+
+    /// The keysym of a modifier key named in a `{down: ...}` or `{up: ...}`
+    /// step: a modifier like `Shift` or `C`, meaning its left key, or a
+    /// modifier keysym like `Control_R`.
+    pub fn parse_modifier_key(&self, name: &str) -> Result<KeySym, KeyParseError> {
+        let keysym_name = match name {
+            "Shift" | "S" => "Shift_L",
+            "Ctrl" | "Control" | "C" => "Control_L",
+            "Alt" | "M" => "Alt_L",
+            "Super" => "Super_L",
+            "Hyper" | "H" => "Hyper_L",
+            "AltGr" | "G" => "ISO_Level3_Shift",
+            _ => name,
+        };
+        match keysym_from_name(keysym_name) {
+            Some(keysym) if is_modifier_keysym(keysym) => Ok(keysym),
+            _ => Err(KeyParseError::NotModifier(name.to_string())),
+        }
+    }
+}
+
+/// Looks a name up in Xlib's keysym table, which covers all of keysymdef.h
+/// (`Prior`, `KP_Enter`, `XF86AudioPlay`, ...).
+fn keysym_from_name(name: &str) -> Option<KeySym> {
+    let name = CString::new(name).ok()?;
+    let keysym = unsafe { xlib::XStringToKeysym(name.as_ptr()) };
+    (keysym != xlib::NoSymbol as KeySym).then_some(keysym)
+}
+
+/// The keycode of the key producing `keysym` on a US QWERTY keyboard, for
+/// the keys whose place differs between layouts. Keycodes are the evdev
+/// ones every current X server uses.
+pub fn qwerty_keycode(keysym: KeySym) -> Option<KeyCode> {
+    const ROWS: [(&str, KeyCode); 4] = [
+        ("1234567890-=", 10),
+        ("qwertyuiop[]", 24),
+        ("asdfghjkl;'`", 38),
+        ("zxcvbnm,./", 52),
+    ];
+    let ch = char::from_u32(u32::try_from(keysym).ok()?)?.to_ascii_lowercase();
+    if ch == '\\' {
+        return Some(51);
+    }
+    ROWS.iter().find_map(|(row, first)| {
+        let index = row.chars().position(|key| key == ch)?;
+        Some(first + index as KeyCode)
+    })
+}
+
+/// Latin-1 characters share their keysym value with the code point; everything
+/// else uses the Unicode keysym range.
+fn keysym_from_char(ch: char) -> KeySym {
+    let code = ch as KeySym;
+    if code < 0x100 {
+        code
+    } else {
+        0x0100_0000 | code
     }
+}
+
+/// Keycodes that had no symbols and were rebound to inject keysyms missing from
+/// the keyboard layout, e.g. `€` on a US layout.
+#[derive(Debug, Default)]
+struct ScratchKeycodes {
+    bound: Vec<(KeyCode, KeySym)>,
+}
+
+/// Keypad keysyms, the range Xlib's `IsKeypadKey` checks.
+fn is_keypad_keysym(keysym: KeySym) -> bool {
+    (keysym::XK_KP_Space as KeySym..=keysym::XK_KP_Equal as KeySym).contains(&keysym)
+}
 
+/// Modifier keysyms that can be held down as a target: Shift, Control, Alt,
+/// Meta, Super and Hyper on either side, plus AltGr. The lock keys in the
+/// same range toggle instead and are left out.
+pub fn is_modifier_keysym(keysym: KeySym) -> bool {
+    let lock_keys = [keysym::XK_Caps_Lock, keysym::XK_Shift_Lock].map(|k| k as KeySym);
+    ((keysym::XK_Shift_L as KeySym..=keysym::XK_Hyper_R as KeySym).contains(&keysym)
+        && !lock_keys.contains(&keysym))
+        || keysym == keysym::XK_ISO_Level3_Shift as KeySym
+}
+
+/// Keys that toggle a locked modifier when pressed on their own.
+pub fn is_lock_keysym(keysym: KeySym) -> bool {
+    [
+        keysym::XK_Caps_Lock,
+        keysym::XK_Shift_Lock,
+        keysym::XK_Num_Lock,
+    ]
+    .map(|k| k as KeySym)
+    .contains(&keysym)
+}
+
+#[derive(Debug, Clone)]
+pub struct KeyMapper {
+    /// The connection that grabs keys and reads events, used for lookups.
+    display: *mut Display,
+    /// A second connection only used to send events, so injected keys are
+    /// never interleaved with the requests of the event loop.
+    inject: Option<Rc<DisplayHandle>>,
+    /// Knows where AltGr and Hyper are, which can move with the mapping.
+    parser: RefCell<KeyParser>,
+    scratch: Rc<RefCell<ScratchKeycodes>>,
+    xtest: Option<Rc<XTest>>,
+    grabbed_keys: Rc<RefCell<Vec<KeyPress>>>,
+    /// `send_to` windows. XTest only reaches the focused window, so keys
+    /// for these are always sent to them directly.
+    send_to_windows: Rc<RefCell<Vec<xlib::Window>>>,
+    /// The time of the key event being remapped, stamped on the events sent
+    /// for it.
+    event_time: Rc<Cell<Time>>,
+    altgr_mask: Cell<u32>,
+    keycode_cache: RefCell<HashMap<KeySym, KeyCode>>,
+    /// `from` keys are QWERTY places rather than keysyms.
+    physical_keys: Cell<bool>,
+}
+
+impl KeyMapper {
+    pub fn new(display: *mut Display) -> Self {
+        let inject = DisplayHandle::open(&DisplayHandle::name_of(display));
+        if inject.is_none() {
+            warn!("Failed to open injection connection, sending over the main one");
+        }
+        let inject_display = inject.as_ref().map_or(display, |d| d.as_ptr());
+
+        let key_mapper = Self {
+            display,
+            inject: inject.map(Rc::new),
+            parser: RefCell::new(KeyParser::new()),
+            scratch: Rc::new(RefCell::new(ScratchKeycodes::default())),
+            xtest: XTest::open(inject_display).map(Rc::new),
+            grabbed_keys: Rc::new(RefCell::new(Vec::new())),
+            send_to_windows: Rc::new(RefCell::new(Vec::new())),
+            event_time: Rc::new(Cell::new(xlib::CurrentTime)),
+            altgr_mask: Cell::new(xlib::Mod5Mask),
+            keycode_cache: RefCell::new(HashMap::new()),
+            physical_keys: Cell::new(false),
+        };
+        key_mapper.resolve_modifiers();
+        key_mapper
+    }
+
+    /// Looks up which modifier bits AltGr and Hyper are bound to in the
+    /// server's modifier mapping.
+    fn resolve_modifiers(&self) {
+        let altgr = self.keycode_from_keysym(keysym::XK_ISO_Level3_Shift as KeySym);
+        if let Some(mask) = self.modifier_mask(altgr) {
+            debug!("AltGr is modifier mask {:#x}", mask);
+            self.altgr_mask.set(mask);
+            self.parser
+                .borrow_mut()
+                .set_modifier_mask(&["AltGr", "G"], mask);
+        }
+        let hyper = self.keycode_from_keysym(keysym::XK_Hyper_L as KeySym);
+        if let Some(mask) = self.modifier_mask(hyper) {
+            debug!("Hyper is modifier mask {:#x}", mask);
+            self.parser
+                .borrow_mut()
+                .set_modifier_mask(&["Hyper", "H"], mask);
+        }
+    }
+
+    /// Looks up again everything that came from the keyboard and modifier
+    /// mapping, after it changed: cached keycodes, the scratch keycodes
+    /// still bound, and the AltGr and Hyper modifier bits.
+    pub fn refresh_mapping(&self) {
+        self.clear_keycode_cache();
+        self.scratch
+            .borrow_mut()
+            .bound
+            .retain(|&(keycode, keysym)| {
+                let bound = unsafe { xlib::XkbKeycodeToKeysym(self.display, keycode, 0, 0) };
+                if bound != keysym {
+                    debug!("Scratch keycode {} was rebound, forgetting it", keycode);
+                }
+                bound == keysym
+            });
+        self.resolve_modifiers();
+    }
+
+    /// Tells the mapper which keys we grab. Injecting one of those through
+    /// XTest would trigger our own grab again, so they're always sent
+    /// directly to the window instead.
+    pub fn set_grabbed_keys(&self, keys: &[KeyPress]) {
+        *self.grabbed_keys.borrow_mut() = keys.to_vec();
+    }
+
+    /// Tells the mapper which windows remaps send keys to in the background.
+    pub fn set_send_to_windows(&self, windows: &[xlib::Window]) {
+        *self.send_to_windows.borrow_mut() = windows.to_vec();
+    }
+
+    /// Sets the time of the key event keys are sent for, so applications
+    /// that order input by timestamp see them where the original key was.
+    /// `CurrentTime` lets the server stamp them, for keys sent from a timer.
+    pub fn set_event_time(&self, time: Time) {
+        self.event_time.set(time);
+    }
+
+    /// The bit of a modifier named like in key expressions, e.g. `Shift`.
+    pub fn named_modifier_mask(&self, name: &str) -> Option<u32> {
+        self.parser.borrow().modifier_mask(name)
+    }
+
+    /// The modifiers held right now, whatever the last key event said.
+    pub fn held_modifiers(&self) -> u32 {
+        let (mut root, mut child) = (0, 0);
+        let (mut root_x, mut root_y, mut x, mut y) = (0, 0, 0, 0);
+        let mut mask = 0;
+        unsafe {
+            xlib::XQueryPointer(
+                self.display,
+                xlib::XDefaultRootWindow(self.display),
+                &mut root,
+                &mut child,
+                &mut root_x,
+                &mut root_y,
+                &mut x,
+                &mut y,
+                &mut mask,
+            );
+        }
+        mask
+    }
+
+    pub fn parse_key(&self, key_expr: &str) -> Option<(KeySym, u32)> {
+        debug!("Parsing key expression: '{}'", key_expr);
+        match self.parser.borrow().parse(key_expr) {
+            Ok((keysym, modifiers)) => {
+                debug!(
+                    "Parsed '{}' -> keysym={:#x}, modifiers={:#x}",
+                    key_expr, keysym, modifiers
+                );
+                Some((keysym, modifiers))
+            }
+            Err(e) => {
+                warn!("Failed to parse key expression '{}': {}", key_expr, e);
+                None
+            }
+        }
+    }
+
+    /// Makes [`KeyMapper::from_keycode`] look keys up by their place on a
+    /// US QWERTY keyboard.
+    pub fn set_physical_keys(&self, physical_keys: bool) {
+        self.physical_keys.set(physical_keys);
+    }
+
+    /// The keycode a remap's `from` key is grabbed as: the key producing
+    /// `keysym`, or with physical keys the key in its QWERTY place, so the
+    /// remap stays on it whatever layout is active.
+    pub fn from_keycode(&self, keysym: KeySym) -> KeyCode {
+        if self.physical_keys.get() {
+            if let Some(keycode) = qwerty_keycode(keysym) {
+                return keycode;
+            }
+        }
+        self.keycode_from_keysym(keysym)
+    }
+
+    /// The keycode producing `keysym`, or 0 if the layout has none. Found
+    /// keycodes are cached until [`KeyMapper::clear_keycode_cache`].
     pub fn keycode_from_keysym(&self, keysym: KeySym) -> KeyCode {
-        unsafe { xlib::XKeysymToKeycode(self.display, keysym) as KeyCode }
+        if let Some(&keycode) = self.keycode_cache.borrow().get(&keysym) {
+            return keycode;
+        }
+        let keycode = unsafe { xlib::XKeysymToKeycode(self.display, keysym) as KeyCode };
+        // Misses aren't cached: a scratch binding may provide the keysym later
+        if keycode != 0 {
+            self.keycode_cache.borrow_mut().insert(keysym, keycode);
+        }
+        keycode
+    }
+
+    /// Forgets cached keycodes, for when the keyboard mapping changed.
+    pub fn clear_keycode_cache(&self) {
+        self.keycode_cache.borrow_mut().clear();
+    }
+
+    /// `key` in config notation, e.g. `C-b`, for messages about it.
+    pub fn describe(&self, key: KeyPress) -> String {
+        let name = unsafe { xlib::XKeysymToString(self.keycode_levels(key.keycode)[0]) };
+        let name = if name.is_null() {
+            format!("keycode {}", key.keycode)
+        } else {
+            unsafe { CStr::from_ptr(name) }
+                .to_string_lossy()
+                .into_owned()
+        };
+        key_expression(key.modifiers, self.altgr_mask.get(), &name)
+    }
+
+    /// The keysym `keycode` types with the modifiers and layout group of
+    /// `state`, as a client would look it up.
+    pub fn typed_keysym(&self, keycode: KeyCode, state: u32) -> KeySym {
+        let mut keysym = 0;
+        unsafe {
+            let mut event: XKeyEvent = std::mem::zeroed();
+            event.type_ = xlib::KeyPress;
+            event.display = self.display;
+            event.keycode = keycode as u32;
+            event.state = state;
+            xlib::XLookupString(
+                &mut event,
+                std::ptr::null_mut(),
+                0,
+                &mut keysym,
+                std::ptr::null_mut(),
+            );
+        }
+        keysym
+    }
+
+    /// Keypad keys carry two keysyms, e.g. `KP_End` and `KP_1`, and NumLock
+    /// picks between them. Returns the NumLock state `keysym` needs, or `None`
+    /// when both levels mean the same thing (`KP_Enter`, `KP_Add`, ...).
+    pub fn numlock_requirement(&self, keysym: KeySym) -> Option<bool> {
+        if !is_keypad_keysym(keysym) {
+            return None;
+        }
+        let keycode = self.keycode_from_keysym(keysym);
+        if keycode == 0 {
+            return None;
+        }
+        let [level1, level2, ..] = self.keycode_levels(keycode);
+        if level1 == level2 || !is_keypad_keysym(level2) {
+            None
+        } else if keysym == level2 {
+            Some(true)
+        } else if keysym == level1 {
+            Some(false)
+        } else {
+            None
+        }
+    }
+
+    /// The keysyms of `keycode` in the first group: plain, Shift, AltGr and
+    /// AltGr+Shift.
+    fn keycode_levels(&self, keycode: KeyCode) -> [KeySym; 4] {
+        self.group_levels(keycode, 0)
+    }
+
+    /// The keysyms of `keycode` in layout `group`. Keys with fewer groups
+    /// wrap around like the server does, so the keypad types the same in
+    /// every layout.
+    fn group_levels(&self, keycode: KeyCode, group: usize) -> [KeySym; 4] {
+        let groups = (0..4)
+            .take_while(|&group| unsafe {
+                xlib::XkbKeycodeToKeysym(self.display, keycode, group, 0) != 0
+            })
+            .count()
+            .max(1);
+        let group = (group % groups) as c_int;
+        [0, 1, 2, 3]
+            .map(|level| unsafe { xlib::XkbKeycodeToKeysym(self.display, keycode, group, level) })
+    }
+
+    /// The active layout group, or the first when XKB can't tell.
+    fn current_group(&self) -> usize {
+        unsafe {
+            let mut state: xlib::XkbStateRec = std::mem::zeroed();
+            if xlib::XkbGetState(self.display, XKB_USE_CORE_KBD, &mut state) != 0 {
+                return 0;
+            }
+            state.group as usize
+        }
+    }
+
+    /// A keycode that types `keysym` in layout `group`, and the shift level
+    /// it is on. `XKeysymToKeycode` knows nothing of the active group and
+    /// happily returns the US position of `z` while a German layout has `y`
+    /// there, so its answer is only the first guess.
+    fn keycode_in_group(&self, keysym: KeySym, group: usize) -> Option<(KeyCode, usize)> {
+        let guess = self.keycode_from_keysym(keysym);
+        if guess != 0 {
+            if let Some(level) = key_level(self.group_levels(guess, group), keysym) {
+                return Some((guess, level));
+            }
+        }
+        let (mut min_keycode, mut max_keycode): (c_int, c_int) = (0, 0);
+        unsafe {
+            xlib::XDisplayKeycodes(self.display, &mut min_keycode, &mut max_keycode);
+        }
+        (min_keycode..=max_keycode).find_map(|keycode| {
+            let keycode = keycode as KeyCode;
+            key_level(self.group_levels(keycode, group), keysym).map(|level| (keycode, level))
+        })
+    }
+
+    /// The modifier bit NumLock is bound to, usually Mod2.
+    pub fn num_lock_mask(&self) -> u32 {
+        let num_lock = self.keycode_from_keysym(keysym::XK_Num_Lock as KeySym);
+        self.modifier_mask(num_lock).unwrap_or(xlib::Mod2Mask)
+    }
+
+    /// The modifier bits that are locks rather than held: Lock, plus
+    /// whichever bits NumLock and ScrollLock are bound to in the server's
+    /// modifier mapping. Grabs cover every combination of them, and remaps
+    /// ignore them.
+    pub fn lock_mask(&self) -> u32 {
+        lock_mask(&self.modifier_keycodes(), |keycode| {
+            self.keycode_levels(keycode)[0]
+        })
+    }
+
+    /// The modifier bit `keycode` sets according to the server's modifier
+    /// mapping, if it is bound to one.
+    pub fn modifier_mask(&self, keycode: KeyCode) -> Option<u32> {
+        if keycode == 0 {
+            return None;
+        }
+        self.modifier_keycodes()
+            .into_iter()
+            .find(|(_, keycodes)| keycodes.contains(&keycode))
+            .map(|(mask, _)| mask)
+    }
+
+    /// The server's modifier mapping: each modifier bit with the keycodes
+    /// bound to it.
+    fn modifier_keycodes(&self) -> Vec<(u32, Vec<KeyCode>)> {
+        unsafe {
+            let modmap = xlib::XGetModifierMapping(self.display);
+            if modmap.is_null() {
+                return Vec::new();
+            }
+            let per_mod = (*modmap).max_keypermod as usize;
+            let keycodes = std::slice::from_raw_parts((*modmap).modifiermap, per_mod * 8);
+            let mapping = keycodes
+                .chunks(per_mod.max(1))
+                .enumerate()
+                .map(|(index, mod_keys)| {
+                    let bound = mod_keys.iter().copied().filter(|&k| k != 0).collect();
+                    (1 << index, bound)
+                })
+                .collect();
+            xlib::XFreeModifiermap(modmap);
+            mapping
+        }
     }
 
     pub fn send_key(&self, window: xlib::Window, keysym: KeySym, modifiers: u32) {
@@ -116,23 +610,90 @@ impl KeyMapper {
             "Sending key: keysym={:#x}, modifiers={:#x} to window={}",
             keysym, modifiers, window
         );
-        unsafe {
-            let keycode = self.keycode_from_keysym(keysym);
+        // The receiving client decides between KP_1 and KP_End from the
+        // NumLock bit in the event state, not from the server's lock state
+        let numlock = self.numlock_requirement(keysym);
+        let mut modifiers = match numlock {
+            Some(true) => modifiers | self.num_lock_mask(),
+            Some(false) => modifiers & !self.num_lock_mask(),
+            None => modifiers,
+        };
+        let group = self.current_group();
+        let mut keycode = self.keycode_from_keysym(keysym);
+        if numlock.is_none() {
+            // A keysym missing from the active layout gets a scratch keycode
+            let found = self.keycode_in_group(keysym, group);
+            keycode = found.map_or(0, |(keycode, _)| keycode);
+            if let Some((_, level @ 1..)) = found {
+                debug!(
+                    "Keysym {:#x} is on level {}, adjusting modifiers",
+                    keysym,
+                    level + 1
+                );
+                if level % 2 == 1 {
+                    modifiers |= xlib::ShiftMask;
+                }
+                if level >= 2 {
+                    modifiers |= self.altgr_mask.get();
+                }
+            }
+        }
 
-            if keycode == 0 {
-                warn!("Failed to get keycode for keysym {:#x}", keysym);
-                return;
+        if keycode == 0 {
+            match self.bind_scratch_keycode(keysym) {
+                Some(scratch) => keycode = scratch,
+                None => {
+                    warn!("Failed to get keycode for keysym {:#x}", keysym);
+                    return;
+                }
             }
+        }
 
+        // Keypad keys depend on the NumLock bit of the event, which XTest
+        // can't set without toggling the lock
+        if numlock.is_none() && !self.send_to_windows.borrow().contains(&window) {
+            if let Some(xtest) = &self.xtest {
+                let key_press = KeyPress { keycode, modifiers };
+                if !self.grabbed_keys.borrow().contains(&key_press) {
+                    debug!("Injecting keysym {:#x} through XTest", keysym);
+                    self.inject_key(xtest, keycode, modifiers);
+                    return;
+                }
+            }
+        }
+
+        // Clients look the keysym up in the group the event state carries
+        self.send_keycode(window, keycode, group_state(modifiers, group));
+    }
+
+    pub fn send_keycode(&self, window: xlib::Window, keycode: KeyCode, modifiers: u32) {
+        self.send_key_event(window, keycode, modifiers, true);
+        self.send_key_event(window, keycode, modifiers, false);
+    }
+
+    /// Sends a lone press or release of `keycode`, for keys held across
+    /// several others.
+    fn send_key_event(&self, window: xlib::Window, keycode: KeyCode, modifiers: u32, press: bool) {
+        let display = self.inject_display();
+        let (type_, mask) = if press {
+            (xlib::KeyPress, xlib::KeyPressMask)
+        } else {
+            (xlib::KeyRelease, xlib::KeyReleaseMask)
+        };
+        unsafe {
+            // Let grab changes made on the main connection take effect first
+            if display != self.display {
+                xlib::XSync(self.display, xlib::False);
+            }
             let mut event = XKeyEvent {
-                type_: xlib::KeyPress,
+                type_,
                 serial: 0,
                 send_event: xlib::True,
-                display: self.display,
+                display,
                 window,
-                root: xlib::XDefaultRootWindow(self.display),
+                root: xlib::XDefaultRootWindow(display),
                 subwindow: 0,
-                time: xlib::CurrentTime,
+                time: self.event_time.get(),
                 x: 1,
                 y: 1,
                 x_root: 1,
@@ -142,28 +703,132 @@ impl KeyMapper {
                 same_screen: xlib::True,
             };
 
-            // Send key press
             let result = xlib::XSendEvent(
-                self.display,
+                display,
                 window,
                 xlib::True,
-                xlib::KeyPressMask,
+                mask,
                 &mut event as *mut XKeyEvent as *mut xlib::XEvent,
             );
-            debug!("XSendEvent press result: {}", result);
-
-            // Send key release
-            event.type_ = xlib::KeyRelease;
-            let result = xlib::XSendEvent(
-                self.display,
-                window,
-                xlib::True,
-                xlib::KeyReleaseMask,
-                &mut event as *mut XKeyEvent as *mut xlib::XEvent,
+            debug!(
+                "XSendEvent {} result: {}",
+                if press { "press" } else { "release" },
+                result
             );
-            debug!("XSendEvent release result: {}", result);
 
-            xlib::XFlush(self.display);
+            xlib::XSync(display, xlib::False);
+        }
+    }
+
+    /// Taps `keycode` through XTest with exactly `modifiers` held. Modifier
+    /// keys the user holds that aren't wanted are released first and the
+    /// missing ones pressed, then both are put back, so clients that read
+    /// the keyboard state rather than the event see the same modifiers.
+    fn inject_key(&self, xtest: &XTest, keycode: KeyCode, modifiers: u32) {
+        let mut keys = [0 as c_char; 32];
+        unsafe {
+            xlib::XQueryKeymap(self.display, keys.as_mut_ptr());
+        }
+        let locks = self.lock_mask();
+        let (released, pressed) = modifier_changes(
+            &self.modifier_keycodes(),
+            |keycode| is_key_down(&keys, keycode),
+            modifiers & !locks,
+            locks,
+        );
+        debug!(
+            "Tapping keycode={}: releasing {:?}, pressing {:?}",
+            keycode, released, pressed
+        );
+
+        self.release_keyboard_grab();
+        for &modifier in &released {
+            xtest.release_key(modifier);
+        }
+        for &modifier in &pressed {
+            xtest.press_key(modifier);
+        }
+        xtest.tap_key(keycode);
+        for &modifier in pressed.iter().rev() {
+            xtest.release_key(modifier);
+        }
+        for &modifier in released.iter().rev() {
+            xtest.press_key(modifier);
+        }
+        // XTest can't carry the original time; the server stamps the events
+        // on arrival, so wait for it before handling the next one
+        unsafe {
+            xlib::XSync(self.inject_display(), xlib::False);
+        }
+    }
+
+    pub fn has_xtest(&self) -> bool {
+        self.xtest.is_some()
+    }
+
+    /// Presses or releases `keycode` through XTest, for macro playback.
+    pub fn fake_key(&self, keycode: KeyCode, press: bool) {
+        let Some(xtest) = &self.xtest else {
+            return;
+        };
+        if press {
+            xtest.press_key(keycode);
+        } else {
+            xtest.release_key(keycode);
+        }
+    }
+
+    /// Clicks the mouse at the pointer through XTest.
+    pub fn click(&self, click: MouseClick) {
+        let Some(xtest) = &self.xtest else {
+            warn!("XTest unavailable, can't send {}", click.name());
+            return;
+        };
+        let (button, count) = click.button();
+        debug!("Clicking button {} {} time(s)", button, count);
+        xtest.click_button(button, count);
+    }
+
+    /// Releases our keyboard grab and the modifier keys held down, so keys
+    /// injected next reach the focused window unmodified.
+    pub fn release_held_modifiers(&self) {
+        let Some(xtest) = &self.xtest else {
+            return;
+        };
+        let mut keys = [0 as c_char; 32];
+        unsafe {
+            xlib::XQueryKeymap(self.display, keys.as_mut_ptr());
+        }
+        let locks = self.lock_mask();
+        self.release_keyboard_grab();
+        for (mask, keycodes) in self.modifier_keycodes() {
+            for keycode in keycodes {
+                if mask & locks == 0 && is_key_down(&keys, keycode) {
+                    xtest.release_key(keycode);
+                }
+            }
+        }
+    }
+
+    /// The keys injected through XTest since the last call.
+    pub fn take_injected(&self) -> Vec<(KeyCode, bool)> {
+        self.xtest
+            .as_ref()
+            .map_or_else(Vec::new, |xtest| xtest.take_injected())
+    }
+
+    fn inject_display(&self) -> *mut Display {
+        self.inject.as_ref().map_or(self.display, |d| d.as_ptr())
+    }
+
+    /// Our own passive grab keeps the keyboard grabbed while the triggering
+    /// key is held, and XTest events would come straight back to us. The
+    /// grab belongs to the main connection, and requests on different
+    /// connections aren't ordered, so sync before injecting.
+    fn release_keyboard_grab(&self) {
+        unsafe {
+            xlib::XUngrabKeyboard(self.display, self.event_time.get());
+            xlib::XSync(self.display, xlib::False);
         }
     }
 
@@ -177,4 +842,555 @@ impl KeyMapper {
             }
         }
     }
+
+    /// Sends `steps` in order. Modifiers pressed with `{down: ...}` apply to
+    /// every key until their `{up: ...}`; any still down at the end are
+    /// released then.
+    pub fn send_steps(&self, window: xlib::Window, steps: &[SequenceStep], key_delay: Duration) {
+        debug!("Sending sequence: {:?} to window={}", steps, window);
+        let mut held: Vec<(KeyCode, u32)> = Vec::new();
+        let held_mask = |held: &[(KeyCode, u32)]| held.iter().fold(0, |mask, (_, m)| mask | m);
+        for (i, step) in steps.iter().enumerate() {
+            if i > 0 && !key_delay.is_zero() && !matches!(step, SequenceStep::Delay(_)) {
+                thread::sleep(key_delay);
+            }
+            match step {
+                SequenceStep::Key(key) => match self.parse_key(key) {
+                    Some((keysym, modifiers)) => {
+                        self.send_key(window, keysym, modifiers | held_mask(&held))
+                    }
+                    None => warn!("Failed to parse key in sequence: '{}'", key),
+                },
+                SequenceStep::Repeat { key, count } => match self.parse_key(key) {
+                    Some((keysym, modifiers)) => {
+                        for n in 0..*count {
+                            if n > 0 && !key_delay.is_zero() {
+                                thread::sleep(key_delay);
+                            }
+                            self.send_key(window, keysym, modifiers | held_mask(&held));
+                        }
+                    }
+                    None => warn!("Failed to parse key in sequence: '{}'", key),
+                },
+                SequenceStep::Delay(ms) => thread::sleep(Duration::from_millis(*ms)),
+                SequenceStep::Down(name) => {
+                    if let Some((keycode, mask)) = self.modifier_key(name) {
+                        self.send_key_event(window, keycode, held_mask(&held), true);
+                        held.push((keycode, mask));
+                    }
+                }
+                SequenceStep::Up(name) => {
+                    if let Some((keycode, _)) = self.modifier_key(name) {
+                        // A release carries the state from before it
+                        let state = held_mask(&held);
+                        held.retain(|&(held_keycode, _)| held_keycode != keycode);
+                        self.send_key_event(window, keycode, state, false);
+                    }
+                }
+            }
+        }
+        while let Some((keycode, _)) = held.last().copied() {
+            let state = held_mask(&held);
+            held.pop();
+            self.send_key_event(window, keycode, state, false);
+        }
+    }
+
+    /// The keycode and modifier bit of a modifier key named in a sequence.
+    pub fn modifier_key(&self, name: &str) -> Option<(KeyCode, u32)> {
+        let keysym = match self.parser.borrow().parse_modifier_key(name) {
+            Ok(keysym) => keysym,
+            Err(e) => {
+                warn!("{}", e);
+                return None;
+            }
+        };
+        let keycode = self.keycode_from_keysym(keysym);
+        if keycode == 0 {
+            warn!("No key produces modifier '{}'", name);
+            return None;
+        }
+        Some((keycode, self.modifier_mask(keycode).unwrap_or(0)))
+    }
+
+    /// Sends a sequence from a background thread over its own X connection, so
+    /// delays between keys don't block the event loop.
+    pub fn spawn_steps(&self, window: xlib::Window, steps: Vec<SequenceStep>, key_delay: Duration) {
+        let display_name = DisplayHandle::name_of(self.display);
+        // The thread's mapper must also keep grabbed keys and send_to
+        // windows away from XTest
+        let grabbed_keys = self.grabbed_keys.borrow().clone();
+        let send_to_windows = self.send_to_windows.borrow().clone();
+
+        thread::spawn(move || {
+            let Some(display) = DisplayHandle::open(&display_name) else {
+                warn!("Failed to open X display for delayed sequence");
+                return;
+            };
+
+            let key_mapper = KeyMapper::new(display.as_ptr());
+            key_mapper.set_grabbed_keys(&grabbed_keys);
+            key_mapper.set_send_to_windows(&send_to_windows);
+            key_mapper.send_steps(window, &steps, key_delay);
+
+            // Once the server has processed the events, clients already queued
+            // them ahead of the MappingNotify the restore generates.
+            unsafe {
+                xlib::XSync(display.as_ptr(), xlib::False);
+            }
+            key_mapper.restore_keyboard_mapping();
+        });
+    }
+
+    /// Holds the modifier `keysym` down as a real key press for as long as the
+    /// `trigger` key stays pressed, so clients see e.g. `Control_R` itself
+    /// rather than just a state bit. The triggering press ungrabs the keyboard,
+    /// so its release goes to the focused window; a thread with its own
+    /// connection watches the keymap for it instead.
+    pub fn hold_modifier(&self, window: xlib::Window, keysym: KeySym, trigger: KeyCode) {
+        let Some(xtest) = &self.xtest else {
+            debug!("XTest unavailable, tapping modifier {:#x} instead", keysym);
+            self.send_key(window, keysym, 0);
+            return;
+        };
+        let keycode = self.keycode_from_keysym(keysym);
+        if keycode == 0 {
+            warn!("Failed to get keycode for modifier keysym {:#x}", keysym);
+            return;
+        }
+
+        debug!(
+            "Holding modifier keycode={} while keycode={} is down",
+            keycode, trigger
+        );
+        self.release_keyboard_grab();
+        xtest.press_key(keycode);
+
+        let display_name = DisplayHandle::name_of(self.display);
+        thread::spawn(move || {
+            let Some(display) = DisplayHandle::open(&display_name) else {
+                warn!("Failed to open X display to release held modifier");
+                return;
+            };
+
+            let mut keys = [0 as c_char; 32];
+            loop {
+                unsafe {
+                    xlib::XQueryKeymap(display.as_ptr(), keys.as_mut_ptr());
+                }
+                if !is_key_down(&keys, trigger) {
+                    break;
+                }
+                thread::sleep(Duration::from_millis(10));
+            }
+
+            debug!("Releasing held modifier keycode={}", keycode);
+            if let Some(xtest) = XTest::open(display.as_ptr()) {
+                xtest.release_key(keycode);
+            }
+        });
+    }
+
+    /// Binds `keysym` to a keycode that has no symbols in the current layout.
+    /// Bindings are kept so repeated sends don't remap the keyboard each time;
+    /// when no free keycode is left the oldest binding is reused.
+    fn bind_scratch_keycode(&self, keysym: KeySym) -> Option<KeyCode> {
+        let mut scratch = self.scratch.borrow_mut();
+        if let Some(&(keycode, _)) = scratch.bound.iter().find(|(_, sym)| *sym == keysym) {
+            return Some(keycode);
+        }
+
+        let keycode = match self.find_unused_keycode(&scratch.bound) {
+            Some(keycode) => keycode,
+            None if !scratch.bound.is_empty() => scratch.bound.remove(0).0,
+            None => return None,
+        };
+
+        debug!(
+            "Binding keysym {:#x} to scratch keycode {}",
+            keysym, keycode
+        );
+        let mut syms = [keysym, keysym];
+        unsafe {
+            xlib::XChangeKeyboardMapping(
+                self.display,
+                keycode as c_int,
+                syms.len() as c_int,
+                syms.as_mut_ptr(),
+                1,
+            );
+            xlib::XSync(self.display, xlib::False);
+        }
+        scratch.bound.push((keycode, keysym));
+        self.clear_keycode_cache();
+        Some(keycode)
+    }
+
+    fn find_unused_keycode(&self, bound: &[(KeyCode, KeySym)]) -> Option<KeyCode> {
+        unsafe {
+            let mut min_keycode: c_int = 0;
+            let mut max_keycode: c_int = 0;
+            xlib::XDisplayKeycodes(self.display, &mut min_keycode, &mut max_keycode);
+
+            let count = max_keycode - min_keycode + 1;
+            let mut syms_per_keycode: c_int = 0;
+            let mapping = xlib::XGetKeyboardMapping(
+                self.display,
+                min_keycode as KeyCode,
+                count,
+                &mut syms_per_keycode,
+            );
+            if mapping.is_null() {
+                return None;
+            }
+
+            let per = syms_per_keycode as usize;
+            let syms = std::slice::from_raw_parts(mapping, count as usize * per);
+            let unused = (0..count as usize).rev().find_map(|i| {
+                let keycode = (min_keycode as usize + i) as KeyCode;
+                let free = syms[i * per..(i + 1) * per]
+                    .iter()
+                    .all(|&sym| sym == xlib::NoSymbol as KeySym);
+                let taken = bound.iter().any(|&(kc, _)| kc == keycode);
+                (free && !taken).then_some(keycode)
+            });
+
+            xlib::XFree(mapping as *mut _);
+            unused
+        }
+    }
+
+    /// Clears every scratch keycode binding made by `send_key`.
+    pub fn restore_keyboard_mapping(&self) {
+        self.clear_keycode_cache();
+        let mut scratch = self.scratch.borrow_mut();
+        for (keycode, keysym) in scratch.bound.drain(..) {
+            debug!("Restoring scratch keycode {} (was {:#x})", keycode, keysym);
+            let mut no_symbol = [xlib::NoSymbol as KeySym];
+            unsafe {
+                xlib::XChangeKeyboardMapping(
+                    self.display,
+                    keycode as c_int,
+                    1,
+                    no_symbol.as_mut_ptr(),
+                    1,
+                );
+            }
+        }
+        unsafe {
+            xlib::XFlush(self.display);
+        }
+    }
+}
+
+/// The lowest shift level of a key that produces `keysym`: 1 for `A` or `%`,
+/// 2 or 3 for AltGr characters like `€`. Keys with the same symbol on every
+/// level stay at 0 and need no extra modifiers.
+fn key_level(levels: [KeySym; 4], keysym: KeySym) -> Option<usize> {
+    levels.iter().position(|&sym| sym == keysym)
+}
+
+/// `modifiers` with layout `group` in bits 13 and 14 of the core event
+/// state, like `XkbBuildCoreState`.
+fn group_state(modifiers: u32, group: usize) -> u32 {
+    modifiers | ((group as u32 & 0x3) << 13)
+}
+
+/// Prefixes `key` with the names of the `modifiers` the way configs write
+/// them, e.g. `C-M-Left`.
+fn key_expression(modifiers: u32, altgr_mask: u32, key: &str) -> String {
+    let names = [
+        (xlib::ControlMask, "C"),
+        (xlib::Mod1Mask, "M"),
+        (xlib::ShiftMask, "S"),
+        (xlib::Mod4Mask, "Super"),
+        (altgr_mask, "AltGr"),
+        (xlib::Mod3Mask, "Mod3"),
+        (xlib::Mod5Mask, "Mod5"),
+    ];
+    let mut expr = String::new();
+    let mut named = 0;
+    for (mask, name) in names {
+        if modifiers & mask != 0 && named & mask == 0 {
+            expr.push_str(name);
+            expr.push('-');
+            named |= mask;
+        }
+    }
+    expr + key
+}
+
+/// Lock plus the modifier bits only lock keys are bound to. A bit shared
+/// with a held modifier, say Scroll Lock next to Hyper on Mod3, is left
+/// out so remaps using that modifier still match.
+fn lock_mask(mapping: &[(u32, Vec<KeyCode>)], keysym_of: impl Fn(KeyCode) -> KeySym) -> u32 {
+    let lock_keys = [
+        keysym::XK_Caps_Lock,
+        keysym::XK_Shift_Lock,
+        keysym::XK_Num_Lock,
+        keysym::XK_Scroll_Lock,
+    ]
+    .map(|k| k as KeySym);
+    mapping
+        .iter()
+        .filter(|(_, keycodes)| {
+            !keycodes.is_empty()
+                && keycodes
+                    .iter()
+                    .all(|&keycode| lock_keys.contains(&keysym_of(keycode)))
+        })
+        .fold(xlib::LockMask, |mask, (bit, _)| mask | bit)
+}
+
+/// The modifier keys to release and to press so that exactly `modifiers`
+/// are held, given the modifier mapping and which keys are down. Lock
+/// modifiers are toggles rather than held keys, so they're left alone.
+fn modifier_changes(
+    mapping: &[(u32, Vec<KeyCode>)],
+    is_down: impl Fn(KeyCode) -> bool,
+    modifiers: u32,
+    locks: u32,
+) -> (Vec<KeyCode>, Vec<KeyCode>) {
+    let (mut released, mut pressed) = (Vec::new(), Vec::new());
+    for (mask, keycodes) in mapping {
+        if mask & locks != 0 {
+            continue;
+        }
+        let down: Vec<KeyCode> = keycodes.iter().copied().filter(|&k| is_down(k)).collect();
+        if modifiers & mask == 0 {
+            released.extend(down);
+        } else if down.is_empty() {
+            pressed.extend(keycodes.first());
+        }
+    }
+    (released, pressed)
+}
+
+/// Looks `keycode` up in the bit vector returned by XQueryKeymap.
+fn is_key_down(keys: &[c_char; 32], keycode: KeyCode) -> bool {
+    keys[keycode as usize / 8] as u8 & (1 << (keycode % 8)) != 0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_qwerty_keycode() {
+        assert_eq!(qwerty_keycode('q' as KeySym), Some(24));
+        assert_eq!(qwerty_keycode('A' as KeySym), Some(38));
+        assert_eq!(qwerty_keycode('/' as KeySym), Some(61));
+        assert_eq!(qwerty_keycode('\\' as KeySym), Some(51));
+        assert_eq!(qwerty_keycode(keysym::XK_Left as KeySym), None);
+    }
+
+    #[test]
+    fn test_parse_unicode_key() {
+        let parser = KeyParser::new();
+        assert_eq!(parser.parse("M-é"), Ok((0xe9, xlib::Mod1Mask)));
+        assert_eq!(parser.parse("€"), Ok((0x0100_20ac, 0)));
+    }
+
+    #[test]
+    fn test_key_expression() {
+        let mods = xlib::ControlMask | xlib::ShiftMask | xlib::Mod5Mask;
+        assert_eq!(key_expression(mods, xlib::Mod5Mask, "b"), "C-S-AltGr-b");
+        assert_eq!(
+            key_expression(xlib::Mod5Mask, xlib::Mod3Mask, "x"),
+            "Mod5-x"
+        );
+        assert_eq!(key_expression(0, xlib::Mod5Mask, "Left"), "Left");
+    }
+
+    #[test]
+    fn test_parse_minus_and_malformed_expressions() {
+        let parser = KeyParser::new();
+        let minus = keysym::XK_minus as KeySym;
+        assert_eq!(parser.parse("-"), Ok((minus, 0)));
+        assert_eq!(parser.parse("C--"), Ok((minus, xlib::ControlMask)));
+        assert_eq!(
+            parser.parse("C-M--"),
+            Ok((minus, xlib::ControlMask | xlib::Mod1Mask))
+        );
+        for expr in ["", "Ctrl-", "C-M-"] {
+            assert_eq!(
+                parser.parse(expr),
+                Err(KeyParseError::MissingKey(expr.to_string()))
+            );
+        }
+        for expr in ["-a", "--", "C---", "C--a"] {
+            assert_eq!(
+                parser.parse(expr),
+                Err(KeyParseError::UnknownModifier(String::new())),
+                "{}",
+                expr
+            );
+        }
+    }
+
+    #[test]
+    fn test_parse_falls_back_to_xlib_names() {
+        let parser = KeyParser::new();
+        assert_eq!(parser.parse("Prior"), Ok((keysym::XK_Prior as KeySym, 0)));
+        assert_eq!(
+            parser.parse("S-KP_Enter"),
+            Ok((keysym::XK_KP_Enter as KeySym, xlib::ShiftMask))
+        );
+        assert_eq!(
+            parser.parse("Nonexistent"),
+            Err(KeyParseError::UnknownKey("Nonexistent".to_string()))
+        );
+    }
+
+    #[test]
+    fn test_parse_media_keys() {
+        let parser = KeyParser::new();
+        for name in [
+            "XF86AudioRaiseVolume",
+            "XF86AudioPlay",
+            "XF86MonBrightnessUp",
+        ] {
+            let (keysym, _) = parser.parse(name).unwrap();
+            assert!(
+                (0x1008_ff00..=0x1008_ffff).contains(&keysym),
+                "{} -> {:#x}",
+                name,
+                keysym
+            );
+        }
+    }
+
+    #[test]
+    fn test_keypad_keysyms() {
+        let parser = KeyParser::new();
+        for name in ["KP_0", "KP_9", "KP_Enter", "KP_Add", "KP_End"] {
+            let (keysym, _) = parser.parse(name).unwrap();
+            assert!(is_keypad_keysym(keysym), "{} should be a keypad key", name);
+        }
+        assert!(!is_keypad_keysym(keysym::XK_Num_Lock as KeySym));
+        assert!(!is_keypad_keysym(keysym::XK_1 as KeySym));
+    }
+
+    #[test]
+    fn test_modifier_keysyms() {
+        let parser = KeyParser::new();
+        for name in [
+            "Control_R",
+            "Alt_L",
+            "Shift_R",
+            "Super_L",
+            "ISO_Level3_Shift",
+        ] {
+            let (keysym, _) = parser.parse(name).unwrap();
+            assert!(is_modifier_keysym(keysym), "{} should be a modifier", name);
+        }
+        for name in ["Caps_Lock", "a", "Num_Lock"] {
+            let (keysym, _) = parser.parse(name).unwrap();
+            assert!(!is_modifier_keysym(keysym), "{} isn't held", name);
+        }
+        for name in ["Caps_Lock", "Num_Lock"] {
+            let (keysym, _) = parser.parse(name).unwrap();
+            assert!(is_lock_keysym(keysym), "{} should be a lock key", name);
+        }
+        assert!(!is_lock_keysym(parser.parse("Menu").unwrap().0));
+    }
+
+    #[test]
+    fn test_key_level() {
+        let a = [keysym::XK_a as KeySym, keysym::XK_A as KeySym, 0, 0];
+        assert_eq!(key_level(a, keysym::XK_A as KeySym), Some(1));
+        assert_eq!(key_level(a, keysym::XK_a as KeySym), Some(0));
+
+        let five = [keysym::XK_5 as KeySym, keysym::XK_percent as KeySym, 0, 0];
+        assert_eq!(key_level(five, keysym::XK_percent as KeySym), Some(1));
+
+        // German layout: AltGr-e is €
+        let e = [
+            keysym::XK_e as KeySym,
+            keysym::XK_E as KeySym,
+            0x20ac,
+            0x20ac,
+        ];
+        assert_eq!(key_level(e, 0x20ac), Some(2));
+
+        let scratch = [0x20ac; 4];
+        assert_eq!(key_level(scratch, 0x20ac), Some(0));
+    }
+
+    #[test]
+    fn test_group_state() {
+        assert_eq!(group_state(xlib::ShiftMask, 0), xlib::ShiftMask);
+        assert_eq!(group_state(xlib::ShiftMask, 1), xlib::ShiftMask | 0x2000);
+        assert_eq!(group_state(0, 3), 0x6000);
+    }
+
+    #[test]
+    fn test_parse_altgr_and_hyper() {
+        let parser = KeyParser::new();
+        assert_eq!(parser.parse("AltGr-e"), Ok((0x65, xlib::Mod5Mask)));
+        assert_eq!(parser.parse("H-a"), Ok((0x61, xlib::Mod3Mask)));
+
+        let mut parser = KeyParser::new();
+        parser.set_modifier_mask(&["Hyper", "H"], xlib::Mod4Mask);
+        assert_eq!(parser.parse("Hyper-a"), Ok((0x61, xlib::Mod4Mask)));
+    }
+
+    #[test]
+    fn test_lock_mask() {
+        let keysyms = HashMap::from([
+            (66, keysym::XK_Caps_Lock as KeySym),
+            (77, keysym::XK_Num_Lock as KeySym),
+            (78, keysym::XK_Scroll_Lock as KeySym),
+            (207, keysym::XK_Hyper_L as KeySym),
+        ]);
+        let keysym_of = |keycode| keysyms[&keycode];
+        let mapping = vec![
+            (xlib::LockMask, vec![66]),
+            (xlib::Mod2Mask, vec![77]),
+            (xlib::Mod3Mask, vec![78]),
+        ];
+        assert_eq!(
+            lock_mask(&mapping, keysym_of),
+            xlib::LockMask | xlib::Mod2Mask | xlib::Mod3Mask
+        );
+
+        // NumLock unbound, Scroll Lock sharing Mod3 with Hyper
+        let mapping = vec![(xlib::Mod2Mask, vec![]), (xlib::Mod3Mask, vec![78, 207])];
+        assert_eq!(lock_mask(&mapping, keysym_of), xlib::LockMask);
+    }
+
+    #[test]
+    fn test_modifier_changes() {
+        let mapping = vec![
+            (xlib::ShiftMask, vec![50, 62]),
+            (xlib::LockMask, vec![66]),
+            (xlib::ControlMask, vec![37, 105]),
+            (xlib::Mod1Mask, vec![64]),
+        ];
+        // C-b held, sending Left: Control goes up and comes back after
+        let held = [37, 66];
+        let down = |keycode| held.contains(&keycode);
+        assert_eq!(
+            modifier_changes(&mapping, down, 0, xlib::LockMask),
+            (vec![37], vec![])
+        );
+        // Sending S-C-x: Control is already down, Shift gets pressed
+        assert_eq!(
+            modifier_changes(
+                &mapping,
+                down,
+                xlib::ShiftMask | xlib::ControlMask,
+                xlib::LockMask
+            ),
+            (vec![], vec![50])
+        );
+    }
+
+    #[test]
+    fn test_split_chord() {
+        assert_eq!(KeyParser::split_chord("j+k"), Some(("j", "k")));
+        assert_eq!(KeyParser::split_chord("+"), None);
+        assert_eq!(KeyParser::split_chord("C-+"), None);
+        assert_eq!(KeyParser::split_chord("C-b"), None);
+    }
 }
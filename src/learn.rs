@@ -0,0 +1,85 @@
+//! Learning mode: counts the key combos pressed in each window class that no
+//! remap covers there, to show which shortcuts are in use before writing
+//! rules for them.
+
+use crate::config::{same_key, Config, MatchContext};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// Unremapped combos by window class, with how often each was pressed.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Report {
+    counts: BTreeMap<String, BTreeMap<String, u64>>,
+}
+
+impl Report {
+    /// Counts `key` pressed in a window of `class`, unless a remap of
+    /// `config` applies to it there.
+    pub fn record(&mut self, config: Option<&Config>, class: &str, key: &str) {
+        let context = MatchContext {
+            window_class: Some(class),
+            ..Default::default()
+        };
+        let remapped = config.is_some_and(|config| {
+            config
+                .matching_remaps(&context)
+                .iter()
+                .any(|(_, remap)| same_key(&remap.from, key))
+        });
+        if !remapped {
+            *self
+                .counts
+                .entry(class.to_string())
+                .or_default()
+                .entry(key.to_string())
+                .or_default() += 1;
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.counts.is_empty()
+    }
+}
+
+/// Each class with its combos, most pressed first.
+impl fmt::Display for Report {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (class, keys) in &self.counts {
+            writeln!(f, "{}", class)?;
+            let mut keys: Vec<_> = keys.iter().collect();
+            keys.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+            for (key, count) in keys {
+                writeln!(f, "  {:>6}  {}", count, key)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_report_skips_remapped_keys() {
+        let config = Config::from_yaml(
+            "global: [{'C-a': 'Home'}]\nwindows: [{class_only: [firefox], remaps: [{'C-b': 'Left'}]}]",
+        )
+        .unwrap();
+        let mut report = Report::default();
+        for (class, key) in [
+            ("firefox", "C-t"),
+            ("firefox", "C-b"),
+            ("kitty", "C-b"),
+            ("firefox", "Ctrl-a"),
+            ("firefox", "C-w"),
+            ("firefox", "C-t"),
+        ] {
+            report.record(Some(&config), class, key);
+        }
+        assert_eq!(
+            report.to_string(),
+            "firefox\n       2  C-t\n       1  C-w\nkitty\n       1  C-b\n"
+        );
+    }
+}
@@ -1,4 +1,63 @@
+//! Window-aware key remapping for X11.
+//!
+//! The remapper grabs the keys configured for the focused window and sends
+//! their replacements, switching rules as focus and keyboard layout change.
+//! [`Remapper`] is the entry point for embedding it:
+//!
+//! ```no_run
+//! use simple_x11_remapper::{Config, Remapper};
+//! use std::path::Path;
+//!
+//! let config = Config::load(Path::new("config.yaml"))?;
+//! Remapper::builder().config(config).run()?;
+//! # Ok::<(), anyhow::Error>(())
+//! ```
+
+mod backend;
 pub mod config;
-pub mod event_handler;
+pub mod control;
+mod display;
+pub mod doctor;
+mod dual_role;
+mod dump;
+mod event_handler;
+mod ewmh;
+mod expand;
+mod explain;
+mod grabs;
+pub mod import;
+pub mod init;
 pub mod key_mapper;
-pub mod window_manager;
+pub mod learn;
+mod macros;
+mod modmap;
+mod mouse_keys;
+mod notify;
+mod panic_guard;
+pub mod plugin;
+mod remapper;
+mod script;
+pub mod state;
+mod stats;
+mod status;
+mod template;
+mod timer;
+#[cfg(feature = "tray")]
+pub mod tray;
+#[cfg(feature = "wasm-plugins")]
+mod wasm_plugin;
+mod window_manager;
+mod x_error;
+mod xinput;
+mod xkb;
+mod xrandr;
+mod xrecord;
+mod xtest;
+
+pub use config::{AdhocRemaps, Config, ConfigError};
+pub use dump::{DisplayDump, Dump, ResolvedKey, ResolvedRemap};
+pub use explain::{Candidate, Explanation, KeyExplanation};
+pub use remapper::{Remapper, RemapperBuilder, RemapperError, ShutdownHandle, CONFIRM_KEY};
+pub use stats::Stats;
+pub use status::{DisplayStatus, Status};
+pub use x_error::XError;
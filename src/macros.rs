@@ -0,0 +1,218 @@
+use crate::key_mapper::KeyMapper;
+use crate::xrecord::{drop_injected, RecordedKey, Recorder};
+use log::{debug, info, warn};
+use std::collections::{HashMap, VecDeque};
+use std::rc::Rc;
+use std::time::{Duration, Instant};
+use x11::xlib::{Display, KeyCode};
+
+/// A key of a recorded macro and how long after the previous one it came.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MacroEvent {
+    pub keycode: KeyCode,
+    pub pressed: bool,
+    pub delay: Duration,
+}
+
+/// Keystroke macros for `{record_macro: n}` and `{play_macro: n}`, kept in
+/// memory by slot. Recording watches the keyboard through XRecord, so keys
+/// reach the focused window as usual while they're captured.
+pub struct Macros {
+    display: *mut Display,
+    key_mapper: Rc<KeyMapper>,
+    slots: HashMap<u32, Vec<MacroEvent>>,
+    recording: Option<Recording>,
+    /// Keys of the macro being played, each with when it's due.
+    playing: VecDeque<(Instant, KeyCode, bool)>,
+}
+
+struct Recording {
+    slot: u32,
+    recorder: Recorder,
+    keys: Vec<RecordedKey>,
+    /// Keys we injected ourselves that XRecord hasn't reported yet.
+    injected: Vec<(KeyCode, bool)>,
+}
+
+impl Macros {
+    pub fn new(display: *mut Display, key_mapper: Rc<KeyMapper>) -> Self {
+        Self {
+            display,
+            key_mapper,
+            slots: HashMap::new(),
+            recording: None,
+            playing: VecDeque::new(),
+        }
+    }
+
+    /// Starts recording into `slot`, or stops the recording in progress:
+    /// the combo that starts a recording also ends it.
+    pub fn toggle_recording(&mut self, slot: u32) {
+        if self.recording.is_some() {
+            self.stop_recording();
+            return;
+        }
+        if !self.playing.is_empty() {
+            debug!("Macro playing, not recording macro {}", slot);
+            return;
+        }
+        let Some(recorder) = Recorder::open(self.display) else {
+            warn!("XRecord unavailable, can't record macro {}", slot);
+            return;
+        };
+        info!("Recording macro {}", slot);
+        self.recording = Some(Recording {
+            slot,
+            recorder,
+            keys: Vec::new(),
+            injected: Vec::new(),
+        });
+    }
+
+    fn stop_recording(&mut self) {
+        self.take_recorded(&self.key_mapper.take_injected());
+        let Some(recording) = self.recording.take() else {
+            return;
+        };
+        let events = trim_unbalanced(&recording.keys);
+        info!(
+            "Recorded macro {} with {} key events",
+            recording.slot,
+            events.len()
+        );
+        self.slots.insert(recording.slot, events);
+    }
+
+    /// The XRecord connection of the recording in progress, to wait on.
+    pub fn recorder_fd(&self) -> Option<i32> {
+        self.recording.as_ref().map(|r| r.recorder.fd())
+    }
+
+    /// Adds the keys XRecord saw since the last call to the recording, less
+    /// the ones our own remaps injected: playing back the physical keys
+    /// fires those remaps again. `injected` are the keys injected since the
+    /// last call.
+    pub fn take_recorded(&mut self, injected: &[(KeyCode, bool)]) {
+        let Some(recording) = &mut self.recording else {
+            return;
+        };
+        recording.injected.extend(injected);
+        let keys = recording.recorder.take_keys();
+        let keys = drop_injected(keys, &mut recording.injected);
+        recording.keys.extend(keys);
+    }
+
+    /// Plays the macro in `slot` from the event loop, keeping the recorded
+    /// pauses between keys.
+    pub fn play(&mut self, slot: u32) {
+        if self.recording.is_some() || !self.playing.is_empty() {
+            debug!("Not playing macro {} while recording or playing", slot);
+            return;
+        }
+        let Some(events) = self.slots.get(&slot) else {
+            warn!("Macro {} hasn't been recorded", slot);
+            return;
+        };
+        if !self.key_mapper.has_xtest() {
+            warn!("XTest unavailable, can't play macro {}", slot);
+            return;
+        }
+        info!("Playing macro {}", slot);
+        // The combo's modifiers would apply to the played keys
+        self.key_mapper.release_held_modifiers();
+        let mut due = Instant::now();
+        for event in events {
+            due += event.delay;
+            self.playing.push_back((due, event.keycode, event.pressed));
+        }
+    }
+
+    /// When the next key of the macro being played is due.
+    pub fn next_due(&self) -> Option<Instant> {
+        self.playing.front().map(|(due, _, _)| *due)
+    }
+
+    /// Injects the keys of the macro being played that are due at `now`.
+    pub fn play_due(&mut self, now: Instant) {
+        while let Some(&(due, keycode, pressed)) = self.playing.front() {
+            if due > now {
+                break;
+            }
+            self.playing.pop_front();
+            self.key_mapper.fake_key(keycode, pressed);
+        }
+    }
+}
+
+/// Turns recorded keys into macro events, dropping releases of keys pressed
+/// before the recording started and presses never released before it
+/// stopped: those belong to the combos that started and stopped it.
+fn trim_unbalanced(keys: &[RecordedKey]) -> Vec<MacroEvent> {
+    let mut kept = vec![false; keys.len()];
+    let mut down: HashMap<KeyCode, Vec<usize>> = HashMap::new();
+    for (i, key) in keys.iter().enumerate() {
+        if key.pressed {
+            down.entry(key.keycode).or_default().push(i);
+        } else if let Some(press) = down.get_mut(&key.keycode).and_then(Vec::pop) {
+            kept[press] = true;
+            kept[i] = true;
+        }
+    }
+
+    let mut previous = None;
+    keys.iter()
+        .zip(kept)
+        .filter(|(_, kept)| *kept)
+        .map(|(key, _)| {
+            let delay = previous.map_or(0, |time| key.time.saturating_sub(time));
+            previous = Some(key.time);
+            MacroEvent {
+                keycode: key.keycode,
+                pressed: key.pressed,
+                delay: Duration::from_millis(delay),
+            }
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use x11::xlib::Time;
+
+    fn key(keycode: KeyCode, pressed: bool, time: Time) -> RecordedKey {
+        RecordedKey {
+            keycode,
+            state: 0,
+            pressed,
+            time,
+        }
+    }
+
+    #[test]
+    fn test_trim_unbalanced() {
+        let keys = [
+            key(37, false, 100), // Control of the start combo released
+            key(38, true, 200),
+            key(38, false, 250),
+            key(39, true, 400),
+            key(39, false, 420),
+            key(37, true, 900), // the stop combo
+            key(27, true, 950),
+        ];
+        let events = trim_unbalanced(&keys);
+        let summary: Vec<_> = events
+            .iter()
+            .map(|e| (e.keycode, e.pressed, e.delay.as_millis()))
+            .collect();
+        assert_eq!(
+            summary,
+            [
+                (38, true, 0),
+                (38, false, 50),
+                (39, true, 150),
+                (39, false, 20)
+            ]
+        );
+    }
+}
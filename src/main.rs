@@ -1,123 +1,392 @@
-mod config;
-mod event_handler;
-mod key_mapper;
-mod window_manager;
+mod log_file;
 
 use anyhow::{Context, Result};
-use config::Config;
-use event_handler::EventHandler;
-use log::{debug, error, info, warn};
-use std::env;
-use std::fs;
-use std::os::raw::c_int;
-use std::ptr;
-use x11::xlib::{self, Display, XErrorEvent, XEvent};
-
-static mut ERROR_OCCURED: bool = false;
-
-extern "C" fn error_handler(_display: *mut Display, event: *mut XErrorEvent) -> c_int {
-    unsafe {
-        ERROR_OCCURED = true;
-        error!(
-            "X11 Error: code={}, request={}, minor={}",
-            (*event).error_code,
-            (*event).request_code,
-            (*event).minor_code
-        );
-    }
-    0
+use clap::{CommandFactory, Parser, Subcommand, ValueEnum};
+use log::{info, warn, LevelFilter};
+use log_file::RotatingFile;
+use signal_hook::consts::{SIGINT, SIGTERM};
+use signal_hook::iterator::Signals;
+use simple_x11_remapper::doctor::{self, Severity};
+use simple_x11_remapper::{control, import, init, learn, state, AdhocRemaps, Config, Remapper};
+use std::path::{Path, PathBuf};
+use std::thread;
+use std::time::Duration;
+
+/// Window-aware key remapper for X11.
+#[derive(Debug, Parser)]
+#[command(version)]
+struct Cli {
+    /// Config file to remap with. Defaults to
+    /// $XDG_CONFIG_HOME/simple-x11-remapper/config.yaml or ~/.config/simple-x11-remapper/config.yaml
+    #[arg(value_name = "CONFIG")]
+    config_path: Option<PathBuf>,
+    /// Config file to remap with, instead of the positional argument
+    #[arg(short, long, value_name = "PATH", conflicts_with = "config_path")]
+    config: Option<PathBuf>,
+    /// Try this config: unless C-M-Return is pressed or `confirm` run in
+    /// time, the running remapper goes back to its config, or this one stops
+    #[arg(long = "try", value_name = "PATH", conflicts_with_all = ["config_path", "config"])]
+    try_config: Option<PathBuf>,
+    /// Seconds a --try config has to be confirmed in
+    #[arg(
+        long,
+        value_name = "SECONDS",
+        default_value_t = 60,
+        requires = "try_config"
+    )]
+    revert_after: u64,
+    /// Remap a key for this run only, like 'C-b=Left', the target written
+    /// as in the config; repeat for several
+    #[arg(long = "remap", value_name = "FROM=TO")]
+    remaps: Vec<String>,
+    /// Limit the --remap remaps to this window class; repeat for several
+    #[arg(long = "class", value_name = "CLASS", requires = "remaps")]
+    classes: Vec<String>,
+    /// X display to remap; repeat for several. Defaults to $DISPLAY
+    #[arg(long = "display", value_name = "NAME", global = true)]
+    displays: Vec<String>,
+    /// Write logs to this file, rotated at 10 MiB, instead of stderr
+    #[arg(long, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// Log level, overriding RUST_LOG: off, error, warn, info, debug or trace
+    #[arg(long, value_name = "LEVEL")]
+    log_level: Option<LevelFilter>,
+    /// Show a system tray icon to pause, resume and reload the remapper
+    #[cfg(feature = "tray")]
+    #[arg(long)]
+    tray: bool,
+    #[command(subcommand)]
+    command: Option<Command>,
 }
 
-fn main() -> Result<()> {
-    env_logger::init();
+#[derive(Debug, Subcommand)]
+enum Command {
+    /// Show how often each remap fired in the running remapper
+    Stats,
+    /// Show the focused window class, layout and grab conflicts
+    Status,
+    /// List the named rules and whether they're enabled
+    Rules,
+    /// Print the effective config and active remaps as JSON
+    Dump,
+    /// Show which remap a key like 'C-b' fires in the focused window, or why
+    /// none does
+    Explain { key: String },
+    /// Release every grab in the running remapper until it's resumed
+    Pause,
+    /// Resume remapping after `pause`
+    Resume,
+    /// Make the running remapper read its config file again
+    Reload,
+    /// Keep the config the running remapper is trying
+    Confirm,
+    /// Enable a named rule in the running remapper
+    Enable { rule: String },
+    /// Disable a named rule in the running remapper
+    Disable { rule: String },
+    /// Check the X environment and that the config's keys resolve
+    Doctor {
+        #[arg(value_name = "CONFIG")]
+        config: Option<PathBuf>,
+    },
+    /// Write a starter config from the windows and keys you use
+    Init {
+        #[arg(value_name = "OUTPUT", default_value = "config.yaml")]
+        output: PathBuf,
+    },
+    /// Count the shortcuts pressed in each window that the config doesn't
+    /// remap, and print them when Enter is pressed
+    Learn {
+        #[arg(value_name = "CONFIG")]
+        config: Option<PathBuf>,
+    },
+    /// Convert another tool's config and print it as YAML
+    Import { format: ImportFormat, file: PathBuf },
+    /// Print a shell completion script
+    Completions { shell: clap_complete::Shell },
+}
 
-    let args: Vec<String> = env::args().collect();
-    if args.len() != 2 {
-        eprintln!("Usage: {} <config.yaml>", args[0]);
-        std::process::exit(1);
+#[derive(Debug, Clone, Copy, ValueEnum)]
+enum ImportFormat {
+    Xremap,
+    Xmodmap,
+    Xbindkeys,
+}
+
+fn main() -> Result<()> {
+    let cli = Cli::parse();
+    match cli.command {
+        None => run(cli),
+        Some(Command::Stats) => run_control("stats"),
+        Some(Command::Status) => run_control("status"),
+        Some(Command::Rules) => run_control("rules"),
+        Some(Command::Dump) => run_control("dump"),
+        Some(Command::Explain { key }) => run_control(&format!("explain {}", key)),
+        Some(Command::Pause) => run_control("pause"),
+        Some(Command::Resume) => run_control("resume"),
+        Some(Command::Reload) => run_control("reload"),
+        Some(Command::Confirm) => run_control("confirm"),
+        Some(Command::Enable { rule }) => run_control(&format!("enable {}", rule)),
+        Some(Command::Disable { rule }) => run_control(&format!("disable {}", rule)),
+        Some(Command::Doctor { ref config }) => run_doctor(first_display(&cli), config.as_deref()),
+        Some(Command::Init { ref output }) => run_init(first_display(&cli), output),
+        Some(Command::Learn { ref config }) => run_learn(first_display(&cli), config.as_deref()),
+        Some(Command::Import { format, ref file }) => run_import(format, file),
+        Some(Command::Completions { shell }) => {
+            let mut command = Cli::command();
+            let name = command.get_name().to_string();
+            clap_complete::generate(shell, &mut command, name, &mut std::io::stdout());
+            Ok(())
+        }
     }
+}
+
+/// The first of [`Config::default_paths`] that exists.
+fn default_config() -> Option<PathBuf> {
+    Config::default_paths()
+        .into_iter()
+        .find(|path| path.is_file())
+}
+
+fn first_display(cli: &Cli) -> &str {
+    cli.displays.first().map_or("", String::as_str)
+}
 
-    info!("Starting xremap with config: {}", args[1]);
+fn run(cli: Cli) -> Result<()> {
+    let revert_after = Duration::from_secs(cli.revert_after);
+    let trial = match &cli.try_config {
+        // A running remapper tries the config itself, and goes back to its own
+        Some(path) if control::request(&control::default_path(), "ping").is_ok() => {
+            let path = std::path::absolute(path)?;
+            return run_control(&format!("try {} {}", cli.revert_after, path.display()));
+        }
+        Some(_) => Some(revert_after),
+        None => None,
+    };
+    let config_path = cli
+        .try_config
+        .or(cli.config)
+        .or(cli.config_path)
+        .or_else(default_config);
+    if config_path.is_none() && cli.remaps.is_empty() {
+        let searched: Vec<String> = Config::default_paths()
+            .iter()
+            .map(|path| path.display().to_string())
+            .collect();
+        Cli::command()
+            .error(
+                clap::error::ErrorKind::MissingRequiredArgument,
+                format!(
+                    "no config file given, and none found at {}",
+                    searched.join(" or ")
+                ),
+            )
+            .exit();
+    }
 
-    let config_content = fs::read_to_string(&args[1])
-        .with_context(|| format!("Failed to read config file: {}", args[1]))?;
+    let mut logger = env_logger::Builder::from_default_env();
+    if let Some(level) = cli.log_level {
+        logger.filter_level(level);
+    }
+    if let Some(path) = &cli.log_file {
+        let file = RotatingFile::open(path, log_file::DEFAULT_MAX_BYTES, log_file::DEFAULT_KEEP)
+            .with_context(|| format!("failed to open log file {}", path.display()))?;
+        logger.target(env_logger::Target::Pipe(Box::new(file)));
+    }
+    logger.init();
 
-    let config = Config::from_yaml(&config_content).context("Failed to parse config file")?;
+    let adhoc = AdhocRemaps {
+        remaps: cli.remaps,
+        classes: cli.classes,
+    };
+    let config = match &config_path {
+        Some(path) => {
+            info!("Starting xremap with config: {}", path.display());
+            Config::load_with_adhoc(path, adhoc)?
+        }
+        None => {
+            info!("Starting xremap with only the --remap remaps");
+            Config::from_yaml_with_adhoc("{}", adhoc)?
+        }
+    };
 
-    info!("Loaded config with {} window rules", config.windows.len());
+    info!(
+        "Loaded config with {} global remaps and {} window rules",
+        config.global.len(),
+        config.windows.len()
+    );
     for (i, window) in config.windows.iter().enumerate() {
         info!(
-            "Window rule {}: class_only={:?}, class_not={:?}, remaps={}",
+            "Window rule {}: class_only={:?}, class_not={:?}, layout_only={:?}, remaps={}",
             i,
             window.class_only,
             window.class_not,
+            window.layout_only,
             window.remaps.len()
         );
     }
 
-    unsafe {
-        let display = xlib::XOpenDisplay(ptr::null());
-        if display.is_null() {
-            anyhow::bail!("Failed to open X display");
-        }
-        info!("Successfully opened X display");
-
-        xlib::XSetErrorHandler(Some(error_handler));
+    // The tray's thread uses Xlib too, which must be told before any call
+    #[cfg(feature = "tray")]
+    if cli.tray {
+        simple_x11_remapper::tray::init_threads();
+    }
 
-        let root = xlib::XDefaultRootWindow(display);
-        xlib::XSelectInput(
-            display,
-            root,
-            xlib::KeyPressMask | xlib::PropertyChangeMask | xlib::SubstructureNotifyMask,
+    let mut builder = Remapper::builder().config(config);
+    for display in &cli.displays {
+        builder = builder.display(display);
+    }
+    let socket_path = control::default_path();
+    if control::request(&socket_path, "ping").is_ok() {
+        warn!(
+            "Another remapper is answering on {}, not opening a control socket",
+            socket_path.display()
         );
+    } else {
+        builder = builder.control_socket(&socket_path);
+    }
+    if let Some(revert_after) = trial {
+        builder = builder.trial(revert_after);
+    }
+    if let Some(path) = state::default_path() {
+        builder = builder.state_file(path);
+    }
+    let mut remapper = builder.build()?;
 
-        let mut event_handler = EventHandler::new(display, config);
-        event_handler.initialize();
-
-        info!("xremap initialized successfully");
-        println!("xremap started. Listening for key events...");
-        println!("Press Ctrl-C to quit");
-        println!("Set RUST_LOG=debug for verbose output");
-
-        let mut event: XEvent = std::mem::zeroed();
-
-        loop {
-            xlib::XNextEvent(display, &mut event);
-
-            match event.get_type() {
-                xlib::KeyPress => {
-                    let key_event = event.key;
-                    debug!(
-                        "KeyPress: keycode={}, state={}",
-                        key_event.keycode, key_event.state
-                    );
-                    event_handler.handle_key_press(key_event.keycode as u8, key_event.state);
-                }
-                xlib::PropertyNotify => {
-                    debug!("PropertyNotify event");
-                    event_handler.handle_property_notify();
-                }
-                xlib::MappingNotify => {
-                    debug!("MappingNotify event");
-                    event_handler.handle_mapping_notify();
-                }
-                xlib::ClientMessage => {
-                    let client_event = event.client_message;
-                    debug!(
-                        "ClientMessage: type={}, format={}",
-                        client_event.message_type, client_event.format
-                    );
-                }
-                _ => {
-                    debug!("Unhandled event type: {}", event.get_type());
-                }
+    #[cfg(feature = "tray")]
+    if cli.tray {
+        let display = cli.displays.first().cloned().unwrap_or_default();
+        thread::spawn(move || {
+            if let Err(e) = simple_x11_remapper::tray::run(&display, &socket_path) {
+                warn!("No tray icon: {}", e);
             }
+        });
+    }
+
+    let shutdown = remapper.shutdown_handle();
+    let mut signals = Signals::new([SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        if let Some(signal) = signals.forever().next() {
+            info!("Received signal {}, shutting down", signal);
+            shutdown.shutdown();
+        }
+    });
+
+    info!("xremap initialized successfully");
+    println!("xremap started. Listening for key events...");
+    println!("Press Ctrl-C to quit");
+    println!("Set RUST_LOG=debug for verbose output");
 
-            if ERROR_OCCURED {
-                ERROR_OCCURED = false;
+    remapper.run()?;
+    info!("xremap stopped");
+    Ok(())
+}
+
+/// Sends `command` to the running remapper and prints its reply, exiting
+/// non-zero if it's an error.
+fn run_control(command: &str) -> Result<()> {
+    let reply = control::request(&control::default_path(), command)
+        .context("failed to reach a running remapper")?;
+    print!("{}", reply);
+    if reply.starts_with("error:") {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Watches keys until Enter is pressed, then prints the shortcuts used in
+/// each window that the config has no remap for.
+fn run_learn(display: &str, config_path: Option<&Path>) -> Result<()> {
+    let config_path = config_path.map(Path::to_path_buf).or_else(default_config);
+    let config = config_path.as_deref().map(Config::load).transpose()?;
+    let mut watcher = init::Watcher::open(display)?;
+    println!("Use your applications as usual. Come back here and press Enter to see the");
+    println!("shortcuts you pressed that the config doesn't remap.");
+    let (done, finished) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let _ = std::io::stdin().read_line(&mut String::new());
+        let _ = done.send(());
+    });
+    let mut report = learn::Report::default();
+    while finished.try_recv().is_err() {
+        for press in watcher.take_presses() {
+            if !press.typing {
+                report.record(config.as_ref(), &press.class, &press.key);
             }
         }
+        thread::sleep(std::time::Duration::from_millis(50));
+    }
+
+    if report.is_empty() {
+        println!("No unremapped shortcuts were pressed.");
+    } else {
+        print!("{}", report);
+    }
+    Ok(())
+}
+
+/// Converts another tool's config and prints it as YAML, with what couldn't
+/// be converted on stderr.
+fn run_import(format: ImportFormat, path: &Path) -> Result<()> {
+    let content = std::fs::read_to_string(path)
+        .with_context(|| format!("failed to read {}", path.display()))?;
+    let import = match format {
+        ImportFormat::Xremap => import::xremap(&content)?,
+        ImportFormat::Xmodmap => import::xmodmap(&content)?,
+        ImportFormat::Xbindkeys => import::xbindkeys(&content)?,
+    };
+    for warning in &import.warnings {
+        eprintln!("warning: {}", warning);
+    }
+    print!("{}", import::to_yaml(&import.config)?);
+    Ok(())
+}
+
+/// Prints the environment checks and exits non-zero if any failed.
+fn run_doctor(display: &str, config_path: Option<&Path>) -> Result<()> {
+    let config_path = config_path.map(Path::to_path_buf).or_else(default_config);
+    let config = config_path.as_deref().map(Config::load).transpose()?;
+    let checks = doctor::diagnose(display, config.as_ref());
+    for check in &checks {
+        println!("{}", check);
+    }
+    if checks.iter().any(|check| check.severity == Severity::Error) {
+        std::process::exit(1);
+    }
+    Ok(())
+}
+
+/// Watches focus and keys until Enter is pressed, then writes a starter
+/// config for the windows seen.
+fn run_init(display: &str, output: &Path) -> Result<()> {
+    if output.exists() {
+        anyhow::bail!("{} already exists, not overwriting it", output.display());
+    }
+
+    let mut watcher = init::Watcher::open(display)?;
+    println!("Switch to each window you want rules for and press the shortcuts (with Ctrl,");
+    println!("Alt or Super) you want to remap there. Come back here and press Enter when done.");
+    let (done, finished) = std::sync::mpsc::channel();
+    thread::spawn(move || {
+        let _ = std::io::stdin().read_line(&mut String::new());
+        let _ = done.send(());
+    });
+    while finished.try_recv().is_err() {
+        for news in watcher.poll() {
+            println!("  {}", news);
+        }
+        thread::sleep(std::time::Duration::from_millis(50));
     }
+
+    std::fs::write(output, init::starter_config(watcher.windows()))
+        .with_context(|| format!("failed to write {}", output.display()))?;
+    println!(
+        "Wrote {} with {} window rules. Uncomment the remaps and edit their targets, then run:",
+        output.display(),
+        watcher.windows().len()
+    );
+    println!("  simple-x11-remapper {}", output.display());
+    Ok(())
 }
 
 #[cfg(test)]
@@ -139,4 +408,60 @@ windows:
         assert_eq!(config.windows.len(), 1);
         assert_eq!(config.windows[0].remaps.len(), 2);
     }
+
+    #[test]
+    fn test_parse_display_args() {
+        Cli::command().debug_assert();
+
+        let cli =
+            Cli::try_parse_from(["remapper", "--display", ":0", "config.yaml", "--display=:1"])
+                .unwrap();
+        assert_eq!(cli.config_path, Some(PathBuf::from("config.yaml")));
+        assert_eq!(cli.displays, vec![":0", ":1"]);
+        assert_eq!(cli.log_file, None);
+
+        let cli = Cli::try_parse_from([
+            "remapper",
+            "--log-file=/tmp/remapper.log",
+            "--log-level",
+            "debug",
+            "-c",
+            "c.yaml",
+        ])
+        .unwrap();
+        assert_eq!(cli.log_file, Some(PathBuf::from("/tmp/remapper.log")));
+        assert_eq!(cli.log_level, Some(LevelFilter::Debug));
+        assert_eq!(cli.config, Some(PathBuf::from("c.yaml")));
+
+        assert!(Cli::try_parse_from(["remapper", "--display"]).is_err());
+
+        let cli = Cli::try_parse_from(["remapper", "--display", ":1", "doctor", "c.yaml"]).unwrap();
+        assert!(matches!(
+            cli.command,
+            Some(Command::Doctor { config: Some(_) })
+        ));
+        assert_eq!(cli.displays, vec![":1"]);
+
+        let cli = Cli::try_parse_from([
+            "remapper",
+            "--remap",
+            "C-b=Left",
+            "--class=chromium",
+            "--remap=C-f=Right",
+        ])
+        .unwrap();
+        assert_eq!(cli.remaps, vec!["C-b=Left", "C-f=Right"]);
+        assert_eq!(cli.classes, vec!["chromium"]);
+        assert!(Cli::try_parse_from(["remapper", "--class", "chromium"]).is_err());
+        let cli =
+            Cli::try_parse_from(["remapper", "--try", "new.yaml", "--revert-after=10"]).unwrap();
+        assert_eq!(cli.try_config, Some(PathBuf::from("new.yaml")));
+        assert_eq!(cli.revert_after, 10);
+        assert_eq!(Cli::try_parse_from(["remapper"]).unwrap().revert_after, 60);
+        assert!(Cli::try_parse_from(["remapper", "--revert-after", "10"]).is_err());
+        assert!(Cli::try_parse_from(["remapper", "--try", "a.yaml", "b.yaml"]).is_err());
+
+        let cli = Cli::try_parse_from(["remapper", "enable", "emacs keys"]).unwrap();
+        assert!(matches!(cli.command, Some(Command::Enable { rule }) if rule == "emacs keys"));
+    }
 }
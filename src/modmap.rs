@@ -0,0 +1,217 @@
+use crate::config::{modifier_index, ModmapEntry};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::raw::c_int;
+use x11::xlib::{self, Display, KeyCode, KeySym, XModifierKeymap};
+
+/// The `modmap` changes made to a display's keyboard and modifier mapping,
+/// undone when dropped.
+pub struct Modmap {
+    display: *mut Display,
+    /// Keysyms of the changed keycodes before we touched them.
+    saved_keys: Vec<(KeyCode, Vec<KeySym>)>,
+    /// Keysyms we gave the changed keycodes. Xlib's keysym lookups only see
+    /// them once it processes the MappingNotify, so later entries look here.
+    changed_keys: HashMap<KeyCode, Vec<KeySym>>,
+    saved_modifiers: Option<*mut XModifierKeymap>,
+}
+
+impl Modmap {
+    /// Applies `entries` in order, like xmodmap would.
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn apply(display: *mut Display, entries: &[ModmapEntry]) -> Self {
+        let mut modmap = Self {
+            display,
+            saved_keys: Vec::new(),
+            changed_keys: HashMap::new(),
+            saved_modifiers: None,
+        };
+        if entries.is_empty() {
+            return modmap;
+        }
+        info!("Applying {} modmap entries", entries.len());
+
+        let mut modifiers: *mut XModifierKeymap = std::ptr::null_mut();
+        for entry in entries {
+            debug!("Modmap entry: {:?}", entry);
+            match entry {
+                ModmapEntry::Keycode { keycode, keysyms } => {
+                    modmap.set_keysyms(*keycode, keysyms);
+                }
+                ModmapEntry::Keysym { keysym, keysyms } => match modmap.keycode_of(keysym) {
+                    Some(keycode) => modmap.set_keysyms(keycode, keysyms),
+                    None => warn!("modmap: no key produces '{}'", keysym),
+                },
+                ModmapEntry::Clear { clear } => {
+                    let Some(index) = modifier_index(clear) else {
+                        warn!("modmap: unknown modifier '{}'", clear);
+                        continue;
+                    };
+                    let map = modmap.modifiers(&mut modifiers);
+                    unsafe {
+                        let per_mod = (*map).max_keypermod as usize;
+                        let row = (*map).modifiermap.add(index * per_mod);
+                        std::ptr::write_bytes(row, 0, per_mod);
+                    }
+                }
+                ModmapEntry::Add { add, keys } | ModmapEntry::Remove { remove: add, keys } => {
+                    let Some(index) = modifier_index(add) else {
+                        warn!("modmap: unknown modifier '{}'", add);
+                        continue;
+                    };
+                    let index = index as c_int;
+                    let is_add = matches!(entry, ModmapEntry::Add { .. });
+                    for key in keys {
+                        let Some(keycode) = modmap.keycode_of(key) else {
+                            warn!("modmap: no key produces '{}'", key);
+                            continue;
+                        };
+                        let map = modmap.modifiers(&mut modifiers);
+                        modifiers = unsafe {
+                            if is_add {
+                                xlib::XInsertModifiermapEntry(map, keycode, index)
+                            } else {
+                                xlib::XDeleteModifiermapEntry(map, keycode, index)
+                            }
+                        };
+                    }
+                }
+            }
+        }
+
+        unsafe {
+            if !modifiers.is_null() {
+                if xlib::XSetModifierMapping(display, modifiers) != xlib::MappingSuccess as i32 {
+                    warn!("modmap: modifier mapping not changed, a modifier key is held down");
+                }
+                xlib::XFreeModifiermap(modifiers);
+            }
+            xlib::XSync(display, xlib::False);
+        }
+        modmap.refresh();
+        modmap
+    }
+
+    /// Drops Xlib's cached keyboard mapping, which it would otherwise only
+    /// notice as stale when the MappingNotify comes round.
+    fn refresh(&self) {
+        let mut event: xlib::XMappingEvent = unsafe { std::mem::zeroed() };
+        event.type_ = xlib::MappingNotify;
+        event.display = self.display;
+        for request in [xlib::MappingKeyboard, xlib::MappingModifier] {
+            event.request = request;
+            unsafe {
+                xlib::XRefreshKeyboardMapping(&mut event);
+            }
+        }
+    }
+
+    /// The working copy of the modifier mapping, saving the original first.
+    fn modifiers(&mut self, modifiers: &mut *mut XModifierKeymap) -> *mut XModifierKeymap {
+        if modifiers.is_null() {
+            unsafe {
+                self.saved_modifiers = Some(xlib::XGetModifierMapping(self.display));
+                *modifiers = xlib::XGetModifierMapping(self.display);
+            }
+        }
+        *modifiers
+    }
+
+    fn keycode_of(&self, name: &str) -> Option<KeyCode> {
+        let keysym = keysym_from_name(name)?;
+        if let Some((&keycode, _)) = self
+            .changed_keys
+            .iter()
+            .find(|(_, keysyms)| keysyms.contains(&keysym))
+        {
+            return Some(keycode);
+        }
+        let keycode = unsafe { xlib::XKeysymToKeycode(self.display, keysym) };
+        (keycode != 0 && !self.changed_keys.contains_key(&keycode)).then_some(keycode)
+    }
+
+    fn set_keysyms(&mut self, keycode: KeyCode, names: &[String]) {
+        let mut keysyms: Vec<KeySym> = names
+            .iter()
+            .map(|name| keysym_from_name(name).unwrap_or(xlib::NoSymbol as KeySym))
+            .collect();
+        if keysyms.is_empty() {
+            keysyms.push(xlib::NoSymbol as KeySym);
+        }
+        if !self.saved_keys.iter().any(|(saved, _)| *saved == keycode) {
+            self.saved_keys
+                .push((keycode, current_keysyms(self.display, keycode)));
+        }
+        unsafe {
+            xlib::XChangeKeyboardMapping(
+                self.display,
+                keycode as c_int,
+                keysyms.len() as c_int,
+                keysyms.as_mut_ptr(),
+                1,
+            );
+        }
+        self.changed_keys.insert(keycode, keysyms);
+    }
+
+    /// Drops the changes without undoing them, for when the keyboard they
+    /// were made to is gone: restoring the saved mapping would overwrite
+    /// the new keyboard's with the old one's.
+    pub fn forget(mut self) {
+        self.saved_keys.clear();
+        if let Some(saved) = self.saved_modifiers.take() {
+            unsafe {
+                xlib::XFreeModifiermap(saved);
+            }
+        }
+    }
+}
+
+impl Drop for Modmap {
+    fn drop(&mut self) {
+        if self.saved_keys.is_empty() && self.saved_modifiers.is_none() {
+            return;
+        }
+        info!("Restoring keyboard mapping changed by modmap");
+        unsafe {
+            for (keycode, keysyms) in &mut self.saved_keys {
+                xlib::XChangeKeyboardMapping(
+                    self.display,
+                    *keycode as c_int,
+                    keysyms.len() as c_int,
+                    keysyms.as_mut_ptr(),
+                    1,
+                );
+            }
+            if let Some(saved) = self.saved_modifiers.take() {
+                xlib::XSetModifierMapping(self.display, saved);
+                xlib::XFreeModifiermap(saved);
+            }
+            xlib::XSync(self.display, xlib::False);
+        }
+        self.refresh();
+    }
+}
+
+fn keysym_from_name(name: &str) -> Option<KeySym> {
+    if name == "NoSymbol" {
+        return Some(xlib::NoSymbol as KeySym);
+    }
+    let name = CString::new(name).ok()?;
+    let keysym = unsafe { xlib::XStringToKeysym(name.as_ptr()) };
+    (keysym != xlib::NoSymbol as KeySym).then_some(keysym)
+}
+
+fn current_keysyms(display: *mut Display, keycode: KeyCode) -> Vec<KeySym> {
+    unsafe {
+        let mut per_keycode = 0;
+        let keysyms = xlib::XGetKeyboardMapping(display, keycode, 1, &mut per_keycode);
+        if keysyms.is_null() {
+            return vec![xlib::NoSymbol as KeySym];
+        }
+        let saved = std::slice::from_raw_parts(keysyms, per_keycode as usize).to_vec();
+        xlib::XFree(keysyms as *mut _);
+        saved
+    }
+}
@@ -0,0 +1,108 @@
+use crate::config::MouseModeSettings;
+use std::time::Duration;
+use x11::xlib::KeyCode;
+
+/// How often the pointer moves while a direction key is held.
+pub const STEP_INTERVAL: Duration = Duration::from_millis(16);
+
+/// What a key does in mouse mode.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MouseKey {
+    /// Move the pointer by this many steps across and down.
+    Move(i32, i32),
+    Click,
+    Exit,
+}
+
+/// Mouse mode while it's on: the keys it reacts to and the direction keys
+/// held down.
+#[derive(Debug)]
+pub struct MouseKeys {
+    settings: MouseModeSettings,
+    keys: Vec<(KeyCode, MouseKey)>,
+    held: Vec<(KeyCode, (i32, i32))>,
+    /// Steps moved since a direction key went down, for acceleration.
+    steps: u32,
+}
+
+impl MouseKeys {
+    pub fn new(settings: MouseModeSettings, keys: Vec<(KeyCode, MouseKey)>) -> Self {
+        Self {
+            settings,
+            keys,
+            held: Vec::new(),
+            steps: 0,
+        }
+    }
+
+    pub fn key(&self, keycode: KeyCode) -> Option<MouseKey> {
+        self.keys
+            .iter()
+            .find(|(key, _)| *key == keycode)
+            .map(|(_, action)| *action)
+    }
+
+    /// Starts moving in the direction of `keycode`. Returns whether the
+    /// pointer was standing still, so the caller starts stepping it.
+    pub fn press(&mut self, keycode: KeyCode, direction: (i32, i32)) -> bool {
+        let was_still = self.held.is_empty();
+        if !self.held.iter().any(|(key, _)| *key == keycode) {
+            self.held.push((keycode, direction));
+        }
+        if was_still {
+            self.steps = 0;
+        }
+        was_still
+    }
+
+    pub fn release(&mut self, keycode: KeyCode) {
+        self.held.retain(|(key, _)| *key != keycode);
+    }
+
+    /// How far to move the pointer this step, or `None` once no direction
+    /// key is held.
+    pub fn step(&mut self) -> Option<(i32, i32)> {
+        if self.held.is_empty() {
+            return None;
+        }
+        let (dx, dy) = self
+            .held
+            .iter()
+            .fold((0, 0), |(x, y), (_, (dx, dy))| (x + dx, y + dy));
+        let speed = (self.settings.speed as f64 + self.settings.acceleration * self.steps as f64)
+            .min(self.settings.max_speed as f64)
+            .round() as i32;
+        self.steps += 1;
+        Some((dx.signum() * speed, dy.signum() * speed))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_steps_accelerate_up_to_max_speed() {
+        let settings = MouseModeSettings {
+            exit: "Escape".to_string(),
+            speed: 2,
+            max_speed: 3,
+            acceleration: 0.5,
+        };
+        let mut mouse_keys = MouseKeys::new(settings, Vec::new());
+        assert_eq!(mouse_keys.step(), None);
+
+        assert!(mouse_keys.press(43, (-1, 0)));
+        assert!(!mouse_keys.press(44, (0, 1)));
+        assert_eq!(mouse_keys.step(), Some((-2, 2)));
+        assert_eq!(mouse_keys.step(), Some((-3, 3)));
+        assert_eq!(mouse_keys.step(), Some((-3, 3)));
+
+        mouse_keys.release(43);
+        mouse_keys.release(44);
+        assert_eq!(mouse_keys.step(), None);
+        // Pressing again starts over from the initial speed
+        mouse_keys.press(44, (0, 1));
+        assert_eq!(mouse_keys.step(), Some((0, 2)));
+    }
+}
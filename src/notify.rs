@@ -0,0 +1,24 @@
+//! Desktop notifications through `notify-send`, so any libnotify-compatible
+//! notification daemon shows them without linking against libnotify.
+
+use log::warn;
+use std::process::{Command, Stdio};
+
+/// Shows a notification with `summary` and `body`, without waiting for it.
+pub fn send(summary: &str, body: &str) {
+    let child = Command::new("notify-send")
+        .args([
+            "--app-name=simple-x11-remapper",
+            "--expire-time=2000",
+            summary,
+            body,
+        ])
+        .stdin(Stdio::null())
+        .spawn();
+    match child {
+        Ok(mut child) => {
+            std::thread::spawn(move || child.wait());
+        }
+        Err(e) => warn!("Failed to run notify-send: {}", e),
+    }
+}
@@ -0,0 +1,142 @@
+//! Keeps a panic from leaving the keyboard hijacked. Key grabs outlive a
+//! panic until the process exits, and a frozen synchronous grab or a held
+//! keyboard grab leaves every key dead with no visible cause.
+//!
+//! Event handling runs under [`catch`]: a panic there is logged, the
+//! keyboard let go, and the event loop carries on. Any other panic on the
+//! event loop's thread goes through the hook [`install`] sets, which
+//! releases every grab of the registered displays and flushes them before
+//! the panic goes on as usual.
+
+use crate::display::root_windows;
+use log::error;
+use std::cell::Cell;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Mutex, Once};
+use std::thread::{self, ThreadId};
+use x11::xlib::{self, Display};
+
+/// The displays grabs are released on, by address, and the thread that
+/// opened each: Xlib connections aren't safe to use from another one.
+static DISPLAYS: Mutex<Vec<(usize, ThreadId)>> = Mutex::new(Vec::new());
+
+thread_local! {
+    /// How many [`catch`] calls this thread is inside, whose panics are
+    /// recovered from rather than released for.
+    static CATCHING: Cell<usize> = const { Cell::new(0) };
+}
+
+/// Installs the panic hook, once; the hook that was set before still runs
+/// after it.
+pub fn install() {
+    static INSTALL: Once = Once::new();
+    INSTALL.call_once(|| {
+        let previous = panic::take_hook();
+        panic::set_hook(Box::new(move |info| {
+            if CATCHING.get() == 0 {
+                release_all();
+            }
+            previous(info);
+        }));
+    });
+}
+
+/// Releases `display`'s grabs if a panic on this thread isn't caught,
+/// until [`unregister`]ed.
+pub fn register(display: *mut Display) {
+    lock().push((display as usize, thread::current().id()));
+}
+
+pub fn unregister(display: *mut Display) {
+    lock().retain(|&(address, _)| address != display as usize);
+}
+
+fn lock() -> std::sync::MutexGuard<'static, Vec<(usize, ThreadId)>> {
+    // A panic while holding the lock leaves the list itself intact
+    DISPLAYS.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+fn release_all() {
+    let current = thread::current().id();
+    let displays: Vec<usize> = lock()
+        .iter()
+        .filter(|(_, thread)| *thread == current)
+        .map(|&(address, _)| address)
+        .collect();
+    if !displays.is_empty() {
+        error!("Panicking, releasing every key grab");
+    }
+    for address in displays {
+        let display = address as *mut Display;
+        unsafe {
+            let mut focus = 0;
+            let mut revert_to = 0;
+            xlib::XGetInputFocus(display, &mut focus, &mut revert_to);
+            // Grabs of GrabTarget::Client are on the focused window
+            let windows = root_windows(display)
+                .into_iter()
+                .chain((focus > xlib::PointerRoot as u64).then_some(focus));
+            for window in windows {
+                xlib::XUngrabKey(display, xlib::AnyKey, xlib::AnyModifier, window);
+            }
+        }
+        release_keyboard(display);
+    }
+}
+
+/// Thaws a synchronous grab and lets go of a keyboard grab.
+fn release_keyboard(display: *mut Display) {
+    unsafe {
+        xlib::XAllowEvents(display, xlib::AsyncKeyboard, xlib::CurrentTime);
+        xlib::XUngrabKeyboard(display, xlib::CurrentTime);
+        xlib::XFlush(display);
+    }
+}
+
+/// Runs `f`, handling events for `display`. If it panics, the keyboard is
+/// released, in case the handler left it grabbed or frozen, and `None` is
+/// returned instead.
+pub fn catch<R>(display: *mut Display, f: impl FnOnce() -> R) -> Option<R> {
+    let result = catch_quietly(f);
+    if result.is_none() {
+        error!("Recovered from a panic while handling events, releasing the keyboard");
+        release_keyboard(display);
+    }
+    result
+}
+
+/// Runs `f`, without the hook releasing grabs if it panics.
+fn catch_quietly<R>(f: impl FnOnce() -> R) -> Option<R> {
+    CATCHING.set(CATCHING.get() + 1);
+    let result = panic::catch_unwind(AssertUnwindSafe(f));
+    CATCHING.set(CATCHING.get() - 1);
+    result.ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_catch_quietly() {
+        assert_eq!(catch_quietly(|| 1), Some(1));
+        let nested = catch_quietly(|| {
+            assert_eq!(CATCHING.get(), 1);
+            catch_quietly(|| -> () { panic!("in a handler") })
+        });
+        assert_eq!(nested, Some(None));
+        assert_eq!(catch_quietly(|| -> () { panic!("in a handler") }), None);
+        assert_eq!(CATCHING.get(), 0);
+    }
+
+    #[test]
+    fn test_register_displays() {
+        let display = 0x1234 as *mut Display;
+        register(display);
+        assert!(lock()
+            .iter()
+            .any(|&(address, thread)| address == 0x1234 && thread == thread::current().id()));
+        unregister(display);
+        assert!(lock().iter().all(|&(address, _)| address != 0x1234));
+    }
+}
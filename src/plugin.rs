@@ -0,0 +1,380 @@
+//! Actions provided by shared libraries, for `{plugin: name}` remaps.
+//!
+//! A plugin is a `.so` listed under the config's `plugins` that exports
+//! `sxr_plugin_init`, a function without arguments returning a pointer to a
+//! [`PluginInfo`] that stays valid until the library is unloaded. Its `run`
+//! is called on the event loop's thread each time a remap fires, so it
+//! should return quickly and leave anything slow to a thread or process of
+//! its own.
+//!
+//! A `.wasm` plugin is run in a sandbox instead, with the `wasm-plugins`
+//! feature: see `wasm_plugin.rs`.
+
+use crate::config::PluginSource;
+use log::{info, warn};
+use std::ffi::{c_char, c_int, c_void, CStr, CString};
+use std::fmt;
+use std::os::unix::ffi::OsStrExt;
+use std::path::Path;
+use std::rc::Rc;
+use thiserror::Error;
+use x11::xlib::Window;
+
+/// The version of [`PluginInfo`] and [`PluginContext`] this build speaks.
+/// Plugins built for another one are refused.
+pub const ABI_VERSION: u32 = 1;
+
+/// The symbol every plugin exports.
+const INIT_SYMBOL: &CStr = c"sxr_plugin_init";
+
+type RunFn = extern "C" fn(argument: *const c_char, context: *const PluginContext) -> c_int;
+
+/// What a plugin's `sxr_plugin_init` returns.
+#[repr(C)]
+pub struct PluginInfo {
+    /// [`ABI_VERSION`] as the plugin was built against it.
+    pub abi_version: u32,
+    /// The name `{plugin: name}` actions call it by, NUL-terminated UTF-8.
+    pub name: *const c_char,
+    /// Runs the action. `argument` is the action's `arg`, or null without
+    /// one. Returns 0 on success; anything else is logged as a failure.
+    /// A plugin with a null `run` is refused.
+    pub run: Option<RunFn>,
+}
+
+/// Where the action fires, valid for the duration of the `run` call.
+#[repr(C)]
+pub struct PluginContext {
+    /// The focused window, or the `send_to` one.
+    pub window: Window,
+    /// The window's WM_CLASS class, or null if it has none.
+    pub window_class: *const c_char,
+}
+
+#[derive(Debug, Error)]
+pub enum PluginError {
+    #[error("{0}")]
+    Load(String),
+    #[error("doesn't export sxr_plugin_init")]
+    NoInit,
+    #[error("built for plugin ABI {0}, this build speaks {ABI_VERSION}")]
+    Abi(u32),
+    #[error("the plugin name isn't UTF-8")]
+    BadName,
+    #[error("its run function is null")]
+    NoRun,
+    #[error("no plugin '{0}' is loaded")]
+    Unknown(String),
+    #[error("the argument contains a NUL byte")]
+    BadArgument,
+    #[error("returned {0}")]
+    Failed(c_int),
+    #[cfg(feature = "wasm-plugins")]
+    #[error("{0}")]
+    Wasm(#[from] wasmi::Error),
+    #[cfg(not(feature = "wasm-plugins"))]
+    #[error("built without the `wasm-plugins` feature")]
+    WasmDisabled,
+}
+
+/// Sends a key expression like `C-a` to a window, returning whether it
+/// parsed. What sandboxed plugins type keys with.
+pub type KeySender = Rc<dyn Fn(Window, &str) -> bool>;
+
+struct Plugin {
+    name: String,
+    kind: PluginKind,
+}
+
+enum PluginKind {
+    Native {
+        run: RunFn,
+        _library: Library,
+    },
+    #[cfg(feature = "wasm-plugins")]
+    Wasm(Box<std::cell::RefCell<crate::wasm_plugin::WasmPlugin>>),
+}
+
+/// A dlopen handle, closed on drop; null for plugins linked in.
+struct Library(*mut c_void);
+
+impl Drop for Library {
+    fn drop(&mut self) {
+        if !self.0.is_null() {
+            unsafe { libc::dlclose(self.0) };
+        }
+    }
+}
+
+impl Plugin {
+    fn open(path: &Path) -> Result<Self, PluginError> {
+        let path = CString::new(path.as_os_str().as_bytes())
+            .map_err(|_| PluginError::Load("path contains a NUL byte".to_string()))?;
+        let handle = unsafe { libc::dlopen(path.as_ptr(), libc::RTLD_NOW | libc::RTLD_LOCAL) };
+        if handle.is_null() {
+            return Err(PluginError::Load(dl_error()));
+        }
+        let init = unsafe { libc::dlsym(handle, INIT_SYMBOL.as_ptr()) };
+        if init.is_null() {
+            unsafe { libc::dlclose(handle) };
+            return Err(PluginError::NoInit);
+        }
+        let init: extern "C" fn() -> *const PluginInfo = unsafe { std::mem::transmute(init) };
+        let info = init();
+        unsafe { Self::from_info(handle, info) }.inspect_err(|_| unsafe {
+            libc::dlclose(handle);
+        })
+    }
+
+    /// # Safety
+    ///
+    /// `info` must be null or point to a valid [`PluginInfo`] whose name is
+    /// NUL-terminated.
+    unsafe fn from_info(handle: *mut c_void, info: *const PluginInfo) -> Result<Self, PluginError> {
+        let Some(info) = info.as_ref() else {
+            return Err(PluginError::Load(
+                "sxr_plugin_init returned null".to_string(),
+            ));
+        };
+        // Only the version is known to be there in a plugin of another ABI
+        if info.abi_version != ABI_VERSION {
+            return Err(PluginError::Abi(info.abi_version));
+        }
+        if info.name.is_null() {
+            return Err(PluginError::BadName);
+        }
+        let name = CStr::from_ptr(info.name)
+            .to_str()
+            .map_err(|_| PluginError::BadName)?;
+        let run = info.run.ok_or(PluginError::NoRun)?;
+        Ok(Self {
+            name: name.to_string(),
+            kind: PluginKind::Native {
+                run,
+                _library: Library(handle),
+            },
+        })
+    }
+
+    /// A sandboxed plugin, called by its file name without `.wasm`.
+    #[cfg(feature = "wasm-plugins")]
+    fn open_wasm(source: &PluginSource, send_key: KeySender) -> Result<Self, PluginError> {
+        let name = source
+            .path
+            .file_stem()
+            .and_then(|stem| stem.to_str())
+            .ok_or(PluginError::BadName)?;
+        let plugin =
+            crate::wasm_plugin::WasmPlugin::load(&source.path, source.allow_exec, send_key)?;
+        Ok(Self {
+            name: name.to_string(),
+            kind: PluginKind::Wasm(Box::new(std::cell::RefCell::new(plugin))),
+        })
+    }
+
+    #[cfg(not(feature = "wasm-plugins"))]
+    fn open_wasm(_source: &PluginSource, _send_key: KeySender) -> Result<Self, PluginError> {
+        Err(PluginError::WasmDisabled)
+    }
+}
+
+fn dl_error() -> String {
+    let message = unsafe { libc::dlerror() };
+    if message.is_null() {
+        return "dlopen failed".to_string();
+    }
+    unsafe { CStr::from_ptr(message) }
+        .to_string_lossy()
+        .into_owned()
+}
+
+/// The plugins the config loads, by name.
+#[derive(Default)]
+pub struct Plugins {
+    plugins: Vec<Plugin>,
+}
+
+impl fmt::Debug for Plugins {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_list()
+            .entries(self.plugins.iter().map(|plugin| &plugin.name))
+            .finish()
+    }
+}
+
+impl Plugins {
+    /// Loads the config's plugins. One that fails to load is logged and
+    /// left out, so its actions do nothing; a name taken by an earlier
+    /// plugin is too. `send_key` is how sandboxed plugins type keys.
+    pub fn load(sources: &[PluginSource], send_key: KeySender) -> Self {
+        let mut plugins = Self::default();
+        for source in sources {
+            let plugin = if source.is_wasm() {
+                Plugin::open_wasm(source, send_key.clone())
+            } else {
+                Plugin::open(&source.path)
+            };
+            match plugin {
+                Ok(plugin) => plugins.add(plugin, &source.path),
+                Err(e) => warn!("Can't load plugin {}: {}", source.path.display(), e),
+            }
+        }
+        plugins
+    }
+
+    fn add(&mut self, plugin: Plugin, path: &Path) {
+        if self.contains(&plugin.name) {
+            warn!(
+                "Plugin {} is called '{}' like an earlier one, skipping it",
+                path.display(),
+                plugin.name
+            );
+            return;
+        }
+        info!("Loaded plugin '{}' from {}", plugin.name, path.display());
+        self.plugins.push(plugin);
+    }
+
+    pub fn contains(&self, name: &str) -> bool {
+        self.plugins.iter().any(|plugin| plugin.name == name)
+    }
+
+    /// Runs the plugin called `name` for a remap firing in `window`.
+    pub fn run(
+        &self,
+        name: &str,
+        argument: Option<&str>,
+        window: Window,
+        window_class: Option<&str>,
+    ) -> Result<(), PluginError> {
+        let plugin = self
+            .plugins
+            .iter()
+            .find(|plugin| plugin.name == name)
+            .ok_or_else(|| PluginError::Unknown(name.to_string()))?;
+        let run = match &plugin.kind {
+            PluginKind::Native { run, .. } => run,
+            #[cfg(feature = "wasm-plugins")]
+            PluginKind::Wasm(plugin) => {
+                return plugin.borrow_mut().run(argument, window, window_class);
+            }
+        };
+        let argument = argument
+            .map(CString::new)
+            .transpose()
+            .map_err(|_| PluginError::BadArgument)?;
+        // A class with a NUL byte in it is passed as none
+        let window_class = window_class.and_then(|class| CString::new(class).ok());
+        let context = PluginContext {
+            window,
+            window_class: window_class
+                .as_ref()
+                .map_or(std::ptr::null(), |class| class.as_ptr()),
+        };
+        let argument = argument
+            .as_ref()
+            .map_or(std::ptr::null(), |argument| argument.as_ptr());
+        match run(argument, &context) {
+            0 => Ok(()),
+            code => Err(PluginError::Failed(code)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+
+    /// The argument, window and class of a call.
+    type Call = (Option<String>, Window, Option<String>);
+
+    thread_local! {
+        static CALLS: RefCell<Vec<Call>> =
+            const { RefCell::new(Vec::new()) };
+    }
+
+    fn string(ptr: *const c_char) -> Option<String> {
+        (!ptr.is_null()).then(|| {
+            unsafe { CStr::from_ptr(ptr) }
+                .to_string_lossy()
+                .into_owned()
+        })
+    }
+
+    extern "C" fn record(argument: *const c_char, context: *const PluginContext) -> c_int {
+        let context = unsafe { &*context };
+        let call = (
+            string(argument),
+            context.window,
+            string(context.window_class),
+        );
+        CALLS.with(|calls| calls.borrow_mut().push(call));
+        if string(argument).as_deref() == Some("fail") {
+            3
+        } else {
+            0
+        }
+    }
+
+    fn linked(name: &CStr, abi_version: u32) -> Result<Plugin, PluginError> {
+        let info = PluginInfo {
+            abi_version,
+            name: name.as_ptr(),
+            run: Some(record),
+        };
+        unsafe { Plugin::from_info(std::ptr::null_mut(), &info) }
+    }
+
+    #[test]
+    fn test_run_plugin() {
+        let mut plugins = Plugins::default();
+        plugins.add(linked(c"osc52", ABI_VERSION).unwrap(), Path::new("a.so"));
+        plugins.add(linked(c"osc52", ABI_VERSION).unwrap(), Path::new("b.so"));
+        assert_eq!(plugins.plugins.len(), 1);
+        assert!(plugins.contains("osc52"));
+
+        plugins
+            .run("osc52", Some("text"), 42, Some("kitty"))
+            .unwrap();
+        plugins.run("osc52", None, 7, None).unwrap();
+        assert!(matches!(
+            plugins.run("osc52", Some("fail"), 7, None),
+            Err(PluginError::Failed(3))
+        ));
+        assert!(matches!(
+            plugins.run("ydotool", None, 7, None),
+            Err(PluginError::Unknown(_))
+        ));
+        CALLS.with(|calls| {
+            assert_eq!(
+                calls.borrow()[..2],
+                [
+                    (Some("text".to_string()), 42, Some("kitty".to_string())),
+                    (None, 7, None),
+                ]
+            );
+        });
+    }
+
+    #[test]
+    fn test_refuse_plugin() {
+        assert!(matches!(
+            linked(c"osc52", ABI_VERSION + 1),
+            Err(PluginError::Abi(v)) if v == ABI_VERSION + 1
+        ));
+        let info = PluginInfo {
+            abi_version: ABI_VERSION,
+            name: c"osc52".as_ptr(),
+            run: None,
+        };
+        assert!(matches!(
+            unsafe { Plugin::from_info(std::ptr::null_mut(), &info) },
+            Err(PluginError::NoRun)
+        ));
+        assert!(matches!(
+            Plugin::open(Path::new("/nonexistent/plugin.so")),
+            Err(PluginError::Load(_))
+        ));
+    }
+}
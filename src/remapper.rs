@@ -0,0 +1,794 @@
+use crate::config::Config;
+use crate::control::ControlSocket;
+use crate::display::{intern_atom, root_windows, DisplayHandle};
+use crate::dump::Dump;
+use crate::event_handler::EventHandler;
+use crate::explain::Explanation;
+use crate::key_mapper::KeyParser;
+use crate::notify;
+use crate::panic_guard;
+use crate::state::State;
+use crate::stats::Stats;
+use crate::status::Status;
+use crate::x_error::{self, XError};
+use log::{debug, info, warn};
+use std::collections::BTreeMap;
+use std::ffi::CString;
+use std::io;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use x11::xlib::{self, Atom, Window, XClientMessageEvent, XEvent};
+
+#[derive(Debug, Error)]
+pub enum RemapperError {
+    #[error("no config given to the remapper builder")]
+    MissingConfig,
+    #[error("failed to open X display {0}")]
+    OpenDisplay(String),
+    #[error("failed to listen on control socket {0}")]
+    ControlSocket(PathBuf, #[source] io::Error),
+}
+
+/// Configures a [`Remapper`]. Only the config is required; the display
+/// defaults to `$DISPLAY`.
+#[derive(Debug, Default)]
+pub struct RemapperBuilder {
+    config: Option<Config>,
+    display_names: Vec<String>,
+    control_socket: Option<PathBuf>,
+    trial: Option<Duration>,
+    state_file: Option<PathBuf>,
+}
+
+/// The key that keeps a config on trial.
+pub const CONFIRM_KEY: &str = "C-M-Return";
+
+impl RemapperBuilder {
+    pub fn config(mut self, config: Config) -> Self {
+        self.config = Some(config);
+        self
+    }
+
+    /// X display to connect to, e.g. `:1`. Call it again to remap several
+    /// displays from one process, each with its own grabs and focus.
+    pub fn display(mut self, name: impl Into<String>) -> Self {
+        self.display_names.push(name.into());
+        self
+    }
+
+    /// Answer commands like `stats` on a Unix socket at `path`, see
+    /// [`crate::control`].
+    pub fn control_socket(mut self, path: impl Into<PathBuf>) -> Self {
+        self.control_socket = Some(path.into());
+        self
+    }
+
+    /// Keep whether remapping is paused, the rules enabled or disabled at
+    /// runtime and the flags toggled on in `path`, see [`crate::state`].
+    /// What's saved there is restored first.
+    pub fn state_file(mut self, path: impl Into<PathBuf>) -> Self {
+        self.state_file = Some(path.into());
+        self
+    }
+
+    /// Run the config on trial: unless it's confirmed within `revert_after`,
+    /// with [`CONFIRM_KEY`] or the `confirm` command, the remapper
+    /// stops and releases everything, so a config that breaks the keyboard
+    /// can't lock anyone out.
+    pub fn trial(mut self, revert_after: Duration) -> Self {
+        self.trial = Some(revert_after);
+        self
+    }
+
+    /// Connects to the X servers and grabs the keys for the focused windows.
+    pub fn build(self) -> Result<Remapper, RemapperError> {
+        let config = self.config.ok_or(RemapperError::MissingConfig)?;
+        let mut names = self.display_names;
+        if names.is_empty() {
+            names.push(std::env::var("DISPLAY").unwrap_or_default());
+        }
+        let mut remapper = Remapper::open(config, &names)?;
+        if let Some(path) = self.control_socket {
+            let socket = ControlSocket::bind(&path)
+                .map_err(|e| RemapperError::ControlSocket(path.clone(), e))?;
+            info!("Listening for commands on {}", path.display());
+            remapper.control = Some(socket);
+        }
+        if let Some(path) = self.state_file {
+            remapper.restore_state(State::load(&path));
+            remapper.state_file = Some(path);
+        }
+        if let Some(revert_after) = self.trial {
+            remapper.start_trial(None, revert_after);
+        }
+        Ok(remapper)
+    }
+
+    /// Shorthand for `build()` followed by [`Remapper::run`].
+    pub fn run(self) -> Result<(), RemapperError> {
+        self.build()?.run()
+    }
+}
+
+/// The remapping engine: X connections plus the key grabs and handlers for
+/// their focused windows, kept up to date as focus and layout change.
+///
+/// ```no_run
+/// use simple_x11_remapper::{Config, Remapper};
+///
+/// let config = Config::from_yaml("windows: [{remaps: [{'C-b': 'Left'}]}]").unwrap();
+/// Remapper::builder().config(config).run().unwrap();
+/// ```
+pub struct Remapper {
+    sessions: Vec<Session>,
+    shutdown: ShutdownHandle,
+    control: Option<ControlSocket>,
+    trial: Option<Trial>,
+    /// Where the runtime state is saved, if anywhere, and what was last.
+    state_file: Option<PathBuf>,
+    saved_state: State,
+    /// Rules enabled or disabled at runtime, by name, kept over reloads.
+    rule_overrides: BTreeMap<String, bool>,
+}
+
+/// A config on trial, switched away from unless it's confirmed in time.
+struct Trial {
+    deadline: Instant,
+    /// The config to go back to; `None` to stop the remapper instead, when
+    /// it started with the one on trial.
+    previous: Option<Config>,
+}
+
+/// One display and the event handler driving it.
+struct Session {
+    // Declared before the display so it's dropped first: dropping it
+    // ungrabs the keys and restores the keyboard mapping over the connection
+    event_handler: EventHandler,
+    display: DisplayHandle,
+}
+
+/// Stops a running [`Remapper`] from another thread, e.g. a signal handler
+/// thread. [`Remapper::run`] returns once the request arrives, and dropping
+/// the remapper releases everything it grabbed.
+#[derive(Debug, Clone)]
+pub struct ShutdownHandle {
+    requested: Arc<AtomicBool>,
+    display_name: CString,
+    window: Window,
+    atom: Atom,
+}
+
+impl ShutdownHandle {
+    pub fn shutdown(&self) {
+        self.requested.store(true, Ordering::SeqCst);
+
+        // Wake the event loop, which is blocked waiting for X events
+        let Some(display) = DisplayHandle::open(&self.display_name) else {
+            warn!("Failed to open X display to deliver shutdown request");
+            return;
+        };
+        unsafe {
+            let mut event: XClientMessageEvent = std::mem::zeroed();
+            event.type_ = xlib::ClientMessage;
+            event.window = self.window;
+            event.message_type = self.atom;
+            event.format = 32;
+            xlib::XSendEvent(
+                display.as_ptr(),
+                self.window,
+                xlib::False,
+                0,
+                &mut event as *mut XClientMessageEvent as *mut XEvent,
+            );
+            xlib::XFlush(display.as_ptr());
+        }
+    }
+
+    fn is_requested(&self) -> bool {
+        self.requested.load(Ordering::SeqCst)
+    }
+}
+
+impl Remapper {
+    pub fn builder() -> RemapperBuilder {
+        RemapperBuilder::default()
+    }
+
+    fn open(config: Config, names: &[String]) -> Result<Self, RemapperError> {
+        x_error::install_handler();
+        panic_guard::install();
+
+        let sessions = names
+            .iter()
+            .map(|name| Session::open(config.clone(), name))
+            .collect::<Result<Vec<_>, _>>()?;
+
+        let display = sessions[0].display.as_ptr();
+        let shutdown = unsafe {
+            // An unmapped window of our own for the shutdown message to target;
+            // events sent to it with no mask go to the client that created it
+            let root = xlib::XDefaultRootWindow(display);
+            ShutdownHandle {
+                requested: Arc::new(AtomicBool::new(false)),
+                display_name: DisplayHandle::name_of(display),
+                window: xlib::XCreateSimpleWindow(display, root, 0, 0, 1, 1, 0, 0, 0),
+                atom: intern_atom(display, c"_SIMPLE_X11_REMAPPER_SHUTDOWN", false),
+            }
+        };
+
+        Ok(Self {
+            sessions,
+            shutdown,
+            control: None,
+            trial: None,
+            state_file: None,
+            saved_state: State::default(),
+            rule_overrides: BTreeMap::new(),
+        })
+    }
+
+    /// A handle that makes [`Remapper::run`] return, usable from any thread.
+    pub fn shutdown_handle(&self) -> ShutdownHandle {
+        self.shutdown.clone()
+    }
+
+    /// Processes X events from all displays until shutdown is requested
+    /// through a [`ShutdownHandle`].
+    pub fn run(&mut self) -> Result<(), RemapperError> {
+        while !self.shutdown.is_requested() {
+            for session in &mut self.sessions {
+                panic_guard::catch(session.display.as_ptr(), || {
+                    session.process_pending();
+                    session.event_handler.handle_recorded_keys();
+                    session.event_handler.handle_timers();
+                });
+            }
+            for x_error in x_error::take_pending() {
+                for session in &mut self.sessions {
+                    session.handle_x_error(&x_error);
+                }
+            }
+            self.handle_control_requests();
+            self.handle_trial();
+            self.save_state();
+            if !self.shutdown.is_requested() {
+                self.wait_for_events();
+            }
+        }
+        info!("Shutdown requested, stopping event loop");
+        Ok(())
+    }
+
+    /// Remap hits since startup, summed over all displays.
+    pub fn stats(&self) -> Stats {
+        let mut stats = Stats::default();
+        for session in &self.sessions {
+            stats.merge(&session.event_handler.stats());
+        }
+        stats
+    }
+
+    /// Focus, layout and grab conflicts of every display.
+    pub fn status(&self) -> Status {
+        Status {
+            displays: self
+                .sessions
+                .iter()
+                .map(|session| session.event_handler.status())
+                .collect(),
+        }
+    }
+
+    /// The config and what of it applies on every display.
+    pub fn dump(&self) -> Dump {
+        Dump {
+            config: self.sessions[0].event_handler.config().clone(),
+            displays: self
+                .sessions
+                .iter()
+                .map(|session| session.event_handler.dump())
+                .collect(),
+        }
+    }
+
+    /// What `key` does in the focused window of every display.
+    pub fn explain(&self, key: &str) -> Explanation {
+        Explanation {
+            displays: self
+                .sessions
+                .iter()
+                .map(|session| session.event_handler.explain(key))
+                .collect(),
+        }
+    }
+
+    /// Pauses or resumes remapping on every display.
+    pub fn set_paused(&mut self, paused: bool) {
+        for session in &mut self.sessions {
+            session.event_handler.set_paused(paused);
+        }
+        if self.sessions[0].event_handler.config().notify {
+            let (summary, body) = if paused {
+                ("Remapping paused", "Keys reach applications as typed")
+            } else {
+                ("Remapping resumed", "Remaps apply again")
+            };
+            notify::send(summary, body);
+        }
+    }
+
+    /// Reads the config again from the file it was loaded from and switches
+    /// every display to it. The old config stays when the new one is invalid.
+    pub fn reload(&mut self) -> anyhow::Result<()> {
+        let path = self.sessions[0]
+            .event_handler
+            .config()
+            .path
+            .clone()
+            .ok_or_else(|| anyhow::anyhow!("the config wasn't loaded from a file"))?;
+        let adhoc = self.sessions[0].event_handler.config().adhoc.clone();
+        let config = Config::load_with_adhoc(&path, adhoc)?;
+        info!("Reloaded config from {}", path.display());
+        let notify = config.notify;
+        self.set_config(config);
+        if notify {
+            notify::send("Config reloaded", &path.display().to_string());
+        }
+        Ok(())
+    }
+
+    /// Switches every display to `config`, with the rules enabled or
+    /// disabled at runtime kept that way.
+    fn set_config(&mut self, config: Config) {
+        for session in &mut self.sessions {
+            session.event_handler.set_config(config.clone());
+            for (name, enabled) in &self.rule_overrides {
+                session.event_handler.set_rule_enabled(name, *enabled);
+            }
+        }
+    }
+
+    /// The state [`RemapperBuilder::state_file`] keeps: the flags are those
+    /// on on any display.
+    fn state(&self) -> State {
+        let mut flags: Vec<String> = self
+            .sessions
+            .iter()
+            .flat_map(|session| session.event_handler.flags())
+            .cloned()
+            .collect();
+        flags.sort();
+        flags.dedup();
+        State {
+            paused: self.sessions[0].event_handler.is_paused(),
+            rules: self.rule_overrides.clone(),
+            flags,
+        }
+    }
+
+    /// Applies a saved state, quietly: rules the config no longer has are
+    /// forgotten.
+    fn restore_state(&mut self, state: State) {
+        if state != State::default() {
+            info!(
+                "Restoring state: paused={}, rules={:?}, flags={:?}",
+                state.paused, state.rules, state.flags
+            );
+        }
+        for session in &mut self.sessions {
+            session.event_handler.set_flags(state.flags.clone());
+            session.event_handler.set_paused(state.paused);
+        }
+        if state.paused {
+            warn!("Remapping stays paused as it was when the remapper stopped, until resumed");
+        }
+        for (name, enabled) in state.rules {
+            let mut found = false;
+            for session in &mut self.sessions {
+                found |= session.event_handler.set_rule_enabled(&name, enabled);
+            }
+            if found {
+                self.rule_overrides.insert(name, enabled);
+            }
+        }
+        self.saved_state = self.state();
+    }
+
+    /// Writes the state to the state file if it changed since last time.
+    fn save_state(&mut self) {
+        let Some(path) = &self.state_file else {
+            return;
+        };
+        let state = self.state();
+        if state == self.saved_state {
+            return;
+        }
+        if let Err(e) = state.save(path) {
+            warn!("Can't save state to {}: {}", path.display(), e);
+        }
+        self.saved_state = state;
+    }
+
+    /// Switches every display to `config` on trial: unless
+    /// [confirmed](Self::confirm) within `revert_after`, the config before
+    /// it comes back. Trying another meanwhile restarts the clock but still
+    /// goes back to the config from before the first.
+    pub fn try_config(&mut self, config: Config, revert_after: Duration) {
+        let previous = match self.trial.take() {
+            Some(trial) => trial.previous,
+            None => Some(self.sessions[0].event_handler.config().clone()),
+        };
+        self.set_config(config);
+        self.start_trial(previous, revert_after);
+    }
+
+    fn start_trial(&mut self, previous: Option<Config>, revert_after: Duration) {
+        let outcome = if previous.is_some() {
+            "the previous config comes back"
+        } else {
+            "the remapper stops"
+        };
+        info!(
+            "Trying the config for {} s; unless {} is pressed or `confirm` sent, {}",
+            revert_after.as_secs(),
+            CONFIRM_KEY,
+            outcome
+        );
+        for session in &mut self.sessions {
+            session
+                .event_handler
+                .set_confirm_key(Some(CONFIRM_KEY.to_string()));
+        }
+        if self.sessions[0].event_handler.config().notify {
+            notify::send(
+                "Config on trial",
+                &format!(
+                    "Press {} within {} s to keep it, or {}",
+                    CONFIRM_KEY,
+                    revert_after.as_secs(),
+                    outcome
+                ),
+            );
+        }
+        self.trial = Some(Trial {
+            deadline: Instant::now() + revert_after,
+            previous,
+        });
+    }
+
+    /// Keeps the config on trial. Returns whether there was one.
+    pub fn confirm(&mut self) -> bool {
+        if self.trial.take().is_none() {
+            return false;
+        }
+        info!("Config confirmed");
+        for session in &mut self.sessions {
+            session.event_handler.set_confirm_key(None);
+        }
+        if self.sessions[0].event_handler.config().notify {
+            notify::send("Config confirmed", "It stays in use");
+        }
+        true
+    }
+
+    /// Confirms the trial if its key was pressed, or ends it if its time
+    /// is up.
+    fn handle_trial(&mut self) {
+        let mut confirmed = false;
+        for session in &mut self.sessions {
+            confirmed |= session.event_handler.take_confirmed();
+        }
+        if confirmed {
+            self.confirm();
+            return;
+        }
+        let Some(trial) = self.trial.take_if(|trial| trial.deadline <= Instant::now()) else {
+            return;
+        };
+        let notify = self.sessions[0].event_handler.config().notify;
+        match trial.previous {
+            Some(previous) => {
+                warn!("Config on trial wasn't confirmed, going back to the previous one");
+                for session in &mut self.sessions {
+                    session.event_handler.set_confirm_key(None);
+                }
+                self.set_config(previous);
+                if notify {
+                    notify::send("Config reverted", "The one on trial wasn't confirmed");
+                }
+            }
+            None => {
+                warn!("Config on trial wasn't confirmed, stopping");
+                if notify {
+                    notify::send("Remapper stopped", "The config on trial wasn't confirmed");
+                }
+                self.shutdown.requested.store(true, Ordering::SeqCst);
+            }
+        }
+    }
+
+    /// Enables or disables the window rules called `name` on every display.
+    /// Returns whether the config has any.
+    pub fn set_rule_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        let mut found = false;
+        for session in &mut self.sessions {
+            found |= session.event_handler.set_rule_enabled(name, enabled);
+        }
+        if found {
+            self.rule_overrides.insert(name.to_string(), enabled);
+        }
+        if found && self.sessions[0].event_handler.config().notify {
+            let (state, body) = if enabled {
+                ("enabled", "Its remaps apply in matching windows again")
+            } else {
+                ("disabled", "Its remaps are off until it's enabled")
+            };
+            notify::send(&format!("Rule '{}' {}", name, state), body);
+        }
+        found
+    }
+
+    fn handle_control_requests(&mut self) {
+        let Some(control) = self.control.take() else {
+            return;
+        };
+        control.serve(|command| self.handle_command(command));
+        self.control = Some(control);
+    }
+
+    fn handle_command(&mut self, command: &str) -> String {
+        let (name, arg) = match command.split_once(' ') {
+            Some((name, arg)) => (name, arg.trim()),
+            None => (command, ""),
+        };
+        match (name, arg) {
+            ("ping", "") => "pong\n".to_string(),
+            ("stats", "") => self.stats().to_string(),
+            ("status", "") => self.status().to_string(),
+            ("rules", "") => self.rules(),
+            ("dump", "") => match serde_json::to_string_pretty(&self.dump()) {
+                Ok(json) => json + "\n",
+                Err(e) => format!("error: {}\n", e),
+            },
+            ("pause" | "resume", "") => {
+                self.set_paused(name == "pause");
+                format!("{}d\n", name)
+            }
+            ("reload", "") => match self.reload() {
+                Ok(()) => "reloaded\n".to_string(),
+                Err(e) => format!("error: {:#}\n", e),
+            },
+            ("try", arg) if !arg.is_empty() => self.handle_try(arg),
+            ("confirm", "") => {
+                if self.confirm() {
+                    "confirmed\n".to_string()
+                } else {
+                    "error: no config is on trial\n".to_string()
+                }
+            }
+            ("explain", key) if !key.is_empty() => {
+                let parser = KeyParser::new();
+                let invalid = match KeyParser::split_chord(key) {
+                    Some((first, second)) => parser.parse(first).and(parser.parse(second)).err(),
+                    None => parser.parse(key).err(),
+                };
+                match invalid {
+                    Some(e) => format!("error: invalid key expression '{}': {}\n", key, e),
+                    None => self.explain(key).to_string(),
+                }
+            }
+            ("enable" | "disable", rule) if !rule.is_empty() => {
+                if self.set_rule_enabled(rule, name == "enable") {
+                    format!("{}d rule '{}'\n", name, rule)
+                } else {
+                    format!("error: no rule named '{}'\n", rule)
+                }
+            }
+            _ => format!("error: unknown command '{}'\n", command),
+        }
+    }
+
+    /// `try SECONDS PATH`: loads the config at `PATH`, with the remaps
+    /// given on the command line, and tries it for `SECONDS`.
+    fn handle_try(&mut self, arg: &str) -> String {
+        let Some((seconds, path)) = arg
+            .split_once(' ')
+            .and_then(|(seconds, path)| Some((seconds.parse().ok()?, path.trim())))
+        else {
+            return "error: expected try SECONDS PATH\n".to_string();
+        };
+        let adhoc = self.sessions[0].event_handler.config().adhoc.clone();
+        let config = Config::load_with_adhoc(Path::new(path), adhoc);
+        match config {
+            Ok(config) => {
+                self.try_config(config, Duration::from_secs(seconds));
+                format!(
+                    "trying {} for {} s, press {} or run `confirm` to keep it\n",
+                    path, seconds, CONFIRM_KEY
+                )
+            }
+            Err(e) => format!("error: {:#}\n", e),
+        }
+    }
+
+    /// The named window rules and whether they're enabled, one per line.
+    fn rules(&self) -> String {
+        let config = self.sessions[0].event_handler.config();
+        config
+            .windows
+            .iter()
+            .filter_map(|rule| {
+                let state = if rule.enabled { "enabled" } else { "disabled" };
+                rule.name
+                    .as_ref()
+                    .map(|name| format!("{:<8}  {}\n", state, name))
+            })
+            .collect()
+    }
+
+    /// The last X errors the servers reported, oldest first.
+    pub fn recent_errors(&self) -> Vec<XError> {
+        x_error::recent()
+    }
+
+    /// Blocks until one of the X connections, XRecord connections or the
+    /// control socket has data to read or the nearest timer of any event handler is due.
+    fn wait_for_events(&self) {
+        let now = Instant::now();
+        let timeout_ms = self
+            .sessions
+            .iter()
+            .filter_map(|session| session.event_handler.next_timeout())
+            .chain(
+                self.trial
+                    .iter()
+                    .map(|trial| trial.deadline.saturating_duration_since(now)),
+            )
+            .min()
+            // Round up so a sub-millisecond wait doesn't spin on a zero timeout
+            .map_or(-1, |timeout| {
+                timeout.as_micros().div_ceil(1000).min(i32::MAX as u128) as i32
+            });
+
+        let mut fds: Vec<libc::pollfd> = self
+            .sessions
+            .iter()
+            .map(|session| unsafe { xlib::XConnectionNumber(session.display.as_ptr()) })
+            .chain(
+                self.sessions
+                    .iter()
+                    .flat_map(|session| session.event_handler.recorder_fds()),
+            )
+            .chain(self.control.iter().map(|control| control.fd()))
+            .map(|fd| libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            })
+            .collect();
+        // EINTR from a signal just means another round through the loop
+        unsafe {
+            libc::poll(fds.as_mut_ptr(), fds.len() as libc::nfds_t, timeout_ms);
+        }
+    }
+}
+
+impl Drop for Session {
+    fn drop(&mut self) {
+        panic_guard::unregister(self.display.as_ptr());
+    }
+}
+
+impl Session {
+    fn open(config: Config, name: &str) -> Result<Self, RemapperError> {
+        let c_name =
+            CString::new(name).map_err(|_| RemapperError::OpenDisplay(name.to_string()))?;
+        let display = DisplayHandle::open(&c_name)
+            .ok_or_else(|| RemapperError::OpenDisplay(name.to_string()))?;
+        info!("Successfully opened X display {}", name);
+        panic_guard::register(display.as_ptr());
+
+        unsafe {
+            for root in root_windows(display.as_ptr()) {
+                xlib::XSelectInput(
+                    display.as_ptr(),
+                    root,
+                    xlib::KeyPressMask | xlib::PropertyChangeMask | xlib::SubstructureNotifyMask,
+                );
+            }
+        }
+
+        let mut event_handler = EventHandler::new(display.as_ptr(), config);
+        event_handler.initialize();
+
+        Ok(Self {
+            event_handler,
+            display,
+        })
+    }
+
+    /// Handles every event already received or buffered by Xlib.
+    fn process_pending(&mut self) {
+        let display = self.display.as_ptr();
+        let mut event: XEvent = unsafe { std::mem::zeroed() };
+        loop {
+            while unsafe { xlib::XPending(display) } > 0 {
+                unsafe { xlib::XNextEvent(display, &mut event) };
+                self.handle_event(&event);
+            }
+            // Window changes are applied once per batch. Their round trips
+            // may read more events into Xlib's queue, where poll can't see them.
+            self.event_handler.apply_window_changes();
+            if unsafe { xlib::XQLength(display) } == 0 {
+                break;
+            }
+        }
+    }
+
+    fn handle_x_error(&mut self, x_error: &XError) {
+        if x_error.display == self.display.as_ptr() as usize {
+            self.event_handler.handle_x_error(x_error);
+        }
+    }
+
+    fn handle_event(&mut self, event: &XEvent) {
+        let event_handler = &mut self.event_handler;
+        match event.get_type() {
+            xlib::KeyPress => {
+                let key_event = unsafe { event.key };
+                debug!(
+                    "KeyPress: keycode={}, state={}",
+                    key_event.keycode, key_event.state
+                );
+                event_handler.handle_key_press(
+                    key_event.keycode as u8,
+                    key_event.state,
+                    key_event.time,
+                );
+            }
+            xlib::KeyRelease => {
+                let key_event = unsafe { event.key };
+                debug!("KeyRelease: keycode={}", key_event.keycode);
+                event_handler.handle_key_release(key_event.keycode as u8, key_event.time);
+            }
+            xlib::PropertyNotify => {
+                let property_event = unsafe { event.property };
+                debug!("PropertyNotify event: atom={}", property_event.atom);
+                event_handler.handle_property_notify(property_event.atom);
+            }
+            xlib::FocusIn | xlib::FocusOut => {
+                debug!("Focus change event");
+                event_handler.handle_focus_change();
+            }
+            xlib::ConfigureNotify => {
+                event_handler.handle_configure_notify();
+            }
+            xlib::MappingNotify => {
+                debug!("MappingNotify event");
+                let mut mapping = unsafe { event.mapping };
+                event_handler.handle_mapping_notify(&mut mapping);
+            }
+            xlib::ClientMessage => {
+                let client_event = unsafe { event.client_message };
+                debug!(
+                    "ClientMessage: type={}, format={}",
+                    client_event.message_type, client_event.format
+                );
+            }
+            _ if event_handler.is_hotplug_event(event) => {
+                debug!("XInput2 event");
+                event_handler.handle_hotplug_event(event);
+            }
+            _ if event_handler.is_xkb_event(event) => {
+                debug!("XKB event");
+                event_handler.handle_xkb_event(event);
+            }
+            _ => {
+                debug!("Unhandled event type: {}", event.get_type());
+            }
+        }
+    }
+}
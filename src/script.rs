@@ -0,0 +1,112 @@
+use crate::key_mapper::KeyMapper;
+use log::warn;
+use std::io::Write;
+use std::path::Path;
+use std::process::{Command, Stdio};
+use std::rc::Rc;
+use thiserror::Error;
+use x11::xlib::Window;
+
+/// What a script can see and act on when its remap fires.
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+pub struct ScriptContext {
+    pub key_mapper: Rc<KeyMapper>,
+    pub window: Window,
+    pub window_class: Option<String>,
+}
+
+#[derive(Debug, Error)]
+pub enum ScriptError {
+    #[cfg(feature = "scripting")]
+    #[error("{0}")]
+    Rhai(#[from] Box<rhai::EvalAltResult>),
+    #[cfg(not(feature = "scripting"))]
+    #[error("built without the `scripting` feature")]
+    Disabled,
+}
+
+/// Runs a Rhai script with `send_key`, `send_keys`, `window_class`,
+/// `clipboard_get` and `clipboard_set` available. The script is read on every
+/// run so edits take effect without restarting.
+///
+/// Returns `false` when the script evaluates to `false`, which tells a
+/// conditional remap to pass the original key on.
+#[cfg(feature = "scripting")]
+pub fn run(path: &Path, context: ScriptContext) -> Result<bool, ScriptError> {
+    let context = Rc::new(context);
+    let mut engine = rhai::Engine::new();
+
+    let ctx = context.clone();
+    engine.register_fn("send_key", move |key: &str| {
+        if let Some((keysym, mods)) = ctx.key_mapper.parse_key(key) {
+            ctx.key_mapper.send_key(ctx.window, keysym, mods);
+        }
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("send_keys", move |keys: rhai::Array| {
+        let keys: Vec<String> = keys
+            .into_iter()
+            .filter_map(|k| match k.into_string() {
+                Ok(key) => Some(key),
+                Err(type_name) => {
+                    warn!("send_keys expects strings, got {}", type_name);
+                    None
+                }
+            })
+            .collect();
+        ctx.key_mapper.send_key_sequence(ctx.window, &keys);
+    });
+
+    let ctx = context.clone();
+    engine.register_fn("window_class", move || {
+        ctx.window_class.clone().unwrap_or_default()
+    });
+
+    engine.register_fn("clipboard_get", clipboard_get);
+    engine.register_fn("clipboard_set", clipboard_set);
+
+    let result = engine.eval_file::<rhai::Dynamic>(path.to_path_buf())?;
+    Ok(result.as_bool().unwrap_or(true))
+}
+
+#[cfg(not(feature = "scripting"))]
+pub fn run(_path: &Path, _context: ScriptContext) -> Result<bool, ScriptError> {
+    Err(ScriptError::Disabled)
+}
+
+// The clipboard helpers shell out to xclip rather than owning the selection,
+// which would require answering SelectionRequest events from the main loop.
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+fn clipboard_get() -> String {
+    match Command::new("xclip")
+        .args(["-selection", "clipboard", "-o"])
+        .output()
+    {
+        Ok(output) => String::from_utf8_lossy(&output.stdout).into_owned(),
+        Err(e) => {
+            warn!("Failed to run xclip: {}", e);
+            String::new()
+        }
+    }
+}
+
+#[cfg_attr(not(feature = "scripting"), allow(dead_code))]
+fn clipboard_set(text: &str) {
+    let child = Command::new("xclip")
+        .args(["-selection", "clipboard", "-i"])
+        .stdin(Stdio::piped())
+        .spawn();
+
+    match child {
+        Ok(mut child) => {
+            if let Some(mut stdin) = child.stdin.take() {
+                if let Err(e) = stdin.write_all(text.as_bytes()) {
+                    warn!("Failed to write to xclip: {}", e);
+                }
+            }
+            let _ = child.wait();
+        }
+        Err(e) => warn!("Failed to run xclip: {}", e),
+    }
+}
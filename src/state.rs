@@ -0,0 +1,113 @@
+//! Runtime state kept across restarts: whether remapping is paused, the
+//! named rules turned on or off with `enable`/`disable`, and the flags
+//! `{toggle: flag}` keys turned on. It's saved as JSON whenever it changes,
+//! so a crash or a new session doesn't bring back bindings that were off.
+
+use log::warn;
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct State {
+    #[serde(default)]
+    pub paused: bool,
+    /// Rules enabled or disabled at runtime, by name, overriding the
+    /// config's `enabled`.
+    #[serde(default)]
+    pub rules: BTreeMap<String, bool>,
+    /// The flags toggled on.
+    #[serde(default)]
+    pub flags: Vec<String>,
+}
+
+/// `$XDG_STATE_HOME/simple-x11-remapper/state.json`, or the same under
+/// `~/.local/state`.
+pub fn default_path() -> Option<PathBuf> {
+    state_path(
+        std::env::var_os("XDG_STATE_HOME").map(PathBuf::from),
+        std::env::var_os("HOME").map(PathBuf::from),
+    )
+}
+
+fn state_path(state_home: Option<PathBuf>, home: Option<PathBuf>) -> Option<PathBuf> {
+    let dir = state_home
+        .filter(|dir| dir.is_absolute())
+        .or_else(|| home.map(|home| home.join(".local").join("state")))?;
+    Some(dir.join("simple-x11-remapper").join("state.json"))
+}
+
+impl State {
+    /// The state saved at `path`. Without a file there, or with one that
+    /// doesn't parse, nothing was saved and the default state applies.
+    pub fn load(path: &Path) -> Self {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Self::default(),
+            Err(e) => {
+                warn!("Can't read state file {}: {}", path.display(), e);
+                return Self::default();
+            }
+        };
+        serde_json::from_str(&content).unwrap_or_else(|e| {
+            warn!("Ignoring invalid state file {}: {}", path.display(), e);
+            Self::default()
+        })
+    }
+
+    /// Writes the state to `path`, through a temporary file renamed over
+    /// it so a crash midway leaves the old state rather than half of one.
+    pub fn save(&self, path: &Path) -> io::Result<()> {
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut temp = path.as_os_str().to_owned();
+        temp.push(".tmp");
+        fs::write(&temp, serde_json::to_string_pretty(self)? + "\n")?;
+        fs::rename(&temp, path)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_path() {
+        let home = Some(PathBuf::from("/home/me"));
+        assert_eq!(
+            state_path(Some(PathBuf::from("/state")), home.clone()),
+            Some(PathBuf::from("/state/simple-x11-remapper/state.json"))
+        );
+        assert_eq!(
+            state_path(Some(PathBuf::from("relative")), home),
+            Some(PathBuf::from(
+                "/home/me/.local/state/simple-x11-remapper/state.json"
+            ))
+        );
+        assert_eq!(state_path(None, None), None);
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("remapper-state-{}", std::process::id()));
+        let path = dir.join("nested").join("state.json");
+        assert_eq!(State::load(&path), State::default());
+
+        let state = State {
+            paused: true,
+            rules: [("emacs-keys".to_string(), false)].into(),
+            flags: vec!["resize".to_string()],
+        };
+        state.save(&path).unwrap();
+        assert_eq!(State::load(&path), state);
+
+        fs::write(&path, "{not json").unwrap();
+        assert_eq!(State::load(&path), State::default());
+        fs::write(&path, r#"{"paused": true}"#).unwrap();
+        assert!(State::load(&path).paused);
+        fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,124 @@
+use crate::config::{Config, RuleId};
+use std::collections::BTreeMap;
+use std::fmt;
+
+/// How often each remap fired and in which window classes, to find the
+/// bindings a large config never uses.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Stats {
+    /// Hits by rule and `from` key.
+    remaps: BTreeMap<(RuleId, String), u64>,
+    /// Remap hits by the class of the focused window.
+    classes: BTreeMap<String, u64>,
+}
+
+impl Stats {
+    /// Stats with every remap of `config` at zero hits, so unused ones show up.
+    pub fn new(config: &Config) -> Self {
+        let remaps = config
+            .all_remaps()
+            .map(|(rule, remap)| ((rule, remap.from.clone()), 0))
+            .collect();
+        Self {
+            remaps,
+            classes: BTreeMap::new(),
+        }
+    }
+
+    pub fn record(&mut self, rule: RuleId, from: &str, window_class: Option<&str>) {
+        *self.remaps.entry((rule, from.to_string())).or_default() += 1;
+        let class = window_class.unwrap_or("(unknown)");
+        *self.classes.entry(class.to_string()).or_default() += 1;
+    }
+
+    /// Hits of the remap `from` in `rule`.
+    pub fn remap_hits(&self, rule: RuleId, from: &str) -> u64 {
+        self.remaps
+            .get(&(rule, from.to_string()))
+            .copied()
+            .unwrap_or(0)
+    }
+
+    pub fn class_hits(&self, class: &str) -> u64 {
+        self.classes.get(class).copied().unwrap_or(0)
+    }
+
+    /// Adds the counts of `other`, e.g. from another display.
+    pub fn merge(&mut self, other: &Stats) {
+        for (key, hits) in &other.remaps {
+            *self.remaps.entry(key.clone()).or_default() += hits;
+        }
+        for (class, hits) in &other.classes {
+            *self.classes.entry(class.clone()).or_default() += hits;
+        }
+    }
+}
+
+/// Tables sorted by hits, most used first.
+impl fmt::Display for Stats {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let mut remaps: Vec<_> = self.remaps.iter().collect();
+        remaps.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        writeln!(f, "{:>8}  {:>6}  FROM", "HITS", "RULE")?;
+        for ((rule, from), hits) in remaps {
+            writeln!(f, "{:>8}  {:>6}  {}", hits, rule.to_string(), from)?;
+        }
+
+        let mut classes: Vec<_> = self.classes.iter().collect();
+        classes.sort_by(|a, b| b.1.cmp(a.1).then_with(|| a.0.cmp(b.0)));
+        writeln!(f)?;
+        writeln!(f, "{:>8}  CLASS", "HITS")?;
+        for (class, hits) in classes {
+            writeln!(f, "{:>8}  {}", hits, class)?;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_stats_count_and_list_unused_remaps() {
+        let config = Config::from_yaml(
+            "global:
+  - 'C-a': 'Home'
+windows:
+  - class_only: ['firefox']
+    remaps:
+      - 'C-b': 'Left'
+      - 'C-f': 'Right'
+",
+        )
+        .unwrap();
+        let mut stats = Stats::new(&config);
+        stats.record(RuleId::Window(0), "C-b", Some("firefox"));
+        stats.record(RuleId::Window(0), "C-b", Some("firefox"));
+
+        let mut other = Stats::new(&config);
+        other.record(RuleId::Window(0), "C-b", None);
+        stats.merge(&other);
+
+        assert_eq!(stats.remap_hits(RuleId::Window(0), "C-b"), 3);
+        assert_eq!(stats.remap_hits(RuleId::Window(0), "C-f"), 0);
+        assert_eq!(stats.remap_hits(RuleId::Global, "C-a"), 0);
+        assert_eq!(stats.class_hits("firefox"), 2);
+        assert_eq!(stats.class_hits("(unknown)"), 1);
+
+        let table = stats.to_string();
+        let rows: Vec<&str> = table.lines().collect();
+        assert_eq!(
+            rows[1].split_whitespace().collect::<Vec<_>>(),
+            ["3", "0", "C-b"]
+        );
+        assert_eq!(
+            rows[2].split_whitespace().collect::<Vec<_>>(),
+            ["0", "global", "C-a"]
+        );
+        assert_eq!(
+            rows[3].split_whitespace().collect::<Vec<_>>(),
+            ["0", "0", "C-f"]
+        );
+    }
+}
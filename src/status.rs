@@ -0,0 +1,53 @@
+use std::fmt;
+
+/// What a running remapper is doing on each of its displays.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct Status {
+    pub displays: Vec<DisplayStatus>,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DisplayStatus {
+    pub display: String,
+    pub window_class: Option<String>,
+    pub layout: Option<String>,
+    pub grabbed_keys: usize,
+    pub paused: bool,
+    /// Keys in config notation whose grab failed because another client,
+    /// often the window manager or a hotkey daemon, already holds it.
+    pub grab_conflicts: Vec<String>,
+}
+
+impl fmt::Display for Status {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for status in &self.displays {
+            writeln!(f, "Display {}", status.display)?;
+            writeln!(
+                f,
+                "  Window class: {}",
+                status.window_class.as_deref().unwrap_or("(unknown)")
+            )?;
+            writeln!(
+                f,
+                "  Layout: {}",
+                status.layout.as_deref().unwrap_or("(unknown)")
+            )?;
+            writeln!(
+                f,
+                "  Remapping: {}",
+                if status.paused { "paused" } else { "active" }
+            )?;
+            writeln!(f, "  Grabbed keys: {}", status.grabbed_keys)?;
+            if status.grab_conflicts.is_empty() {
+                writeln!(f, "  Grab conflicts: none")?;
+            } else {
+                writeln!(
+                    f,
+                    "  Grab conflicts (held by another client): {}",
+                    status.grab_conflicts.join(", ")
+                )?;
+            }
+        }
+        Ok(())
+    }
+}
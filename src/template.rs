@@ -0,0 +1,742 @@
+//! The templating pass a config goes through before it's parsed, so one
+//! file can serve several machines.
+//!
+//! Lines between `#% if <condition>` and `#% endif` (with `#% elif` and
+//! `#% else` in between) are kept only when the condition holds. They're
+//! YAML comments, so the file stays valid YAML, and dropped lines are left
+//! blank so errors still point at the right line. A condition tests
+//! `hostname` or `env.NAME`: `hostname == laptop`, `env.DISPLAY != ':0'`,
+//! or just `env.NAME` for a variable that's set and not empty, `!` in front
+//! negating it. Values with spaces or `=` in them are quoted.
+//!
+//! Then `${name}` is replaced with the top-level `variables` entry of that
+//! name, `${hostname}` with the host name and `${env.NAME}` with the
+//! environment variable. Values are escaped for the scalar they land in, so
+//! quotes or a `: ` in one can't change the YAML around it, and strings
+//! stay strings. Other `${...}`,
+//! like shell variables in `exec` commands, are left alone with a warning;
+//! `$${` writes a literal `${`.
+
+use crate::config::ConfigError;
+use log::warn;
+use std::collections::BTreeMap;
+
+/// What templates can test and use besides the config's variables.
+#[derive(Debug, Clone, Default)]
+pub struct Environment {
+    pub hostname: String,
+    pub vars: BTreeMap<String, String>,
+}
+
+impl Environment {
+    /// The machine's host name and the process environment.
+    pub fn current() -> Self {
+        Self {
+            hostname: hostname().unwrap_or_default(),
+            vars: std::env::vars().collect(),
+        }
+    }
+
+    /// The value a condition or `${...}` names, if it's a built-in one.
+    fn builtin(&self, name: &str) -> Option<String> {
+        if name == "hostname" {
+            return Some(self.hostname.clone());
+        }
+        let var = name.strip_prefix("env.")?;
+        Some(self.vars.get(var).cloned().unwrap_or_default())
+    }
+}
+
+fn hostname() -> Option<String> {
+    let mut buf = [0u8; 256];
+    if unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) } != 0 {
+        return None;
+    }
+    let len = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+    String::from_utf8(buf[..len].to_vec()).ok()
+}
+
+/// Applies the conditionals and substitutions of `content`.
+pub fn render(content: &str, env: &Environment) -> Result<String, ConfigError> {
+    let content = apply_conditionals(content, env)?;
+    let variables = variables(&content, env)?;
+    Ok(substitute(&content, env, &variables, Quoting::Plain))
+}
+
+/// An `#% if` being read, and whether its lines are kept.
+struct Branch {
+    /// The line of the `if`, for an error if it's never closed.
+    line: usize,
+    /// The lines around it are kept.
+    outer: bool,
+    /// A branch of it was taken already, so later ones aren't.
+    taken: bool,
+    active: bool,
+    /// An `#% else` was seen, so no more branches may follow.
+    done: bool,
+}
+
+fn apply_conditionals(content: &str, env: &Environment) -> Result<String, ConfigError> {
+    let mut branches: Vec<Branch> = Vec::new();
+    let mut lines = Vec::new();
+    for (index, text) in content.lines().enumerate() {
+        let line = index + 1;
+        let error = |message: String| ConfigError {
+            line: Some(line),
+            column: None,
+            message,
+            source_line: Some(text.to_string()),
+        };
+        let active = branches.last().is_none_or(|branch| branch.active);
+        let Some(directive) = text.trim_start().strip_prefix("#%") else {
+            lines.push(if active { text } else { "" });
+            continue;
+        };
+        lines.push("");
+        let directive = directive.trim();
+        let (keyword, condition) = directive
+            .split_once(char::is_whitespace)
+            .map_or((directive, ""), |(keyword, rest)| (keyword, rest.trim()));
+        match keyword {
+            "if" => {
+                let holds = active && evaluate(condition, env).map_err(error)?;
+                branches.push(Branch {
+                    line,
+                    outer: active,
+                    taken: holds,
+                    active: holds,
+                    done: false,
+                });
+            }
+            "elif" | "else" => {
+                let Some(branch) = branches.last_mut().filter(|branch| !branch.done) else {
+                    return Err(error(format!("`#% {}` without an `#% if`", keyword)));
+                };
+                let holds = if keyword == "else" {
+                    branch.done = true;
+                    true
+                } else {
+                    evaluate(condition, env).map_err(error)?
+                };
+                branch.active = branch.outer && !branch.taken && holds;
+                branch.taken |= branch.active;
+            }
+            "endif" => {
+                if branches.pop().is_none() {
+                    return Err(error("`#% endif` without an `#% if`".to_string()));
+                }
+            }
+            other => {
+                return Err(error(format!(
+                    "unknown template directive '{}', expected if, elif, else or endif",
+                    other
+                )))
+            }
+        }
+    }
+    if let Some(branch) = branches.last() {
+        return Err(ConfigError {
+            line: Some(branch.line),
+            column: None,
+            message: "`#% if` without an `#% endif`".to_string(),
+            source_line: content.lines().nth(branch.line - 1).map(str::to_string),
+        });
+    }
+    let mut rendered = lines.join("\n");
+    if content.ends_with('\n') {
+        rendered.push('\n');
+    }
+    Ok(rendered)
+}
+
+/// Whether `condition` holds: `name`, `!name`, `name == value` or
+/// `name != value`.
+fn evaluate(condition: &str, env: &Environment) -> Result<bool, String> {
+    let operand = |name: &str| {
+        env.builtin(name).ok_or_else(|| {
+            format!(
+                "'{}' can't be tested, conditions test hostname or env.NAME",
+                name
+            )
+        })
+    };
+    match tokenize(condition)?[..] {
+        [] => Err("`#% if` needs a condition".to_string()),
+        [Token::Word(name)] => Ok(!operand(name)?.is_empty()),
+        [Token::Not, Token::Word(name)] => Ok(operand(name)?.is_empty()),
+        [Token::Word(name), Token::Equal, Token::Word(value) | Token::Quoted(value)] => {
+            Ok(operand(name)? == value)
+        }
+        [Token::Word(name), Token::NotEqual, Token::Word(value) | Token::Quoted(value)] => {
+            Ok(operand(name)? != value)
+        }
+        _ => Err(format!(
+            "can't read condition '{}', expected name, !name, name == value or name != value",
+            condition
+        )),
+    }
+}
+
+/// A piece of a condition.
+#[derive(Debug, PartialEq)]
+enum Token<'a> {
+    Word(&'a str),
+    /// A `'...'` or `"..."` value, without its quotes.
+    Quoted(&'a str),
+    Equal,
+    NotEqual,
+    Not,
+}
+
+/// Splits `condition` into tokens, reading quoted values first so an `==`
+/// or `!=` inside one is part of the value.
+fn tokenize(condition: &str) -> Result<Vec<Token<'_>>, String> {
+    let mut tokens = Vec::new();
+    let mut rest = condition.trim_start();
+    while let Some(c) = rest.chars().next() {
+        let len = if c == '\'' || c == '"' {
+            let end = rest[1..]
+                .find(c)
+                .ok_or_else(|| format!("unclosed quote in condition '{}'", condition))?;
+            tokens.push(Token::Quoted(&rest[1..end + 1]));
+            end + 2
+        } else if rest.starts_with("==") {
+            tokens.push(Token::Equal);
+            2
+        } else if rest.starts_with("!=") {
+            tokens.push(Token::NotEqual);
+            2
+        } else if c == '!' {
+            tokens.push(Token::Not);
+            1
+        } else {
+            let len = rest
+                .char_indices()
+                .find(|&(i, c)| {
+                    c.is_whitespace()
+                        || c == '\''
+                        || c == '"'
+                        || rest[i..].starts_with("==")
+                        || rest[i..].starts_with("!=")
+                })
+                .map_or(rest.len(), |(i, _)| i);
+            tokens.push(Token::Word(&rest[..len]));
+            len
+        };
+        rest = rest[len..].trim_start();
+    }
+    Ok(tokens)
+}
+
+/// The config's `variables`, with built-ins in their values substituted.
+/// A config that doesn't parse has none; the real parse reports why.
+fn variables(content: &str, env: &Environment) -> Result<BTreeMap<String, Variable>, ConfigError> {
+    let Ok(serde_yaml::Value::Mapping(config)) = serde_yaml::from_str(content) else {
+        return Ok(BTreeMap::new());
+    };
+    let Some(serde_yaml::Value::Mapping(entries)) = config.get("variables") else {
+        return Ok(BTreeMap::new());
+    };
+    let mut variables = BTreeMap::new();
+    for (name, value) in entries {
+        let name = name.as_str().unwrap_or_default();
+        let (value, string) = match value {
+            serde_yaml::Value::String(value) => (value.clone(), true),
+            serde_yaml::Value::Number(value) => (value.to_string(), false),
+            serde_yaml::Value::Bool(value) => (value.to_string(), false),
+            _ => {
+                return Err(ConfigError {
+                    line: None,
+                    column: None,
+                    message: format!("variable '{}' must be a string", name),
+                    source_line: None,
+                })
+            }
+        };
+        let text = substitute(&value, env, &BTreeMap::new(), Quoting::Raw);
+        variables.insert(name.to_string(), Variable { text, string });
+    }
+    Ok(variables)
+}
+
+/// A `variables` entry's value, and whether it was a string rather than a
+/// number or bool.
+struct Variable {
+    text: String,
+    string: bool,
+}
+
+/// What the text at some point of a config is inside of, which decides how
+/// a value substituted there is escaped.
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Quoting {
+    /// Taken as it is: a variable's own value, a comment or a `|` or `>`
+    /// block scalar.
+    Raw,
+    Plain,
+    Single,
+    Double,
+}
+
+/// Follows the quoting of a config's text as it's copied, so values go in
+/// as part of the scalar they're written in and can't end it early.
+struct Scanner {
+    quoting: Quoting,
+    /// A quote here would start a quoted scalar.
+    scalar_start: bool,
+    /// The last character read outside quotes on this line.
+    prev: char,
+    /// Still in the indentation of a line, which is this deep so far.
+    indenting: bool,
+    indent: usize,
+    comment: bool,
+    /// A `'` ended a single-quoted scalar, unless another follows it.
+    closing: bool,
+    /// The next character of a double-quoted scalar is escaped.
+    escaped: bool,
+    /// Open `[` and `{`, in which `,` ends a plain scalar.
+    flow: usize,
+    /// This line's value is a `|` or `>` block scalar's header.
+    block_header: bool,
+    /// The indentation of the line a block scalar is the value of; deeper
+    /// lines are its text.
+    block: Option<usize>,
+}
+
+impl Scanner {
+    fn new(quoting: Quoting) -> Self {
+        Self {
+            quoting,
+            scalar_start: true,
+            prev: '\n',
+            indenting: true,
+            indent: 0,
+            comment: false,
+            closing: false,
+            escaped: false,
+            flow: 0,
+            block_header: false,
+            block: None,
+        }
+    }
+
+    /// The quoting at the end of the text read so far.
+    fn quoting(&self) -> Quoting {
+        if self.comment {
+            Quoting::Raw
+        } else {
+            self.quoting
+        }
+    }
+
+    fn read(&mut self, text: &str) {
+        for c in text.chars() {
+            self.read_char(c);
+        }
+    }
+
+    /// Reads past a substituted value.
+    fn read_value(&mut self) {
+        self.read_char('x');
+    }
+
+    fn read_char(&mut self, c: char) {
+        if c == '\n' {
+            if self.block_header && self.quoting == Quoting::Plain {
+                self.block = Some(self.indent);
+            }
+            self.indenting = true;
+            self.indent = 0;
+            self.comment = false;
+            self.block_header = false;
+            self.closing = false;
+            if self.quoting == Quoting::Plain || self.quoting == Quoting::Raw {
+                self.scalar_start = true;
+                self.prev = '\n';
+            }
+            return;
+        }
+        if self.indenting {
+            if c == ' ' {
+                self.indent += 1;
+                return;
+            }
+            self.indenting = false;
+            match self.block {
+                Some(indent) if self.indent > indent => self.quoting = Quoting::Raw,
+                Some(_) => {
+                    self.block = None;
+                    self.quoting = Quoting::Plain;
+                }
+                None => {}
+            }
+        }
+        if self.comment || self.quoting == Quoting::Raw {
+            return;
+        }
+        if self.closing {
+            self.closing = false;
+            if c == '\'' {
+                // `''` is a quote in a single-quoted scalar
+                self.quoting = Quoting::Single;
+                return;
+            }
+        }
+        match self.quoting {
+            Quoting::Single if c == '\'' => {
+                self.quoting = Quoting::Plain;
+                self.closing = true;
+                self.prev = c;
+            }
+            Quoting::Double if self.escaped => self.escaped = false,
+            Quoting::Double if c == '\\' => self.escaped = true,
+            Quoting::Double if c == '"' => {
+                self.quoting = Quoting::Plain;
+                self.prev = c;
+            }
+            Quoting::Single | Quoting::Double | Quoting::Raw => {}
+            Quoting::Plain => self.read_plain(c),
+        }
+    }
+
+    fn read_plain(&mut self, c: char) {
+        if c.is_whitespace() {
+            if matches!(self.prev, ':' | '-' | '?') {
+                self.scalar_start = true;
+            }
+            self.prev = ' ';
+            return;
+        }
+        if c == '#' && matches!(self.prev, ' ' | '\n') {
+            self.comment = true;
+            return;
+        }
+        let scalar_start = self.scalar_start;
+        self.scalar_start = false;
+        self.block_header = match c {
+            '|' | '>' => scalar_start,
+            '+' | '-' | '0'..='9' => self.block_header,
+            _ => false,
+        };
+        match c {
+            '\'' if scalar_start => self.quoting = Quoting::Single,
+            '"' if scalar_start => self.quoting = Quoting::Double,
+            '[' | '{' if scalar_start => {
+                self.flow += 1;
+                self.scalar_start = true;
+            }
+            ',' if self.flow > 0 => self.scalar_start = true,
+            ']' | '}' if self.flow > 0 => self.flow -= 1,
+            _ => {}
+        }
+        self.prev = c;
+    }
+}
+
+/// `value` written so it reads back as itself where the scanner is.
+/// A string that's a whole plain scalar is quoted if YAML would read it as
+/// a bool, null or number.
+fn escape(value: &str, string: bool, whole: bool, scanner: &Scanner) -> String {
+    let retyped = || {
+        string
+            && whole
+            && !matches!(
+                serde_yaml::from_str(value),
+                Ok(serde_yaml::Value::String(_))
+            )
+    };
+    match scanner.quoting() {
+        Quoting::Raw => value.to_string(),
+        Quoting::Single => value.replace('\'', "''"),
+        Quoting::Double => escape_double(value),
+        Quoting::Plain if is_plain(value, scanner.flow > 0) && !retyped() => value.to_string(),
+        Quoting::Plain => format!("\"{}\"", escape_double(value)),
+    }
+}
+
+/// Whether a plain scalar ends where `rest` starts: at the end of the line,
+/// a comment, a `:` after a key or, in `[...]` or `{...}`, a `,` or the
+/// closing bracket.
+fn ends_plain(rest: &str, flow: bool) -> bool {
+    let line = rest.lines().next().unwrap_or_default();
+    let next = line.trim_start();
+    next.is_empty()
+        || (next.starts_with('#') && next.len() < line.len())
+        || line == ":"
+        || line.starts_with(": ")
+        || (flow && next.starts_with([',', ']', '}']))
+}
+
+fn escape_double(value: &str) -> String {
+    let mut escaped = String::with_capacity(value.len());
+    for c in value.chars() {
+        match c {
+            '\\' => escaped.push_str("\\\\"),
+            '"' => escaped.push_str("\\\""),
+            '\n' => escaped.push_str("\\n"),
+            '\t' => escaped.push_str("\\t"),
+            c => escaped.push(c),
+        }
+    }
+    escaped
+}
+
+/// Whether `value` can go in a plain scalar as it is, without ending it or
+/// changing what it means.
+fn is_plain(value: &str, flow: bool) -> bool {
+    let special = |c: char| {
+        c.is_control()
+            || "'\"&*!|>%@`".contains(c)
+            || (flow && ",[]{}".contains(c))
+            || (!flow && "[]{}".contains(c))
+    };
+    !value.is_empty()
+        && value.trim() == value
+        && !value.contains(": ")
+        && !value.contains(" #")
+        && !value.ends_with(':')
+        && !value.starts_with(['#', '-', '?', ':', ','])
+        && !value.chars().any(special)
+}
+
+fn substitute(
+    content: &str,
+    env: &Environment,
+    variables: &BTreeMap<String, Variable>,
+    quoting: Quoting,
+) -> String {
+    let mut rendered = String::with_capacity(content.len());
+    let mut scanner = Scanner::new(quoting);
+    let mut rest = content;
+    while let Some(start) = rest.find("${") {
+        scanner.read(&rest[..start]);
+        let starts_scalar = scanner.scalar_start;
+        scanner.read("${");
+        if rest[..start].ends_with('$') {
+            rendered.push_str(&rest[..start + 2]);
+            rest = &rest[start + 2..];
+            // `$${` is written as `${`
+            rendered.remove(rendered.len() - 3);
+            continue;
+        }
+        rendered.push_str(&rest[..start]);
+        let after = &rest[start + 2..];
+        let Some(end) = after.find('}') else {
+            rendered.push_str("${");
+            rest = after;
+            continue;
+        };
+        let name = &after[..end];
+        let value = env.builtin(name).map(|text| (text, true)).or_else(|| {
+            let variable = variables.get(name)?;
+            Some((variable.text.clone(), variable.string))
+        });
+        match value {
+            Some((value, string)) => {
+                let tail = &after[end + 1..];
+                let whole = starts_scalar && ends_plain(tail, scanner.flow > 0);
+                rendered.push_str(&escape(&value, string, whole, &scanner));
+                scanner.read_value();
+                rest = &after[end + 1..];
+            }
+            None => {
+                warn!(
+                    "'${{{}}}' isn't a variable and is left as it is; write '$${{{}}}' if that's meant",
+                    name, name
+                );
+                rendered.push_str("${");
+                rest = after;
+            }
+        }
+    }
+    rendered.push_str(rest);
+    rendered
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn laptop() -> Environment {
+        Environment {
+            hostname: "laptop".to_string(),
+            vars: [("XDG_SESSION_DESKTOP".to_string(), "i3".to_string())].into(),
+        }
+    }
+
+    #[test]
+    fn test_conditionals() {
+        let content = "\
+global:
+#% if hostname == laptop
+  - 'F1': 'XF86MonBrightnessDown'
+#% elif hostname == 'desktop'
+  - 'F1': 'F1'
+#% else
+  - 'F1': null
+#% endif
+#% if !env.XDG_SESSION_DESKTOP
+  - 'F2': 'F2'
+#% endif
+  - 'C-b': 'Left'
+";
+        let rendered = render(content, &laptop()).unwrap();
+        assert_eq!(
+            rendered,
+            "global:\n\n  - 'F1': 'XF86MonBrightnessDown'\n\n\n\n\n\n\n\n\n  - 'C-b': 'Left'\n"
+        );
+        let desktop = Environment {
+            hostname: "desktop".to_string(),
+            ..Default::default()
+        };
+        let rendered = render(content, &desktop).unwrap();
+        assert!(rendered.contains("'F1': 'F1'"), "{}", rendered);
+        assert!(rendered.contains("'F2': 'F2'"), "{}", rendered);
+        assert!(!rendered.contains("null"), "{}", rendered);
+    }
+
+    #[test]
+    fn test_nested_conditionals() {
+        let content = "\
+#% if hostname == desktop
+#% if env.XDG_SESSION_DESKTOP == i3
+a
+#% else
+b
+#% endif
+#% else
+c
+#% endif
+";
+        assert_eq!(render(content, &laptop()).unwrap().trim(), "c");
+    }
+
+    #[test]
+    fn test_conditional_errors() {
+        let err = render("a\n#% if hostname == x\nb\n", &laptop()).unwrap_err();
+        assert_eq!(err.line, Some(2));
+        assert!(
+            err.message.contains("without an `#% endif`"),
+            "{}",
+            err.message
+        );
+
+        let err = render("#% endif\n", &laptop()).unwrap_err();
+        assert_eq!(err.line, Some(1));
+
+        let err = render("#% if user == me\n#% endif\n", &laptop()).unwrap_err();
+        assert!(
+            err.message.contains("'user' can't be tested"),
+            "{}",
+            err.message
+        );
+
+        let err = render("#% if env.X\n#% else\n#% else\n#% endif\n", &laptop()).unwrap_err();
+        assert_eq!(err.line, Some(3));
+
+        let err = render("#% if hostname == laptop == x\n#% endif\n", &laptop()).unwrap_err();
+        assert!(
+            err.message.contains("can't read condition"),
+            "{}",
+            err.message
+        );
+    }
+
+    #[test]
+    fn test_conditions_with_equals_in_values() {
+        let env = Environment {
+            hostname: "a==b".to_string(),
+            vars: [("OPTS".to_string(), "x=1 != y".to_string())].into(),
+        };
+        let holds = |condition: &str| evaluate(condition, &env).unwrap();
+        assert!(holds("hostname == 'a==b'"));
+        assert!(holds("hostname==\"a==b\""));
+        assert!(!holds("hostname != 'a==b'"));
+        assert!(holds("hostname != 'a=b'"));
+        assert!(holds("env.OPTS == 'x=1 != y'"));
+        assert!(!holds("env.OPTS != \"x=1 != y\""));
+        assert!(holds("!env.UNSET"));
+        assert!(evaluate("hostname == 'a==b", &env).is_err());
+    }
+
+    #[test]
+    fn test_substituted_strings_keep_their_type() {
+        let content = r#"
+variables:
+  answer: 'yes'
+  port: '8080'
+  delay: 20
+  flag: true
+key_delay_ms: ${delay}
+observe: ${flag}
+global:
+  - 'F1': ${answer}
+  - 'F2':
+      - ${port}
+      - 'Return'
+  - 'F3': {exec: 'echo ${port}'}
+  - 'F4': ${port}-1
+"#;
+        let rendered = render(content, &laptop()).unwrap();
+        let config: serde_yaml::Value = serde_yaml::from_str(&rendered).expect(&rendered);
+        assert_eq!(config["key_delay_ms"], serde_yaml::Value::from(20));
+        assert_eq!(config["observe"], serde_yaml::Value::from(true));
+        assert_eq!(config["global"][0]["F1"], serde_yaml::Value::from("yes"));
+        assert_eq!(
+            config["global"][1]["F2"][0],
+            serde_yaml::Value::from("8080")
+        );
+        assert_eq!(
+            config["global"][2]["F3"]["exec"],
+            serde_yaml::Value::from("echo 8080")
+        );
+        assert_eq!(config["global"][3]["F4"], serde_yaml::Value::from("8080-1"));
+    }
+
+    #[test]
+    fn test_substitution() {
+        let content = "\
+variables:
+  mod: Super
+  term: '${env.XDG_SESSION_DESKTOP}-term'
+global:
+  - '${mod}-Return': {exec: '${term} --title ${hostname} $HOME ${PWD} $${mod}'}
+";
+        let rendered = render(content, &laptop()).unwrap();
+        assert!(
+            rendered
+                .contains("'Super-Return': {exec: 'i3-term --title laptop $HOME ${PWD} ${mod}'}"),
+            "{}",
+            rendered
+        );
+    }
+
+    #[test]
+    fn test_substituted_values_are_escaped() {
+        let content = r#"
+variables:
+  title: "it's: #1"
+  cmd: 'a "b" \c'
+global:
+  - 'F1': {exec: 'echo ${title}'}
+  - 'F2': {exec: "${cmd} ${title}"}
+  - 'F3':
+      exec: ${cmd}
+  - 'F4':
+      exec: |
+        echo ${title}
+"#;
+        let rendered = render(content, &laptop()).unwrap();
+        let config: serde_yaml::Value = serde_yaml::from_str(&rendered).expect(&rendered);
+        let exec = |index: usize, key: &str| {
+            config["global"][index][key]["exec"]
+                .as_str()
+                .unwrap_or_else(|| panic!("{}", rendered))
+                .to_string()
+        };
+        assert_eq!(exec(0, "F1"), "echo it's: #1");
+        assert_eq!(exec(1, "F2"), "a \"b\" \\c it's: #1");
+        assert_eq!(exec(2, "F3"), "a \"b\" \\c");
+        assert_eq!(exec(3, "F4"), "echo it's: #1\n");
+    }
+}
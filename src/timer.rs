@@ -0,0 +1,77 @@
+use std::time::{Duration, Instant};
+
+/// Deadlines serviced by the event loop, which polls the X connections with
+/// the nearest one as timeout instead of sleeping in a thread.
+#[derive(Debug)]
+pub struct Timers<T> {
+    entries: Vec<(Instant, T)>,
+}
+
+impl<T> Default for Timers<T> {
+    fn default() -> Self {
+        Self {
+            entries: Vec::new(),
+        }
+    }
+}
+
+impl<T> Timers<T> {
+    pub fn schedule(&mut self, after: Duration, event: T) {
+        self.schedule_at(Instant::now() + after, event);
+    }
+
+    pub fn schedule_at(&mut self, deadline: Instant, event: T) {
+        self.entries.push((deadline, event));
+    }
+
+    /// Drops the pending timers `f` matches.
+    pub fn cancel(&mut self, mut f: impl FnMut(&T) -> bool) {
+        self.entries.retain(|(_, event)| !f(event));
+    }
+
+    /// Time left until the nearest deadline, zero if one already passed.
+    pub fn next_timeout(&self, now: Instant) -> Option<Duration> {
+        self.entries
+            .iter()
+            .map(|(deadline, _)| deadline.saturating_duration_since(now))
+            .min()
+    }
+
+    /// Removes and returns the timers due at `now`, earliest first.
+    pub fn take_expired(&mut self, now: Instant) -> Vec<T> {
+        let mut expired = Vec::new();
+        let mut i = 0;
+        while i < self.entries.len() {
+            if self.entries[i].0 <= now {
+                expired.push(self.entries.swap_remove(i));
+            } else {
+                i += 1;
+            }
+        }
+        expired.sort_by_key(|(deadline, _)| *deadline);
+        expired.into_iter().map(|(_, event)| event).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_timers_expire_in_order() {
+        let start = Instant::now();
+        let mut timers = Timers::default();
+        timers.schedule_at(start + Duration::from_millis(30), "late");
+        timers.schedule_at(start + Duration::from_millis(10), "early");
+        timers.schedule_at(start + Duration::from_millis(20), "cancelled");
+        timers.cancel(|event| *event == "cancelled");
+
+        assert_eq!(timers.next_timeout(start), Some(Duration::from_millis(10)));
+        assert!(timers.take_expired(start).is_empty());
+        assert_eq!(
+            timers.take_expired(start + Duration::from_millis(40)),
+            vec!["early", "late"]
+        );
+        assert_eq!(timers.next_timeout(start), None);
+    }
+}
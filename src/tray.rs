@@ -0,0 +1,537 @@
+//! A system tray icon showing whether the running remapper is active, with
+//! a menu to pause, resume and reload it. It docks with the XEmbed system
+//! tray protocol most panels support, and talks to the remapper over the
+//! control socket like the CLI does, so it needs its own thread and X
+//! connection but no toolkit.
+
+use crate::control;
+use crate::display::{intern_atom, DisplayHandle};
+use log::{debug, info, warn};
+use std::collections::HashMap;
+use std::ffi::CString;
+use std::os::raw::{c_int, c_long, c_ulong};
+use std::path::{Path, PathBuf};
+use std::time::{Duration, Instant};
+use thiserror::Error;
+use x11::xlib::{self, Display, Window, XEvent, GC};
+
+/// How often the remapper's state is polled.
+const REFRESH: Duration = Duration::from_secs(1);
+/// How long to wait for a panel with a tray at startup.
+const DOCK_TIMEOUT: Duration = Duration::from_secs(30);
+const SYSTEM_TRAY_REQUEST_DOCK: c_long = 0;
+const XEMBED_MAPPED: c_ulong = 1;
+
+#[derive(Debug, Error)]
+pub enum TrayError {
+    #[error("failed to open X display '{0}'")]
+    Display(String),
+    #[error("no system tray appeared within {0:?}")]
+    NoTray(Duration),
+}
+
+/// What the icon shows.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum State {
+    Active,
+    Paused,
+    /// No remapper answers on the control socket.
+    Unreachable,
+}
+
+impl State {
+    /// The state a `status` reply describes.
+    fn from_status(reply: &str) -> Self {
+        if reply.lines().any(|line| line.trim() == "Remapping: paused") {
+            State::Paused
+        } else {
+            State::Active
+        }
+    }
+
+    fn color(self) -> &'static str {
+        match self {
+            State::Active => "forest green",
+            State::Paused => "gray60",
+            State::Unreachable => "firebrick",
+        }
+    }
+}
+
+/// The names of the enabled rules in a `rules` reply.
+fn enabled_rules(reply: &str) -> Vec<String> {
+    reply
+        .lines()
+        .filter_map(|line| line.strip_prefix("enabled"))
+        .map(|name| name.trim().to_string())
+        .collect()
+}
+
+/// A menu line and the control command clicking it sends, if any.
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct MenuItem {
+    label: String,
+    command: Option<&'static str>,
+}
+
+impl MenuItem {
+    fn label(label: impl Into<String>) -> Self {
+        Self {
+            label: label.into(),
+            command: None,
+        }
+    }
+
+    fn command(label: &str, command: &'static str) -> Self {
+        Self {
+            label: label.to_string(),
+            command: Some(command),
+        }
+    }
+}
+
+fn menu_items(state: State, rules: &[String]) -> Vec<MenuItem> {
+    let mut items = vec![MenuItem::label(match state {
+        State::Active => "Remapping active",
+        State::Paused => "Remapping paused",
+        State::Unreachable => "Remapper not running",
+    })];
+    if state == State::Unreachable {
+        return items;
+    }
+    if !rules.is_empty() {
+        items.push(MenuItem::label(format!("Rules: {}", rules.join(", "))));
+    }
+    items.push(match state {
+        State::Paused => MenuItem::command("Resume", "resume"),
+        _ => MenuItem::command("Pause", "pause"),
+    });
+    items.push(MenuItem::command("Reload config", "reload"));
+    items
+}
+
+/// Makes Xlib safe to use from several threads, as the tray runs beside
+/// the remapper. Call it before any other Xlib call of the process.
+pub fn init_threads() {
+    unsafe {
+        xlib::XInitThreads();
+    }
+}
+
+/// Shows the icon on `display_name` (`$DISPLAY` when empty) for the
+/// remapper answering on `socket`, until the process exits.
+pub fn run(display_name: &str, socket: &Path) -> Result<(), TrayError> {
+    let handle = CString::new(display_name)
+        .ok()
+        .and_then(|name| DisplayHandle::open(&name))
+        .ok_or_else(|| TrayError::Display(display_name.to_string()))?;
+    let mut tray = Tray::new(handle.as_ptr(), socket.to_path_buf());
+
+    let deadline = Instant::now() + DOCK_TIMEOUT;
+    while !tray.dock() {
+        if Instant::now() > deadline {
+            return Err(TrayError::NoTray(DOCK_TIMEOUT));
+        }
+        std::thread::sleep(REFRESH);
+    }
+    info!("Docked in the system tray");
+    tray.run();
+    Ok(())
+}
+
+struct Tray {
+    display: *mut Display,
+    socket: PathBuf,
+    icon: Window,
+    menu: Window,
+    gc: GC,
+    font: *mut xlib::XFontStruct,
+    size: (u32, u32),
+    state: State,
+    rules: Vec<String>,
+    /// The menu's items while it's open.
+    open_menu: Vec<MenuItem>,
+    docked: bool,
+    /// Colors by name, allocated once rather than on every redraw.
+    pixels: HashMap<&'static str, c_ulong>,
+}
+
+impl Tray {
+    fn new(display: *mut Display, socket: PathBuf) -> Self {
+        unsafe {
+            let root = xlib::XDefaultRootWindow(display);
+            let icon = xlib::XCreateSimpleWindow(display, root, 0, 0, 22, 22, 0, 0, 0);
+            let info: [c_ulong; 2] = [0, XEMBED_MAPPED];
+            let xembed_info = intern_atom(display, c"_XEMBED_INFO", false);
+            xlib::XChangeProperty(
+                display,
+                icon,
+                xembed_info,
+                xembed_info,
+                32,
+                xlib::PropModeReplace,
+                info.as_ptr() as *const u8,
+                info.len() as c_int,
+            );
+            xlib::XSelectInput(
+                display,
+                icon,
+                xlib::ExposureMask | xlib::ButtonPressMask | xlib::StructureNotifyMask,
+            );
+
+            let menu = xlib::XCreateSimpleWindow(
+                display,
+                root,
+                0,
+                0,
+                1,
+                1,
+                1,
+                xlib::XBlackPixel(display, xlib::XDefaultScreen(display)),
+                xlib::XWhitePixel(display, xlib::XDefaultScreen(display)),
+            );
+            let mut attributes: xlib::XSetWindowAttributes = std::mem::zeroed();
+            attributes.override_redirect = xlib::True;
+            xlib::XChangeWindowAttributes(display, menu, xlib::CWOverrideRedirect, &mut attributes);
+            xlib::XSelectInput(display, menu, xlib::ExposureMask | xlib::ButtonPressMask);
+
+            let gc = xlib::XCreateGC(display, icon, 0, std::ptr::null_mut());
+            let font = xlib::XLoadQueryFont(display, c"fixed".as_ptr());
+            if !font.is_null() {
+                xlib::XSetFont(display, gc, (*font).fid);
+            }
+            let colors = [State::Active, State::Paused, State::Unreachable]
+                .map(State::color)
+                .into_iter()
+                .chain(["black", "gray40"]);
+            let pixels = colors
+                .map(|name| (name, alloc_pixel(display, name)))
+                .collect();
+            Self {
+                display,
+                socket,
+                icon,
+                menu,
+                gc,
+                font,
+                size: (22, 22),
+                state: State::Unreachable,
+                rules: Vec::new(),
+                open_menu: Vec::new(),
+                docked: false,
+                pixels,
+            }
+        }
+    }
+
+    /// Asks the tray of the default screen to embed the icon. Returns
+    /// whether there's a tray to ask.
+    fn dock(&mut self) -> bool {
+        unsafe {
+            let screen = xlib::XDefaultScreen(self.display);
+            let name = CString::new(format!("_NET_SYSTEM_TRAY_S{}", screen)).unwrap();
+            let selection = intern_atom(self.display, &name, false);
+            let owner = xlib::XGetSelectionOwner(self.display, selection);
+            if owner == 0 {
+                return false;
+            }
+            let mut event: xlib::XClientMessageEvent = std::mem::zeroed();
+            event.type_ = xlib::ClientMessage;
+            event.window = owner;
+            event.message_type = intern_atom(self.display, c"_NET_SYSTEM_TRAY_OPCODE", false);
+            event.format = 32;
+            event.data.set_long(0, xlib::CurrentTime as c_long);
+            event.data.set_long(1, SYSTEM_TRAY_REQUEST_DOCK);
+            event.data.set_long(2, self.icon as c_long);
+            xlib::XSendEvent(
+                self.display,
+                owner,
+                xlib::False,
+                xlib::NoEventMask,
+                &mut event as *mut xlib::XClientMessageEvent as *mut XEvent,
+            );
+            xlib::XFlush(self.display);
+        }
+        self.docked = true;
+        true
+    }
+
+    fn run(&mut self) {
+        let fd = unsafe { xlib::XConnectionNumber(self.display) };
+        let mut next_refresh = Instant::now();
+        loop {
+            while unsafe { xlib::XPending(self.display) } > 0 {
+                let mut event: XEvent = unsafe { std::mem::zeroed() };
+                unsafe { xlib::XNextEvent(self.display, &mut event) };
+                self.handle_event(&event);
+            }
+            if Instant::now() >= next_refresh {
+                self.refresh();
+                next_refresh = Instant::now() + REFRESH;
+            }
+            let timeout = next_refresh.saturating_duration_since(Instant::now());
+            let mut pollfd = libc::pollfd {
+                fd,
+                events: libc::POLLIN,
+                revents: 0,
+            };
+            unsafe {
+                libc::poll(&mut pollfd, 1, timeout.as_millis() as c_int);
+            }
+        }
+    }
+
+    /// Polls the remapper's state, redrawing the icon when it changed, and
+    /// docks again if the panel was restarted.
+    fn refresh(&mut self) {
+        let state = match control::request(&self.socket, "status") {
+            Ok(reply) => State::from_status(&reply),
+            Err(e) => {
+                debug!("Remapper unreachable: {}", e);
+                State::Unreachable
+            }
+        };
+        self.rules = control::request(&self.socket, "rules")
+            .map(|reply| enabled_rules(&reply))
+            .unwrap_or_default();
+        if state != self.state {
+            self.state = state;
+            self.draw_icon();
+        }
+        if !self.docked {
+            self.dock();
+        }
+    }
+
+    fn handle_event(&mut self, event: &XEvent) {
+        match event.get_type() {
+            xlib::Expose if unsafe { event.expose.count } == 0 => {
+                if unsafe { event.expose.window } == self.menu {
+                    self.draw_menu();
+                } else {
+                    self.draw_icon();
+                }
+            }
+            xlib::ConfigureNotify => {
+                let configure = unsafe { event.configure };
+                if configure.window == self.icon {
+                    self.size = (configure.width as u32, configure.height as u32);
+                    self.draw_icon();
+                }
+            }
+            xlib::ReparentNotify => {
+                // Back on the root window: the panel went away
+                let reparent = unsafe { event.reparent };
+                if reparent.parent == unsafe { xlib::XDefaultRootWindow(self.display) } {
+                    self.docked = false;
+                }
+            }
+            xlib::ButtonPress => {
+                let button = unsafe { event.button };
+                if button.window == self.menu {
+                    self.click_menu(button.x, button.y);
+                } else if button.button == xlib::Button1 && self.state != State::Unreachable {
+                    let command = if self.state == State::Paused {
+                        "resume"
+                    } else {
+                        "pause"
+                    };
+                    self.send(command);
+                } else {
+                    self.open_menu(button.x_root, button.y_root);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    fn send(&mut self, command: &str) {
+        match control::request(&self.socket, command) {
+            Ok(reply) if reply.starts_with("error:") => warn!("{}: {}", command, reply.trim()),
+            Ok(_) => {}
+            Err(e) => warn!("Failed to send {} to the remapper: {}", command, e),
+        }
+        self.refresh();
+    }
+
+    fn line_height(&self) -> i32 {
+        if self.font.is_null() {
+            return 16;
+        }
+        unsafe { (*self.font).ascent + (*self.font).descent + 6 }
+    }
+
+    fn open_menu(&mut self, x: i32, y: i32) {
+        self.open_menu = menu_items(self.state, &self.rules);
+        let width = self
+            .open_menu
+            .iter()
+            .map(|item| self.text_width(&item.label))
+            .max()
+            .unwrap_or(0)
+            + 16;
+        let height = self.line_height() * self.open_menu.len() as i32;
+        unsafe {
+            // Keep the menu on screen, above a bottom panel
+            let screen = xlib::XDefaultScreen(self.display);
+            let screen_height = xlib::XDisplayHeight(self.display, screen);
+            let y = if y + height > screen_height {
+                y - height
+            } else {
+                y
+            };
+            xlib::XMoveResizeWindow(self.display, self.menu, x, y, width as u32, height as u32);
+            xlib::XMapRaised(self.display, self.menu);
+            // Clicks anywhere come to the menu, so one outside closes it
+            xlib::XGrabPointer(
+                self.display,
+                self.menu,
+                xlib::False,
+                xlib::ButtonPressMask as u32,
+                xlib::GrabModeAsync,
+                xlib::GrabModeAsync,
+                0,
+                0,
+                xlib::CurrentTime,
+            );
+        }
+        self.draw_menu();
+    }
+
+    fn click_menu(&mut self, x: i32, y: i32) {
+        let mut attributes: xlib::XWindowAttributes = unsafe { std::mem::zeroed() };
+        unsafe {
+            xlib::XGetWindowAttributes(self.display, self.menu, &mut attributes);
+            xlib::XUngrabPointer(self.display, xlib::CurrentTime);
+            xlib::XUnmapWindow(self.display, self.menu);
+        }
+        let items = std::mem::take(&mut self.open_menu);
+        if x < 0 || x >= attributes.width || y < 0 {
+            return;
+        }
+        let command = items
+            .get((y / self.line_height()) as usize)
+            .and_then(|item| item.command);
+        if let Some(command) = command {
+            self.send(command);
+        }
+    }
+
+    fn text_width(&self, text: &str) -> i32 {
+        if self.font.is_null() {
+            return 8 * text.len() as i32;
+        }
+        unsafe { xlib::XTextWidth(self.font, text.as_ptr() as *const _, text.len() as c_int) }
+    }
+
+    fn draw_icon(&self) {
+        let (width, height) = self.size;
+        let diameter = width.min(height).saturating_sub(6);
+        unsafe {
+            xlib::XClearWindow(self.display, self.icon);
+            xlib::XSetForeground(self.display, self.gc, self.pixel(self.state.color()));
+            xlib::XFillArc(
+                self.display,
+                self.icon,
+                self.gc,
+                ((width - diameter) / 2) as c_int,
+                ((height - diameter) / 2) as c_int,
+                diameter,
+                diameter,
+                0,
+                360 * 64,
+            );
+            xlib::XFlush(self.display);
+        }
+    }
+
+    fn draw_menu(&self) {
+        let line_height = self.line_height();
+        let ascent = if self.font.is_null() {
+            12
+        } else {
+            unsafe { (*self.font).ascent }
+        };
+        unsafe {
+            xlib::XClearWindow(self.display, self.menu);
+            for (i, item) in self.open_menu.iter().enumerate() {
+                let color = if item.command.is_some() {
+                    "black"
+                } else {
+                    "gray40"
+                };
+                xlib::XSetForeground(self.display, self.gc, self.pixel(color));
+                xlib::XDrawString(
+                    self.display,
+                    self.menu,
+                    self.gc,
+                    8,
+                    i as c_int * line_height + 3 + ascent,
+                    item.label.as_ptr() as *const _,
+                    item.label.len() as c_int,
+                );
+            }
+            xlib::XFlush(self.display);
+        }
+    }
+
+    fn pixel(&self, name: &str) -> c_ulong {
+        self.pixels[name]
+    }
+}
+
+/// The pixel value of a named color, black when the server doesn't know
+/// it.
+fn alloc_pixel(display: *mut Display, name: &str) -> c_ulong {
+    let name = CString::new(name).unwrap();
+    unsafe {
+        let screen = xlib::XDefaultScreen(display);
+        let colormap = xlib::XDefaultColormap(display, screen);
+        let mut color: xlib::XColor = std::mem::zeroed();
+        let mut exact: xlib::XColor = std::mem::zeroed();
+        if xlib::XAllocNamedColor(display, colormap, name.as_ptr(), &mut color, &mut exact) == 0 {
+            return xlib::XBlackPixel(display, screen);
+        }
+        color.pixel
+    }
+}
+
+impl Drop for Tray {
+    fn drop(&mut self) {
+        unsafe {
+            if !self.font.is_null() {
+                xlib::XFreeFont(self.display, self.font);
+            }
+            xlib::XFreeGC(self.display, self.gc);
+            xlib::XDestroyWindow(self.display, self.menu);
+            xlib::XDestroyWindow(self.display, self.icon);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_state_and_menu() {
+        let status = "Display :0\n  Window class: kitty\n  Remapping: paused\n";
+        assert_eq!(State::from_status(status), State::Paused);
+        let rules = enabled_rules("enabled   emacs-keys\ndisabled  vim-keys\n");
+        assert_eq!(rules, ["emacs-keys"]);
+
+        let items = menu_items(State::Paused, &rules);
+        let labels: Vec<_> = items.iter().map(|item| item.label.as_str()).collect();
+        assert_eq!(
+            labels,
+            [
+                "Remapping paused",
+                "Rules: emacs-keys",
+                "Resume",
+                "Reload config"
+            ]
+        );
+        assert_eq!(items[2].command, Some("resume"));
+        assert_eq!(menu_items(State::Unreachable, &rules).len(), 1);
+    }
+}
@@ -0,0 +1,338 @@
+//! `.wasm` plugins, run by the wasmi interpreter in a sandbox. A module
+//! reaches nothing but the host functions it imports from `sxr`:
+//!
+//! - `send_key(ptr, len) -> i32`: types a key expression like `C-a` into
+//!   the window; -1 if it doesn't parse
+//! - `exec(ptr, len) -> i32`: runs a shell command like `{exec: ...}`, if
+//!   the config gives the plugin `allow_exec`; -1 otherwise
+//! - `window_class(ptr, cap) -> i32`: writes up to `cap` bytes of the
+//!   window's class and returns its length; -1 without one
+//!
+//! It exports `memory`, `sxr_run(ptr, len) -> i32` (0 on success) and, to
+//! take an argument, `sxr_alloc(len) -> ptr` for the host to write it to.
+//! Without an argument `sxr_run` gets an empty one. WASI imports are stubs
+//! failing with ENOSYS, so modules built for `wasm32-wasip1` load without
+//! getting at files, sockets or clocks. Each run has a fuel budget, so a
+//! module stuck in a loop traps instead of hanging the event loop.
+
+use crate::event_handler::spawn_command;
+use crate::plugin::{KeySender, PluginError};
+use log::{debug, warn};
+use std::path::Path;
+use wasmi::{
+    Caller, Config, Engine, Error, Extern, Linker, Memory, Module, Store, StoreLimits,
+    StoreLimitsBuilder, TypedFunc, Val,
+};
+use x11::xlib::Window;
+
+/// Roughly the instructions one run may execute.
+const FUEL_PER_RUN: u64 = 10_000_000;
+
+/// The most linear memory a module may grow to.
+const MEMORY_LIMIT: usize = 16 << 20;
+
+/// The longest string a module may pass to a host function.
+const STRING_LIMIT: usize = 64 << 10;
+
+/// The errno WASI calls fail with: the function isn't supported.
+const WASI_ENOSYS: i32 = 52;
+
+/// What the host functions see of the remap firing.
+struct Host {
+    limits: StoreLimits,
+    send_key: KeySender,
+    allow_exec: bool,
+    window: Window,
+    window_class: Option<String>,
+}
+
+pub struct WasmPlugin {
+    store: Store<Host>,
+    run: TypedFunc<(i32, i32), i32>,
+    alloc: Option<TypedFunc<i32, i32>>,
+    memory: Option<Memory>,
+}
+
+impl WasmPlugin {
+    pub fn load(path: &Path, allow_exec: bool, send_key: KeySender) -> Result<Self, PluginError> {
+        let wasm = std::fs::read(path).map_err(|e| PluginError::Load(e.to_string()))?;
+        Self::from_bytes(&wasm, allow_exec, send_key)
+    }
+
+    fn from_bytes(wasm: &[u8], allow_exec: bool, send_key: KeySender) -> Result<Self, PluginError> {
+        let mut config = Config::default();
+        config.consume_fuel(true);
+        let engine = Engine::new(&config);
+        let module = Module::new(&engine, wasm)?;
+
+        let host = Host {
+            limits: StoreLimitsBuilder::new().memory_size(MEMORY_LIMIT).build(),
+            send_key,
+            allow_exec,
+            window: 0,
+            window_class: None,
+        };
+        let mut store = Store::new(&engine, host);
+        store.limiter(|host| &mut host.limits);
+        // The start function runs on the same budget
+        store.set_fuel(FUEL_PER_RUN)?;
+
+        let mut linker = Linker::new(&engine);
+        define_host_functions(&mut linker)?;
+        stub_wasi(&mut linker, &module);
+        let instance = linker.instantiate_and_start(&mut store, &module)?;
+        Ok(Self {
+            run: instance.get_typed_func(&store, "sxr_run")?,
+            alloc: instance.get_typed_func(&store, "sxr_alloc").ok(),
+            memory: instance.get_memory(&store, "memory"),
+            store,
+        })
+    }
+
+    pub fn run(
+        &mut self,
+        argument: Option<&str>,
+        window: Window,
+        window_class: Option<&str>,
+    ) -> Result<(), PluginError> {
+        self.store.set_fuel(FUEL_PER_RUN)?;
+        let host = self.store.data_mut();
+        host.window = window;
+        host.window_class = window_class.map(str::to_string);
+
+        let (ptr, len) = match argument {
+            Some(argument) if !argument.is_empty() => self.write_argument(argument)?,
+            _ => (0, 0),
+        };
+        match self.run.call(&mut self.store, (ptr, len))? {
+            0 => Ok(()),
+            code => Err(PluginError::Failed(code)),
+        }
+    }
+
+    /// Copies `argument` into memory the module allocated for it.
+    fn write_argument(&mut self, argument: &str) -> Result<(i32, i32), Error> {
+        let (Some(alloc), Some(memory)) = (&self.alloc, &self.memory) else {
+            return Err(Error::new(
+                "taking an argument needs sxr_alloc and memory exported",
+            ));
+        };
+        let len = i32::try_from(argument.len()).map_err(|_| Error::new("argument too long"))?;
+        let ptr = alloc.call(&mut self.store, len)?;
+        memory
+            .write(&mut self.store, ptr as u32 as usize, argument.as_bytes())
+            .map_err(|e| Error::new(e.to_string()))?;
+        Ok((ptr, len))
+    }
+}
+
+fn define_host_functions(linker: &mut Linker<Host>) -> Result<(), Error> {
+    linker.func_wrap(
+        "sxr",
+        "send_key",
+        |caller: Caller<'_, Host>, ptr: i32, len: i32| -> Result<i32, Error> {
+            let key = read_string(&caller, ptr, len)?;
+            let host = caller.data();
+            debug!("Plugin sending key: {}", key);
+            if (host.send_key)(host.window, &key) {
+                Ok(0)
+            } else {
+                warn!("Plugin sent unparsable key '{}'", key);
+                Ok(-1)
+            }
+        },
+    )?;
+    linker.func_wrap(
+        "sxr",
+        "exec",
+        |caller: Caller<'_, Host>, ptr: i32, len: i32| -> Result<i32, Error> {
+            let command = read_string(&caller, ptr, len)?;
+            if !caller.data().allow_exec {
+                warn!("Plugin without allow_exec tried to run '{}'", command);
+                return Ok(-1);
+            }
+            debug!("Plugin running command: {}", command);
+            spawn_command(&command);
+            Ok(0)
+        },
+    )?;
+    linker.func_wrap(
+        "sxr",
+        "window_class",
+        |mut caller: Caller<'_, Host>, ptr: i32, cap: i32| -> Result<i32, Error> {
+            let Some(class) = caller.data().window_class.clone() else {
+                return Ok(-1);
+            };
+            let len = class.len().min(cap.max(0) as usize);
+            guest_memory(&caller)?
+                .write(&mut caller, ptr as u32 as usize, &class.as_bytes()[..len])
+                .map_err(|e| Error::new(e.to_string()))?;
+            i32::try_from(class.len()).map_err(|_| Error::new("window class too long"))
+        },
+    )?;
+    Ok(())
+}
+
+/// Links every `wasi_snapshot_preview1` import of `module` to a stub
+/// failing with ENOSYS; `proc_exit` ends the run.
+fn stub_wasi(linker: &mut Linker<Host>, module: &Module) {
+    for import in module.imports() {
+        let Some(ty) = import.ty().func() else {
+            continue;
+        };
+        if import.module() != "wasi_snapshot_preview1" {
+            continue;
+        }
+        let exits = import.name() == "proc_exit";
+        // A name imported twice is already defined; the second import
+        // fails to link with a type mismatch if it differs
+        let _ = linker.func_new(
+            import.module(),
+            import.name(),
+            ty.clone(),
+            move |_, params, results| {
+                if exits {
+                    let status = match params.first() {
+                        Some(Val::I32(status)) => *status,
+                        _ => 0,
+                    };
+                    return Err(Error::i32_exit(status));
+                }
+                if let Some(errno) = results.first_mut() {
+                    *errno = Val::I32(WASI_ENOSYS);
+                }
+                Ok(())
+            },
+        );
+    }
+}
+
+fn guest_memory(caller: &Caller<'_, Host>) -> Result<Memory, Error> {
+    caller
+        .get_export("memory")
+        .and_then(Extern::into_memory)
+        .ok_or_else(|| Error::new("the module exports no memory"))
+}
+
+/// Reads a string the module passed, checking it lies in its memory before
+/// allocating room for it.
+fn read_string(caller: &Caller<'_, Host>, ptr: i32, len: i32) -> Result<String, Error> {
+    let memory = guest_memory(caller)?;
+    let (ptr, len) = (ptr as u32 as usize, len as u32 as usize);
+    if len > STRING_LIMIT {
+        return Err(Error::new("the string is too long"));
+    }
+    if ptr.saturating_add(len) > memory.data_size(caller) {
+        return Err(Error::new("the string is out of the module's memory"));
+    }
+    let mut bytes = vec![0; len];
+    memory
+        .read(caller, ptr, &mut bytes)
+        .map_err(|e| Error::new(e.to_string()))?;
+    String::from_utf8(bytes).map_err(|_| Error::new("the string isn't UTF-8"))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    /// Types `C-a` if the window's class starts with `k`, and tries to run
+    /// its argument. Fails with the `exec` result.
+    const PLUGIN: &str = r#"
+(module
+  (import "sxr" "send_key" (func $send_key (param i32 i32) (result i32)))
+  (import "sxr" "exec" (func $exec (param i32 i32) (result i32)))
+  (import "sxr" "window_class" (func $window_class (param i32 i32) (result i32)))
+  (import "wasi_snapshot_preview1" "fd_write"
+    (func $fd_write (param i32 i32 i32 i32) (result i32)))
+  (memory (export "memory") 1)
+  (data (i32.const 0) "C-a")
+  (func (export "sxr_alloc") (param i32) (result i32) (i32.const 1024))
+  (func (export "sxr_run") (param $ptr i32) (param $len i32) (result i32)
+    (if (i32.ne (call $fd_write (i32.const 0) (i32.const 0) (i32.const 0) (i32.const 0))
+                (i32.const 52))
+      (then unreachable))
+    (drop (call $window_class (i32.const 512) (i32.const 16)))
+    (if (i32.eq (i32.load8_u (i32.const 512)) (i32.const 107))
+      (then (drop (call $send_key (i32.const 0) (i32.const 3)))))
+    (if (result i32) (i32.eqz (local.get $len))
+      (then (i32.const 0))
+      (else (call $exec (local.get $ptr) (local.get $len)))))
+)"#;
+
+    /// The keys a plugin sent, and to which window.
+    type Sent = Rc<RefCell<Vec<(Window, String)>>>;
+
+    fn plugin(wat: &str, allow_exec: bool) -> (WasmPlugin, Sent) {
+        let sent = Rc::new(RefCell::new(Vec::new()));
+        let log = sent.clone();
+        let send_key: KeySender = Rc::new(move |window, key: &str| {
+            log.borrow_mut().push((window, key.to_string()));
+            true
+        });
+        let plugin = WasmPlugin::from_bytes(wat.as_bytes(), allow_exec, send_key).unwrap();
+        (plugin, sent)
+    }
+
+    #[test]
+    fn test_host_functions() {
+        let (mut plugin, sent) = plugin(PLUGIN, false);
+        plugin.run(None, 42, Some("kitty")).unwrap();
+        plugin.run(None, 7, Some("firefox")).unwrap();
+        plugin.run(None, 7, None).unwrap();
+        assert_eq!(*sent.borrow(), [(42, "C-a".to_string())]);
+        // Without allow_exec, exec refuses
+        assert!(matches!(
+            plugin.run(Some("true"), 7, None),
+            Err(PluginError::Failed(-1))
+        ));
+    }
+
+    #[test]
+    fn test_fuel_runs_out() {
+        let wat = r#"(module (func (export "sxr_run") (param i32 i32) (result i32)
+            (loop $forever (br $forever)) (i32.const 0)))"#;
+        let (mut plugin, _) = plugin(wat, false);
+        assert!(plugin.run(None, 0, None).is_err());
+        // The next run gets a fresh budget and traps again rather than
+        // failing to start
+        assert!(plugin.run(None, 0, None).is_err());
+    }
+
+    #[test]
+    fn test_string_bounds_are_checked() {
+        // A negative length, one past the memory's end and one over the limit
+        for (ptr, len) in [(0, -1), ((2 << 20) - 4, 16), (0, 1 << 20)] {
+            let wat = format!(
+                r#"(module
+  (import "sxr" "send_key" (func $send_key (param i32 i32) (result i32)))
+  (memory (export "memory") 32)
+  (func (export "sxr_run") (param i32 i32) (result i32)
+    (call $send_key (i32.const {}) (i32.const {}))))"#,
+                ptr, len
+            );
+            let (mut plugin, sent) = plugin(&wat, false);
+            assert!(
+                plugin.run(None, 0, None).is_err(),
+                "{} bytes at {}",
+                len,
+                ptr
+            );
+            assert!(sent.borrow().is_empty());
+        }
+    }
+
+    #[test]
+    fn test_memory_is_limited() {
+        let wat = r#"(module (memory 1) (func (export "sxr_run") (param i32 i32) (result i32)
+            (memory.grow (i32.const 1024))))"#;
+        let (mut plugin, _) = plugin(wat, false);
+        // memory.grow returns -1 when refused
+        assert!(matches!(
+            plugin.run(None, 0, None),
+            Err(PluginError::Failed(-1))
+        ));
+    }
+}
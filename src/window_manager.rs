@@ -1,207 +1,326 @@
+use crate::backend::XBackend;
+use crate::display::{atom_name, intern_atom, root_windows, Property, TextProperty};
+use crate::key_mapper::KeyPress;
+use crate::x_error;
 use log::{debug, warn};
-use std::ffi::CStr;
-use std::os::raw::{c_char, c_int, c_ulong};
+use std::os::raw::{c_int, c_ulong};
 use std::ptr;
-use x11::xlib::{self, Display, Window, XTextProperty};
+use x11::xlib::{self, Atom, Display, Window};
 
 pub struct WindowManager {
     display: *mut Display,
-    root_window: Window,
+    /// One root per screen, the default screen's first.
+    roots: Vec<Window>,
     current_window: Option<Window>,
+    /// The client window we selected focus events on.
+    watched_window: Option<Window>,
     wm_class_atom: c_ulong,
     net_active_window_atom: c_ulong,
+    net_client_list_atom: c_ulong,
+    net_wm_state_atom: c_ulong,
+    wm_window_role_atom: c_ulong,
+    net_wm_window_type_atom: c_ulong,
+    /// The lock modifiers keys are also grabbed with.
+    lock_mask: u32,
 }
 
 impl WindowManager {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
     pub fn new(display: *mut Display) -> Self {
-        unsafe {
-            let root_window = xlib::XDefaultRootWindow(display);
-            let wm_class_atom =
-                xlib::XInternAtom(display, b"WM_CLASS\0".as_ptr() as *const c_char, xlib::True);
-            let net_active_window_atom = xlib::XInternAtom(
-                display,
-                b"_NET_ACTIVE_WINDOW\0".as_ptr() as *const c_char,
-                xlib::True,
-            );
-
-            Self {
-                display,
-                root_window,
-                current_window: None,
-                wm_class_atom,
-                net_active_window_atom,
-            }
+        Self {
+            display,
+            roots: root_windows(display),
+            current_window: None,
+            watched_window: None,
+            wm_class_atom: intern_atom(display, c"WM_CLASS", true),
+            net_active_window_atom: intern_atom(display, c"_NET_ACTIVE_WINDOW", true),
+            net_client_list_atom: intern_atom(display, c"_NET_CLIENT_LIST", true),
+            net_wm_state_atom: intern_atom(display, c"_NET_WM_STATE", false),
+            wm_window_role_atom: intern_atom(display, c"WM_WINDOW_ROLE", true),
+            net_wm_window_type_atom: intern_atom(display, c"_NET_WM_WINDOW_TYPE", true),
+            lock_mask: xlib::LockMask | xlib::Mod2Mask,
         }
     }
 
+    /// Sets the lock modifiers grabs are made with. Grabs already in place
+    /// keep the old ones, so they must be released before it changes.
+    pub fn set_lock_mask(&mut self, lock_mask: u32) {
+        self.lock_mask = lock_mask;
+    }
+
+    pub fn lock_mask(&self) -> u32 {
+        self.lock_mask
+    }
+
     pub fn get_active_window(&mut self) -> Option<Window> {
-        unsafe {
-            // Method 1: Try _NET_ACTIVE_WINDOW first
-            let mut actual_type: c_ulong = 0;
-            let mut actual_format: c_int = 0;
-            let mut nitems: c_ulong = 0;
-            let mut bytes_after: c_ulong = 0;
-            let mut prop_data: *mut u8 = ptr::null_mut();
-
-            let result = xlib::XGetWindowProperty(
+        // Method 1: Try _NET_ACTIVE_WINDOW first, on every screen's root
+        for &root in &self.roots {
+            let active = Property::get(
                 self.display,
-                self.root_window,
+                root,
                 self.net_active_window_atom,
-                0,
-                1,
-                xlib::False,
                 xlib::XA_WINDOW,
-                &mut actual_type,
-                &mut actual_format,
-                &mut nitems,
-                &mut bytes_after,
-                &mut prop_data,
+                1,
             );
-
-            if result == xlib::Success as i32 && !prop_data.is_null() && nitems > 0 {
-                let window = *(prop_data as *const Window);
-                debug!("_NET_ACTIVE_WINDOW returned window={}", window);
-                xlib::XFree(prop_data as *mut _);
-                if window != 0 && window != self.root_window {
+            if let Some(&window) = active.as_ref().and_then(|p| p.longs().first()) {
+                debug!(
+                    "_NET_ACTIVE_WINDOW on root={} returned window={}",
+                    root, window
+                );
+                if window != 0 && !self.roots.contains(&window) {
                     self.current_window = Some(window);
                     return Some(window);
                 }
-            } else if !prop_data.is_null() {
-                xlib::XFree(prop_data as *mut _);
             }
+        }
 
-            // Method 2: XGetInputFocus fallback
-            let mut window: Window = 0;
-            let mut revert_to: c_int = 0;
+        // Method 2: XGetInputFocus fallback
+        let mut window: Window = 0;
+        let mut revert_to: c_int = 0;
 
+        unsafe {
             xlib::XGetInputFocus(self.display, &mut window, &mut revert_to);
-            debug!(
-                "XGetInputFocus returned window={}, revert_to={}",
-                window, revert_to
-            );
-
-            if window != 0 && window != 1 && window != self.root_window {
-                self.current_window = Some(window);
-                Some(window)
+        }
+        debug!(
+            "XGetInputFocus returned window={}, revert_to={}",
+            window, revert_to
+        );
+
+        if window != 0 && window != 1 && !self.roots.contains(&window) {
+            self.current_window = Some(window);
+            Some(window)
+        } else {
+            debug!("No valid active window found, trying to find focused window manually");
+            // Method 3: Try to find a window with input focus by checking children
+            if let Some(focused) = self
+                .roots
+                .iter()
+                .find_map(|&root| self.find_focused_window(root))
+            {
+                debug!("Found focused window via tree search: {}", focused);
+                self.current_window = Some(focused);
+                Some(focused)
             } else {
-                debug!("No valid active window found, trying to find focused window manually");
-                // Method 3: Try to find a window with input focus by checking children
-                if let Some(focused) = self.find_focused_window(self.root_window) {
-                    debug!("Found focused window via tree search: {}", focused);
-                    self.current_window = Some(focused);
-                    Some(focused)
-                } else {
-                    debug!("Using root window as fallback");
-                    self.current_window = Some(self.root_window);
-                    Some(self.root_window)
-                }
+                debug!("Using root window as fallback");
+                let root = self.roots[0];
+                self.current_window = Some(root);
+                Some(root)
             }
         }
     }
 
     pub fn get_window_class(&self, window: Window) -> Option<String> {
         debug!("Getting window class for window={}", window);
-        unsafe {
-            // First try direct property lookup without climbing the tree
-            if let Some(class) = self.try_get_class_direct(window) {
-                debug!("Found class directly: '{}'", class);
-                return Some(class);
-            }
+        // First try direct property lookup without climbing the tree
+        if let Some(class) = self.try_get_class_direct(window) {
+            debug!("Found class directly: '{}'", class);
+            return Some(class);
+        }
 
-            // If that fails, climb the window tree
-            let mut prop = XTextProperty {
-                value: ptr::null_mut(),
-                encoding: 0,
-                format: 0,
-                nitems: 0,
-            };
+        // If that fails, climb the window tree
+        let mut search_window = window;
+        let mut depth = 0;
 
-            let mut search_window = window;
-            let mut depth = 0;
+        let prop = loop {
+            debug!("Searching window={} (depth={})", search_window, depth);
 
-            loop {
-                debug!("Searching window={} (depth={})", search_window, depth);
+            // Try WM_CLASS first
+            if let Some(prop) = TextProperty::get(self.display, search_window, self.wm_class_atom) {
+                debug!("Found WM_CLASS property");
+                break prop;
+            }
 
-                // Try WM_CLASS first
-                let status = xlib::XGetTextProperty(
-                    self.display,
-                    search_window,
-                    &mut prop,
-                    self.wm_class_atom,
-                );
+            // If WM_CLASS failed, try getting window name as fallback
+            if let Some(prop) = TextProperty::wm_name(self.display, search_window) {
+                debug!("Found WM_NAME property as fallback");
+                break prop;
+            }
 
-                if status != 0 && prop.nitems > 0 && !prop.value.is_null() {
-                    debug!("Found WM_CLASS property with {} items", prop.nitems);
-                    break;
+            match self.parent_of(search_window) {
+                Some(parent) => search_window = parent,
+                None => {
+                    debug!("Reached root or query failed, stopping search");
+                    return None;
                 }
+            }
+            depth += 1;
 
-                // If WM_CLASS failed, try getting window name as fallback
-                let mut name_prop = XTextProperty {
-                    value: ptr::null_mut(),
-                    encoding: 0,
-                    format: 0,
-                    nitems: 0,
-                };
+            if depth > 20 {
+                warn!("Window class search depth exceeded 20, stopping");
+                return None;
+            }
+        };
 
-                let name_status = xlib::XGetWMName(self.display, search_window, &mut name_prop);
-                if name_status != 0 && name_prop.nitems > 0 && !name_prop.value.is_null() {
-                    debug!(
-                        "Found WM_NAME property as fallback with {} items",
-                        name_prop.nitems
-                    );
-                    prop = name_prop;
-                    break;
-                }
+        let class_str = prop.to_text();
+        debug!("Found window class: '{}'", class_str);
+        Some(class_str)
+    }
 
-                let mut root: Window = 0;
-                let mut parent: Window = 0;
-                let mut children: *mut Window = ptr::null_mut();
-                let mut n_children: u32 = 0;
+    /// A window whose class contains `class`, ignoring case like
+    /// `class_only`. Looks through the window manager's _NET_CLIENT_LIST,
+    /// or the window tree when it has none.
+    pub fn find_window_by_class(&self, class: &str) -> Option<Window> {
+        let class = class.to_lowercase();
+        let matches = |window: Window| {
+            self.try_get_class_direct(window)
+                .is_some_and(|c| c.to_lowercase().contains(&class))
+        };
+        let mut listed = false;
+        for &root in &self.roots {
+            let Some(clients) = Property::get(
+                self.display,
+                root,
+                self.net_client_list_atom,
+                xlib::XA_WINDOW,
+                4096,
+            ) else {
+                continue;
+            };
+            listed = true;
+            if let Some(&window) = clients.longs().iter().find(|&&w| matches(w)) {
+                return Some(window);
+            }
+        }
+        if listed {
+            return None;
+        }
+        self.roots
+            .iter()
+            .find_map(|&root| self.find_in_tree(root, &matches))
+    }
 
-                let query_status = xlib::XQueryTree(
-                    self.display,
-                    search_window,
-                    &mut root,
-                    &mut parent,
-                    &mut children,
-                    &mut n_children,
-                );
+    fn find_in_tree(&self, parent: Window, matches: &dyn Fn(Window) -> bool) -> Option<Window> {
+        let (_, _, children) = self.query_tree(parent)?;
+        children.into_iter().find_map(|child| {
+            if matches(child) {
+                Some(child)
+            } else {
+                self.find_in_tree(child, matches)
+            }
+        })
+    }
 
-                if !children.is_null() {
-                    xlib::XFree(children as *mut _);
-                }
+    /// The WM_WINDOW_ROLE of `window`, which tells apart the windows of one
+    /// application, e.g. Firefox's `browser` and `Preferences`.
+    pub fn get_window_role(&self, window: Window) -> Option<String> {
+        if self.wm_window_role_atom == 0 {
+            return None;
+        }
+        let role = TextProperty::get(self.display, window, self.wm_window_role_atom)?.to_text();
+        (!role.is_empty()).then_some(role)
+    }
 
-                if query_status == 0 || parent == 0 || parent == root {
-                    debug!("Reached root or query failed, stopping search");
-                    return None;
-                }
+    /// The title of `window`: _NET_WM_NAME, or WM_NAME for clients that
+    /// don't set it.
+    pub fn get_window_title(&self, window: Window) -> Option<String> {
+        let net_wm_name = intern_atom(self.display, c"_NET_WM_NAME", false);
+        let title = TextProperty::get(self.display, window, net_wm_name)
+            .or_else(|| TextProperty::wm_name(self.display, window))?
+            .to_text();
+        (!title.is_empty()).then_some(title)
+    }
 
-                search_window = parent;
-                depth += 1;
+    /// The _NET_WM_STATE flags of `window`, lowercased without their
+    /// prefix: `fullscreen`, `maximized_vert`, `hidden` and so on, plus
+    /// `maximized` when it's maximized both ways.
+    pub fn get_window_states(&self, window: Window) -> Vec<String> {
+        let mut states = self.atom_names(window, self.net_wm_state_atom, "_NET_WM_STATE_");
+        if ["maximized_vert", "maximized_horz"]
+            .iter()
+            .all(|m| states.iter().any(|s| s == m))
+        {
+            states.push("maximized".to_string());
+        }
+        states
+    }
 
-                if depth > 20 {
-                    warn!("Window class search depth exceeded 20, stopping");
-                    return None;
-                }
+    /// The _NET_WM_WINDOW_TYPE of `window`, lowercased without the prefix:
+    /// `normal`, `dialog`, `popup_menu`, `tooltip` and so on, most specific
+    /// first.
+    pub fn get_window_types(&self, window: Window) -> Vec<String> {
+        self.atom_names(window, self.net_wm_window_type_atom, "_NET_WM_WINDOW_TYPE_")
+    }
+
+    /// The atoms of an atom-list property, named without `prefix` and
+    /// lowercased. Atoms without the prefix are left out.
+    fn atom_names(&self, window: Window, property: Atom, prefix: &str) -> Vec<String> {
+        if property == 0 {
+            return Vec::new();
+        }
+        let Some(property) = Property::get(self.display, window, property, xlib::XA_ATOM, 64)
+        else {
+            return Vec::new();
+        };
+        property
+            .longs()
+            .iter()
+            .filter_map(|&atom| atom_name(self.display, atom))
+            .filter_map(|name| name.strip_prefix(prefix).map(str::to_lowercase))
+            .collect()
+    }
+
+    /// The center of `window` in root window coordinates.
+    pub fn window_center(&self, window: Window) -> Option<(i32, i32)> {
+        unsafe {
+            let mut attributes: xlib::XWindowAttributes = std::mem::zeroed();
+            if xlib::XGetWindowAttributes(self.display, window, &mut attributes) == 0 {
+                return None;
             }
+            let (mut x, mut y, mut child) = (0, 0, 0);
+            let translated = xlib::XTranslateCoordinates(
+                self.display,
+                window,
+                attributes.root,
+                attributes.width / 2,
+                attributes.height / 2,
+                &mut x,
+                &mut y,
+                &mut child,
+            );
+            (translated != 0).then_some((x, y))
+        }
+    }
 
-            if prop.nitems > 0 && !prop.value.is_null() {
-                let class_str = CStr::from_ptr(prop.value as *const c_char)
-                    .to_string_lossy()
-                    .into_owned();
+    /// The window found by the last focus lookup.
+    pub fn current_window(&self) -> Option<Window> {
+        self.current_window
+    }
 
-                debug!("Found window class: '{}'", class_str);
+    /// Whether a PropertyNotify for `atom` on the focused window can mean
+    /// its _NET_WM_STATE flags changed.
+    pub fn is_state_property(&self, atom: Atom) -> bool {
+        atom == self.net_wm_state_atom
+    }
 
-                if !prop.value.is_null() {
-                    xlib::XFree(prop.value as *mut _);
-                }
+    /// Whether a PropertyNotify for `atom` on a root window can mean focus
+    /// moved. Other root properties change all the time and are ignored.
+    pub fn is_focus_property(&self, atom: Atom) -> bool {
+        atom == self.net_active_window_atom
+    }
 
-                Some(class_str)
-            } else {
-                debug!("No window class found");
-                None
+    /// Selects FocusChange events on `window` instead of the previously
+    /// watched one, so focus moves are noticed under window managers that
+    /// don't maintain _NET_ACTIVE_WINDOW, and PropertyChange events for its
+    /// _NET_WM_STATE.
+    pub fn watch_focus(&mut self, window: Option<Window>) {
+        let window = window.filter(|w| !self.roots.contains(w));
+        if window == self.watched_window {
+            return;
+        }
+        unsafe {
+            if let Some(old) = self.watched_window {
+                xlib::XSelectInput(self.display, old, xlib::NoEventMask);
+            }
+            if let Some(new) = window {
+                debug!("Watching focus changes on window={}", new);
+                xlib::XSelectInput(
+                    self.display,
+                    new,
+                    xlib::FocusChangeMask | xlib::PropertyChangeMask,
+                );
             }
         }
+        self.watched_window = window;
     }
 
     pub fn has_window_changed(&mut self) -> bool {
@@ -220,109 +339,213 @@ impl WindowManager {
     }
 
     fn try_get_class_direct(&self, window: Window) -> Option<String> {
-        unsafe {
-            // Try multiple property types commonly used for window class
-            let properties = [
-                self.wm_class_atom,
-                xlib::XInternAtom(
-                    self.display,
-                    b"_NET_WM_NAME\0".as_ptr() as *const c_char,
-                    xlib::False,
-                ),
-                xlib::XInternAtom(
-                    self.display,
-                    b"WM_NAME\0".as_ptr() as *const c_char,
-                    xlib::False,
-                ),
-            ];
-
-            for &atom in &properties {
-                let mut prop = XTextProperty {
-                    value: ptr::null_mut(),
-                    encoding: 0,
-                    format: 0,
-                    nitems: 0,
-                };
-
-                let status = xlib::XGetTextProperty(self.display, window, &mut prop, atom);
-
-                if status != 0 && prop.nitems > 0 && !prop.value.is_null() {
-                    let result = if prop.encoding == xlib::XA_STRING {
-                        CStr::from_ptr(prop.value as *const c_char)
-                            .to_string_lossy()
-                            .into_owned()
-                    } else {
-                        let mut list: *mut *mut c_char = ptr::null_mut();
-                        let mut count: c_int = 0;
-                        let convert_status = xlib::XmbTextPropertyToTextList(
-                            self.display,
-                            &prop,
-                            &mut list,
-                            &mut count,
-                        );
-
-                        if convert_status == xlib::Success as i32 && count > 0 && !list.is_null() {
-                            let first_str = *list;
-                            let result = CStr::from_ptr(first_str).to_string_lossy().into_owned();
-                            xlib::XFreeStringList(list);
-                            result
-                        } else {
-                            String::new()
-                        }
-                    };
-
-                    xlib::XFree(prop.value as *mut _);
-
-                    if !result.is_empty() {
-                        debug!("Found property value: '{}' from atom {}", result, atom);
-                        return Some(result);
-                    }
-                }
+        // Try multiple property types commonly used for window class
+        let properties = [
+            self.wm_class_atom,
+            intern_atom(self.display, c"_NET_WM_NAME", false),
+            intern_atom(self.display, c"WM_NAME", false),
+        ];
+
+        for &atom in &properties {
+            let Some(prop) = TextProperty::get(self.display, window, atom) else {
+                continue;
+            };
+            let result = prop.to_text();
+            if !result.is_empty() {
+                debug!("Found property value: '{}' from atom {}", result, atom);
+                return Some(result);
             }
-
-            None
         }
+
+        None
     }
 
-    fn find_focused_window(&self, parent: Window) -> Option<Window> {
-        unsafe {
-            let mut root: Window = 0;
-            let mut parent_return: Window = 0;
-            let mut children: *mut Window = ptr::null_mut();
-            let mut n_children: u32 = 0;
+    /// The parent of `window`, or `None` at the top of the tree.
+    fn parent_of(&self, window: Window) -> Option<Window> {
+        let (root, parent, _) = self.query_tree(window)?;
+        (parent != 0 && parent != root).then_some(parent)
+    }
 
+    /// XQueryTree, returning the root, parent and children of `window`.
+    fn query_tree(&self, window: Window) -> Option<(Window, Window, Vec<Window>)> {
+        let mut root: Window = 0;
+        let mut parent: Window = 0;
+        let mut children: *mut Window = ptr::null_mut();
+        let mut n_children: u32 = 0;
+
+        unsafe {
             let status = xlib::XQueryTree(
                 self.display,
-                parent,
+                window,
                 &mut root,
-                &mut parent_return,
+                &mut parent,
                 &mut children,
                 &mut n_children,
             );
 
-            if status == 0 || children.is_null() {
-                return None;
+            let child_list = if children.is_null() {
+                Vec::new()
+            } else {
+                let list = std::slice::from_raw_parts(children, n_children as usize).to_vec();
+                xlib::XFree(children as *mut _);
+                list
+            };
+
+            (status != 0).then_some((root, parent, child_list))
+        }
+    }
+
+    fn find_focused_window(&self, parent: Window) -> Option<Window> {
+        let (_, _, children) = self.query_tree(parent)?;
+
+        for child in children {
+            // Check if this window has WM_CLASS (indicates it's a real application window)
+            if self.try_get_class_direct(child).is_some() {
+                debug!("Found window with class: {}", child);
+                return Some(child);
             }
 
-            let children_slice = std::slice::from_raw_parts(children, n_children as usize);
+            // Recursively search children
+            if let Some(focused) = self.find_focused_window(child) {
+                return Some(focused);
+            }
+        }
 
-            for &child in children_slice {
-                // Check if this window has WM_CLASS (indicates it's a real application window)
-                if self.try_get_class_direct(child).is_some() {
-                    debug!("Found window with class: {}", child);
-                    xlib::XFree(children as *mut _);
-                    return Some(child);
-                }
+        None
+    }
+}
+
+impl XBackend for WindowManager {
+    fn active_window(&mut self) -> Option<Window> {
+        self.get_active_window()
+    }
+
+    fn window_class(&self, window: Window) -> Option<String> {
+        self.get_window_class(window)
+    }
+
+    fn window_role(&self, window: Window) -> Option<String> {
+        self.get_window_role(window)
+    }
+
+    fn window_states(&self, window: Window) -> Vec<String> {
+        self.get_window_states(window)
+    }
+
+    fn window_center(&self, window: Window) -> Option<(i32, i32)> {
+        WindowManager::window_center(self, window)
+    }
+
+    fn grab_keys(&mut self, window: Window, keys: &[KeyPress], sync: &[KeyPress]) -> Vec<KeyPress> {
+        let (display, lock_mask) = (self.display, self.lock_mask);
+        // Remember each request's serial to tell which key a BadAccess is for
+        let (requests, errors) = x_error::trap(display, || {
+            let mut requests = Vec::new();
+            for &key_press in keys {
+                debug!(
+                    "Grabbing key: keycode={}, modifiers={:#x}",
+                    key_press.keycode, key_press.modifiers
+                );
+                let keyboard_mode = if sync.contains(&key_press) {
+                    xlib::GrabModeSync
+                } else {
+                    xlib::GrabModeAsync
+                };
 
-                // Recursively search children
-                if let Some(focused) = self.find_focused_window(child) {
-                    xlib::XFree(children as *mut _);
-                    return Some(focused);
+                // Also grab with NumLock, CapsLock and ScrollLock on, which
+                // would otherwise make the modifiers not match
+                for modifiers in lock_variants(key_press.modifiers, lock_mask) {
+                    unsafe {
+                        requests.push((xlib::XNextRequest(display), key_press));
+                        xlib::XGrabKey(
+                            display,
+                            key_press.keycode as i32,
+                            modifiers,
+                            window,
+                            xlib::True,
+                            xlib::GrabModeAsync,
+                            keyboard_mode,
+                        );
+                    }
+                }
+            }
+            requests
+        });
+
+        let mut failed = Vec::new();
+        for x_error in errors.iter().filter(|e| e.is_bad_access()) {
+            if let Some(&(_, key)) = requests
+                .iter()
+                .find(|(serial, _)| *serial == x_error.serial)
+            {
+                if !failed.contains(&key) {
+                    failed.push(key);
                 }
             }
+        }
+        failed
+    }
 
-            xlib::XFree(children as *mut _);
-            None
+    fn ungrab_keys(&mut self, window: Window, keys: &[KeyPress]) {
+        let (display, lock_mask) = (self.display, self.lock_mask);
+        let ungrab = || {
+            for key_press in keys {
+                debug!(
+                    "Ungrabbing key: keycode={}, modifiers={:#x}",
+                    key_press.keycode, key_press.modifiers
+                );
+                for modifiers in lock_variants(key_press.modifiers, lock_mask) {
+                    unsafe {
+                        xlib::XUngrabKey(display, key_press.keycode as i32, modifiers, window);
+                    }
+                }
+            }
+        };
+        if self.roots.contains(&window) {
+            ungrab();
+            unsafe {
+                xlib::XFlush(display);
+            }
+        } else {
+            // A client window may be gone already
+            x_error::trap(display, ungrab);
         }
     }
 }
+
+/// `modifiers` with every combination of the bits in `lock_mask` added.
+fn lock_variants(modifiers: u32, lock_mask: u32) -> Vec<u32> {
+    let bits: Vec<u32> = (0..8)
+        .map(|index| 1 << index)
+        .filter(|bit| lock_mask & bit != 0)
+        .collect();
+    (0..1u32 << bits.len())
+        .map(|subset| {
+            bits.iter()
+                .enumerate()
+                .filter(|(i, _)| subset & (1 << i) != 0)
+                .fold(modifiers, |modifiers, (_, bit)| modifiers | bit)
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_lock_variants() {
+        let variants = lock_variants(xlib::ControlMask, xlib::LockMask | xlib::Mod2Mask);
+        assert_eq!(
+            variants,
+            vec![
+                xlib::ControlMask,
+                xlib::ControlMask | xlib::LockMask,
+                xlib::ControlMask | xlib::Mod2Mask,
+                xlib::ControlMask | xlib::LockMask | xlib::Mod2Mask,
+            ]
+        );
+        let scroll_lock = xlib::LockMask | xlib::Mod2Mask | xlib::Mod3Mask;
+        assert_eq!(lock_variants(0, scroll_lock).len(), 8);
+    }
+}
@@ -0,0 +1,168 @@
+use log::error;
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::os::raw::{c_int, c_ulong};
+use std::sync::Mutex;
+use x11::xlib::{self, Display, XErrorEvent, XID};
+
+/// How many errors [`recent`] keeps for inspection.
+const RECENT_LIMIT: usize = 32;
+
+/// An X protocol error reported through the Xlib error handler.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct XError {
+    pub error_code: u8,
+    pub request_code: u8,
+    pub minor_code: u8,
+    /// The window, atom or other resource the failed request referred to.
+    pub resource_id: XID,
+    pub serial: c_ulong,
+    /// Address of the connection that received the error.
+    pub(crate) display: usize,
+}
+
+impl XError {
+    pub fn is_bad_window(&self) -> bool {
+        self.error_code == xlib::BadWindow
+    }
+
+    /// What XGrabKey fails with when another client holds the grab.
+    pub fn is_bad_access(&self) -> bool {
+        self.error_code == xlib::BadAccess
+    }
+}
+
+struct ErrorLog {
+    pending: VecDeque<XError>,
+    recent: VecDeque<XError>,
+}
+
+static ERRORS: Mutex<ErrorLog> = Mutex::new(ErrorLog {
+    pending: VecDeque::new(),
+    recent: VecDeque::new(),
+});
+
+thread_local! {
+    /// Errors caught by the active [`trap`] calls on this thread, innermost
+    /// last. Xlib reports errors on the thread whose request caused them.
+    static TRAPS: RefCell<Vec<Vec<XError>>> = const { RefCell::new(Vec::new()) };
+}
+
+/// Installs the handler that queues X errors instead of exiting, which is
+/// Xlib's default.
+pub fn install_handler() {
+    unsafe {
+        xlib::XSetErrorHandler(Some(error_handler));
+    }
+}
+
+extern "C" fn error_handler(display: *mut Display, event: *mut XErrorEvent) -> c_int {
+    let event = unsafe { &*event };
+    let x_error = XError {
+        error_code: event.error_code,
+        request_code: event.request_code,
+        minor_code: event.minor_code,
+        resource_id: event.resourceid,
+        serial: event.serial,
+        display: display as usize,
+    };
+    error!(
+        "X11 Error: code={}, request={}, minor={}, resource={:#x}",
+        x_error.error_code, x_error.request_code, x_error.minor_code, x_error.resource_id
+    );
+    push(x_error);
+    0
+}
+
+fn push(x_error: XError) {
+    // The handler runs inside Xlib calls and must not panic, so a poisoned
+    // lock is used as is
+    let trapped = TRAPS.with(|traps| match traps.borrow_mut().last_mut() {
+        Some(trapped) => {
+            trapped.push(x_error);
+            true
+        }
+        None => false,
+    });
+
+    let mut errors = ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    if !trapped {
+        errors.pending.push_back(x_error);
+    }
+    if errors.recent.len() == RECENT_LIMIT {
+        errors.recent.pop_front();
+    }
+    errors.recent.push_back(x_error);
+}
+
+/// Runs `f` and returns the X errors its requests caused, instead of leaving
+/// them for the event loop. Syncs before and after so errors from earlier and
+/// from `f`'s own requests are told apart.
+#[allow(clippy::not_unsafe_ptr_arg_deref)]
+pub fn trap<T>(display: *mut Display, f: impl FnOnce() -> T) -> (T, Vec<XError>) {
+    unsafe {
+        xlib::XSync(display, xlib::False);
+    }
+    begin_trap();
+    let result = f();
+    unsafe {
+        xlib::XSync(display, xlib::False);
+    }
+    (result, end_trap())
+}
+
+fn begin_trap() {
+    TRAPS.with(|traps| traps.borrow_mut().push(Vec::new()));
+}
+
+fn end_trap() -> Vec<XError> {
+    TRAPS.with(|traps| traps.borrow_mut().pop().unwrap_or_default())
+}
+
+/// Removes and returns the errors reported since the last call.
+pub fn take_pending() -> Vec<XError> {
+    let mut errors = ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    errors.pending.drain(..).collect()
+}
+
+/// The last errors reported, oldest first, whether or not they were taken.
+pub fn recent() -> Vec<XError> {
+    let errors = ERRORS.lock().unwrap_or_else(|e| e.into_inner());
+    errors.recent.iter().copied().collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn bad_window(resource_id: XID) -> XError {
+        XError {
+            error_code: xlib::BadWindow,
+            request_code: 20,
+            minor_code: 0,
+            resource_id,
+            serial: 1,
+            display: 0,
+        }
+    }
+
+    #[test]
+    fn test_errors_are_queued_and_kept() {
+        let error = bad_window(0x1234);
+        push(error);
+
+        assert!(take_pending().contains(&error));
+        assert!(!take_pending().contains(&error));
+        assert!(recent().contains(&error));
+        assert!(error.is_bad_window());
+    }
+
+    #[test]
+    fn test_trapped_errors_skip_the_queue() {
+        let error = bad_window(0x5678);
+        begin_trap();
+        push(error);
+        assert_eq!(end_trap(), vec![error]);
+        assert!(!take_pending().contains(&error));
+    }
+}
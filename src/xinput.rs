@@ -0,0 +1,102 @@
+use log::debug;
+use std::ffi::CString;
+use std::fmt;
+use x11::xlib::{self, Display, XEvent};
+use x11_dl::xinput2::{self, XIEventMask, XIHierarchyEvent, XInput2};
+
+/// Keyboards coming and going, watched through XInput2 hierarchy events.
+/// libXi is loaded at runtime like XRandR, so the remapper still starts
+/// without it; keyboards plugged in then just aren't noticed.
+pub struct Hotplug {
+    display: *mut Display,
+    /// Kept loaded: libXi hooked its event decoding into Xlib.
+    _lib: XInput2,
+    opcode: i32,
+}
+
+impl fmt::Debug for Hotplug {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Hotplug")
+    }
+}
+
+impl Hotplug {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn open(display: *mut Display) -> Option<Self> {
+        let lib = match XInput2::open() {
+            Ok(lib) => lib,
+            Err(e) => {
+                debug!("libXi not available: {}", e);
+                return None;
+            }
+        };
+        let name = CString::new("XInputExtension").ok()?;
+        let (mut opcode, mut event_base, mut error_base) = (0, 0, 0);
+        let supported = unsafe {
+            xlib::XQueryExtension(
+                display,
+                name.as_ptr(),
+                &mut opcode,
+                &mut event_base,
+                &mut error_base,
+            )
+        } != 0;
+        let (mut major, mut minor) = (2, 0);
+        if !supported
+            || unsafe { (lib.XIQueryVersion)(display as *mut _, &mut major, &mut minor) }
+                != xlib::Success as i32
+        {
+            debug!("X server doesn't support XInput 2");
+            return None;
+        }
+
+        let mut mask = [0u8; 2];
+        xinput2::XISetMask(&mut mask, xinput2::XI_HierarchyChanged);
+        let mut event_mask = XIEventMask {
+            deviceid: xinput2::XIAllDevices,
+            mask_len: mask.len() as i32,
+            mask: mask.as_mut_ptr(),
+        };
+        unsafe {
+            let root = xlib::XDefaultRootWindow(display);
+            (lib.XISelectEvents)(display as *mut _, root, &mut event_mask, 1);
+        }
+        Some(Self {
+            display,
+            _lib: lib,
+            opcode,
+        })
+    }
+
+    /// Whether `event` comes from XInput2.
+    pub fn is_event(&self, event: &XEvent) -> bool {
+        event.get_type() == xlib::GenericEvent
+            && unsafe { event.generic_event_cookie.extension } == self.opcode
+    }
+
+    /// Whether `event` reports a keyboard plugged in, enabled or removed.
+    pub fn keyboards_changed(&self, event: &XEvent) -> bool {
+        if !self.is_event(event) {
+            return false;
+        }
+        let mut cookie = unsafe { event.generic_event_cookie };
+        if unsafe { xlib::XGetEventData(self.display, &mut cookie) } == 0 {
+            return false;
+        }
+        let mut changed = false;
+        if cookie.evtype == xinput2::XI_HierarchyChanged {
+            let hierarchy = unsafe { &*(cookie.data as *const XIHierarchyEvent) };
+            let infos = unsafe {
+                std::slice::from_raw_parts(hierarchy.info, hierarchy.num_info.max(0) as usize)
+            };
+            let flags = xinput2::XISlaveAdded | xinput2::XISlaveRemoved | xinput2::XIDeviceEnabled;
+            changed = infos
+                .iter()
+                .any(|info| info._use == xinput2::XISlaveKeyboard && info.flags & flags != 0);
+        }
+        unsafe {
+            xlib::XFreeEventData(self.display, &mut cookie);
+        }
+        changed
+    }
+}
@@ -0,0 +1,216 @@
+use crate::display::{intern_atom, Property};
+use log::{debug, warn};
+use std::os::raw::{c_int, c_uint, c_ulong};
+use x11::xlib::{self, Display, XEvent};
+
+/// `XkbUseCoreKbd` from XKB.h, which the x11 crate doesn't export.
+pub const XKB_USE_CORE_KBD: c_uint = 0x0100;
+
+/// Access to the XKB keyboard state: the active layout group and the layout
+/// names configured through setxkbmap.
+#[derive(Debug, Clone)]
+pub struct Xkb {
+    display: *mut Display,
+    event_base: Option<c_int>,
+    rules_names_atom: c_ulong,
+}
+
+impl Xkb {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn new(display: *mut Display) -> Self {
+        unsafe {
+            let mut opcode: c_int = 0;
+            let mut event_base: c_int = 0;
+            let mut error_base: c_int = 0;
+            let mut major: c_int = 1;
+            let mut minor: c_int = 0;
+
+            let available = xlib::XkbQueryExtension(
+                display,
+                &mut opcode,
+                &mut event_base,
+                &mut error_base,
+                &mut major,
+                &mut minor,
+            ) != 0;
+
+            let event_base = if available {
+                debug!(
+                    "XKB {}.{} available, event base {}",
+                    major, minor, event_base
+                );
+                xlib::XkbSelectEventDetails(
+                    display,
+                    XKB_USE_CORE_KBD,
+                    xlib::XkbStateNotify as c_uint,
+                    xlib::XkbGroupStateMask,
+                    xlib::XkbGroupStateMask,
+                );
+                // Report auto-repeat as repeated presses without releases in
+                // between, so a held key isn't mistaken for a string of taps
+                let mut supported: c_int = 0;
+                xlib::XkbSetDetectableAutoRepeat(display, xlib::True, &mut supported);
+                if supported == 0 {
+                    warn!("Detectable auto-repeat unsupported, held keys repeat as taps");
+                }
+                Some(event_base)
+            } else {
+                warn!("XKB extension not available, layout conditions will never match");
+                None
+            };
+
+            let rules_names_atom = intern_atom(display, c"_XKB_RULES_NAMES", false);
+
+            Self {
+                display,
+                event_base,
+                rules_names_atom,
+            }
+        }
+    }
+
+    /// Whether `event` reports a change of the active layout group.
+    pub fn is_group_change(&self, event: &XEvent) -> bool {
+        let Some(event_base) = self.event_base else {
+            return false;
+        };
+        if event.get_type() != event_base {
+            return false;
+        }
+        let xkb_event = unsafe { &*(event as *const XEvent as *const xlib::XkbAnyEvent) };
+        xkb_event.xkb_type == xlib::XkbStateNotify
+    }
+
+    pub fn current_group(&self) -> Option<usize> {
+        self.event_base?;
+        unsafe {
+            let mut state: xlib::XkbStateRec = std::mem::zeroed();
+            if xlib::XkbGetState(self.display, XKB_USE_CORE_KBD, &mut state) != 0 {
+                return None;
+            }
+            Some(state.group as usize)
+        }
+    }
+
+    /// Name of the active layout, e.g. `us` or `de`.
+    pub fn current_layout(&self) -> Option<String> {
+        let group = self.current_group()?;
+        self.layout_names().into_iter().nth(group)
+    }
+
+    /// Locks the layout group selected by `target`, like setxkbmap's group
+    /// switching keys would.
+    pub fn switch_layout(&self, target: &LayoutTarget) {
+        let Some(current) = self.current_group() else {
+            warn!("Cannot switch layout without XKB");
+            return;
+        };
+        let layouts = self.layout_names();
+        let count = layouts.len().max(1);
+
+        let group = match target {
+            LayoutTarget::Next => (current + 1) % count,
+            LayoutTarget::Previous => (current + count - 1) % count,
+            LayoutTarget::Name(name) => {
+                match layouts.iter().position(|l| l.eq_ignore_ascii_case(name)) {
+                    Some(group) => group,
+                    None => {
+                        warn!("Layout '{}' is not configured (have {:?})", name, layouts);
+                        return;
+                    }
+                }
+            }
+        };
+
+        debug!("Locking layout group {} ({:?})", group, layouts.get(group));
+        unsafe {
+            xlib::XkbLockGroup(self.display, XKB_USE_CORE_KBD, group as c_uint);
+            xlib::XFlush(self.display);
+        }
+    }
+
+    /// Locks or unlocks the modifiers in `mask`, e.g. to undo the CapsLock
+    /// toggle of a remapped Caps_Lock key.
+    pub fn set_locked_modifiers(&self, mask: u32, locked: bool) {
+        if self.event_base.is_none() {
+            return;
+        }
+        unsafe {
+            xlib::XkbLockModifiers(
+                self.display,
+                XKB_USE_CORE_KBD,
+                mask,
+                if locked { mask } else { 0 },
+            );
+            xlib::XFlush(self.display);
+        }
+    }
+
+    pub fn layout_names(&self) -> Vec<String> {
+        let root = unsafe { xlib::XDefaultRootWindow(self.display) };
+        Property::get(
+            self.display,
+            root,
+            self.rules_names_atom,
+            xlib::XA_STRING,
+            1024,
+        )
+        .map(|prop| parse_rules_layouts(prop.bytes()))
+        .unwrap_or_default()
+    }
+}
+
+/// Which layout a `{layout: ...}` action switches to.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum LayoutTarget {
+    Next,
+    Previous,
+    Name(String),
+}
+
+impl LayoutTarget {
+    pub fn parse(value: &str) -> Self {
+        match value {
+            "next" => LayoutTarget::Next,
+            "prev" | "previous" => LayoutTarget::Previous,
+            name => LayoutTarget::Name(name.to_string()),
+        }
+    }
+}
+
+/// `_XKB_RULES_NAMES` holds NUL-separated rules, model, layout, variant and
+/// options; the layout field lists one comma-separated name per group.
+fn parse_rules_layouts(data: &[u8]) -> Vec<String> {
+    data.split(|&b| b == 0)
+        .nth(2)
+        .map(|layouts| {
+            String::from_utf8_lossy(layouts)
+                .split(',')
+                .map(|layout| layout.trim().to_string())
+                .filter(|layout| !layout.is_empty())
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_rules_layouts() {
+        let data = b"evdev\0pc105\0us,de\0,nodeadkeys\0grp:alt_shift_toggle\0";
+        assert_eq!(parse_rules_layouts(data), vec!["us", "de"]);
+        assert!(parse_rules_layouts(b"evdev\0pc105").is_empty());
+    }
+
+    #[test]
+    fn test_layout_target_parse() {
+        assert_eq!(LayoutTarget::parse("next"), LayoutTarget::Next);
+        assert_eq!(LayoutTarget::parse("prev"), LayoutTarget::Previous);
+        assert_eq!(
+            LayoutTarget::parse("de"),
+            LayoutTarget::Name("de".to_string())
+        );
+    }
+}
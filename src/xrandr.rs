@@ -0,0 +1,61 @@
+use crate::display::atom_name;
+use log::debug;
+use std::fmt;
+use x11::xlib::{self, Display};
+use x11_dl::xrandr::Xrandr as XrandrLib;
+
+/// The RandR monitor list, loaded at runtime like XTest so the remapper still
+/// starts without libXrandr. Monitors are named after their outputs, as
+/// `xrandr --listmonitors` shows them (`DP-1`, `HDMI-A-0`).
+pub struct Monitors {
+    display: *mut Display,
+    lib: XrandrLib,
+}
+
+impl fmt::Debug for Monitors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Monitors")
+    }
+}
+
+impl Monitors {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn open(display: *mut Display) -> Option<Self> {
+        let lib = match XrandrLib::open() {
+            Ok(lib) => lib,
+            Err(e) => {
+                debug!("libXrandr not available: {}", e);
+                return None;
+            }
+        };
+        let (mut event_base, mut error_base) = (0, 0);
+        let supported =
+            unsafe { (lib.XRRQueryExtension)(display as *mut _, &mut event_base, &mut error_base) }
+                != 0;
+        if !supported {
+            debug!("X server doesn't support the RANDR extension");
+            return None;
+        }
+        Some(Self { display, lib })
+    }
+
+    /// The name of the monitor containing the point `(x, y)` of the root
+    /// window.
+    pub fn monitor_at(&self, x: i32, y: i32) -> Option<String> {
+        unsafe {
+            let root = xlib::XDefaultRootWindow(self.display);
+            let mut count = 0;
+            let monitors =
+                (self.lib.XRRGetMonitors)(self.display as *mut _, root, xlib::True, &mut count);
+            if monitors.is_null() {
+                return None;
+            }
+            let name = std::slice::from_raw_parts(monitors, count.max(0) as usize)
+                .iter()
+                .find(|m| x >= m.x && x < m.x + m.width && y >= m.y && y < m.y + m.height)
+                .and_then(|m| atom_name(self.display, m.name));
+            (self.lib.XRRFreeMonitors)(monitors);
+            name
+        }
+    }
+}
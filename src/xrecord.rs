@@ -0,0 +1,245 @@
+use crate::display::DisplayHandle;
+use log::{debug, warn};
+use std::cell::RefCell;
+use std::fmt;
+use std::os::raw::{c_char, c_int};
+use x11::xlib::{self, Display, KeyCode, Time};
+use x11_dl::xrecord::{
+    XRecordAllClients, XRecordContext, XRecordFromServer, XRecordInterceptData,
+    Xf86vmode as XRecordLib,
+};
+
+/// A key event of any client, seen through XRecord.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct RecordedKey {
+    pub keycode: KeyCode,
+    pub state: u32,
+    pub pressed: bool,
+    /// The server time of the event, in milliseconds.
+    pub time: Time,
+}
+
+/// What the intercept callback writes to, shared with it through the
+/// closure pointer.
+struct Shared {
+    events: RefCell<Vec<RecordedKey>>,
+    free_data: unsafe extern "C" fn(*mut XRecordInterceptData),
+}
+
+/// Watches the keyboard with the RECORD extension, loaded at runtime like
+/// XTest. Recording sees every key without grabbing it, so other clients
+/// keep their combos, but it can't stop a key from reaching the window.
+pub struct Recorder {
+    lib: XRecordLib,
+    context: XRecordContext,
+    shared: Box<Shared>,
+    // Recording needs a connection of its own to stream the data over, and
+    // another one to create and stop the context
+    data: DisplayHandle,
+    control: DisplayHandle,
+}
+
+impl fmt::Debug for Recorder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("Recorder")
+    }
+}
+
+impl Recorder {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn open(display: *mut Display) -> Option<Self> {
+        let lib = match XRecordLib::open() {
+            Ok(lib) => lib,
+            Err(e) => {
+                debug!("libXtst not available for XRecord: {}", e);
+                return None;
+            }
+        };
+        let name = DisplayHandle::name_of(display);
+        let control = DisplayHandle::open(&name)?;
+        let data = DisplayHandle::open(&name)?;
+
+        unsafe {
+            let (mut major, mut minor) = (0, 0);
+            if (lib.XRecordQueryVersion)(control.as_ptr() as *mut _, &mut major, &mut minor) == 0 {
+                warn!("X server doesn't support the RECORD extension");
+                return None;
+            }
+            debug!("RECORD extension version {}.{}", major, minor);
+
+            let range = (lib.XRecordAllocRange)();
+            if range.is_null() {
+                return None;
+            }
+            (*range).device_events.first = xlib::KeyPress as u8;
+            (*range).device_events.last = xlib::KeyRelease as u8;
+            let mut clients = XRecordAllClients;
+            let mut ranges = range;
+            let context = (lib.XRecordCreateContext)(
+                control.as_ptr() as *mut _,
+                0,
+                &mut clients,
+                1,
+                &mut ranges,
+                1,
+            );
+            xlib::XFree(range as *mut _);
+            if context == 0 {
+                warn!("Failed to create XRecord context");
+                return None;
+            }
+            xlib::XSync(control.as_ptr(), xlib::False);
+
+            let shared = Box::new(Shared {
+                events: RefCell::new(Vec::new()),
+                free_data: lib.XRecordFreeData,
+            });
+            let enabled = (lib.XRecordEnableContextAsync)(
+                data.as_ptr() as *mut _,
+                context,
+                Some(intercept),
+                &*shared as *const Shared as *mut c_char,
+            );
+            if enabled == 0 {
+                warn!("Failed to enable XRecord context");
+                (lib.XRecordFreeContext)(control.as_ptr() as *mut _, context);
+                return None;
+            }
+
+            Some(Self {
+                lib,
+                context,
+                shared,
+                data,
+                control,
+            })
+        }
+    }
+
+    /// The connection recorded keys arrive on, to wait for with poll.
+    pub fn fd(&self) -> c_int {
+        unsafe { xlib::XConnectionNumber(self.data.as_ptr()) }
+    }
+
+    /// Reads what arrived on the connection and returns the keys seen since
+    /// the last call.
+    pub fn take_keys(&self) -> Vec<RecordedKey> {
+        unsafe {
+            (self.lib.XRecordProcessReplies)(self.data.as_ptr() as *mut _);
+        }
+        std::mem::take(&mut *self.shared.events.borrow_mut())
+    }
+}
+
+impl Drop for Recorder {
+    fn drop(&mut self) {
+        unsafe {
+            let control = self.control.as_ptr() as *mut _;
+            (self.lib.XRecordDisableContext)(control, self.context);
+            (self.lib.XRecordFreeContext)(control, self.context);
+            xlib::XSync(self.control.as_ptr(), xlib::False);
+        }
+    }
+}
+
+unsafe extern "C" fn intercept(closure: *mut c_char, data: *mut XRecordInterceptData) {
+    let shared = &*(closure as *const Shared);
+    let intercepted = &*data;
+    if intercepted.category == XRecordFromServer && !intercepted.data.is_null() {
+        // data_len counts 4-byte units
+        let bytes = std::slice::from_raw_parts(intercepted.data, intercepted.data_len as usize * 4);
+        if let Some(key) = parse_key_event(bytes, intercepted.client_swapped != 0) {
+            shared.events.borrow_mut().push(key);
+        }
+    }
+    (shared.free_data)(data);
+}
+
+/// Removes the keys `injected` lists from `keys`, and those entries from
+/// `injected`.
+pub fn drop_injected(
+    keys: Vec<RecordedKey>,
+    injected: &mut Vec<(KeyCode, bool)>,
+) -> Vec<RecordedKey> {
+    keys.into_iter()
+        .filter(|key| {
+            let position = injected
+                .iter()
+                .position(|&event| event == (key.keycode, key.pressed));
+            position.map(|i| injected.remove(i)).is_none()
+        })
+        .collect()
+}
+
+/// Decodes a core protocol KeyPress or KeyRelease (xKeyButtonPointer): the
+/// type, the keycode as detail, the time at offset 4 and the modifier state
+/// at offset 28.
+fn parse_key_event(bytes: &[u8], swapped: bool) -> Option<RecordedKey> {
+    if bytes.len() < 32 {
+        return None;
+    }
+    let pressed = match bytes[0] as c_int & 0x7f {
+        xlib::KeyPress => true,
+        xlib::KeyRelease => false,
+        _ => return None,
+    };
+    let mut time = u32::from_ne_bytes([bytes[4], bytes[5], bytes[6], bytes[7]]);
+    let mut state = u16::from_ne_bytes([bytes[28], bytes[29]]);
+    if swapped {
+        time = time.swap_bytes();
+        state = state.swap_bytes();
+    }
+    Some(RecordedKey {
+        keycode: bytes[1],
+        state: state as u32,
+        pressed,
+        time: time as Time,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_parse_key_event() {
+        let mut bytes = [0u8; 32];
+        bytes[0] = xlib::KeyPress as u8;
+        bytes[1] = 56;
+        bytes[4..8].copy_from_slice(&1500u32.to_ne_bytes());
+        bytes[28..30].copy_from_slice(&(xlib::ControlMask as u16).to_ne_bytes());
+
+        assert_eq!(
+            parse_key_event(&bytes, false),
+            Some(RecordedKey {
+                keycode: 56,
+                state: xlib::ControlMask,
+                pressed: true,
+                time: 1500,
+            })
+        );
+
+        bytes[0] = xlib::MotionNotify as u8;
+        assert_eq!(parse_key_event(&bytes, false), None);
+        assert_eq!(parse_key_event(&bytes[..8], false), None);
+    }
+
+    #[test]
+    fn test_drop_injected() {
+        let recorded = |keycode, pressed| RecordedKey {
+            keycode,
+            state: 0,
+            pressed,
+            time: 0,
+        };
+        let keys = vec![
+            recorded(56, true),
+            recorded(113, true),
+            recorded(113, false),
+        ];
+        let mut injected = vec![(113, true), (113, false), (114, true)];
+        let kept = drop_injected(keys, &mut injected);
+        assert_eq!(kept, [recorded(56, true)]);
+        assert_eq!(injected, [(114, true)]);
+    }
+}
@@ -0,0 +1,119 @@
+use log::{debug, warn};
+use std::cell::RefCell;
+use std::fmt;
+use std::os::raw::{c_int, c_uint};
+use x11::xlib::{self, Display, KeyCode};
+use x11_dl::xtest::Xf86vmode as XTestLib;
+
+/// The XTest extension, loaded at runtime so the remapper still starts where
+/// libXtst isn't installed. Unlike XSendEvent, XTest input goes through the
+/// server like a real key press and triggers other clients' key grabs.
+pub struct XTest {
+    display: *mut Display,
+    lib: XTestLib,
+    /// The keys injected since the last `take_injected`, so keys seen
+    /// through XRecord can be told from our own.
+    injected: RefCell<Vec<(KeyCode, bool)>>,
+}
+
+impl fmt::Debug for XTest {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("XTest")
+    }
+}
+
+impl XTest {
+    #[allow(clippy::not_unsafe_ptr_arg_deref)]
+    pub fn open(display: *mut Display) -> Option<Self> {
+        let lib = match XTestLib::open() {
+            Ok(lib) => lib,
+            Err(e) => {
+                debug!("libXtst not available: {}", e);
+                return None;
+            }
+        };
+
+        let (mut event_base, mut error_base, mut major, mut minor) = (0, 0, 0, 0);
+        let supported = unsafe {
+            (lib.XTestQueryExtension)(
+                display as *mut _,
+                &mut event_base,
+                &mut error_base,
+                &mut major,
+                &mut minor,
+            )
+        } != 0;
+
+        if !supported {
+            warn!("X server doesn't support the XTEST extension");
+            return None;
+        }
+        debug!("XTEST {}.{} available", major, minor);
+        Some(Self {
+            display,
+            lib,
+            injected: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// Presses and releases `keycode`. Callers release their keyboard grab
+    /// first, or the fake events come straight back to the grabbing client.
+    pub fn tap_key(&self, keycode: KeyCode) {
+        unsafe {
+            self.fake_key(keycode, true);
+            self.fake_key(keycode, false);
+            xlib::XFlush(self.display);
+        }
+    }
+
+    /// Presses `keycode` and leaves it down until `release_key`, so modifier
+    /// keys apply to whatever the user types meanwhile.
+    pub fn press_key(&self, keycode: KeyCode) {
+        self.fake_key(keycode, true);
+        unsafe {
+            xlib::XFlush(self.display);
+        }
+    }
+
+    pub fn release_key(&self, keycode: KeyCode) {
+        self.fake_key(keycode, false);
+        unsafe {
+            xlib::XFlush(self.display);
+        }
+    }
+
+    /// Clicks pointer `button` `count` times wherever the pointer is.
+    pub fn click_button(&self, button: u32, count: u32) {
+        for _ in 0..count {
+            for press in [true, false] {
+                unsafe {
+                    (self.lib.XTestFakeButtonEvent)(
+                        self.display as *mut _,
+                        button as c_uint,
+                        press as c_int,
+                        xlib::CurrentTime,
+                    );
+                }
+            }
+        }
+        unsafe {
+            xlib::XFlush(self.display);
+        }
+    }
+
+    pub fn take_injected(&self) -> Vec<(KeyCode, bool)> {
+        std::mem::take(&mut *self.injected.borrow_mut())
+    }
+
+    fn fake_key(&self, keycode: KeyCode, press: bool) {
+        self.injected.borrow_mut().push((keycode, press));
+        unsafe {
+            (self.lib.XTestFakeKeyEvent)(
+                self.display as *mut _,
+                keycode as c_uint,
+                press as c_int,
+                xlib::CurrentTime,
+            );
+        }
+    }
+}
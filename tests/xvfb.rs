@@ -0,0 +1,393 @@
+//! End-to-end tests against a real X server. Each test starts Xvfb, maps a
+//! window of a known class, runs the remapper on that display and types keys
+//! through XTest, then checks what the window received.
+//!
+//! They need `Xvfb` and libXtst, so they only build with the `xvfb-tests`
+//! feature:
+//!
+//! ```sh
+//! RUSTFLAGS="-lX11" cargo test --features xvfb-tests --test xvfb
+//! ```
+
+#![cfg(feature = "xvfb-tests")]
+
+use simple_x11_remapper::{Config, Remapper, ShutdownHandle};
+use std::ffi::CString;
+use std::path::Path;
+use std::process::{Child, Command, Stdio};
+use std::sync::{mpsc, Mutex, MutexGuard};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+use x11::keysym;
+use x11::xlib::{self, Display, KeySym, Window};
+use x11_dl::xtest::Xf86vmode as XTestLib;
+
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Tests pick display numbers by looking for free ones and share the one
+/// keyboard of their server, so they run one at a time.
+static SERIAL: Mutex<()> = Mutex::new(());
+
+fn serial() -> MutexGuard<'static, ()> {
+    SERIAL.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+/// An X connection, closed when dropped.
+struct Connection(*mut Display);
+
+impl Connection {
+    fn open(name: &str) -> Option<Self> {
+        let name = CString::new(name).unwrap();
+        let display = unsafe { xlib::XOpenDisplay(name.as_ptr()) };
+        (!display.is_null()).then_some(Self(display))
+    }
+}
+
+impl Drop for Connection {
+    fn drop(&mut self) {
+        unsafe {
+            xlib::XCloseDisplay(self.0);
+        }
+    }
+}
+
+/// A private X server, killed when dropped.
+struct Xvfb {
+    child: Child,
+    name: String,
+}
+
+impl Xvfb {
+    fn start() -> Self {
+        let number = (90..200)
+            .find(|n| {
+                !Path::new(&format!("/tmp/.X11-unix/X{}", n)).exists()
+                    && !Path::new(&format!("/tmp/.X{}-lock", n)).exists()
+            })
+            .expect("no free display number");
+        let name = format!(":{}", number);
+        let child = Command::new("Xvfb")
+            .args([&name, "-screen", "0", "1024x768x24", "-nolisten", "tcp"])
+            .stdout(Stdio::null())
+            .stderr(Stdio::null())
+            .spawn()
+            .expect("failed to start Xvfb, is it installed?");
+        let xvfb = Self { child, name };
+
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            if Connection::open(&xvfb.name).is_some() {
+                return xvfb;
+            }
+            thread::sleep(Duration::from_millis(50));
+        }
+        panic!("Xvfb on {} didn't accept connections", xvfb.name);
+    }
+
+    /// Presses `modifiers`, taps `key` and releases the modifiers again,
+    /// like a user typing the combination.
+    fn type_key(&self, modifiers: &[u32], key: u32) {
+        let mut events: Vec<(u32, bool)> = modifiers.iter().map(|&m| (m, true)).collect();
+        events.extend([(key, true), (key, false)]);
+        events.extend(modifiers.iter().rev().map(|&m| (m, false)));
+        self.fake_keys(&events);
+    }
+
+    /// Presses (`true`) and releases keys by keysym, in order.
+    fn fake_keys(&self, events: &[(u32, bool)]) {
+        let display = Connection::open(&self.name).unwrap();
+        let xtest = XTestLib::open().expect("libXtst not available");
+        for &(keysym, press) in events {
+            unsafe {
+                let keycode = xlib::XKeysymToKeycode(display.0, keysym as KeySym);
+                assert_ne!(keycode, 0, "no key for keysym {:#x}", keysym);
+                (xtest.XTestFakeKeyEvent)(display.0 as *mut _, keycode as u32, press as i32, 0);
+            }
+        }
+        unsafe {
+            xlib::XSync(display.0, xlib::False);
+        }
+    }
+}
+
+impl Drop for Xvfb {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+/// A mapped window with the input focus, recording the keys it receives.
+struct TestWindow {
+    display: Connection,
+    window: Window,
+}
+
+impl TestWindow {
+    /// Maps a window of class `class`, focuses it and marks it active in
+    /// `_NET_ACTIVE_WINDOW`, as a window manager would.
+    fn open(server: &Xvfb, class: &str) -> Self {
+        let display = Connection::open(&server.name).unwrap();
+        let d = display.0;
+        unsafe {
+            let root = xlib::XDefaultRootWindow(d);
+            let window = xlib::XCreateSimpleWindow(d, root, 0, 0, 200, 100, 0, 0, 0);
+            let class = CString::new(class).unwrap();
+            let mut hint = xlib::XClassHint {
+                res_name: class.as_ptr() as *mut _,
+                res_class: class.as_ptr() as *mut _,
+            };
+            xlib::XSetClassHint(d, window, &mut hint);
+            xlib::XSelectInput(
+                d,
+                window,
+                xlib::KeyPressMask | xlib::KeyReleaseMask | xlib::StructureNotifyMask,
+            );
+            xlib::XMapWindow(d, window);
+            let mut event: xlib::XEvent = std::mem::zeroed();
+            while event.get_type() != xlib::MapNotify {
+                xlib::XWindowEvent(d, window, xlib::StructureNotifyMask, &mut event);
+            }
+
+            xlib::XSetInputFocus(d, window, xlib::RevertToParent, xlib::CurrentTime);
+            let active = xlib::XInternAtom(d, c"_NET_ACTIVE_WINDOW".as_ptr(), xlib::False);
+            xlib::XChangeProperty(
+                d,
+                root,
+                active,
+                xlib::XA_WINDOW,
+                32,
+                xlib::PropModeReplace,
+                &window as *const Window as *const u8,
+                1,
+            );
+            xlib::XSync(d, xlib::False);
+            Self { display, window }
+        }
+    }
+
+    /// The keysym (unshifted) and state of the next key press the window
+    /// gets, skipping modifier keys.
+    fn next_key_press(&self) -> Option<(u32, u32)> {
+        self.next_key_event()
+            .map(|(keysym, key)| (keysym, key.state))
+    }
+
+    /// Like `next_key_press`, with the whole event.
+    fn next_key_event(&self) -> Option<(u32, xlib::XKeyEvent)> {
+        let d = self.display.0;
+        let deadline = Instant::now() + TIMEOUT;
+        while Instant::now() < deadline {
+            let mut event: xlib::XEvent = unsafe { std::mem::zeroed() };
+            let found =
+                unsafe { xlib::XCheckWindowEvent(d, self.window, xlib::KeyPressMask, &mut event) };
+            if found == 0 {
+                thread::sleep(Duration::from_millis(10));
+                continue;
+            }
+            let mut key = unsafe { event.key };
+            let keysym = unsafe { xlib::XLookupKeysym(&mut key, 0) } as u32;
+            if !(keysym::XK_Shift_L..=keysym::XK_Hyper_R).contains(&keysym) {
+                return Some((keysym, key));
+            }
+        }
+        None
+    }
+}
+
+/// The remapper running on its own thread, stopped when dropped.
+struct RunningRemapper {
+    shutdown: ShutdownHandle,
+    thread: Option<JoinHandle<()>>,
+}
+
+impl RunningRemapper {
+    fn start(server: &Xvfb, yaml: &str) -> Self {
+        Self::start_with(server, yaml, None)
+    }
+
+    /// Starts the remapper with its config on trial for `revert_after`.
+    fn start_with(server: &Xvfb, yaml: &str, revert_after: Option<Duration>) -> Self {
+        let config = Config::from_yaml(yaml).expect("invalid test config");
+        let name = server.name.clone();
+        let (sender, receiver) = mpsc::channel();
+        let thread = thread::spawn(move || {
+            let mut builder = Remapper::builder().config(config).display(name);
+            if let Some(revert_after) = revert_after {
+                builder = builder.trial(revert_after);
+            }
+            let mut remapper = builder.build().expect("failed to start the remapper");
+            sender.send(remapper.shutdown_handle()).unwrap();
+            remapper.run().expect("remapper failed");
+        });
+        let shutdown = receiver
+            .recv_timeout(TIMEOUT)
+            .expect("remapper didn't start");
+        // Give the event loop a moment to flush its grabs
+        thread::sleep(Duration::from_millis(200));
+        Self {
+            shutdown,
+            thread: Some(thread),
+        }
+    }
+}
+
+impl Drop for RunningRemapper {
+    fn drop(&mut self) {
+        self.shutdown.shutdown();
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[test]
+fn remaps_keys_in_matching_window() {
+    let _serial = serial();
+    let server = Xvfb::start();
+    let window = TestWindow::open(&server, "remap-test");
+    let _remapper = RunningRemapper::start(
+        &server,
+        r#"
+windows:
+  - class_only: ['remap-test']
+    remaps:
+      - 'C-b': 'Left'
+"#,
+    );
+
+    server.type_key(&[keysym::XK_Control_L], keysym::XK_b);
+    let (keysym, state) = window.next_key_press().expect("no key reached the window");
+    assert_eq!(keysym, keysym::XK_Left);
+    assert_eq!(state & xlib::ControlMask, 0, "Control leaked into Left");
+}
+
+#[test]
+fn leaves_other_windows_alone() {
+    let _serial = serial();
+    let server = Xvfb::start();
+    let window = TestWindow::open(&server, "other-app");
+    let _remapper = RunningRemapper::start(
+        &server,
+        r#"
+windows:
+  - class_only: ['remap-test']
+    remaps:
+      - 'C-b': 'Left'
+"#,
+    );
+
+    server.type_key(&[keysym::XK_Control_L], keysym::XK_b);
+    let (keysym, state) = window.next_key_press().expect("no key reached the window");
+    assert_eq!(keysym, keysym::XK_b);
+    assert_ne!(state & xlib::ControlMask, 0);
+}
+
+#[test]
+fn sends_sequences_with_their_modifiers() {
+    let _serial = serial();
+    let server = Xvfb::start();
+    let window = TestWindow::open(&server, "remap-test");
+    let _remapper = RunningRemapper::start(&server, "global: [{'C-t': ['a', 'S-b']}]");
+
+    server.type_key(&[keysym::XK_Control_L], keysym::XK_t);
+    let (keysym, state) = window.next_key_press().expect("no key reached the window");
+    assert_eq!((keysym, state & xlib::ShiftMask), (keysym::XK_a, 0));
+    let (keysym, state) = window.next_key_press().expect("second key missing");
+    assert_eq!(keysym, keysym::XK_b);
+    assert_ne!(state & xlib::ShiftMask, 0);
+    assert_eq!(state & xlib::ControlMask, 0, "Control leaked into S-b");
+}
+
+#[test]
+fn sends_delayed_sequences_to_send_to_windows() {
+    let _serial = serial();
+    let server = Xvfb::start();
+    let player = TestWindow::open(&server, "player");
+    let _focused = TestWindow::open(&server, "remap-test");
+    let _remapper = RunningRemapper::start(
+        &server,
+        "global:\n  - 'F5': ['a', {delay: 20}, 'b']\n    send_to: {class: 'player'}\n",
+    );
+
+    server.type_key(&[], keysym::XK_F5);
+    for expected in [keysym::XK_a, keysym::XK_b] {
+        let (keysym, key) = player
+            .next_key_event()
+            .expect("no key reached send_to window");
+        assert_eq!(keysym, expected);
+        assert_ne!(key.send_event, 0, "key wasn't sent with XSendEvent");
+    }
+}
+
+#[test]
+fn rolls_over_home_row_mods() {
+    let _serial = serial();
+    let server = Xvfb::start();
+    let window = TestWindow::open(&server, "remap-test");
+    let _remapper = RunningRemapper::start(&server, "home_row_mods: {a: Super, s: Alt}");
+
+    // Rolling from a to s types both, and s doesn't stay held as Alt
+    server.fake_keys(&[
+        (keysym::XK_a, true),
+        (keysym::XK_s, true),
+        (keysym::XK_a, false),
+        (keysym::XK_s, false),
+    ]);
+    server.type_key(&[], keysym::XK_j);
+    for expected in [keysym::XK_a, keysym::XK_s, keysym::XK_j] {
+        let (keysym, state) = window.next_key_press().expect("no key reached the window");
+        assert_eq!(keysym, expected);
+        assert_eq!(
+            state & (xlib::Mod1Mask | xlib::Mod4Mask),
+            0,
+            "a modifier stuck"
+        );
+    }
+}
+
+#[cfg(feature = "scripting")]
+#[test]
+fn confirm_key_thaws_conditional_remap_of_it() {
+    let _serial = serial();
+    let server = Xvfb::start();
+    let window = TestWindow::open(&server, "remap-test");
+    let script = std::env::temp_dir().join(format!("remapper-replay-{}.rhai", std::process::id()));
+    std::fs::write(&script, "false\n").unwrap();
+    let yaml = format!(
+        r#"
+remaps:
+  - 'C-M-Return': {{script: '{}'}}
+    conditional: true
+"#,
+        script.display()
+    );
+    let _remapper = RunningRemapper::start_with(&server, &yaml, Some(Duration::from_secs(60)));
+
+    // The confirm key is taken over the synchronous grab of its remap, and
+    // the keyboard doesn't stay frozen after it
+    server.type_key(&[keysym::XK_Control_L, keysym::XK_Alt_L], keysym::XK_Return);
+    server.type_key(&[], keysym::XK_j);
+    let (keysym, _) = window.next_key_press().expect("the keyboard froze");
+    assert_eq!(keysym, keysym::XK_j);
+    std::fs::remove_file(&script).unwrap();
+}
+
+#[test]
+fn one_shot_tapped_twice_is_cancelled() {
+    let _serial = serial();
+    let server = Xvfb::start();
+    let window = TestWindow::open(&server, "remap-test");
+    let _remapper = RunningRemapper::start(&server, "global: [{'F12': {one_shot: 'Shift'}}]");
+
+    server.type_key(&[], keysym::XK_F12);
+    server.type_key(&[], keysym::XK_F12);
+    server.type_key(&[], keysym::XK_j);
+    let (keysym, state) = window.next_key_press().expect("no key reached the window");
+    assert_eq!(keysym, keysym::XK_j);
+    assert_eq!(
+        state & xlib::ShiftMask,
+        0,
+        "the one-shot Shift wasn't cancelled"
+    );
+}